@@ -0,0 +1,341 @@
+use crate::common::pools::{DexVariant, Pool};
+use revm::primitives::Address;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Longest cycle the search will enumerate. Every hop is another full swap to price
+/// and simulate, so anything past 4 is rarely worth the gas.
+pub const MAX_HOPS: usize = 4;
+/// Shortest cycle the search will enumerate. Two-pool routes are handled by `LogEvent`.
+pub const MIN_HOPS: usize = 3;
+/// Cap on routes emitted for a single swap log, so a log on a hub pool can't stall the stream
+pub const MAX_ROUTES_PER_LOG: usize = 64;
+
+/// One direction of a pool, seen from the token being sold
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hop {
+    pub pool: Address,
+    pub variant: DexVariant,
+    pub fee: u32,
+    pub token_in: Address,
+    pub token_out: Address,
+}
+
+/// A closed cycle of swaps that starts and ends on the same token
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub hops: Vec<Hop>,
+}
+
+impl Route {
+    pub fn start_token(&self) -> Address {
+        self.hops[0].token_in
+    }
+
+    pub fn contains_pool(&self, pool: Address) -> bool {
+        self.hops.iter().any(|hop| hop.pool == pool)
+    }
+
+    /// Rotate the cycle so that it starts by selling `token`
+    fn rotated_to(mut self, token: Address) -> Self {
+        if let Some(start) = self.hops.iter().position(|hop| hop.token_in == token) {
+            self.hops.rotate_left(start);
+        }
+        self
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.start_token())?;
+        for hop in &self.hops {
+            write!(
+                f,
+                " -[{:?} {} {:?}]-> {:?}",
+                hop.variant, hop.fee, hop.pool, hop.token_out
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Routes found for a single swap log, sent to the strategy
+#[derive(Debug, Clone)]
+pub struct RouteCandidate {
+    pub touched_pool: Address,
+    pub block_number: Option<u64>,
+    pub routes: Vec<Route>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PoolEdge {
+    pool: Address,
+    variant: DexVariant,
+    fee: u32,
+}
+
+/// Tokens are nodes, pools are undirected edges between their two tokens
+#[derive(Debug, Default)]
+pub struct TokenGraph {
+    adjacency: HashMap<Address, HashMap<Address, Vec<PoolEdge>>>,
+    pools: HashMap<Address, Pool>,
+    /// Tokens that failed classification, no route passes through them
+    excluded: HashSet<Address>,
+}
+
+impl TokenGraph {
+    pub fn from_pools<'a>(pools: impl IntoIterator<Item = &'a Pool>) -> Self {
+        let mut graph = Self::default();
+        for pool in pools {
+            graph.insert(pool);
+        }
+        graph
+    }
+
+    pub fn insert(&mut self, pool: &Pool) {
+        if pool.token0 == pool.token1 || self.pools.contains_key(&pool.address) {
+            return;
+        }
+        let edge = PoolEdge {
+            pool: pool.address,
+            variant: pool.version,
+            fee: pool.fee,
+        };
+        for (from, to) in [(pool.token0, pool.token1), (pool.token1, pool.token0)] {
+            self.adjacency
+                .entry(from)
+                .or_default()
+                .entry(to)
+                .or_default()
+                .push(edge);
+        }
+        self.pools.insert(pool.address, *pool);
+    }
+
+    pub fn pool(&self, address: &Address) -> Option<&Pool> {
+        self.pools.get(address)
+    }
+
+    /// Stop routing through `token`, returns false if it was already excluded
+    pub fn exclude_token(&mut self, token: Address) -> bool {
+        self.excluded.insert(token)
+    }
+
+    pub fn is_excluded(&self, token: &Address) -> bool {
+        self.excluded.contains(token)
+    }
+
+    pub fn token_count(&self) -> usize {
+        self.adjacency.len()
+    }
+
+    pub fn pool_count(&self) -> usize {
+        self.pools.len()
+    }
+
+    /// Every cycle of `MIN_HOPS..=max_hops` swaps through `root` that trades on `touched_pool`,
+    /// in both directions, rotated to start at `root`. No token is visited twice.
+    ///
+    /// The search starts from the touched pool and walks back to its other side, so only
+    /// the neighbourhood of that pool is explored rather than every cycle through `root`.
+    pub fn cycles_through(
+        &self,
+        root: Address,
+        touched_pool: Address,
+        max_hops: usize,
+    ) -> Vec<Route> {
+        let Some(pool) = self.pools.get(&touched_pool) else {
+            return Vec::new();
+        };
+        if self.is_excluded(&pool.token0) || self.is_excluded(&pool.token1) {
+            return Vec::new();
+        }
+        let touched = PoolEdge {
+            pool: pool.address,
+            variant: pool.version,
+            fee: pool.fee,
+        };
+
+        let mut routes = Vec::new();
+        for (from, to) in [(pool.token0, pool.token1), (pool.token1, pool.token0)] {
+            let mut search = CycleSearch {
+                graph: self,
+                root,
+                target: from,
+                visited: HashSet::from([from, to]),
+                hops: vec![hop(touched, from, to)],
+                routes: &mut routes,
+            };
+            // the touched pool is already one hop, the rest of the cycle closes from `to` back to `from`
+            for remaining in (MIN_HOPS - 1)..max_hops {
+                search.extend(to, remaining);
+            }
+        }
+
+        routes
+            .into_iter()
+            .take(MAX_ROUTES_PER_LOG)
+            .map(|route| route.rotated_to(root))
+            .collect()
+    }
+}
+
+fn hop(edge: PoolEdge, token_in: Address, token_out: Address) -> Hop {
+    Hop {
+        pool: edge.pool,
+        variant: edge.variant,
+        fee: edge.fee,
+        token_in,
+        token_out,
+    }
+}
+
+struct CycleSearch<'a> {
+    graph: &'a TokenGraph,
+    root: Address,
+    target: Address,
+    visited: HashSet<Address>,
+    hops: Vec<Hop>,
+    routes: &'a mut Vec<Route>,
+}
+
+impl CycleSearch<'_> {
+    /// Extend the path at `current` by exactly `remaining` hops ending on `target`
+    fn extend(&mut self, current: Address, remaining: usize) {
+        if self.routes.len() >= MAX_ROUTES_PER_LOG {
+            return;
+        }
+        let Some(neighbours) = self.graph.adjacency.get(&current) else {
+            return;
+        };
+
+        if remaining == 1 {
+            if !self.visited.contains(&self.root) {
+                return;
+            }
+            for edge in neighbours.get(&self.target).into_iter().flatten() {
+                if self.hops.iter().any(|hop| hop.pool == edge.pool) {
+                    continue;
+                }
+                let mut hops = self.hops.clone();
+                hops.push(hop(*edge, current, self.target));
+                self.routes.push(Route { hops });
+            }
+            return;
+        }
+
+        // With two hops left and the root still unvisited, the next token has to be the root
+        let next_tokens: Vec<Address> = if remaining == 2 && !self.visited.contains(&self.root) {
+            vec![self.root]
+        } else {
+            neighbours.keys().copied().collect()
+        };
+
+        for next in next_tokens {
+            if self.visited.contains(&next) || self.graph.is_excluded(&next) {
+                continue;
+            }
+            let Some(edges) = neighbours.get(&next) else {
+                continue;
+            };
+            self.visited.insert(next);
+            for edge in edges {
+                self.hops.push(hop(*edge, current, next));
+                self.extend(next, remaining - 1);
+                self.hops.pop();
+            }
+            self.visited.remove(&next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::pools::test_pool;
+
+    fn token(byte: u8) -> Address {
+        Address::repeat_byte(byte)
+    }
+
+    // WETH = 1, A = 2, B = 3, C = 4
+    fn graph() -> TokenGraph {
+        TokenGraph::from_pools(&[
+            test_pool(0x10, DexVariant::UniswapV2, 1, 2, 3000),
+            test_pool(0x11, DexVariant::UniswapV3, 2, 3, 3000),
+            test_pool(0x12, DexVariant::UniswapV2, 3, 1, 3000),
+            test_pool(0x13, DexVariant::UniswapV3, 3, 4, 3000),
+            test_pool(0x14, DexVariant::UniswapV2, 4, 1, 3000),
+            test_pool(0x15, DexVariant::UniswapV2, 2, 4, 3000),
+        ])
+    }
+
+    fn assert_is_cycle(route: &Route, root: Address) {
+        assert_eq!(route.start_token(), root);
+        assert_eq!(route.hops.last().unwrap().token_out, root);
+        for pair in route.hops.windows(2) {
+            assert_eq!(pair[0].token_out, pair[1].token_in);
+        }
+    }
+
+    #[test]
+    fn test_triangles_through_touched_pool() {
+        let graph = graph();
+        let weth = token(1);
+        let routes = graph.cycles_through(weth, token(0x11), 3);
+
+        // WETH -> A -> B -> WETH and the reverse direction
+        assert_eq!(routes.len(), 2);
+        for route in &routes {
+            assert_is_cycle(route, weth);
+            assert_eq!(route.hops.len(), 3);
+            assert!(route.contains_pool(token(0x11)));
+        }
+    }
+
+    #[test]
+    fn test_four_hop_cycles() {
+        let graph = graph();
+        let weth = token(1);
+        let routes = graph.cycles_through(weth, token(0x13), MAX_HOPS);
+
+        for route in &routes {
+            assert_is_cycle(route, weth);
+            assert!(route.contains_pool(token(0x13)));
+            let mut tokens: Vec<_> = route.hops.iter().map(|hop| hop.token_in).collect();
+            tokens.sort();
+            tokens.dedup();
+            assert_eq!(tokens.len(), route.hops.len(), "token visited twice");
+        }
+        // B -> C closes through WETH directly, or through A on the way out or back
+        assert_eq!(routes.iter().filter(|r| r.hops.len() == 3).count(), 2);
+        assert_eq!(routes.iter().filter(|r| r.hops.len() == 4).count(), 4);
+    }
+
+    #[test]
+    fn test_excluded_tokens_are_skipped() {
+        let mut graph = graph();
+        let weth = token(1);
+        assert!(graph.exclude_token(token(3)));
+        assert!(!graph.exclude_token(token(3)));
+
+        assert!(graph.cycles_through(weth, token(0x11), MAX_HOPS).is_empty());
+        let routes = graph.cycles_through(weth, token(0x15), MAX_HOPS);
+        assert!(!routes.is_empty());
+        for route in &routes {
+            assert!(route.hops.iter().all(|hop| hop.token_out != token(3)));
+        }
+    }
+
+    #[test]
+    fn test_no_cycle_without_root() {
+        let graph = TokenGraph::from_pools(&[
+            test_pool(0x10, DexVariant::UniswapV2, 2, 3, 3000),
+            test_pool(0x11, DexVariant::UniswapV2, 3, 4, 3000),
+            test_pool(0x12, DexVariant::UniswapV2, 4, 2, 3000),
+        ]);
+        assert!(graph
+            .cycles_through(token(1), token(0x10), MAX_HOPS)
+            .is_empty());
+    }
+}
@@ -1,2 +1,7 @@
+pub mod graph;
+pub mod optimizer;
 pub mod simulation;
 pub mod strategy;
+pub mod uniswap_v2;
+pub mod uniswap_v3;
+pub mod v3_math;
@@ -0,0 +1,348 @@
+use crate::arbitrage::graph::Route;
+use crate::arbitrage::uniswap_v2::{
+    get_amount_out, ConstantProductQuoter, FEE_DENOMINATOR, FEE_NUMERATOR,
+};
+use crate::arbitrage::uniswap_v3::V3PoolState;
+use alloy_primitives::{I256, U512};
+use anyhow::{anyhow, Result};
+use revm::primitives::{Address, U256};
+
+/// Upper bound on profit function evaluations for the golden-section search
+pub const MAX_ITERATIONS: usize = 128;
+/// The search stops once the bracket is narrower than `range / TOLERANCE_DIVISOR`
+pub const TOLERANCE_DIVISOR: u64 = 1_000_000;
+
+/// 1/phi scaled by 1e18, the golden-section shrink factor
+const INV_PHI_E18: u64 = 618_033_988_749_894_848;
+const E18: u64 = 1_000_000_000_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfitSample {
+    pub amount_in: U256,
+    pub profit: I256,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ArbitrageResult {
+    pub optimal_amount: U256,
+    /// Gross profit, before gas
+    pub possible_profit: U256,
+    /// Number of profit function evaluations it took to find `optimal_amount`
+    pub iterations: usize,
+    /// Points of the profit curve that could be priced, in evaluation order
+    pub samples: Vec<ProfitSample>,
+    /// Gas the final simulation of `optimal_amount` used, zero until `with_gas` is called
+    pub gas_used: u64,
+    /// `gas_used` at the gas price passed to `with_gas`, in wei
+    pub gas_cost: U256,
+    /// `possible_profit - gas_cost`, negative when gas eats the profit
+    pub net_profit: I256,
+}
+
+impl ArbitrageResult {
+    /// Price the gas of sending the trade, `possible_profit` has to be in WETH by now
+    pub fn with_gas(self, gas_used: u64, gas_price: U256) -> Self {
+        let gas_cost = gas_price.saturating_mul(U256::from(gas_used));
+        let net_profit = to_signed(self.possible_profit).saturating_sub(to_signed(gas_cost));
+        Self {
+            gas_used,
+            gas_cost,
+            net_profit,
+            ..self
+        }
+    }
+
+    /// Whether the trade still clears `margin` wei after gas
+    pub fn is_profitable(&self, margin: U256) -> bool {
+        self.net_profit > to_signed(margin)
+    }
+
+    fn from_samples(samples: Vec<ProfitSample>) -> Self {
+        let best = samples.iter().max_by_key(|sample| sample.profit).copied();
+        match best {
+            Some(best) if best.profit.is_positive() => Self {
+                optimal_amount: best.amount_in,
+                possible_profit: best.profit.unsigned_abs(),
+                iterations: samples.len(),
+                samples,
+                ..Default::default()
+            },
+            _ => Self {
+                iterations: samples.len(),
+                samples,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn to_signed(value: U256) -> I256 {
+    I256::try_from(value).unwrap_or(I256::MAX)
+}
+
+/// Closed-form optimum for buying on one V2 pair and selling on another.
+///
+/// Chaining two constant-product swaps gives `out = E*x / (F + G*x)` with
+/// `E = 997^2 * a_out * b_out`, `F = 1000^2 * a_in * b_in` and
+/// `G = 997 * (1000 * b_in + 997 * a_out)`, so `d(out - x)/dx = 0` at
+/// `x = (sqrt(E*F) - F) / G`. Reserves are `(in, out)` from the trader's side of each pair.
+pub fn optimal_v2_to_v2(buy: (U256, U256), sell: (U256, U256)) -> ArbitrageResult {
+    let (a_in, a_out) = buy;
+    let (b_in, b_out) = sell;
+    let fee_num = U512::from(FEE_NUMERATOR);
+    let fee_den = U512::from(FEE_DENOMINATOR);
+
+    let e = fee_num * fee_num * U512::from(a_out) * U512::from(b_out);
+    let f = fee_den * fee_den * U512::from(a_in) * U512::from(b_in);
+    let g = fee_num * (fee_den * U512::from(b_in) + fee_num * U512::from(a_out));
+
+    // Marginal rate at x = 0 is E/F, no arbitrage unless it beats 1
+    if e <= f || g.is_zero() {
+        return ArbitrageResult::default();
+    }
+
+    let sqrt_ef = (e.saturating_mul(f)).root(2);
+    let optimal = (sqrt_ef - f) / g;
+    let optimal = U256::from_limbs_slice(&optimal.as_limbs()[..4]);
+
+    let profit = |amount_in: U256| -> Option<I256> {
+        let middle = get_amount_out(amount_in, a_in, a_out).ok()?;
+        let out = get_amount_out(middle, b_in, b_out).ok()?;
+        I256::try_from(out)
+            .ok()?
+            .checked_sub(I256::try_from(amount_in).ok()?)
+    };
+
+    // Integer rounding in getAmountOut can move the optimum by a wei either way
+    let samples = [
+        optimal.saturating_sub(U256::from(1)),
+        optimal,
+        optimal + U256::from(1),
+    ]
+    .into_iter()
+    .filter(|amount| !amount.is_zero())
+    .filter_map(|amount_in| profit(amount_in).map(|profit| ProfitSample { amount_in, profit }))
+    .collect();
+
+    ArbitrageResult::from_samples(samples)
+}
+
+/// Golden-section search for the maximum of a unimodal profit curve on `[lower, upper]`.
+/// Amounts the profit function can't price (`None`) are treated as the worst possible outcome,
+/// which keeps the bracket inside what the pools can actually fill.
+pub fn golden_section_search<F>(mut profit: F, lower: U256, upper: U256) -> ArbitrageResult
+where
+    F: FnMut(U256) -> Option<I256>,
+{
+    let mut samples = Vec::new();
+    let mut evaluate = |amount_in: U256, samples: &mut Vec<ProfitSample>| -> I256 {
+        let value = profit(amount_in).unwrap_or(I256::MIN);
+        samples.push(ProfitSample {
+            amount_in,
+            profit: value,
+        });
+        value
+    };
+
+    if upper <= lower {
+        return ArbitrageResult::default();
+    }
+
+    let tolerance = ((upper - lower) / U256::from(TOLERANCE_DIVISOR)).max(U256::from(2));
+    let shrink = |width: U256| width * U256::from(INV_PHI_E18) / U256::from(E18);
+
+    let (mut a, mut b) = (lower, upper);
+    let mut c = b - shrink(b - a);
+    let mut d = a + shrink(b - a);
+    let mut fc = evaluate(c, &mut samples);
+    let mut fd = evaluate(d, &mut samples);
+
+    while b - a > tolerance && samples.len() < MAX_ITERATIONS {
+        if fc >= fd {
+            // maximum is in [a, d]
+            b = d;
+            d = c;
+            fd = fc;
+            c = b - shrink(b - a);
+            fc = evaluate(c, &mut samples);
+        } else {
+            // maximum is in [c, b]
+            a = c;
+            c = d;
+            fc = fd;
+            d = a + shrink(b - a);
+            fd = evaluate(d, &mut samples);
+        }
+    }
+
+    // Every evaluation counts as an iteration, but only priced points make the curve
+    let iterations = samples.len();
+    samples.retain(|sample| sample.profit != I256::MIN);
+    ArbitrageResult {
+        iterations,
+        ..ArbitrageResult::from_samples(samples)
+    }
+}
+
+/// Optimal input for the arboo flash swap: sell `token_in` into the V3 pool, buy it back on V2.
+/// The search is bounded by the V2 reserve of `token_in`, since no input larger than
+/// what the pair holds can ever be bought back.
+pub fn optimal_v3_to_v2(
+    v3_pool: &V3PoolState,
+    v2_pair: &ConstantProductQuoter,
+    token_in: Address,
+    token_out: Address,
+) -> ArbitrageResult {
+    let upper = match v2_pair.reserve_of(token_in) {
+        Ok(reserve) if !reserve.is_zero() => reserve,
+        _ => return ArbitrageResult::default(),
+    };
+
+    golden_section_search(
+        |amount_in| {
+            let v3_amount_out = v3_pool.quote_exact_in(token_in, amount_in).ok()?;
+            let buy_back_amount = v2_pair.quote_exact_in(token_out, v3_amount_out).ok()?;
+            I256::try_from(buy_back_amount)
+                .ok()?
+                .checked_sub(I256::try_from(amount_in).ok()?)
+        },
+        U256::from(1),
+        upper,
+    )
+}
+
+/// Off-chain pricing for one hop of a route
+#[derive(Debug, Clone)]
+pub enum PoolQuoter {
+    V2(ConstantProductQuoter),
+    V3(V3PoolState),
+}
+
+impl PoolQuoter {
+    pub fn quote_exact_in(&self, token_in: Address, amount_in: U256) -> Result<U256> {
+        match self {
+            PoolQuoter::V2(pair) => pair.quote_exact_in(token_in, amount_in),
+            PoolQuoter::V3(pool) => pool.quote_exact_in(token_in, amount_in),
+        }
+    }
+}
+
+/// Optimal input for a multi-hop cycle, with `quoters[i]` pricing `route.hops[i]`.
+/// `upper` caps the search, amounts the pools can't fill are pruned by the search itself.
+pub fn optimal_route(
+    route: &Route,
+    quoters: &[PoolQuoter],
+    upper: U256,
+) -> Result<ArbitrageResult> {
+    if route.hops.len() != quoters.len() {
+        return Err(anyhow!(
+            "route has {} hops but {} quoters",
+            route.hops.len(),
+            quoters.len()
+        ));
+    }
+
+    Ok(golden_section_search(
+        |amount_in| {
+            let mut amount = amount_in;
+            for (hop, quoter) in route.hops.iter().zip(quoters) {
+                amount = quoter.quote_exact_in(hop.token_in, amount).ok()?;
+            }
+            I256::try_from(amount)
+                .ok()?
+                .checked_sub(I256::try_from(amount_in).ok()?)
+        },
+        U256::from(1),
+        upper,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ether(amount: u64) -> U256 {
+        U256::from(amount) * U256::from(10).pow(U256::from(18))
+    }
+
+    fn brute_force_best(buy: (U256, U256), sell: (U256, U256), step: U256) -> I256 {
+        let mut best = I256::ZERO;
+        let mut amount = step;
+        while amount < buy.0 {
+            let middle = get_amount_out(amount, buy.0, buy.1).unwrap();
+            let out = get_amount_out(middle, sell.0, sell.1).unwrap();
+            let profit = I256::from_raw(out) - I256::from_raw(amount);
+            best = best.max(profit);
+            amount += step;
+        }
+        best
+    }
+
+    #[test]
+    fn test_optimal_v2_to_v2_beats_grid() {
+        // token is 1% cheaper on pool A than on pool B
+        let buy = (ether(1_000), ether(2_020_000));
+        let sell = (ether(2_000_000), ether(1_000));
+
+        let result = optimal_v2_to_v2(buy, sell);
+        assert!(result.possible_profit > U256::ZERO);
+        let grid_best = brute_force_best(buy, sell, ether(1) / U256::from(10));
+        assert!(I256::from_raw(result.possible_profit) >= grid_best);
+    }
+
+    #[test]
+    fn test_optimal_v2_to_v2_no_opportunity() {
+        let pool = (ether(1_000), ether(2_000_000));
+        let result = optimal_v2_to_v2(pool, (pool.1, pool.0));
+        assert_eq!(result.optimal_amount, U256::ZERO);
+        assert_eq!(result.possible_profit, U256::ZERO);
+    }
+
+    #[test]
+    fn test_golden_section_matches_closed_form() {
+        let buy = (ether(1_000), ether(2_020_000));
+        let sell = (ether(2_000_000), ether(1_000));
+        let closed_form = optimal_v2_to_v2(buy, sell);
+
+        let searched = golden_section_search(
+            |amount_in| {
+                let middle = get_amount_out(amount_in, buy.0, buy.1).ok()?;
+                let out = get_amount_out(middle, sell.0, sell.1).ok()?;
+                Some(I256::from_raw(out) - I256::from_raw(amount_in))
+            },
+            U256::from(1),
+            buy.0,
+        );
+
+        assert!(searched.iterations <= MAX_ITERATIONS);
+        assert_eq!(searched.samples.len(), searched.iterations);
+        assert!(searched
+            .samples
+            .iter()
+            .any(|sample| sample.amount_in == searched.optimal_amount));
+        // the curve is flat at the top, a 1e-6 bracket lands within a few wei of profit
+        let diff = closed_form
+            .possible_profit
+            .abs_diff(searched.possible_profit);
+        assert!(diff < closed_form.possible_profit / U256::from(1_000_000));
+    }
+
+    #[test]
+    fn test_gas_is_taken_off_the_gross_profit() {
+        let result = ArbitrageResult {
+            possible_profit: U256::from(1_000_000),
+            ..Default::default()
+        };
+
+        // 150k gas at 5 wei
+        let cheap = result.clone().with_gas(150_000, U256::from(5));
+        assert_eq!(cheap.gas_cost, U256::from(750_000));
+        assert_eq!(cheap.net_profit, I256::try_from(250_000).unwrap());
+        assert!(cheap.is_profitable(U256::from(100_000)));
+        assert!(!cheap.is_profitable(U256::from(250_000)));
+
+        let expensive = result.with_gas(150_000, U256::from(10));
+        assert_eq!(expensive.net_profit, I256::try_from(-500_000).unwrap());
+        assert!(!expensive.is_profitable(U256::ZERO));
+    }
+}
@@ -1,9 +1,7 @@
+use crate::common::access_list::{AccessSet, RouteKey};
 use crate::common::revm::{EvmSimulator, Tx};
 use ::log::info;
-use alloy::eips::BlockId;
 use alloy::network::Ethereum;
-use alloy::providers::{Provider, ProviderBuilder, RootProvider};
-use alloy::pubsub::PubSubFrontend;
 use alloy::rpc::client::WsConnect;
 use alloy::signers::k256::ecdsa::SigningKey;
 use alloy::signers::k256::Secp256k1;
@@ -12,10 +10,40 @@ use alloy_primitives::aliases::U24;
 use alloy_sol_types::SolCall;
 use anyhow::Result;
 use revm::primitives::{address, Address, Bytecode, U256};
-use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex as TokioMutex;
 
+alloy::sol! {
+    // custom errors of contracts/src/arboo.sol, `abi` keeps the parameter names for the
+    // error registry
+    #[derive(Debug, PartialEq, Eq)]
+    #[sol(abi)]
+    error UnderflowError(uint256 buyBackAmount, uint256 amountIn);
+    #[derive(Debug, PartialEq, Eq)]
+    #[sol(abi)]
+    error AmountLessThanZero();
+    #[derive(Debug, PartialEq, Eq)]
+    #[sol(abi)]
+    error NotSender(address sender);
+    #[derive(Debug, PartialEq, Eq)]
+    #[sol(abi)]
+    error BuyBackAmountLessThanAmountIn(uint256 buyBackAmount, uint256 amountIn);
+    #[derive(Debug, PartialEq, Eq)]
+    #[sol(abi)]
+    error ProfitIsZero();
+}
+
+/// One run of the flash swap
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SwapRun {
+    /// WETH the owner gained, zero if the swap lost money
+    pub profit: U256,
+    /// Gas the swap transaction used, reverted runs included
+    pub gas_used: u64,
+}
+
+/// Run the flash swap for `amount` on a fork of the simulator, the simulator itself is
+/// left untouched.
 pub async fn simulation(
     target_pool: Address,
     token_a: Address,
@@ -23,35 +51,94 @@ pub async fn simulation(
     amount: U256,
     fee: U24,
     simulator: Arc<TokioMutex<EvmSimulator<'_>>>,
-    provider: Arc<RootProvider<PubSubFrontend>>,
-) -> Result<U256> {
-    let latest_block_number = provider.get_block_number().await?;
-    let block_id = BlockId::from_str(latest_block_number.to_string().as_str()).unwrap();
-    let latest_block = provider
-        .get_block(block_id, alloy::rpc::types::BlockTransactionsKind::Full)
+) -> Result<SwapRun> {
+    simulate_amounts(target_pool, token_a, token_b, &[amount], fee, simulator)
         .await?
-        .ok_or(anyhow::Error::msg("Error getting block"))?;
-    // info!(
-    //     "latest block timestamp: {:?}",
-    //     latest_block.header.timestamp
-    // );
-    let latest_gas_limit = latest_block.header.gas_limit;
-    let latest_gas_price = U256::from(latest_block.header.base_fee_per_gas.expect("gas"));
+        .remove(0)
+}
+
+/// Run the flash swap once per amount, each on its own fork of the simulator's current
+/// state and on its own blocking thread. Gas is priced at the base fee of the block the
+/// simulator is pinned to. Results are in the order of `amounts`.
+///
+/// The slots the successful runs touched are recorded as the route's access set.
+pub async fn simulate_amounts(
+    target_pool: Address,
+    token_a: Address,
+    token_b: Address,
+    amounts: &[U256],
+    fee: U24,
+    simulator: Arc<TokioMutex<EvmSimulator<'_>>>,
+) -> Result<Vec<Result<SwapRun>>> {
+    let fork = simulator.lock().await.fork();
+    let latest_gas_limit = fork.env.block.gas_limit.saturating_to::<u64>();
+    let latest_gas_price = fork.env.block.basefee;
+
+    let runs = amounts.iter().map(|amount| {
+        let (fork, amount) = (fork.clone(), *amount);
+        tokio::task::spawn_blocking(move || {
+            flash_swap_profit(
+                &mut fork.simulator(),
+                target_pool,
+                token_a,
+                token_b,
+                amount,
+                fee,
+                latest_gas_limit,
+                latest_gas_price,
+            )
+        })
+    });
+
+    // every amount's slots go into the route's access set, they all touch the same pools
+    let mut accesses = AccessSet::default();
+    let results = futures::future::join_all(runs)
+        .await
+        .into_iter()
+        // a panicking run only fails its own amount
+        .map(|run| run.map_err(anyhow::Error::from).and_then(|run| run))
+        .map(|run| {
+            run.map(|(run, run_accesses)| {
+                accesses.merge(run_accesses);
+                run
+            })
+        })
+        .collect();
+
+    let route = RouteKey {
+        pool: target_pool,
+        token_in: token_a,
+        token_out: token_b,
+    };
+    simulator
+        .lock()
+        .await
+        .record_route_accesses(route, accesses);
+    Ok(results)
+}
 
-    let wallet_address = simulator.lock().await.owner;
+#[allow(clippy::too_many_arguments)]
+fn flash_swap_profit(
+    simulator: &mut EvmSimulator<'_>,
+    target_pool: Address,
+    token_a: Address,
+    token_b: Address,
+    amount: U256,
+    fee: U24,
+    latest_gas_limit: u64,
+    latest_gas_price: U256,
+) -> Result<(SwapRun, AccessSet)> {
+    let wallet_address = simulator.owner;
 
     let weth_balance = check_weth_balance(
         wallet_address,
-        simulator.clone(),
+        simulator,
         &latest_gas_limit,
         &latest_gas_price,
         None,
     )
-    .await
     .inspect_err(|e| info!("Error getting weth balance {:?}", e))?;
 
-    //log::debug!("Initial Weth Balance: {:?}", weth_balance);
-
     alloy::sol! {
         #[derive(Debug)]
         function flashSwap_V3_to_V2(
@@ -70,40 +157,57 @@ pub async fn simulation(
         tokenOut: token_b,
         amountIn: amount,
     };
-    //info!("flash swap function args: {:?}", function_call);
     let function_call_data = function_call.abi_encode();
 
-    let caller = simulator.lock().await.owner;
-    let contract_address = simulator.lock().await.contract_address;
-
     // Note: create the transaction
     let new_tx = Tx {
-        caller,
-        transact_to: contract_address,
+        caller: wallet_address,
+        transact_to: simulator.contract_address,
         data: function_call_data.into(),
         value: U256::ZERO,
         gas_limit: latest_gas_limit,
         gas_price: latest_gas_price,
     };
 
-    simulator.lock().await.call(new_tx)?;
+    // the swap's SLOADs and SSTOREs are exactly what the sent transaction will touch
+    let inspector = &mut simulator.evm.get_mut().context.external;
+    inspector.storage_accesses.clear();
+    inspector.track_storage = true;
+    let swap = simulator.call(new_tx);
+    let inspector = &mut simulator.evm.get_mut().context.external;
+    inspector.track_storage = false;
+    let accesses = AccessSet::from_inspector(inspector);
+
+    // the executor refusing an unprofitable swap is an answer, not a failure
+    if swap.reverted_with::<ProfitIsZero>() || swap.reverted_with::<BuyBackAmountLessThanAmountIn>()
+    {
+        let run = SwapRun {
+            profit: U256::ZERO,
+            gas_used: swap.gas_used().unwrap_or_default(),
+        };
+        return Ok((run, accesses));
+    }
+    if let Some(NotSender { sender }) = swap.revert_as::<NotSender>() {
+        info!("Executor rejected a callback from {:?}", sender);
+    }
+    if let Some(frame) = swap.deepest_revert() {
+        info!("Flash swap failed, {}", frame);
+    }
+    let gas_used = swap.into_result()?.gas_used;
 
     let balance = check_weth_balance(
         wallet_address,
-        simulator.clone(),
+        simulator,
         &latest_gas_limit,
         &latest_gas_price,
         Some(wallet_address),
     )
-    .await
     .inspect_err(|e| info!("Error checking weth balance {e}",))?;
-    //info!("Weth Balance before swap = {weth_balance}");
-    //info!("Balance after swap: {balance}");
 
-    let profit = balance - weth_balance;
+    let profit = balance.saturating_sub(weth_balance);
 
     info!("Profit: {profit}");
-    Ok(profit)
+    Ok((SwapRun { profit, gas_used }, accesses))
 }
 
 pub fn one_ether() -> U256 {
@@ -171,9 +275,9 @@ pub fn arboo_bytecode() -> Bytecode {
     Bytecode::new_raw(bytes.into())
 }
 
-pub async fn check_weth_balance(
+pub fn check_weth_balance(
     wallet_address: Address,
-    simulator: Arc<TokioMutex<EvmSimulator<'_>>>,
+    simulator: &mut EvmSimulator<'_>,
     latest_gas_limit: &u64,
     latest_gas_price: &U256,
     caller: Option<Address>,
@@ -200,9 +304,8 @@ pub async fn check_weth_balance(
     };
 
     let result = simulator
-        .lock()
-        .await
-        .call(new_tx)
+        .staticcall(new_tx)
+        .into_result()
         .inspect_err(|e| info!("There was an error {e}"))?;
 
     let balance = U256::from_be_slice(&result.output);
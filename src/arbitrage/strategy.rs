@@ -1,41 +1,66 @@
+use crate::arbitrage::graph::{Route, RouteCandidate, TokenGraph};
+use crate::arbitrage::optimizer::{optimal_route, optimal_v3_to_v2, ArbitrageResult, PoolQuoter};
 use crate::arbitrage::simulation::{arboo_bytecode, get_address, one_thousand_eth, AddressType};
-use crate::arbitrage::simulation::{one_ether, simulation};
-use crate::common::transaction::{create_input_data, send_transaction};
+use crate::arbitrage::simulation::{one_ether, simulate_amounts, SwapRun};
+use crate::arbitrage::uniswap_v2::ConstantProductQuoter;
+use crate::arbitrage::uniswap_v3::{V3PoolState, DEFAULT_WORD_RANGE};
+use crate::common::access_list::{AccessSet, RouteKey};
+use crate::common::bundle::{submit_and_report, BundleSubmitter};
+use crate::common::call_bundle::verify_and_submit;
+use crate::common::classifier::TokenVerdict;
+use crate::common::fees::{predict_base_fee, PriorityFeePolicy, TxFees};
+use crate::common::mempool::Backrun;
+use crate::common::pools::{DexVariant, Pool};
+use crate::common::registry::PoolRegistry;
+use crate::common::store::{PoolStore, POOL_STORE_PATH};
+use crate::common::transaction::{
+    create_input_data, executor_from_env, send_transaction, sign_transaction, SENDER,
+};
 use crate::common::{
     logs::LogEvent,
-    pairs::{Event, V2PoolCreated, V3PoolCreated},
-    revm::{EvmSimulator, Tx},
+    revm::{EvmSimulator, Tx, VictimTx},
 };
+use alloy::consensus::TxEnvelope;
+use alloy::eips::eip2718::Encodable2718;
 use alloy::eips::BlockId;
 use alloy::network::Ethereum;
 use alloy::providers::{Provider, RootProvider};
 use alloy::pubsub::PubSubFrontend;
-use alloy::rpc::types::{Block, BlockTransactionsKind};
+use alloy::rpc::types::BlockTransactionsKind;
 use alloy::signers::local::PrivateKeySigner;
 use alloy_primitives::aliases::U24;
 use alloy_primitives::{address, Bytes, U160};
 use alloy_sol_types::abi::token;
 use alloy_sol_types::SolCall;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use dotenv::var;
 use log::info;
 use revm::primitives::{Address, U256};
-use std::{
-    collections::HashMap,
-    fs::File,
-    io::{self, BufRead},
-    path::Path,
-    str::FromStr,
-    sync::Arc,
-};
+use std::collections::HashMap;
+use std::path::Path;
+use std::{str::FromStr, sync::Arc};
 use tokio::sync::Mutex;
-use tokio::sync::{broadcast::Sender, Mutex as TokioMutex};
+use tokio::sync::{broadcast::Sender, Mutex as TokioMutex, RwLock};
 
 pub async fn strategy(
     sender: Sender<LogEvent>,
     simulator: Arc<Mutex<EvmSimulator<'_>>>,
     provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+    registry: Arc<RwLock<PoolRegistry>>,
+    graph: Arc<RwLock<TokenGraph>>,
 ) -> Result<()> {
+    let fee_policy = PriorityFeePolicy::from_env()?;
+    let bundle_submitter = BundleSubmitter::from_env()?;
+    if let Some(submitter) = &bundle_submitter {
+        info!("Sending bundles to {} relays", submitter.relays().len());
+    }
+    let min_net_profit = wei_from_env("MIN_NET_PROFIT", DEFAULT_MIN_NET_PROFIT)?;
+
+    // slots learned by earlier runs, so a route's first trade already has its access list
+    let mut store = PoolStore::open(Path::new(POOL_STORE_PATH))?;
+    let mut saved_accesses = store.load_route_accesses()?;
+    simulator.lock().await.route_accesses = saved_accesses.clone();
+
     let mut event_reciever = sender.subscribe();
     loop {
         match event_reciever.recv().await {
@@ -43,8 +68,6 @@ pub async fn strategy(
                 // reserves of the target pool to low?
                 let is_v2_to_v3 = message.pool_variant == 3;
                 //log::debug!("Message: {:?}", message);
-                // Calculate optimal amount
-                let max_input = U256::MAX - U256::from(10).pow(U256::from(18));
 
                 let latest_block = provider
                     .get_block(BlockId::latest(), BlockTransactionsKind::Full)
@@ -52,10 +75,12 @@ pub async fn strategy(
                     .unwrap()
                     .unwrap();
 
-                let block_base_fee = latest_block.header.base_fee_per_gas.unwrap();
+                // the trade lands in the next block at the earliest
+                let base_fee = predict_base_fee(&latest_block);
 
                 load_specific_pools(
                     simulator.clone(),
+                    &registry,
                     message.log_pool_address,
                     message.corresponding_pool_address,
                 )
@@ -65,15 +90,44 @@ pub async fn strategy(
 
                 setup_evm(simulator.clone(), provider.clone()).await?;
 
+                let (v3_pool, v2_pool) = if is_v2_to_v3 {
+                    (message.log_pool_address, message.corresponding_pool_address)
+                } else {
+                    (message.corresponding_pool_address, message.log_pool_address)
+                };
+                let route = RouteKey {
+                    pool: v3_pool,
+                    token_in: message.token0,
+                    token_out: message.token1,
+                };
+                // whatever the last run of this route touched beyond the fixed pool layouts
+                if let Err(err) = simulator.lock().await.prefetch_route(&route).await {
+                    log::debug!("Error prefetching route {:?}: {:?}", route, err);
+                }
+
+                // the V2 pair holds both tokens, so it funds the probe transfers
+                if !tokens_are_tradable(
+                    &simulator,
+                    &graph,
+                    [message.token0, message.token1],
+                    v2_pool,
+                )
+                .await
+                {
+                    continue;
+                }
+
                 //info!("Message: {:?}", message);
                 let optimal_result = match find_optimal_amount_v3_to_v2(
-                    message.token0,
-                    message.token1,
+                    FlashSwapParams {
+                        token_in: message.token0,
+                        token_out: message.token1,
+                        fee: message.fee,
+                        v3_pool,
+                        v2_pool,
+                        gas_price: U256::from(base_fee),
+                    },
                     simulator.clone(),
-                    max_input,
-                    message.fee,
-                    latest_block.clone(),
-                    message.corresponding_pool_address,
                     provider.clone(),
                 )
                 .await
@@ -81,21 +135,36 @@ pub async fn strategy(
                     Ok(res) => res,
                     Err(_) => continue,
                 };
-
-                if optimal_result.possible_profit < U256::from(100_000u128) {
+                save_route_accesses(&mut store, &simulator, &mut saved_accesses, &route).await;
+                if optimal_result.possible_profit.is_zero() {
                     continue;
                 }
-                // simulate with optimal amoun in arbooo
-                let target_pool = if is_v2_to_v3 {
-                    message.log_pool_address
-                } else {
-                    message.corresponding_pool_address
+
+                let Some((optimal_result, fees)) = price_trade(
+                    &fee_policy,
+                    &provider,
+                    optimal_result,
+                    base_fee,
+                    min_net_profit,
+                )
+                .await
+                else {
+                    continue;
                 };
+                // simulate with optimal amoun in arbooo
+                let target_pool = v3_pool;
                 log::debug!(
-                    "Tike taken to calculate optimal amount: {:?}",
-                    time.elapsed()
+                    "Time taken to calculate optimal amount: {:?} ({} iterations)",
+                    time.elapsed(),
+                    optimal_result.iterations
+                );
+                info!(
+                    "Arbitrage opportunity found, gross {} gas cost {} ({} gas) net {}",
+                    optimal_result.possible_profit,
+                    optimal_result.gas_cost,
+                    optimal_result.gas_used,
+                    optimal_result.net_profit
                 );
-                info!("Arbitrage opportunity found");
                 info!(
                     "Creating and sending TX for optimal amount {} to pool {}",
                     optimal_result.optimal_amount, target_pool
@@ -108,33 +177,50 @@ pub async fn strategy(
                     continue;
                 }
 
-                let transaction = create_input_data(
-                    target_pool,
-                    message.fee,
-                    message.token1,
-                    message.token0,
-                    optimal_result.optimal_amount,
+                let trade = sign_trade(
+                    &simulator,
+                    &provider,
+                    &message,
+                    &route,
+                    &optimal_result,
+                    fees,
                 )
-                .await
-                .inspect(|e| info!("Error creating input data: {:?}", e))?;
-
-                let contract_address = var::<&str>("CONTRACT_ADDRESS")?;
-                let contract_address = Address::from_str(&contract_address)?;
+                .await?;
 
-                let nonce = provider
-                    .get_transaction_count(address!("5f1F5565561aC146d24B102D9CDC288992Ab2938"))
-                    .await
-                    .inspect(|e| info!("error getting nonce, {:?}", e))?;
-
-                tokio::spawn(send_transaction(
-                    contract_address,
-                    Some(block_base_fee as u128),
-                    Some(1_500_000),
-                    Some(block_base_fee as u128),
-                    Some(2_000_000),
-                    transaction,
-                    nonce,
-                ));
+                match &bundle_submitter {
+                    // private relays when configured, a reverted bundle costs nothing
+                    Some(submitter) if submitter.simulation_url.is_some() => {
+                        // what eth_callBundle has to agree with before the bundle goes out
+                        let txs = vec![trade.encoded_2718().into()];
+                        let local = simulator
+                            .lock()
+                            .await
+                            .simulate_bundle(&txs, U256::from(base_fee));
+                        let local = match local {
+                            Ok(local) => local,
+                            Err(err) => {
+                                info!("Error simulating bundle: {:?}", err);
+                                continue;
+                            }
+                        };
+                        let submitter = submitter.clone();
+                        let block = latest_block.header.number;
+                        tokio::spawn(async move {
+                            verify_and_submit(&submitter, txs, block, local).await
+                        });
+                    }
+                    Some(submitter) => {
+                        let submitter = submitter.clone();
+                        let txs = vec![trade.encoded_2718().into()];
+                        let block = latest_block.header.number;
+                        tokio::spawn(
+                            async move { submit_and_report(&submitter, txs, block).await },
+                        );
+                    }
+                    None => {
+                        tokio::spawn(send_transaction(trade));
+                    }
+                }
             }
             Err(err) => {
                 info!("Error Recieving message: {err}")
@@ -143,198 +229,537 @@ pub async fn strategy(
     }
 }
 
-#[derive(Debug)]
-pub struct ArbitrageResult {
-    pub optimal_amount: U256,
-    pub possible_profit: U256,
-}
+/// Backruns pending router swaps. The victim runs first on a fork, the arbitrage it leaves
+/// behind is searched on that fork and `[victim, ours]` goes out as one bundle. A backrun
+/// only works as a bundle, so without relays this returns right away.
+pub async fn backrun_strategy(
+    backrun_sender: Sender<Backrun>,
+    simulator: Arc<Mutex<EvmSimulator<'_>>>,
+    provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+    registry: Arc<RwLock<PoolRegistry>>,
+    graph: Arc<RwLock<TokenGraph>>,
+) -> Result<()> {
+    let Some(bundle_submitter) = BundleSubmitter::from_env()? else {
+        info!("No bundle relays set, not backrunning pending swaps");
+        return Ok(());
+    };
+    let fee_policy = PriorityFeePolicy::from_env()?;
+    let min_net_profit = wei_from_env("MIN_NET_PROFIT", DEFAULT_MIN_NET_PROFIT)?;
 
-// lets do a really slow way to see if it's the binary search that is the problem?
+    let mut backrun_reciever = backrun_sender.subscribe();
+    loop {
+        let Backrun {
+            victim,
+            event: message,
+        } = match backrun_reciever.recv().await {
+            Ok(backrun) => backrun,
+            Err(err) => {
+                info!("Error Recieving backrun: {err}");
+                continue;
+            }
+        };
 
-pub async fn find_optimal_amount_v3_to_v2(
-    token_in: Address,
-    token_out: Address,
-    simulator: Arc<TokioMutex<EvmSimulator<'_>>>,
-    max_input: U256,
-    fee: U24,
-    latest_block: Block,
-    target_pool: Address,
-    provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
-) -> Result<ArbitrageResult> {
-    let mut best_profit = U256::ZERO;
-    let mut optimal_amount = U256::ZERO;
-    let mut left = U256::from(10).pow(U256::from(18)); // 1 token
-    let mut right = max_input;
-
-    while left <= right {
-        let mid = (left + right) / U256::from(2);
-        // Only query once per iteration with mid
-
-        let v3_amount_out = simulation(
-            target_pool,
-            token_in,
-            token_out,
-            mid,
-            fee,
+        let latest_block = match provider
+            .get_block(BlockId::latest(), BlockTransactionsKind::Hashes)
+            .await
+        {
+            Ok(Some(block)) => block,
+            _ => continue,
+        };
+        // the victim and the trade land in the next block at the earliest
+        let base_fee = predict_base_fee(&latest_block);
+
+        if let Err(err) = load_specific_pools(
             simulator.clone(),
+            &registry,
+            message.log_pool_address,
+            message.corresponding_pool_address,
+        )
+        .await
+        {
+            info!("Error loading pools: {:?}", err);
+            continue;
+        }
+
+        let (v3_pool, v2_pool) = if message.pool_variant == 3 {
+            (message.log_pool_address, message.corresponding_pool_address)
+        } else {
+            (message.corresponding_pool_address, message.log_pool_address)
+        };
+        let route = RouteKey {
+            pool: v3_pool,
+            token_in: message.token0,
+            token_out: message.token1,
+        };
+        if let Err(err) = simulator.lock().await.prefetch_route(&route).await {
+            log::debug!("Error prefetching route {:?}: {:?}", route, err);
+        }
+        if !tokens_are_tradable(
+            &simulator,
+            &graph,
+            [message.token0, message.token1],
+            v2_pool,
+        )
+        .await
+        {
+            continue;
+        }
+
+        let fork = match fork_after_victim(&simulator, &victim, base_fee).await {
+            Ok(fork) => fork,
+            Err(err) => {
+                log::debug!("Victim {:?} doesn't go through: {}", victim.tx_hash, err);
+                continue;
+            }
+        };
+        // stays on this task like the simulator it was forked from
+        #[allow(clippy::arc_with_non_send_sync)]
+        let fork = Arc::new(Mutex::new(fork));
+        // funding and approvals stay on the fork, the shared simulator is left as it is
+        if let Err(err) = setup_evm(fork.clone(), provider.clone()).await {
+            info!("Error setting up the fork: {:?}", err);
+            continue;
+        }
+
+        let optimal_result = match find_optimal_amount_v3_to_v2(
+            FlashSwapParams {
+                token_in: message.token0,
+                token_out: message.token1,
+                fee: message.fee,
+                v3_pool,
+                v2_pool,
+                gas_price: U256::from(base_fee),
+            },
+            fork.clone(),
             provider.clone(),
         )
         .await
-        .unwrap_or(U256::ZERO);
+        {
+            Ok(res) => res,
+            Err(_) => continue,
+        };
+        if optimal_result.possible_profit.is_zero() {
+            continue;
+        }
+        let Some((optimal_result, fees)) = price_trade(
+            &fee_policy,
+            &provider,
+            optimal_result,
+            base_fee,
+            min_net_profit,
+        )
+        .await
+        else {
+            continue;
+        };
+        info!(
+            "Backrun of {:?} found, gross {} gas cost {} ({} gas) net {}",
+            victim.tx_hash,
+            optimal_result.possible_profit,
+            optimal_result.gas_cost,
+            optimal_result.gas_used,
+            optimal_result.net_profit
+        );
+
+        if provider.get_block_number().await.unwrap_or_default() > latest_block.header.number {
+            info!("Block has passed, opportunity has passed");
+            continue;
+        }
 
-        // Calculate profit based on mid amount
-        let current_profit = v3_amount_out;
+        let trade =
+            match sign_trade(&fork, &provider, &message, &route, &optimal_result, fees).await {
+                Ok(trade) => trade,
+                Err(err) => {
+                    info!("Error signing backrun: {:?}", err);
+                    continue;
+                }
+            };
+
+        // both transactions from the current state, the way the builder will run them
+        let txs = vec![victim.raw, trade.encoded_2718().into()];
+        let local = simulator
+            .lock()
+            .await
+            .simulate_bundle(&txs, U256::from(base_fee));
+        let local = match local {
+            Ok(local) if local.txs.iter().any(|tx| tx.reverted) => {
+                info!("Backrun of {:?} reverts in the bundle", victim.tx_hash);
+                continue;
+            }
+            Ok(local) => local,
+            Err(err) => {
+                info!("Error simulating bundle: {:?}", err);
+                continue;
+            }
+        };
+        let submitter = bundle_submitter.clone();
+        let block = latest_block.header.number;
+        tokio::spawn(async move { verify_and_submit(&submitter, txs, block, local).await });
+    }
+}
 
-        // Update best profit if better
-        if current_profit > best_profit {
-            best_profit = current_profit;
-            optimal_amount = mid;
-            // If profit is increasing, search upper half
-            left = mid + U256::from(1);
-        } else {
-            // If profit is decreasing, search lower half
-            right = mid - U256::from(1);
+/// Fork of `simulator` with the signed `victim` applied at `base_fee`, the state a backrun
+/// is searched on. Fails when the victim doesn't go through.
+async fn fork_after_victim(
+    simulator: &Mutex<EvmSimulator<'_>>,
+    victim: &VictimTx,
+    base_fee: u64,
+) -> Result<EvmSimulator<'static>> {
+    let mut fork = simulator.lock().await.fork().simulator();
+    fork.set_base_fee(U256::from(base_fee)).await;
+    fork.call_raw(&victim.raw).into_result()?;
+    Ok(fork)
+}
+
+/// Choose the tip for `result` and price its gas with it. None when the policy fails or
+/// the net profit doesn't clear `min_net_profit`.
+async fn price_trade(
+    fee_policy: &PriorityFeePolicy,
+    provider: &RootProvider<PubSubFrontend, Ethereum>,
+    result: ArbitrageResult,
+    base_fee: u64,
+    min_net_profit: U256,
+) -> Option<(ArbitrageResult, TxFees)> {
+    // the tip can depend on the profit, so the gas is priced again with it
+    let priority_fee = match fee_policy
+        .priority_fee(provider, result.possible_profit, result.gas_used)
+        .await
+    {
+        Ok(fee) => fee,
+        Err(err) => {
+            log::debug!("Error choosing priority fee: {:?}", err);
+            return None;
         }
+    };
+    let fees = TxFees::new(base_fee, priority_fee);
+    let gas_used = result.gas_used;
+    let result = result.with_gas(gas_used, U256::from(base_fee as u128 + priority_fee));
+
+    if !result.is_profitable(min_net_profit) {
+        log::debug!(
+            "Gross profit {} doesn't cover gas {} plus margin {}",
+            result.possible_profit,
+            result.gas_cost,
+            min_net_profit
+        );
+        return None;
     }
-    if best_profit == U256::ZERO {
-        return Ok(ArbitrageResult {
-            optimal_amount: U256::ZERO,
-            possible_profit: U256::ZERO,
-        });
+    Some((result, fees))
+}
+
+/// Persist the slots the last search of `route` touched, if they changed since the last save
+async fn save_route_accesses(
+    store: &mut PoolStore,
+    simulator: &Mutex<EvmSimulator<'_>>,
+    saved: &mut HashMap<RouteKey, AccessSet>,
+    route: &RouteKey,
+) {
+    let Some(accesses) = simulator.lock().await.route_accesses.get(route).cloned() else {
+        return;
+    };
+    if saved.get(route) == Some(&accesses) {
+        return;
     }
+    match store.save_route_accesses(route, &accesses) {
+        Ok(()) => {
+            saved.insert(*route, accesses);
+        }
+        Err(err) => log::debug!("Error saving accesses of {:?}: {:?}", route, err),
+    }
+}
 
-    // convert optimal amount to weth
+/// Our flash swap on `route`, signed by `SENDER` for the executor
+async fn sign_trade(
+    simulator: &Mutex<EvmSimulator<'_>>,
+    provider: &RootProvider<PubSubFrontend, Ethereum>,
+    message: &LogEvent,
+    route: &RouteKey,
+    result: &ArbitrageResult,
+    fees: TxFees,
+) -> Result<TxEnvelope> {
+    let transaction = create_input_data(
+        route.pool,
+        message.fee,
+        message.token1,
+        message.token0,
+        result.optimal_amount,
+    )
+    .await
+    .inspect(|e| info!("Error creating input data: {:?}", e))?;
+
+    let contract_address =
+        executor_from_env()?.ok_or_else(|| anyhow!("CONTRACT_ADDRESS is not set"))?;
+
+    let nonce = provider
+        .get_transaction_count(SENDER)
+        .await
+        .inspect(|e| info!("error getting nonce, {:?}", e))?;
 
-    alloy::sol! {
-        #[derive(Debug)]
-        function quoteExactInput(
-            bytes memory path,
-            uint256 amountIn
-        ) external returns (uint256 amountOut, uint160[] sqrtPriceX96AfterList, uint32[] initializedTicksCrossedList, uint256 gasEstimate);
+    // headroom over the simulated gas, the state can still move before inclusion
+    let gas_limit = result.gas_used + result.gas_used / 4;
+    let access_list = simulator
+        .lock()
+        .await
+        .route_access_list(route, SENDER, contract_address);
+    sign_transaction(
+        contract_address,
+        fees,
+        gas_limit,
+        transaction,
+        nonce,
+        access_list,
+    )
+    .await
+}
+
+/// Prices the multi-hop cycles found by the token graph. The arboo contract only executes
+/// two-pool flash swaps, so profitable routes are reported but not sent yet.
+pub async fn route_strategy(
+    sender: Sender<RouteCandidate>,
+    provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+) -> Result<()> {
+    let mut candidate_reciever = sender.subscribe();
+    loop {
+        match candidate_reciever.recv().await {
+            Ok(candidate) => {
+                let time = std::time::Instant::now();
+                let block = candidate
+                    .block_number
+                    .map(BlockId::from)
+                    .unwrap_or(BlockId::latest());
+
+                for route in candidate.routes {
+                    let quoters = match load_route_quoters(&route, provider.clone(), block).await {
+                        Ok(quoters) => quoters,
+                        Err(err) => {
+                            log::debug!("Error loading route {}: {:?}", route, err);
+                            continue;
+                        }
+                    };
+
+                    let result = match optimal_route(&route, &quoters, one_thousand_eth()) {
+                        Ok(result) => result,
+                        Err(err) => {
+                            log::debug!("Error pricing route {}: {:?}", route, err);
+                            continue;
+                        }
+                    };
+                    if result.possible_profit.is_zero() {
+                        continue;
+                    }
+                    info!(
+                        "Route {} profitable: {} in for {} profit ({} iterations)",
+                        route, result.optimal_amount, result.possible_profit, result.iterations
+                    );
+                }
+                log::debug!(
+                    "Time taken to price routes through {:?}: {:?}",
+                    candidate.touched_pool,
+                    time.elapsed()
+                );
+            }
+            Err(err) => {
+                info!("Error Recieving route: {err}")
+            }
+        }
     }
+}
 
-    let latest_gas_limit = latest_block.header.gas_limit;
-    let latest_gas_price = U256::from(latest_block.header.base_fee_per_gas.expect("gas"));
-    let mut sim = simulator.lock().await;
-    let mut path = Vec::new();
-    path.extend_from_slice(token_in.as_slice());
-    path.extend_from_slice(&U24::from(3000).to_be_bytes_vec());
-    path.extend_from_slice(get_address(AddressType::Weth).as_slice());
-    let path = alloy::primitives::Bytes::from(path);
-
-    let tx_data = quoteExactInputCall {
-        path,
-        amountIn: best_profit,
+/// Classify every token of the pair except WETH, excluding the bad ones from the graph.
+/// The balance slots of tradable tokens are looked up too, so the next prefetch of their
+/// pools warms the pool balances.
+async fn tokens_are_tradable(
+    simulator: &Mutex<EvmSimulator<'_>>,
+    graph: &RwLock<TokenGraph>,
+    tokens: [Address; 2],
+    holder: Address,
+) -> bool {
+    let weth = get_address(AddressType::Weth);
+    for token in tokens {
+        let mut simulator = simulator.lock().await;
+        let verdict = match token == weth {
+            true => Ok(TokenVerdict::Standard),
+            false => simulator.classify_token(token, holder),
+        };
+        match verdict {
+            Ok(verdict) if verdict.is_tradable() => {
+                if let Err(err) = simulator.find_balance_slot(token) {
+                    log::debug!("Error finding the balance slot of {:?}: {:?}", token, err);
+                }
+            }
+            Ok(verdict) => {
+                drop(simulator);
+                if graph.write().await.exclude_token(token) {
+                    info!("Excluding token {:?}: {}", token, verdict);
+                }
+                return false;
+            }
+            Err(err) => {
+                log::debug!("Error classifying token {:?}: {:?}", token, err);
+                return false;
+            }
+        }
     }
-    .abi_encode();
+    true
+}
 
-    let tx = Tx {
-        caller: sim.owner,
-        transact_to: get_address(AddressType::V2Quoter),
-        data: tx_data.into(),
-        value: U256::ZERO,
-        gas_price: latest_gas_price,
-        gas_limit: latest_gas_limit,
-    };
+async fn load_route_quoters(
+    route: &Route,
+    provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+    block: BlockId,
+) -> Result<Vec<PoolQuoter>> {
+    let mut quoters = Vec::with_capacity(route.hops.len());
+    for hop in &route.hops {
+        let (token0, token1) = if hop.token_in < hop.token_out {
+            (hop.token_in, hop.token_out)
+        } else {
+            (hop.token_out, hop.token_in)
+        };
+        let quoter = match hop.variant {
+            DexVariant::UniswapV2 => PoolQuoter::V2(
+                ConstantProductQuoter::from_provider(provider.clone(), hop.pool).await?,
+            ),
+            DexVariant::UniswapV3 => PoolQuoter::V3(
+                V3PoolState::load(
+                    provider.clone(),
+                    hop.pool,
+                    token0,
+                    token1,
+                    block,
+                    DEFAULT_WORD_RANGE,
+                )
+                .await?,
+            ),
+        };
+        quoters.push(quoter);
+    }
+    Ok(quoters)
+}
 
-    let res = sim.call(tx)?;
+/// A V3 to V2 flash swap to size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashSwapParams {
+    pub token_in: Address,
+    pub token_out: Address,
+    /// Fee tier of the V3 pool
+    pub fee: U24,
+    pub v3_pool: Address,
+    pub v2_pool: Address,
+    /// Price the trade's gas is costed at
+    pub gas_price: U256,
+}
+
+pub async fn find_optimal_amount_v3_to_v2(
+    params: FlashSwapParams,
+    simulator: Arc<TokioMutex<EvmSimulator<'_>>>,
+    provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+) -> Result<ArbitrageResult> {
+    let FlashSwapParams {
+        token_in,
+        token_out,
+        fee,
+        v3_pool,
+        v2_pool,
+        gas_price,
+    } = params;
+
+    // Pool state is read once, every step after this prices both legs off-chain
+    let (token0, token1) = if token_in < token_out {
+        (token_in, token_out)
+    } else {
+        (token_out, token_in)
+    };
+    let v2_quoter =
+        ConstantProductQuoter::from_simulator(&*simulator.lock().await, v2_pool, token0, token1)
+            .await?;
+    let pinned_block = BlockId::from(simulator.lock().await.block_number.to::<u64>());
+    let mut v3_state = V3PoolState::load(
+        provider.clone(),
+        v3_pool,
+        token0,
+        token1,
+        pinned_block,
+        DEFAULT_WORD_RANGE,
+    )
+    .await?;
+    // swaps move the price but not the ticks, so the simulator's price is all that can
+    // differ from the pinned block, e.g. after a pending swap was applied to a fork
+    v3_state.slot0 = simulator.lock().await.get_v3_liquidity(v3_pool).await?;
+
+    let search = optimal_v3_to_v2(&v3_state, &v2_quoter, token_in, token_out);
+    if search.possible_profit.is_zero() {
+        return Ok(search);
+    }
+    let optimal_amount = search.optimal_amount;
+
+    // Confirm the off-chain estimate with full runs of the flash swap, the estimate and a
+    // step either side of it, each from the same pre-state
+    let step = optimal_amount / U256::from(10);
+    let amounts = [optimal_amount - step, optimal_amount, optimal_amount + step];
+    let runs = simulate_amounts(
+        v3_pool,
+        token_in,
+        token_out,
+        &amounts,
+        fee,
+        simulator.clone(),
+    )
+    .await?;
+    let (optimal_amount, best_run) = amounts
+        .into_iter()
+        .zip(runs)
+        .filter_map(|(amount, run)| Some((amount, run.ok()?)))
+        .max_by_key(|(_, run)| run.profit)
+        .unwrap_or((optimal_amount, SwapRun::default()));
+
+    if best_run.profit.is_zero() {
+        return Ok(ArbitrageResult {
+            optimal_amount: U256::ZERO,
+            possible_profit: U256::ZERO,
+            ..search
+        });
+    }
 
-    let possible_profit = decode_quote_output_v3(res.output).expect("failed to decode output");
-    log::debug!("possible_profit {possible_profit}");
+    // the runs measure the owner's WETH balance, so the profit is in WETH already
     Ok(ArbitrageResult {
         optimal_amount,
-        possible_profit,
-    })
+        possible_profit: best_run.profit,
+        ..search
+    }
+    .with_gas(best_run.gas_used, gas_price))
 }
-// Helper function to decode V3 quoter output
-fn decode_quote_output_v3(output: revm::primitives::Bytes) -> Result<U256> {
-    let output = hex::decode(output.to_string().trim_start_matches("0x"))?;
 
-    let number = U256::from_be_slice(&output[0..32]);
+/// Net profit in wei a trade has to clear, unless `MIN_NET_PROFIT` is set
+const DEFAULT_MIN_NET_PROFIT: u128 = 100_000;
 
-    Ok(number)
+/// Wei amount from the environment, `default` when it isn't set
+fn wei_from_env(key: &str, default: u128) -> Result<U256> {
+    match var(key) {
+        Ok(value) => Ok(U256::from_str(&value)?),
+        Err(_) => Ok(U256::from(default)),
+    }
 }
 
-async fn load_specific_pools<'a>(
+async fn load_specific_pools(
     simulator: Arc<Mutex<EvmSimulator<'_>>>,
+    registry: &RwLock<PoolRegistry>,
     pool_a: Address,
     pool_b: Address,
 ) -> Result<()> {
-    let mut pools_map: HashMap<Address, Event> = HashMap::new();
-    let path = Path::new("cache/.cached-pools.csv");
-    let file = File::open(&path).expect("Error getting File");
-    let reader = io::BufReader::new(file);
-
-    let sim = simulator.lock().await;
-
-    for line in reader.lines().skip(1) {
-        // Skip the header line
-        let line = line.expect("Expected Line");
-        let fields: Vec<&str> = line.split(',').collect();
-        match fields[2] {
-            "2" => {
-                let pair_address = Address::from_str(fields[1]).unwrap_or_default();
-                pools_map.insert(
-                    pair_address,
-                    Event::PairCreated(V2PoolCreated {
-                        pair_address: Address::from_str(fields[1]).unwrap_or_default(),
-                        token0: Address::from_str(fields[3]).unwrap_or_default(),
-                        token1: Address::from_str(fields[4]).unwrap_or_default(),
-                        fee: fields[5].parse::<u32>().unwrap_or_default(),
-                        block_number: fields[6].parse::<u64>().unwrap_or_default(),
-                    }),
-                );
-            }
-            "3" => {
-                let pair_address = Address::from_str(fields[1]).unwrap_or_default();
-                pools_map.insert(
-                    pair_address,
-                    Event::PoolCreated(V3PoolCreated {
-                        pair_address: Address::from_str(fields[1]).unwrap_or_default(),
-                        token0: Address::from_str(fields[3]).unwrap_or_default(),
-                        token1: Address::from_str(fields[4]).unwrap_or_default(),
-                        fee: fields[5].parse::<u32>().unwrap_or_default(),
-                        tick_spacing: 0i32,
-                    }),
-                );
-            }
-            &_ => continue,
-        };
-    }
-    match pools_map.get(&pool_a) {
-        Some(pool) => match pool {
-            Event::PoolCreated(pool) => {
-                sim.load_v3_pool_state(pool.pair_address)
-                    .await
-                    .expect("Failed to load v2 pool state");
-            }
-            Event::PairCreated(pool) => {
-                sim.load_v2_pool_state(pool.pair_address)
-                    .await
-                    .expect("Failed to load v2 pool state");
-                sim.load_pool_state(pool.pair_address)
-                    .await
-                    .expect("Failed to load basic state");
-            }
-        },
-        _ => {}
-    };
-
-    match pools_map.get(&pool_b) {
-        Some(pool) => match pool {
-            Event::PoolCreated(pool) => {
-                sim.load_v3_pool_state(pool.pair_address)
-                    .await
-                    .expect("Failed to load v2 pool state");
-            }
-            Event::PairCreated(pool) => {
-                sim.load_v3_pool_state(pool.pair_address)
-                    .await
-                    .expect("Failed to load v2 pool state");
-            }
-        },
-        _ => {}
+    let pools: Vec<Pool> = {
+        let registry = registry.read().await;
+        [pool_a, pool_b]
+            .iter()
+            .filter_map(|pool| registry.get(pool).copied())
+            .collect()
     };
+    let fetched = simulator.lock().await.prefetch_pools(&pools).await?;
+    log::debug!(
+        "Prefetched {} slots of {:?} and {:?}",
+        fetched,
+        pool_a,
+        pool_b
+    );
 
     Ok(())
 }
@@ -354,16 +779,18 @@ async fn setup_evm(
         .unwrap_or_default();
 
     let latest_gas_limit = latest_block.header.gas_limit;
-    let latest_gas_price = U256::from(latest_block.header.base_fee_per_gas.expect("gas"));
-
-    // deploy contract:
+    // the simulator rejects gas priced under the base fee of the block it is pinned to
+    let latest_gas_price = simulator.lock().await.get_base_fee().await;
 
+    // the real executor runs its own code, a local one gets the bundled bytecode
     let contract_address = simulator.lock().await.contract_address;
-    simulator
-        .lock()
-        .await
-        .deploy_code_at(contract_address, arboo_bytecode())
-        .await;
+    if !simulator.lock().await.has_code(contract_address)? {
+        simulator
+            .lock()
+            .await
+            .deploy_code_at(contract_address, arboo_bytecode())
+            .await;
+    }
 
     // set initial eth value;
     let initial_eth_balance = U256::from(1_000_000) * U256::from(10).pow(U256::from(18));
@@ -399,7 +826,7 @@ async fn setup_evm(
         gas_price: latest_gas_price,
     };
 
-    simulator.lock().await.call(new_tx)?;
+    simulator.lock().await.call(new_tx).into_result()?;
 
     alloy::sol! {
         function approve(address spender, uint256 amount) external returns (bool);
@@ -419,6 +846,121 @@ async fn setup_evm(
         gas_price: latest_gas_price,
     };
 
-    simulator.lock().await.call(approve_tx)?;
+    simulator.lock().await.call(approve_tx).into_result()?;
+
+    // the calls above bumped the nonce, signed transactions carry the one of the chain
+    let nonce = provider.get_transaction_count(wallet).await?;
+    simulator.lock().await.set_nonce(wallet, nonce).await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::fork::offline_simulator;
+    use alloy::network::{EthereumWallet, TransactionBuilder};
+    use alloy::rpc::types::TransactionRequest;
+    use revm::primitives::Bytecode;
+
+    /// WETH stand-in: `balanceOf` returns slot 0 for anyone, a call without data sets it to 1
+    fn weth_stub() -> Bytecode {
+        Bytecode::new_raw(
+            vec![
+                0x36, 0x60, 0x0a, 0x57, 0x60, 0x01, 0x60, 0x00, 0x55, 0x00, 0x5b, 0x60, 0x00, 0x54,
+                0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+            ]
+            .into(),
+        )
+    }
+
+    /// Executor stand-in: pays through `weth_stub` once `pool` holds any ether
+    fn executor_stub(pool: Address) -> Bytecode {
+        let mut code = vec![0x73];
+        code.extend_from_slice(pool.as_slice());
+        code.extend_from_slice(&[0x31, 0x15, 0x60, 0x3c, 0x57]);
+        code.extend_from_slice(&[0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00]);
+        code.push(0x73);
+        code.extend_from_slice(get_address(AddressType::Weth).as_slice());
+        code.extend_from_slice(&[0x5a, 0xf1, 0x50, 0x5b, 0x00]);
+        Bytecode::new_raw(code.into())
+    }
+
+    /// A pending transfer of one wei to `pool`
+    async fn signed_victim(pool: Address, nonce: u64) -> VictimTx {
+        let signer = PrivateKeySigner::from_bytes(&[0x42; 32].into()).unwrap();
+        let envelope = TransactionRequest::default()
+            .with_chain_id(1)
+            .with_to(pool)
+            .with_value(U256::from(1))
+            .with_nonce(nonce)
+            .with_max_fee_per_gas(3_000_000_000)
+            .with_max_priority_fee_per_gas(1_000_000_000)
+            .with_gas_limit(21_000)
+            .build(&EthereumWallet::from(signer.clone()))
+            .await
+            .unwrap();
+        VictimTx {
+            tx_hash: *envelope.tx_hash(),
+            from: signer.address(),
+            to: pool,
+            value: U256::from(1),
+            raw: envelope.encoded_2718().into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backrun_is_searched_after_the_victim() {
+        let pool = Address::repeat_byte(0x77);
+        let mut simulator = offline_simulator().await;
+        simulator.evm.get_mut().context.evm.env.block.gas_limit = U256::from(30_000_000);
+        let (owner, executor) = (simulator.owner, simulator.contract_address);
+        simulator.set_eth_balance(owner, one_thousand_eth()).await;
+        simulator
+            .deploy_code_at(executor, executor_stub(pool))
+            .await;
+        simulator
+            .deploy_code_at(get_address(AddressType::Weth), weth_stub())
+            .await;
+        let victim = signed_victim(pool, 0).await;
+        simulator.set_eth_balance(victim.from, one_ether()).await;
+        #[allow(clippy::arc_with_non_send_sync)]
+        let simulator = Arc::new(Mutex::new(simulator));
+
+        let search = |simulator| async move {
+            let runs = simulate_amounts(
+                pool,
+                Address::repeat_byte(0x01),
+                Address::repeat_byte(0x02),
+                &[one_ether()],
+                U24::from(3000),
+                simulator,
+            )
+            .await
+            .unwrap();
+            runs.into_iter().next().unwrap().unwrap().profit
+        };
+
+        // nothing to take before the victim moved the pool
+        assert_eq!(search(simulator.clone()).await, U256::ZERO);
+
+        let fork = fork_after_victim(&simulator, &victim, 1_000_000_000)
+            .await
+            .unwrap();
+        #[allow(clippy::arc_with_non_send_sync)]
+        let fork = Arc::new(Mutex::new(fork));
+        assert_eq!(search(fork.clone()).await, U256::from(1));
+        assert_eq!(fork.lock().await.get_eth_balance(pool).await, U256::from(1));
+        assert_eq!(
+            simulator.lock().await.get_eth_balance(pool).await,
+            U256::ZERO,
+            "the victim ran on the fork only"
+        );
+
+        // signed for a nonce the account hasn't reached
+        let early = signed_victim(pool, 5).await;
+        assert!(fork_after_victim(&simulator, &early, 1_000_000_000)
+            .await
+            .is_err());
+    }
+}
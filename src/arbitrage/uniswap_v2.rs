@@ -0,0 +1,205 @@
+use alloy::network::Ethereum;
+use alloy::providers::RootProvider;
+use alloy::pubsub::PubSubFrontend;
+use anyhow::{anyhow, Result};
+use revm::primitives::{Address, U256};
+use std::sync::Arc;
+
+use crate::common::revm::EvmSimulator;
+
+/// Uniswap V2 charges 0.3% on the input amount, expressed as 997/1000
+pub const FEE_NUMERATOR: u64 = 997;
+pub const FEE_DENOMINATOR: u64 = 1000;
+
+/// Storage slot of the packed `reserve0 | reserve1 | blockTimestampLast` word in UniswapV2Pair
+pub const RESERVES_SLOT: U256 = U256::from_limbs([8, 0, 0, 0]);
+
+alloy::sol! {
+    #[sol(rpc)]
+    interface IV2Pair {
+        function token0() external view returns (address);
+        function token1() external view returns (address);
+        function getReserves() external view returns (uint112 reserve0, uint112 reserve1, uint32 blockTimestampLast);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct V2Reserves {
+    pub reserve0: U256,
+    pub reserve1: U256,
+    pub block_timestamp_last: u32,
+}
+
+impl V2Reserves {
+    /// Unpack the reserves from the raw value of storage slot 8.
+    /// Layout (low to high bits): reserve0 (112) | reserve1 (112) | blockTimestampLast (32)
+    pub fn from_slot(value: U256) -> Self {
+        let mask_112 = (U256::from(1) << 112usize) - U256::from(1);
+        Self {
+            reserve0: value & mask_112,
+            reserve1: (value >> 112usize) & mask_112,
+            block_timestamp_last: (value >> 224usize).to::<u32>(),
+        }
+    }
+
+    /// Returns `(reserve_in, reserve_out)` for a swap in the given direction
+    pub fn oriented(&self, zero_for_one: bool) -> (U256, U256) {
+        if zero_for_one {
+            (self.reserve0, self.reserve1)
+        } else {
+            (self.reserve1, self.reserve0)
+        }
+    }
+}
+
+/// Port of `UniswapV2Library.getAmountOut`
+pub fn get_amount_out(amount_in: U256, reserve_in: U256, reserve_out: U256) -> Result<U256> {
+    if amount_in.is_zero() {
+        return Err(anyhow!("UniswapV2Library: INSUFFICIENT_INPUT_AMOUNT"));
+    }
+    if reserve_in.is_zero() || reserve_out.is_zero() {
+        return Err(anyhow!("UniswapV2Library: INSUFFICIENT_LIQUIDITY"));
+    }
+    let amount_in_with_fee = amount_in
+        .checked_mul(U256::from(FEE_NUMERATOR))
+        .ok_or(anyhow!("amount_in overflow"))?;
+    let numerator = amount_in_with_fee
+        .checked_mul(reserve_out)
+        .ok_or(anyhow!("numerator overflow"))?;
+    let denominator = reserve_in * U256::from(FEE_DENOMINATOR) + amount_in_with_fee;
+    Ok(numerator / denominator)
+}
+
+/// Port of `UniswapV2Library.getAmountIn`
+pub fn get_amount_in(amount_out: U256, reserve_in: U256, reserve_out: U256) -> Result<U256> {
+    if amount_out.is_zero() {
+        return Err(anyhow!("UniswapV2Library: INSUFFICIENT_OUTPUT_AMOUNT"));
+    }
+    if reserve_in.is_zero() || reserve_out.is_zero() || amount_out >= reserve_out {
+        return Err(anyhow!("UniswapV2Library: INSUFFICIENT_LIQUIDITY"));
+    }
+    let numerator = reserve_in
+        .checked_mul(amount_out)
+        .and_then(|n| n.checked_mul(U256::from(FEE_DENOMINATOR)))
+        .ok_or(anyhow!("numerator overflow"))?;
+    let denominator = (reserve_out - amount_out) * U256::from(FEE_NUMERATOR);
+    Ok(numerator / denominator + U256::from(1))
+}
+
+/// A V2 pair with its reserves snapshotted once, so every quote after that is pure math
+#[derive(Debug, Clone, Copy)]
+pub struct ConstantProductQuoter {
+    pub pair: Address,
+    pub token0: Address,
+    pub token1: Address,
+    pub reserves: V2Reserves,
+}
+
+impl ConstantProductQuoter {
+    /// Read token0/token1 and `getReserves()` from the node
+    pub async fn from_provider(
+        provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+        pair: Address,
+    ) -> Result<Self> {
+        let contract = IV2Pair::new(pair, provider);
+        let token0 = contract.token0().call().await?._0;
+        let token1 = contract.token1().call().await?._0;
+        let reserves = contract.getReserves().call().await?;
+
+        Ok(Self {
+            pair,
+            token0,
+            token1,
+            reserves: V2Reserves {
+                reserve0: U256::from(reserves.reserve0),
+                reserve1: U256::from(reserves.reserve1),
+                block_timestamp_last: reserves.blockTimestampLast,
+            },
+        })
+    }
+
+    /// Read the reserves from slot 8 of the simulator's state, so the quote matches
+    /// what a confirmation run against the same `EvmSimulator` will see
+    pub async fn from_simulator(
+        simulator: &EvmSimulator<'_>,
+        pair: Address,
+        token0: Address,
+        token1: Address,
+    ) -> Result<Self> {
+        let reserves = simulator.get_v2_reserves(pair).await?;
+        Ok(Self {
+            pair,
+            token0,
+            token1,
+            reserves,
+        })
+    }
+
+    /// Amount of the other token received for `amount_in` of `token_in`
+    pub fn quote_exact_in(&self, token_in: Address, amount_in: U256) -> Result<U256> {
+        let (reserve_in, reserve_out) = self.reserves.oriented(self.zero_for_one(token_in)?);
+        get_amount_out(amount_in, reserve_in, reserve_out)
+    }
+
+    /// Amount of `token_in` required to receive `amount_out` of the other token
+    pub fn quote_exact_out(&self, token_in: Address, amount_out: U256) -> Result<U256> {
+        let (reserve_in, reserve_out) = self.reserves.oriented(self.zero_for_one(token_in)?);
+        get_amount_in(amount_out, reserve_in, reserve_out)
+    }
+
+    /// Reserve held by the pair for `token`
+    pub fn reserve_of(&self, token: Address) -> Result<U256> {
+        let (reserve, _) = self.reserves.oriented(self.zero_for_one(token)?);
+        Ok(reserve)
+    }
+
+    fn zero_for_one(&self, token_in: Address) -> Result<bool> {
+        if token_in == self.token0 {
+            Ok(true)
+        } else if token_in == self.token1 {
+            Ok(false)
+        } else {
+            Err(anyhow!("token {} is not in pair {}", token_in, self.pair))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ether(amount: u64) -> U256 {
+        U256::from(amount) * U256::from(10).pow(U256::from(18))
+    }
+
+    #[test]
+    fn test_get_amount_out_matches_router() {
+        // 1 ETH into a 100 / 200_000 pool: (1e18 * 997 * 200_000e18) / (100e18 * 1000 + 1e18 * 997)
+        let out = get_amount_out(ether(1), ether(100), ether(200_000)).unwrap();
+        assert_eq!(out, U256::from(1974316068794122597700u128));
+    }
+
+    #[test]
+    fn test_get_amount_in_round_trips() {
+        let reserve_in = ether(100);
+        let reserve_out = ether(200_000);
+        let amount_out = get_amount_out(ether(1), reserve_in, reserve_out).unwrap();
+        let amount_in = get_amount_in(amount_out, reserve_in, reserve_out).unwrap();
+        // getAmountIn rounds up, so it never asks for less than what produced the output
+        assert!(amount_in <= ether(1));
+        assert!(get_amount_out(amount_in, reserve_in, reserve_out).unwrap() >= amount_out);
+    }
+
+    #[test]
+    fn test_reserves_from_slot() {
+        let reserve0 = U256::from(123_456_789u64);
+        let reserve1 = U256::from(987_654_321u64);
+        let timestamp = 1_700_000_000u32;
+        let packed = reserve0 | (reserve1 << 112usize) | (U256::from(timestamp) << 224usize);
+
+        let reserves = V2Reserves::from_slot(packed);
+        assert_eq!(reserves.reserve0, reserve0);
+        assert_eq!(reserves.reserve1, reserve1);
+        assert_eq!(reserves.block_timestamp_last, timestamp);
+    }
+}
@@ -0,0 +1,457 @@
+use crate::arbitrage::simulation::{get_address, AddressType};
+use crate::arbitrage::v3_math::{
+    compute_swap_step, get_sqrt_ratio_at_tick, get_tick_at_sqrt_ratio,
+    next_initialized_tick_within_one_word, MAX_SQRT_RATIO, MAX_TICK, MIN_SQRT_RATIO, MIN_TICK,
+};
+use crate::common::pools::{IV3Pool, PoolLiquidity};
+use crate::common::prefetch::{bitmap_words, initialized_ticks};
+use crate::common::revm::{EvmSimulator, Tx};
+use alloy::eips::BlockId;
+use alloy::providers::RootProvider;
+use alloy::pubsub::PubSubFrontend;
+use alloy_primitives::aliases::{I24, U24};
+use alloy_primitives::{I256, U160};
+use alloy_sol_types::SolCall;
+use anyhow::{anyhow, Result};
+use revm::primitives::{Address, U256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Number of bitmap words loaded on each side of the current tick by default.
+/// One word covers `256 * tick_spacing` ticks, e.g. ~±15% of price for a 0.3% pool.
+pub const DEFAULT_WORD_RANGE: i16 = 2;
+
+/// Output of a native swap simulation, signed from the pool's point of view
+/// like `UniswapV3Pool.swap` (positive = paid into the pool)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapResult {
+    pub amount0: I256,
+    pub amount1: I256,
+    pub sqrt_price_x96: U256,
+    pub tick: i32,
+    pub liquidity: u128,
+    pub ticks_crossed: u32,
+}
+
+/// Snapshot of a V3 pool with enough of the tick bitmap loaded to quote swaps
+/// that cross initialized ticks without running the pool bytecode
+#[derive(Debug, Clone)]
+pub struct V3PoolState {
+    pub address: Address,
+    pub token0: Address,
+    pub token1: Address,
+    pub fee: u32,
+    pub tick_spacing: i32,
+    pub slot0: PoolLiquidity,
+    /// Loaded `tickBitmap` words. A swap that walks into a missing word errors out.
+    pub tick_bitmap: HashMap<i16, U256>,
+    /// `liquidityNet` of every initialized tick inside the loaded words
+    pub ticks: BTreeMap<i32, i128>,
+}
+
+impl V3PoolState {
+    /// Load slot0, liquidity, fee, spacing and `word_range` bitmap words either side
+    /// of the current tick, then the `liquidityNet` of each initialized tick in them
+    pub async fn load(
+        provider: Arc<RootProvider<PubSubFrontend>>,
+        pool: Address,
+        token0: Address,
+        token1: Address,
+        block: BlockId,
+        word_range: i16,
+    ) -> Result<Self> {
+        let contract = IV3Pool::new(pool, provider.clone());
+        let slot0 = PoolLiquidity::load(provider.clone(), pool, block).await?;
+        let fee = contract.fee().block(block).call().await?._0.to::<u32>();
+        let tick_spacing = contract
+            .tickSpacing()
+            .block(block)
+            .call()
+            .await?
+            ._0
+            .as_i32();
+
+        let words = bitmap_words(slot0.tick, tick_spacing, word_range);
+
+        let bitmap_requests = words.iter().map(|word| {
+            let contract = contract.clone();
+            async move {
+                let value = contract.tickBitmap(*word).block(block).call().await?._0;
+                Ok::<_, anyhow::Error>((*word, value))
+            }
+        });
+        let tick_bitmap: HashMap<i16, U256> = futures::future::try_join_all(bitmap_requests)
+            .await?
+            .into_iter()
+            .collect();
+
+        let initialized_ticks: Vec<i32> = tick_bitmap
+            .iter()
+            .flat_map(|(word, bits)| initialized_ticks(*word, *bits, tick_spacing))
+            .collect();
+
+        let tick_requests = initialized_ticks.iter().map(|tick| {
+            let contract = contract.clone();
+            async move {
+                let info = contract
+                    .ticks(I24::try_from(*tick)?)
+                    .block(block)
+                    .call()
+                    .await?;
+                Ok::<_, anyhow::Error>((*tick, info.liquidityNet))
+            }
+        });
+        let ticks: BTreeMap<i32, i128> = futures::future::try_join_all(tick_requests)
+            .await?
+            .into_iter()
+            .collect();
+
+        Ok(Self {
+            address: pool,
+            token0,
+            token1,
+            fee,
+            tick_spacing,
+            slot0,
+            tick_bitmap,
+            ticks,
+        })
+    }
+
+    /// Port of the `UniswapV3Pool.swap` loop, without the fee growth and oracle bookkeeping.
+    /// `amount_specified` > 0 is exact input, < 0 is exact output.
+    pub fn swap(
+        &self,
+        zero_for_one: bool,
+        amount_specified: I256,
+        sqrt_price_limit_x96: U256,
+    ) -> Result<SwapResult> {
+        if amount_specified.is_zero() {
+            return Err(anyhow!("AS"));
+        }
+        let sqrt_price_start = self.slot0.sqrt_price_x96;
+        let limit_ok = if zero_for_one {
+            sqrt_price_limit_x96 < sqrt_price_start && sqrt_price_limit_x96 > MIN_SQRT_RATIO
+        } else {
+            sqrt_price_limit_x96 > sqrt_price_start && sqrt_price_limit_x96 < MAX_SQRT_RATIO
+        };
+        if !limit_ok {
+            return Err(anyhow!("SPL"));
+        }
+
+        let exact_input = amount_specified.is_positive();
+        let mut amount_specified_remaining = amount_specified;
+        let mut amount_calculated = I256::ZERO;
+        let mut sqrt_price_x96 = sqrt_price_start;
+        let mut tick = self.slot0.tick;
+        let mut liquidity = self.slot0.liquidity.to::<u128>();
+        let mut ticks_crossed = 0;
+
+        while !amount_specified_remaining.is_zero() && sqrt_price_x96 != sqrt_price_limit_x96 {
+            let step_sqrt_price_start = sqrt_price_x96;
+            let (tick_next, initialized) = next_initialized_tick_within_one_word(
+                &self.tick_bitmap,
+                tick,
+                self.tick_spacing,
+                zero_for_one,
+            )?;
+            let tick_next = tick_next.clamp(MIN_TICK, MAX_TICK);
+            let sqrt_price_next_x96 = get_sqrt_ratio_at_tick(tick_next)?;
+
+            let target_is_limit = if zero_for_one {
+                sqrt_price_next_x96 < sqrt_price_limit_x96
+            } else {
+                sqrt_price_next_x96 > sqrt_price_limit_x96
+            };
+            let sqrt_price_target_x96 = if target_is_limit {
+                sqrt_price_limit_x96
+            } else {
+                sqrt_price_next_x96
+            };
+
+            let step = compute_swap_step(
+                sqrt_price_x96,
+                sqrt_price_target_x96,
+                liquidity,
+                amount_specified_remaining,
+                self.fee,
+            )?;
+            sqrt_price_x96 = step.sqrt_ratio_next_x96;
+
+            let amount_in = I256::from_raw(step.amount_in + step.fee_amount);
+            let amount_out = I256::from_raw(step.amount_out);
+            if exact_input {
+                amount_specified_remaining -= amount_in;
+                amount_calculated -= amount_out;
+            } else {
+                amount_specified_remaining += amount_out;
+                amount_calculated += amount_in;
+            }
+
+            if sqrt_price_x96 == sqrt_price_next_x96 {
+                if initialized {
+                    let mut liquidity_net = *self
+                        .ticks
+                        .get(&tick_next)
+                        .ok_or(anyhow!("tick {} not loaded", tick_next))?;
+                    if zero_for_one {
+                        liquidity_net = -liquidity_net;
+                    }
+                    liquidity = if liquidity_net < 0 {
+                        liquidity
+                            .checked_sub(liquidity_net.unsigned_abs())
+                            .ok_or(anyhow!("LS"))?
+                    } else {
+                        liquidity
+                            .checked_add(liquidity_net as u128)
+                            .ok_or(anyhow!("LA"))?
+                    };
+                    ticks_crossed += 1;
+                }
+                tick = if zero_for_one {
+                    tick_next - 1
+                } else {
+                    tick_next
+                };
+            } else if sqrt_price_x96 != step_sqrt_price_start {
+                tick = get_tick_at_sqrt_ratio(sqrt_price_x96)?;
+            }
+        }
+
+        let (amount0, amount1) = if zero_for_one == exact_input {
+            (
+                amount_specified - amount_specified_remaining,
+                amount_calculated,
+            )
+        } else {
+            (
+                amount_calculated,
+                amount_specified - amount_specified_remaining,
+            )
+        };
+
+        Ok(SwapResult {
+            amount0,
+            amount1,
+            sqrt_price_x96,
+            tick,
+            liquidity,
+            ticks_crossed,
+        })
+    }
+
+    /// Amount of the other token out for an exact input of `token_in`, using the same
+    /// price limits as the arboo flash swap
+    pub fn quote_exact_in(&self, token_in: Address, amount_in: U256) -> Result<U256> {
+        let zero_for_one = self.zero_for_one(token_in)?;
+        let sqrt_price_limit_x96 = if zero_for_one {
+            MIN_SQRT_RATIO + U256::from(1)
+        } else {
+            MAX_SQRT_RATIO - U256::from(1)
+        };
+        let result = self.swap(
+            zero_for_one,
+            I256::try_from(amount_in)?,
+            sqrt_price_limit_x96,
+        )?;
+        let amount_out = if zero_for_one {
+            result.amount1
+        } else {
+            result.amount0
+        };
+        Ok(amount_out.unsigned_abs())
+    }
+
+    fn zero_for_one(&self, token_in: Address) -> Result<bool> {
+        if token_in == self.token0 {
+            Ok(true)
+        } else if token_in == self.token1 {
+            Ok(false)
+        } else {
+            Err(anyhow!(
+                "token {} is not in pool {}",
+                token_in,
+                self.address
+            ))
+        }
+    }
+}
+
+/// Price a V3 exact input swap with QuoterV2 inside the simulator as a non-committing call.
+/// This is the EVM path the native `V3PoolState` quotes are checked against.
+pub fn quote_exact_input_single_evm(
+    sim: &mut EvmSimulator<'_>,
+    token_in: Address,
+    token_out: Address,
+    fee: U24,
+    amount_in: U256,
+    gas_limit: u64,
+    gas_price: U256,
+) -> Result<U256> {
+    alloy::sol! {
+        struct QuoteExactInputSingleParams {
+            address tokenIn;
+            address tokenOut;
+            uint256 amountIn;
+            uint24 fee;
+            uint160 sqrtPriceLimitX96;
+        }
+        function quoteExactInputSingle(QuoteExactInputSingleParams memory params)
+            external
+            returns (uint256 amountOut, uint160 sqrtPriceX96After, uint32 initializedTicksCrossed, uint256 gasEstimate);
+    }
+
+    let tx_data = quoteExactInputSingleCall {
+        params: QuoteExactInputSingleParams {
+            tokenIn: token_in,
+            tokenOut: token_out,
+            amountIn: amount_in,
+            fee,
+            sqrtPriceLimitX96: U160::ZERO,
+        },
+    }
+    .abi_encode();
+
+    let tx = Tx {
+        caller: sim.owner,
+        transact_to: get_address(AddressType::V2Quoter),
+        data: tx_data.into(),
+        value: U256::ZERO,
+        gas_price,
+        gas_limit,
+    };
+
+    let res = sim.staticcall(tx).into_result()?;
+    let decoded = quoteExactInputSingleCall::abi_decode_returns(&res.output, false)?;
+    Ok(decoded.amountOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrage::v3_math::tick_position;
+    use alloy::providers::{Provider, ProviderBuilder};
+    use alloy::rpc::client::WsConnect;
+    use alloy_primitives::{address, U64};
+
+    fn ether(amount: u64) -> U256 {
+        U256::from(amount) * U256::from(10).pow(U256::from(18))
+    }
+
+    /// Single range pool at price 1 with 2e18 liquidity and no initialized ticks
+    fn flat_pool() -> V3PoolState {
+        let mut tick_bitmap = HashMap::new();
+        for word in -4..=4 {
+            tick_bitmap.insert(word, U256::ZERO);
+        }
+        V3PoolState {
+            address: Address::ZERO,
+            token0: Address::with_last_byte(1),
+            token1: Address::with_last_byte(2),
+            fee: 3000,
+            tick_spacing: 60,
+            slot0: PoolLiquidity {
+                liquidity: ether(2),
+                sqrt_price_x96: get_sqrt_ratio_at_tick(0).unwrap(),
+                tick: 0,
+            },
+            tick_bitmap,
+            ticks: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_swap_within_one_range_matches_closed_form() {
+        let pool = flat_pool();
+        let amount_in = U256::from(10).pow(U256::from(16));
+        let out = pool.quote_exact_in(pool.token0, amount_in).unwrap();
+
+        // x * y = L^2 with the fee taken from the input first
+        let liquidity = ether(2);
+        let amount_in_less_fee = amount_in * U256::from(997) / U256::from(1000);
+        let expected = liquidity * amount_in_less_fee / (liquidity + amount_in_less_fee);
+        assert!(
+            expected.abs_diff(out) <= U256::from(1),
+            "{expected} != {out}"
+        );
+    }
+
+    #[test]
+    fn test_swap_crosses_initialized_tick() {
+        // Extra liquidity in [-600, 600], so selling token0 past tick -600 drops to the base range
+        let mut pool = flat_pool();
+        pool.slot0.liquidity = ether(3);
+        pool.ticks.insert(-600, ether(1).to::<i128>());
+        pool.ticks.insert(600, -ether(1).to::<i128>());
+        let (word, bit) = tick_position(-600 / 60);
+        pool.tick_bitmap.insert(word, U256::from(1) << bit as usize);
+
+        let result = pool
+            .swap(
+                true,
+                I256::try_from(ether(1)).unwrap(),
+                MIN_SQRT_RATIO + U256::from(1),
+            )
+            .unwrap();
+        assert_eq!(result.ticks_crossed, 1);
+        assert_eq!(result.liquidity, ether(2).to::<u128>());
+        assert!(result.tick < -600);
+        assert_eq!(result.amount0, I256::try_from(ether(1)).unwrap());
+        assert!(result.amount1.is_negative());
+    }
+
+    #[test]
+    fn test_swap_errors_when_leaving_loaded_words() {
+        let pool = flat_pool();
+        // Enough input to push the price through all loaded words
+        assert!(pool.quote_exact_in(pool.token0, ether(1_000_000)).is_err());
+    }
+
+    /// Differential check of the native quote against QuoterV2 executed in revm.
+    /// Needs a node: `WS_URL=... cargo test -- --ignored`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_native_quote_matches_revm_quoter() {
+        dotenv::dotenv().ok();
+        let ws_url = std::env::var("WS_URL").expect("no ws url");
+        let provider = ProviderBuilder::new()
+            .on_ws(WsConnect::new(ws_url))
+            .await
+            .unwrap();
+        let provider = Arc::new(provider);
+        let block_number = provider.get_block_number().await.unwrap();
+
+        // USDC/WETH 0.3%
+        let pool = address!("8ad599c3A0ff1De082011EFDDc58f1908eb6e6D8");
+        let usdc = address!("A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48");
+        let weth = get_address(AddressType::Weth);
+
+        let state = V3PoolState::load(
+            provider.clone(),
+            pool,
+            usdc,
+            weth,
+            BlockId::from(block_number),
+            DEFAULT_WORD_RANGE,
+        )
+        .await
+        .unwrap();
+
+        let mut simulator = EvmSimulator::new(provider.clone(), None, U64::from(block_number));
+
+        for amount in [ether(1), ether(50), ether(500)] {
+            let native = state.quote_exact_in(weth, amount).unwrap();
+            let evm = quote_exact_input_single_evm(
+                &mut simulator,
+                weth,
+                usdc,
+                U24::from(state.fee),
+                amount,
+                30_000_000,
+                U256::ZERO,
+            )
+            .unwrap();
+            assert_eq!(native, evm, "amount in {amount}");
+        }
+    }
+}
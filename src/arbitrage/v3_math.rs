@@ -0,0 +1,630 @@
+//! Rust ports of the Uniswap V3 core math libraries (`FullMath`, `TickMath`,
+//! `SqrtPriceMath`, `SwapMath` and the `TickBitmap` word search).
+//! Every function mirrors the Solidity rounding so quotes match the pool to the wei.
+use alloy_primitives::{I256, U512};
+use anyhow::{anyhow, Result};
+use revm::primitives::U256;
+use std::collections::HashMap;
+
+pub const MIN_TICK: i32 = -887272;
+pub const MAX_TICK: i32 = -MIN_TICK;
+pub const MIN_SQRT_RATIO: U256 = U256::from_limbs([4295128739, 0, 0, 0]);
+pub const MAX_SQRT_RATIO: U256 =
+    U256::from_limbs([0x5d951d5263988d26, 0xefd1fc6a50648849, 0xfffd8963, 0]);
+
+const RESOLUTION: usize = 96;
+const Q96: U256 = U256::from_limbs([0, 1 << 32, 0, 0]);
+const FEE_DENOMINATOR: u32 = 1_000_000;
+
+/// `FullMath.mulDiv`: `floor(a * b / denominator)` with a 512 bit intermediate
+pub fn mul_div(a: U256, b: U256, denominator: U256) -> Result<U256> {
+    if denominator.is_zero() {
+        return Err(anyhow!("FullMath: division by zero"));
+    }
+    let product: U512 = a.widening_mul(b);
+    let quotient = product / U512::from(denominator);
+    u512_to_u256(quotient)
+}
+
+/// `ceil(a * b / denominator)` with a 512 bit intermediate
+pub fn mul_div_rounding_up(a: U256, b: U256, denominator: U256) -> Result<U256> {
+    if denominator.is_zero() {
+        return Err(anyhow!("FullMath: division by zero"));
+    }
+    let product: U512 = a.widening_mul(b);
+    let denominator = U512::from(denominator);
+    let mut quotient = product / denominator;
+    if !(product % denominator).is_zero() {
+        quotient += U512::from(1);
+    }
+    u512_to_u256(quotient)
+}
+
+fn div_rounding_up(a: U256, b: U256) -> U256 {
+    let quotient = a / b;
+    if (a % b).is_zero() {
+        quotient
+    } else {
+        quotient + U256::from(1)
+    }
+}
+
+fn u512_to_u256(value: U512) -> Result<U256> {
+    let limbs = value.as_limbs();
+    if limbs[4..].iter().any(|limb| *limb != 0) {
+        return Err(anyhow!("FullMath: result overflows uint256"));
+    }
+    Ok(U256::from_limbs([limbs[0], limbs[1], limbs[2], limbs[3]]))
+}
+
+fn is_uint160(value: U256) -> bool {
+    value.bit_len() <= 160
+}
+
+/// `sqrt(1.0001^tick) * 2^96`, bit for bit with `TickMath.getSqrtRatioAtTick`
+pub fn get_sqrt_ratio_at_tick(tick: i32) -> Result<U256> {
+    let abs_tick = tick.unsigned_abs();
+    if abs_tick > MAX_TICK as u32 {
+        return Err(anyhow!("TickMath: tick {} out of range", tick));
+    }
+
+    // (bit, multiplier) pairs from TickMath.sol
+    const MULTIPLIERS: [(u32, u128); 19] = [
+        (0x2, 0xfff97272373d413259a46990580e213a),
+        (0x4, 0xfff2e50f5f656932ef12357cf3c7fdcc),
+        (0x8, 0xffe5caca7e10e4e61c3624eaa0941cd0),
+        (0x10, 0xffcb9843d60f6159c9db58835c926644),
+        (0x20, 0xff973b41fa98c081472e6896dfb254c0),
+        (0x40, 0xff2ea16466c96a3843ec78b326b52861),
+        (0x80, 0xfe5dee046a99a2a811c461f1969c3053),
+        (0x100, 0xfcbe86c7900a88aedcffc83b479aa3a4),
+        (0x200, 0xf987a7253ac413176f2b074cf7815e54),
+        (0x400, 0xf3392b0822b70005940c7a398e4b70f3),
+        (0x800, 0xe7159475a2c29b7443b29c7fa6e889d9),
+        (0x1000, 0xd097f3bdfd2022b8845ad8f792aa5825),
+        (0x2000, 0xa9f746462d870fdf8a65dc1f90e061e5),
+        (0x4000, 0x70d869a156d2a1b890bb3df62baf32f7),
+        (0x8000, 0x31be135f97d08fd981231505542fcfa6),
+        (0x10000, 0x9aa508b5b7a84e1c677de54f3e99bc9),
+        (0x20000, 0x5d6af8dedb81196699c329225ee604),
+        (0x40000, 0x2216e584f5fa1ea926041bedfe98),
+        (0x80000, 0x48a170391f7dc42444e8fa2),
+    ];
+
+    let mut ratio = if abs_tick & 0x1 != 0 {
+        U256::from(0xfffcb933bd6fad37aa2d162d1a594001u128)
+    } else {
+        U256::from(1) << 128usize
+    };
+    for (bit, multiplier) in MULTIPLIERS {
+        if abs_tick & bit != 0 {
+            ratio = (ratio * U256::from(multiplier)) >> 128usize;
+        }
+    }
+    if tick > 0 {
+        ratio = U256::MAX / ratio;
+    }
+
+    // Q128.128 -> Q64.96, rounding up so getTickAtSqrtRatio stays consistent
+    let remainder = ratio & U256::from(u32::MAX);
+    let rounding = if remainder.is_zero() {
+        U256::ZERO
+    } else {
+        U256::from(1)
+    };
+    Ok((ratio >> 32usize) + rounding)
+}
+
+/// Greatest tick whose ratio is `<= sqrt_price_x96`, the same contract as
+/// `TickMath.getTickAtSqrtRatio`, found by bisecting `get_sqrt_ratio_at_tick`
+pub fn get_tick_at_sqrt_ratio(sqrt_price_x96: U256) -> Result<i32> {
+    if sqrt_price_x96 < MIN_SQRT_RATIO || sqrt_price_x96 >= MAX_SQRT_RATIO {
+        return Err(anyhow!(
+            "TickMath: sqrt price {} out of range",
+            sqrt_price_x96
+        ));
+    }
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while low < high {
+        let mid = low + (high - low + 1) / 2;
+        if get_sqrt_ratio_at_tick(mid)? <= sqrt_price_x96 {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(low)
+}
+
+/// Port of `SqrtPriceMath.getNextSqrtPriceFromAmount0RoundingUp`
+fn get_next_sqrt_price_from_amount0_rounding_up(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    amount: U256,
+    add: bool,
+) -> Result<U256> {
+    if amount.is_zero() {
+        return Ok(sqrt_price_x96);
+    }
+    let numerator1 = U256::from(liquidity) << RESOLUTION;
+    let product = amount.checked_mul(sqrt_price_x96);
+
+    if add {
+        if let Some(product) = product {
+            if let Some(denominator) = numerator1.checked_add(product) {
+                return mul_div_rounding_up(numerator1, sqrt_price_x96, denominator);
+            }
+        }
+        let denominator = (numerator1 / sqrt_price_x96)
+            .checked_add(amount)
+            .ok_or(anyhow!("SqrtPriceMath: denominator overflow"))?;
+        Ok(div_rounding_up(numerator1, denominator))
+    } else {
+        let product = match product {
+            Some(product) if numerator1 > product => product,
+            _ => return Err(anyhow!("SqrtPriceMath: insufficient liquidity for amount0")),
+        };
+        let next = mul_div_rounding_up(numerator1, sqrt_price_x96, numerator1 - product)?;
+        if !is_uint160(next) {
+            return Err(anyhow!("SqrtPriceMath: price overflows uint160"));
+        }
+        Ok(next)
+    }
+}
+
+fn get_next_sqrt_price_from_amount1_rounding_down(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    amount: U256,
+    add: bool,
+) -> Result<U256> {
+    let liquidity = U256::from(liquidity);
+    if add {
+        let quotient = if is_uint160(amount) {
+            (amount << RESOLUTION) / liquidity
+        } else {
+            mul_div(amount, Q96, liquidity)?
+        };
+        let next = sqrt_price_x96
+            .checked_add(quotient)
+            .ok_or(anyhow!("SqrtPriceMath: price overflow"))?;
+        if !is_uint160(next) {
+            return Err(anyhow!("SqrtPriceMath: price overflows uint160"));
+        }
+        Ok(next)
+    } else {
+        let quotient = if is_uint160(amount) {
+            div_rounding_up(amount << RESOLUTION, liquidity)
+        } else {
+            mul_div_rounding_up(amount, Q96, liquidity)?
+        };
+        if sqrt_price_x96 <= quotient {
+            return Err(anyhow!("SqrtPriceMath: insufficient liquidity for amount1"));
+        }
+        Ok(sqrt_price_x96 - quotient)
+    }
+}
+
+pub fn get_next_sqrt_price_from_input(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    amount_in: U256,
+    zero_for_one: bool,
+) -> Result<U256> {
+    if sqrt_price_x96.is_zero() || liquidity == 0 {
+        return Err(anyhow!("SqrtPriceMath: zero price or liquidity"));
+    }
+    if zero_for_one {
+        get_next_sqrt_price_from_amount0_rounding_up(sqrt_price_x96, liquidity, amount_in, true)
+    } else {
+        get_next_sqrt_price_from_amount1_rounding_down(sqrt_price_x96, liquidity, amount_in, true)
+    }
+}
+
+pub fn get_next_sqrt_price_from_output(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    amount_out: U256,
+    zero_for_one: bool,
+) -> Result<U256> {
+    if sqrt_price_x96.is_zero() || liquidity == 0 {
+        return Err(anyhow!("SqrtPriceMath: zero price or liquidity"));
+    }
+    if zero_for_one {
+        get_next_sqrt_price_from_amount1_rounding_down(sqrt_price_x96, liquidity, amount_out, false)
+    } else {
+        get_next_sqrt_price_from_amount0_rounding_up(sqrt_price_x96, liquidity, amount_out, false)
+    }
+}
+
+pub fn get_amount0_delta(
+    sqrt_ratio_a_x96: U256,
+    sqrt_ratio_b_x96: U256,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<U256> {
+    let (sqrt_ratio_a_x96, sqrt_ratio_b_x96) = if sqrt_ratio_a_x96 > sqrt_ratio_b_x96 {
+        (sqrt_ratio_b_x96, sqrt_ratio_a_x96)
+    } else {
+        (sqrt_ratio_a_x96, sqrt_ratio_b_x96)
+    };
+    if sqrt_ratio_a_x96.is_zero() {
+        return Err(anyhow!("SqrtPriceMath: zero price"));
+    }
+    let numerator1 = U256::from(liquidity) << RESOLUTION;
+    let numerator2 = sqrt_ratio_b_x96 - sqrt_ratio_a_x96;
+
+    if round_up {
+        Ok(div_rounding_up(
+            mul_div_rounding_up(numerator1, numerator2, sqrt_ratio_b_x96)?,
+            sqrt_ratio_a_x96,
+        ))
+    } else {
+        Ok(mul_div(numerator1, numerator2, sqrt_ratio_b_x96)? / sqrt_ratio_a_x96)
+    }
+}
+
+pub fn get_amount1_delta(
+    sqrt_ratio_a_x96: U256,
+    sqrt_ratio_b_x96: U256,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<U256> {
+    let (sqrt_ratio_a_x96, sqrt_ratio_b_x96) = if sqrt_ratio_a_x96 > sqrt_ratio_b_x96 {
+        (sqrt_ratio_b_x96, sqrt_ratio_a_x96)
+    } else {
+        (sqrt_ratio_a_x96, sqrt_ratio_b_x96)
+    };
+    let liquidity = U256::from(liquidity);
+    if round_up {
+        mul_div_rounding_up(liquidity, sqrt_ratio_b_x96 - sqrt_ratio_a_x96, Q96)
+    } else {
+        mul_div(liquidity, sqrt_ratio_b_x96 - sqrt_ratio_a_x96, Q96)
+    }
+}
+
+/// One step of `SwapMath.computeSwapStep`, up to the next initialized tick or the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapStep {
+    pub sqrt_ratio_next_x96: U256,
+    pub amount_in: U256,
+    pub amount_out: U256,
+    pub fee_amount: U256,
+}
+
+/// Port of `SwapMath.computeSwapStep`. A positive `amount_remaining` is exact input,
+/// a negative one is exact output.
+pub fn compute_swap_step(
+    sqrt_ratio_current_x96: U256,
+    sqrt_ratio_target_x96: U256,
+    liquidity: u128,
+    amount_remaining: I256,
+    fee_pips: u32,
+) -> Result<SwapStep> {
+    let zero_for_one = sqrt_ratio_current_x96 >= sqrt_ratio_target_x96;
+    let exact_in = !amount_remaining.is_negative();
+    let amount_remaining_abs = amount_remaining.unsigned_abs();
+    let fee_complement = U256::from(FEE_DENOMINATOR - fee_pips);
+
+    let mut amount_in = U256::ZERO;
+    let mut amount_out = U256::ZERO;
+
+    let sqrt_ratio_next_x96 = if exact_in {
+        let amount_remaining_less_fee = mul_div(
+            amount_remaining_abs,
+            fee_complement,
+            U256::from(FEE_DENOMINATOR),
+        )?;
+        amount_in = if zero_for_one {
+            get_amount0_delta(
+                sqrt_ratio_target_x96,
+                sqrt_ratio_current_x96,
+                liquidity,
+                true,
+            )?
+        } else {
+            get_amount1_delta(
+                sqrt_ratio_current_x96,
+                sqrt_ratio_target_x96,
+                liquidity,
+                true,
+            )?
+        };
+        if amount_remaining_less_fee >= amount_in {
+            sqrt_ratio_target_x96
+        } else {
+            get_next_sqrt_price_from_input(
+                sqrt_ratio_current_x96,
+                liquidity,
+                amount_remaining_less_fee,
+                zero_for_one,
+            )?
+        }
+    } else {
+        amount_out = if zero_for_one {
+            get_amount1_delta(
+                sqrt_ratio_target_x96,
+                sqrt_ratio_current_x96,
+                liquidity,
+                false,
+            )?
+        } else {
+            get_amount0_delta(
+                sqrt_ratio_current_x96,
+                sqrt_ratio_target_x96,
+                liquidity,
+                false,
+            )?
+        };
+        if amount_remaining_abs >= amount_out {
+            sqrt_ratio_target_x96
+        } else {
+            get_next_sqrt_price_from_output(
+                sqrt_ratio_current_x96,
+                liquidity,
+                amount_remaining_abs,
+                zero_for_one,
+            )?
+        }
+    };
+
+    let max = sqrt_ratio_target_x96 == sqrt_ratio_next_x96;
+
+    if zero_for_one {
+        amount_in = if max && exact_in {
+            amount_in
+        } else {
+            get_amount0_delta(sqrt_ratio_next_x96, sqrt_ratio_current_x96, liquidity, true)?
+        };
+        amount_out = if max && !exact_in {
+            amount_out
+        } else {
+            get_amount1_delta(
+                sqrt_ratio_next_x96,
+                sqrt_ratio_current_x96,
+                liquidity,
+                false,
+            )?
+        };
+    } else {
+        amount_in = if max && exact_in {
+            amount_in
+        } else {
+            get_amount1_delta(sqrt_ratio_current_x96, sqrt_ratio_next_x96, liquidity, true)?
+        };
+        amount_out = if max && !exact_in {
+            amount_out
+        } else {
+            get_amount0_delta(
+                sqrt_ratio_current_x96,
+                sqrt_ratio_next_x96,
+                liquidity,
+                false,
+            )?
+        };
+    }
+
+    // cap the output amount to not exceed the remaining output amount
+    if !exact_in && amount_out > amount_remaining_abs {
+        amount_out = amount_remaining_abs;
+    }
+
+    let fee_amount = if exact_in && sqrt_ratio_next_x96 != sqrt_ratio_target_x96 {
+        // we didn't reach the target, so take the remainder of the maximum input as fee
+        amount_remaining_abs - amount_in
+    } else {
+        mul_div_rounding_up(amount_in, U256::from(fee_pips), fee_complement)?
+    };
+
+    Ok(SwapStep {
+        sqrt_ratio_next_x96,
+        amount_in,
+        amount_out,
+        fee_amount,
+    })
+}
+
+/// Word and bit of a compressed tick in the `tickBitmap` mapping
+pub fn tick_position(compressed: i32) -> (i16, u8) {
+    ((compressed >> 8) as i16, (compressed & 0xff) as u8)
+}
+
+/// Port of `TickBitmap.nextInitializedTickWithinOneWord` over a map of loaded words.
+/// Errors if the word that has to be read was never loaded, rather than treating it as empty.
+pub fn next_initialized_tick_within_one_word(
+    bitmap: &HashMap<i16, U256>,
+    tick: i32,
+    tick_spacing: i32,
+    lte: bool,
+) -> Result<(i32, bool)> {
+    let mut compressed = tick / tick_spacing;
+    if tick < 0 && tick % tick_spacing != 0 {
+        compressed -= 1; // round towards negative infinity
+    }
+
+    let word = |word_pos: i16| {
+        bitmap
+            .get(&word_pos)
+            .copied()
+            .ok_or(anyhow!("tick bitmap word {} not loaded", word_pos))
+    };
+
+    if lte {
+        let (word_pos, bit_pos) = tick_position(compressed);
+        // all the 1s at or to the right of the current bit_pos
+        let mask = (U256::from(1) << bit_pos as usize) - U256::from(1)
+            + (U256::from(1) << bit_pos as usize);
+        let masked = word(word_pos)? & mask;
+        let initialized = !masked.is_zero();
+        let next = if initialized {
+            let msb = masked.bit_len() as i32 - 1;
+            (compressed - (bit_pos as i32 - msb)) * tick_spacing
+        } else {
+            (compressed - bit_pos as i32) * tick_spacing
+        };
+        Ok((next, initialized))
+    } else {
+        let (word_pos, bit_pos) = tick_position(compressed + 1);
+        // all the 1s at or to the left of the bit_pos
+        let mask = !((U256::from(1) << bit_pos as usize) - U256::from(1));
+        let masked = word(word_pos)? & mask;
+        let initialized = !masked.is_zero();
+        let next = if initialized {
+            let lsb = masked.trailing_zeros() as i32;
+            (compressed + 1 + (lsb - bit_pos as i32)) * tick_spacing
+        } else {
+            (compressed + 1 + (u8::MAX as i32 - bit_pos as i32)) * tick_spacing
+        };
+        Ok((next, initialized))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u(value: &str) -> U256 {
+        value.parse().unwrap()
+    }
+
+    fn ether(amount: i64) -> I256 {
+        I256::try_from(amount).unwrap() * I256::try_from(10u64.pow(18)).unwrap()
+    }
+
+    // encodePriceSqrt() values from the v3-core test helpers
+    const PRICE_1_1: &str = "79228162514264337593543950336";
+    const PRICE_101_100: &str = "79623317895830914510639640423";
+    const PRICE_1000_100: &str = "250541448375047931186413801569";
+
+    #[test]
+    fn test_get_sqrt_ratio_at_tick() {
+        assert_eq!(get_sqrt_ratio_at_tick(MIN_TICK).unwrap(), MIN_SQRT_RATIO);
+        assert_eq!(get_sqrt_ratio_at_tick(MAX_TICK).unwrap(), MAX_SQRT_RATIO);
+        assert_eq!(get_sqrt_ratio_at_tick(0).unwrap(), u(PRICE_1_1));
+        assert_eq!(
+            get_sqrt_ratio_at_tick(1).unwrap(),
+            u("79232123823359799118286999568")
+        );
+        assert_eq!(
+            get_sqrt_ratio_at_tick(-1).unwrap(),
+            u("79224201403219477170569942574")
+        );
+        assert!(get_sqrt_ratio_at_tick(MAX_TICK + 1).is_err());
+    }
+
+    #[test]
+    fn test_get_tick_at_sqrt_ratio() {
+        assert_eq!(get_tick_at_sqrt_ratio(MIN_SQRT_RATIO).unwrap(), MIN_TICK);
+        assert_eq!(
+            get_tick_at_sqrt_ratio(MAX_SQRT_RATIO - U256::from(1)).unwrap(),
+            MAX_TICK - 1
+        );
+        for tick in [-200_000, -60, -1, 0, 1, 60, 200_000] {
+            let ratio = get_sqrt_ratio_at_tick(tick).unwrap();
+            assert_eq!(get_tick_at_sqrt_ratio(ratio).unwrap(), tick);
+            assert_eq!(get_tick_at_sqrt_ratio(ratio + U256::from(1)).unwrap(), tick);
+        }
+    }
+
+    // Cases from v3-core/test/SwapMath.spec.ts
+    #[test]
+    fn test_compute_swap_step_exact_in_capped_at_target() {
+        let step = compute_swap_step(
+            u(PRICE_1_1),
+            u(PRICE_101_100),
+            2_000_000_000_000_000_000,
+            ether(1),
+            600,
+        )
+        .unwrap();
+        assert_eq!(step.amount_in, u("9975124224178055"));
+        assert_eq!(step.fee_amount, u("5988667735148"));
+        assert_eq!(step.amount_out, u("9925619580021728"));
+        assert_eq!(step.sqrt_ratio_next_x96, u(PRICE_101_100));
+    }
+
+    #[test]
+    fn test_compute_swap_step_exact_out_capped_at_target() {
+        let step = compute_swap_step(
+            u(PRICE_1_1),
+            u(PRICE_101_100),
+            2_000_000_000_000_000_000,
+            -ether(1),
+            600,
+        )
+        .unwrap();
+        assert_eq!(step.amount_in, u("9975124224178055"));
+        assert_eq!(step.fee_amount, u("5988667735148"));
+        assert_eq!(step.amount_out, u("9925619580021728"));
+        assert_eq!(step.sqrt_ratio_next_x96, u(PRICE_101_100));
+    }
+
+    #[test]
+    fn test_compute_swap_step_exact_in_fully_spent() {
+        let step = compute_swap_step(
+            u(PRICE_1_1),
+            u(PRICE_1000_100),
+            2_000_000_000_000_000_000,
+            ether(1),
+            600,
+        )
+        .unwrap();
+        assert_eq!(step.amount_in, u("999400000000000000"));
+        assert_eq!(step.fee_amount, u("600000000000000"));
+        assert_eq!(step.amount_out, u("666399946655997866"));
+        assert!(step.sqrt_ratio_next_x96 < u(PRICE_1000_100));
+    }
+
+    #[test]
+    fn test_compute_swap_step_amount_out_capped() {
+        let step = compute_swap_step(
+            u("417332158212080721273783715441582"),
+            u("1452870262520218020823638996"),
+            159344665391607089467575320103,
+            I256::MINUS_ONE,
+            1,
+        )
+        .unwrap();
+        assert_eq!(step.amount_in, U256::from(1));
+        assert_eq!(step.fee_amount, U256::from(1));
+        assert_eq!(step.amount_out, U256::from(1));
+        assert_eq!(
+            step.sqrt_ratio_next_x96,
+            u("417332158212080721273783715441581")
+        );
+    }
+
+    #[test]
+    fn test_next_initialized_tick_within_one_word() {
+        // ticks 70, 78, 84, 139, 240 initialized in word 0 and -200 in word -1 (spacing 1)
+        let mut bitmap = HashMap::new();
+        let mut word0 = U256::ZERO;
+        for tick in [70usize, 78, 84, 139, 240] {
+            word0 |= U256::from(1) << tick;
+        }
+        bitmap.insert(0i16, word0);
+        bitmap.insert(-1i16, U256::from(1) << 56usize); // -200 = -256 + 56
+
+        assert_eq!(
+            next_initialized_tick_within_one_word(&bitmap, 78, 1, false).unwrap(),
+            (84, true)
+        );
+        assert_eq!(
+            next_initialized_tick_within_one_word(&bitmap, 241, 1, false).unwrap(),
+            (255, false)
+        );
+        assert_eq!(
+            next_initialized_tick_within_one_word(&bitmap, 78, 1, true).unwrap(),
+            (78, true)
+        );
+        assert_eq!(
+            next_initialized_tick_within_one_word(&bitmap, 69, 1, true).unwrap(),
+            (0, false)
+        );
+        assert_eq!(
+            next_initialized_tick_within_one_word(&bitmap, -1, 1, true).unwrap(),
+            (-200, true)
+        );
+        assert!(next_initialized_tick_within_one_word(&bitmap, 256, 1, false).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use super::prefetch::prefetch_into;
+use super::revm::EvmSimulator;
+use super::revmInspector::RevmInspector;
+use alloy::eips::eip2930::{AccessList, AccessListItem};
+use alloy::eips::BlockId;
+use alloy::primitives::{Address, U256};
+use anyhow::Result;
+use revm::precompile::Precompiles;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A flash swap the executor runs: borrow on `pool`, sell `token_in` for `token_out`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RouteKey {
+    pub pool: Address,
+    pub token_in: Address,
+    pub token_out: Address,
+}
+
+/// Storage slots a transaction read or wrote, per contract
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessSet {
+    slots: BTreeMap<Address, BTreeSet<U256>>,
+}
+
+impl AccessSet {
+    /// Every SLOAD and SSTORE the inspector recorded since its accesses were last cleared
+    pub fn from_inspector(inspector: &RevmInspector) -> Self {
+        let mut set = Self::default();
+        for (address, accesses) in &inspector.storage_accesses {
+            let address = Address::from_word(*address);
+            for access in accesses {
+                set.insert(address, access.slot.into());
+            }
+        }
+        set
+    }
+
+    pub fn insert(&mut self, address: Address, slot: U256) {
+        self.slots.entry(address).or_default().insert(slot);
+    }
+
+    pub fn merge(&mut self, other: AccessSet) {
+        for (address, slots) in other.slots {
+            self.slots.entry(address).or_default().extend(slots);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn slot_count(&self) -> usize {
+        self.slots.values().map(BTreeSet::len).sum()
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = Address> + '_ {
+        self.slots.keys().copied()
+    }
+
+    pub fn slots(&self) -> impl Iterator<Item = (Address, U256)> + '_ {
+        self.slots
+            .iter()
+            .flat_map(|(address, slots)| slots.iter().map(move |slot| (*address, *slot)))
+    }
+
+    /// EIP-2930 access list with every touched slot, so none of them is paid for cold.
+    /// The sender, `to` and the precompiles are warm anyway, listing them would only add
+    /// 2400 gas each.
+    pub fn to_access_list(&self, sender: Address, to: Address) -> AccessList {
+        let precompiles = Precompiles::latest();
+        AccessList(
+            self.slots
+                .iter()
+                .filter(|(address, slots)| {
+                    !slots.is_empty()
+                        && **address != sender
+                        && **address != to
+                        && !precompiles.contains(address)
+                })
+                .map(|(address, slots)| AccessListItem {
+                    address: *address,
+                    storage_keys: slots.iter().map(|slot| (*slot).into()).collect(),
+                })
+                .collect(),
+        )
+    }
+}
+
+impl EvmSimulator<'_> {
+    /// Remember what a simulation of `route` touched. The last simulation wins, the ticks a
+    /// swap crosses move with the price so older sets go stale.
+    pub fn record_route_accesses(&mut self, route: RouteKey, accesses: AccessSet) {
+        if !accesses.is_empty() {
+            self.route_accesses.insert(route, accesses);
+        }
+    }
+
+    /// Fetch the slots the last simulation of `route` touched in one batch. Returns the
+    /// number of slots fetched, zero for a route that was never simulated.
+    pub async fn prefetch_route(&mut self, route: &RouteKey) -> Result<usize> {
+        let Some(accesses) = self.route_accesses.get(route) else {
+            return Ok(0);
+        };
+        let accounts = accesses.addresses().collect();
+        let keys = accesses.slots().collect();
+        let block = BlockId::from(self.pinned_block().to::<u64>());
+        let base = self.evm.get_mut().context.evm.db.db.clone();
+        prefetch_into(&self.provider, &base, block, accounts, keys).await
+    }
+
+    /// Access list for `sender` sending `route` to `executor`, None until it has been
+    /// simulated. Slots keyed by an account, like token balances, are only right when the
+    /// simulator runs as the real sender and executor.
+    pub fn route_access_list(
+        &self,
+        route: &RouteKey,
+        sender: Address,
+        executor: Address,
+    ) -> Option<AccessList> {
+        self.route_accesses
+            .get(route)
+            .map(|accesses| accesses.to_access_list(sender, executor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::revmInspector::StorageAccess;
+    use alloy::primitives::B256;
+
+    fn access(address: Address, slot: u64, is_write: bool) -> StorageAccess {
+        StorageAccess {
+            address: address.into_word(),
+            slot: U256::from(slot).into(),
+            value: B256::ZERO,
+            is_write,
+        }
+    }
+
+    #[test]
+    fn test_reads_and_writes_end_up_in_the_access_list() {
+        let pool = Address::repeat_byte(0x10);
+        let token = Address::repeat_byte(0x20);
+        let mut inspector = RevmInspector::new();
+        inspector.storage_accesses.insert(
+            pool.into_word(),
+            vec![
+                access(pool, 8, false),
+                access(pool, 8, true),
+                access(pool, 12, false),
+            ],
+        );
+        inspector
+            .storage_accesses
+            .insert(token.into_word(), vec![access(token, 3, true)]);
+
+        let set = AccessSet::from_inspector(&inspector);
+        assert_eq!(set.slot_count(), 3);
+
+        let list = set.to_access_list(Address::repeat_byte(0x01), Address::repeat_byte(0x02));
+        assert_eq!(list.len(), 2);
+        let item = list.iter().find(|item| item.address == pool).unwrap();
+        assert_eq!(
+            item.storage_keys,
+            vec![B256::from(U256::from(8)), B256::from(U256::from(12))]
+        );
+    }
+
+    #[test]
+    fn test_warm_addresses_are_left_out() {
+        let (sender, executor, pool) = (
+            Address::repeat_byte(0x01),
+            Address::repeat_byte(0x02),
+            Address::repeat_byte(0x10),
+        );
+        let mut set = AccessSet::default();
+        for address in [sender, executor, pool, Address::with_last_byte(0x02)] {
+            set.insert(address, U256::from(1));
+        }
+
+        let list = set.to_access_list(sender, executor);
+        assert_eq!(
+            list.iter().map(|item| item.address).collect::<Vec<_>>(),
+            vec![pool]
+        );
+    }
+
+    #[test]
+    fn test_merge_dedups_slots() {
+        let pool = Address::repeat_byte(0x10);
+        let mut first = AccessSet::default();
+        first.insert(pool, U256::from(1));
+        let mut second = AccessSet::default();
+        second.insert(pool, U256::from(1));
+        second.insert(pool, U256::from(2));
+
+        first.merge(second);
+        assert_eq!(
+            first.slots().collect::<Vec<_>>(),
+            vec![(pool, U256::from(1)), (pool, U256::from(2))]
+        );
+    }
+}
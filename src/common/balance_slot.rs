@@ -0,0 +1,286 @@
+use super::revm::EvmSimulator;
+use alloy::primitives::{keccak256, Address, U256};
+use alloy::signers::local::PrivateKeySigner;
+use alloy_sol_types::SolCall;
+use anyhow::{anyhow, Result};
+use revm::primitives::{ExecutionResult, TransactTo};
+
+alloy::sol! {
+    function balanceOf(address account) external view returns (uint256);
+}
+
+/// Highest declaration slot tried for the balances mapping, inherited OpenZeppelin and
+/// upgradeable layouts stay well below this
+const MAX_MAPPING_SLOT: u64 = 256;
+/// Written into a candidate slot to confirm `balanceOf` reads it back unchanged
+const MARKER_BALANCE: U256 = U256::from_limbs([0x1337_1337, 0, 0, 0]);
+
+/// Order in which the compiler hashes the key and the mapping's slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingLayout {
+    /// `keccak256(holder . slot)`
+    Solidity,
+    /// `keccak256(slot . holder)`
+    Vyper,
+}
+
+/// Where a token keeps its `balanceOf` mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalanceSlot {
+    pub slot: U256,
+    pub layout: MappingLayout,
+}
+
+impl BalanceSlot {
+    /// Storage key of `holder`'s balance
+    pub fn storage_key(&self, holder: Address) -> U256 {
+        let mut preimage = [0u8; 64];
+        let (key, slot) = match self.layout {
+            MappingLayout::Solidity => (0, 32),
+            MappingLayout::Vyper => (32, 0),
+        };
+        preimage[key + 12..key + 32].copy_from_slice(holder.as_slice());
+        preimage[slot..slot + 32].copy_from_slice(&self.slot.to_be_bytes::<32>());
+        keccak256(preimage).into()
+    }
+
+    /// Work out which mapping slot and layout produced `key` for `holder`
+    pub fn from_storage_key(holder: Address, key: U256) -> Option<Self> {
+        (0..MAX_MAPPING_SLOT).find_map(|slot| {
+            [MappingLayout::Solidity, MappingLayout::Vyper]
+                .into_iter()
+                .map(|layout| BalanceSlot {
+                    slot: U256::from(slot),
+                    layout,
+                })
+                .find(|candidate| candidate.storage_key(holder) == key)
+        })
+    }
+}
+
+impl EvmSimulator<'_> {
+    /// Find the balances mapping of `token` by tracing the SLOADs of a `balanceOf` call and
+    /// matching them against the Solidity and Vyper mapping layouts. Every match is confirmed
+    /// by writing a marker balance and reading it back, which rules out packed or scaled
+    /// balances. Slots are cached per token, tokens without one per token and block.
+    pub fn find_balance_slot(&mut self, token: Address) -> Result<BalanceSlot> {
+        if let Some(slot) = self.balance_slots.get(&token) {
+            return Ok(*slot);
+        }
+        if self.balance_slot_misses.get(&token) == Some(&self.block_number) {
+            return Err(anyhow!("No balance mapping found for {:?}", token));
+        }
+
+        // any address works, a random one keeps the key from colliding with real storage
+        let holder = PrivateKeySigner::random().address();
+        let loaded = self.trace_balance_of(token, holder)?;
+
+        for key in loaded {
+            let Some(candidate) = BalanceSlot::from_storage_key(holder, key) else {
+                continue;
+            };
+            if self.confirm_balance_slot(token, holder, candidate)? {
+                self.balance_slots.insert(token, candidate);
+                return Ok(candidate);
+            }
+        }
+        self.balance_slot_misses.insert(token, self.block_number);
+        Err(anyhow!("No balance mapping found for {:?}", token))
+    }
+
+    /// Give `holder` exactly `amount` of `token` by writing its balance slot directly
+    pub fn set_erc20_balance(
+        &mut self,
+        token: Address,
+        holder: Address,
+        amount: U256,
+    ) -> Result<()> {
+        let slot = self.find_balance_slot(token)?;
+        self.evm
+            .get_mut()
+            .context
+            .evm
+            .db
+            .insert_account_storage(token, slot.storage_key(holder), amount)
+            .map_err(|err| anyhow!("Error writing balance of {:?}: {:?}", token, err))
+    }
+
+    /// Slots of `token` read while answering `balanceOf(holder)`
+    fn trace_balance_of(&mut self, token: Address, holder: Address) -> Result<Vec<U256>> {
+        let evm = self.evm.get_mut();
+        evm.context.external.storage_accesses.clear();
+        evm.context.external.track_storage = true;
+        let output = self.balance_of(token, holder);
+        let evm = self.evm.get_mut();
+        evm.context.external.track_storage = false;
+        output?;
+        Ok(evm.context.external.loaded_slots(token))
+    }
+
+    /// Write the marker on a dropped fork, so nothing of the probe is left behind
+    fn confirm_balance_slot(
+        &mut self,
+        token: Address,
+        holder: Address,
+        candidate: BalanceSlot,
+    ) -> Result<bool> {
+        let mut probe = self.fork().simulator();
+        probe
+            .evm
+            .get_mut()
+            .context
+            .evm
+            .db
+            .insert_account_storage(token, candidate.storage_key(holder), MARKER_BALANCE)
+            .map_err(|err| anyhow!("Error writing balance of {:?}: {:?}", token, err))?;
+        Ok(probe.balance_of(token, holder)? == MARKER_BALANCE)
+    }
+
+    /// `balanceOf` without committing. The base fee is zeroed for the call so the holder
+    /// needs no ETH for gas.
+    fn balance_of(&mut self, token: Address, holder: Address) -> Result<U256> {
+        let evm = self.evm.get_mut();
+        let base_fee = std::mem::take(&mut evm.context.evm.env.block.basefee);
+        let tx = &mut evm.context.evm.env.tx;
+        tx.caller = holder;
+        tx.transact_to = TransactTo::Call(token);
+        tx.data = balanceOfCall { account: holder }.abi_encode().into();
+        tx.value = U256::ZERO;
+        tx.gas_price = U256::ZERO;
+        tx.gas_limit = 1_000_000;
+
+        let result = evm.transact();
+        evm.context.evm.env.block.basefee = base_fee;
+        match result
+            .map_err(|err| anyhow!("EVM balanceOf failed: {:?}", err))?
+            .result
+        {
+            ExecutionResult::Success { output, .. } => {
+                Ok(balanceOfCall::abi_decode_returns(output.data(), false)?._0)
+            }
+            other => Err(anyhow!("balanceOf failed on {:?}: {:?}", token, other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::address;
+    use alloy_sol_types::SolValue;
+
+    #[test]
+    fn test_storage_keys_match_abi_encoding() {
+        let holder = address!("5f1F5565561aC146d24B102D9CDC288992Ab2938");
+        // WETH9 keeps balanceOf in slot 3
+        let solidity = BalanceSlot {
+            slot: U256::from(3),
+            layout: MappingLayout::Solidity,
+        };
+        let expected: U256 = keccak256((holder, U256::from(3)).abi_encode()).into();
+        assert_eq!(solidity.storage_key(holder), expected);
+
+        let vyper = BalanceSlot {
+            layout: MappingLayout::Vyper,
+            ..solidity
+        };
+        let expected: U256 = keccak256((U256::from(3), holder).abi_encode()).into();
+        assert_eq!(vyper.storage_key(holder), expected);
+    }
+
+    #[test]
+    fn test_layout_round_trip() {
+        let holder = Address::repeat_byte(0x42);
+        for layout in [MappingLayout::Solidity, MappingLayout::Vyper] {
+            let slot = BalanceSlot {
+                slot: U256::from(51),
+                layout,
+            };
+            assert_eq!(
+                BalanceSlot::from_storage_key(holder, slot.storage_key(holder)),
+                Some(slot)
+            );
+        }
+        assert_eq!(BalanceSlot::from_storage_key(holder, U256::from(3)), None);
+    }
+
+    #[tokio::test]
+    async fn test_misses_are_cached_per_block() {
+        use crate::common::fork::offline_simulator;
+        use alloy::primitives::U64;
+        use revm::primitives::Bytecode;
+
+        let mut simulator = offline_simulator().await;
+        let token = Address::repeat_byte(0x77);
+        // balanceOf returns 0 without reading storage
+        let constant = vec![0x60, 0x00, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3];
+        simulator
+            .deploy_code_at(token, Bytecode::new_raw(constant.into()))
+            .await;
+        assert!(simulator.find_balance_slot(token).is_err());
+        assert_eq!(simulator.balance_slot_misses.get(&token), Some(&U64::ZERO));
+
+        // balanceOf returns SLOAD(keccak256(holder . 0)), not traced again in the same block
+        let mapping = vec![
+            0x60, 0x04, 0x35, 0x60, 0x00, 0x52, 0x60, 0x00, 0x60, 0x20, 0x52, 0x60, 0x40, 0x60,
+            0x00, 0x20, 0x54, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+        ];
+        simulator
+            .deploy_code_at(token, Bytecode::new_raw(mapping.into()))
+            .await;
+        assert!(simulator.find_balance_slot(token).is_err());
+
+        simulator.block_number = U64::from(1);
+        let slot = simulator.find_balance_slot(token).unwrap();
+        assert_eq!(
+            slot,
+            BalanceSlot {
+                slot: U256::ZERO,
+                layout: MappingLayout::Solidity,
+            }
+        );
+        // the marker was written on a dropped fork
+        let db = &simulator.evm.get_mut().context.evm.db;
+        assert!(db.accounts[&token].storage.is_empty());
+    }
+
+    /// Needs a node: `WS_URL=... cargo test -- --ignored`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_finds_weth_and_usdc_balance_slots() {
+        use crate::arbitrage::simulation::{get_address, AddressType};
+        use alloy::primitives::U64;
+        use alloy::providers::{Provider, ProviderBuilder, WsConnect};
+        use std::sync::Arc;
+
+        dotenv::dotenv().ok();
+        let ws_url = std::env::var("WS_URL").expect("no ws url");
+        let provider = ProviderBuilder::new()
+            .on_ws(WsConnect::new(ws_url))
+            .await
+            .unwrap();
+        let provider = Arc::new(provider);
+        let block_number = provider.get_block_number().await.unwrap();
+        let mut simulator = EvmSimulator::new(provider, None, U64::from(block_number));
+
+        let weth = get_address(AddressType::Weth);
+        assert_eq!(
+            simulator.find_balance_slot(weth).unwrap(),
+            BalanceSlot {
+                slot: U256::from(3),
+                layout: MappingLayout::Solidity,
+            }
+        );
+
+        // USDC is a proxy, the balances live in the proxy's storage
+        let usdc = address!("A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48");
+        let wallet = Address::repeat_byte(0x42);
+        simulator
+            .set_erc20_balance(usdc, wallet, U256::from(1_000_000_000))
+            .unwrap();
+        assert_eq!(
+            simulator.balance_of(usdc, wallet).unwrap(),
+            U256::from(1_000_000_000)
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use alloy::primitives::{keccak256, Bytes, B256};
+use alloy::signers::local::PrivateKeySigner;
+use alloy::signers::SignerSync;
+use anyhow::{anyhow, Result};
+use dotenv::var;
+use log::info;
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A relay that doesn't answer by then has missed the block anyway
+const RELAY_TIMEOUT: Duration = Duration::from_secs(3);
+/// How far `eth_callBundle` may be off the local simulation, unless
+/// `CALL_BUNDLE_TOLERANCE_BPS` is set
+pub const DEFAULT_TOLERANCE_BPS: u64 = 100;
+
+/// RPC method a relay takes bundles with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleMethod {
+    /// Flashbots style `eth_sendBundle`, taken by most builders
+    EthSendBundle,
+    /// MEV-Share `mev_sendBundle`
+    MevSendBundle,
+}
+
+impl BundleMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BundleMethod::EthSendBundle => "eth_sendBundle",
+            BundleMethod::MevSendBundle => "mev_sendBundle",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relay {
+    pub name: String,
+    pub url: Url,
+    pub method: BundleMethod,
+}
+
+impl Relay {
+    /// `name=url`, the url alone is used as the name too
+    pub fn parse(entry: &str, method: BundleMethod) -> Result<Self> {
+        let (name, url) = match entry.split_once('=') {
+            Some((name, url)) => (name.trim(), url.trim()),
+            None => (entry.trim(), entry.trim()),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            url: Url::from_str(url)?,
+            method,
+        })
+    }
+}
+
+/// What a relay said about a bundle for one block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BundleStatus {
+    /// Relays answer with a bundle hash, None if this one didn't
+    Accepted(Option<B256>),
+    Rejected(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayReport {
+    pub relay: String,
+    pub block: u64,
+    pub status: BundleStatus,
+}
+
+impl fmt::Display for RelayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.status {
+            BundleStatus::Accepted(Some(hash)) => write!(
+                f,
+                "{} accepted bundle {:?} for block {}",
+                self.relay, hash, self.block
+            ),
+            BundleStatus::Accepted(None) => {
+                write!(f, "{} accepted bundle for block {}", self.relay, self.block)
+            }
+            BundleStatus::Rejected(reason) => write!(
+                f,
+                "{} rejected bundle for block {}: {}",
+                self.relay, self.block, reason
+            ),
+        }
+    }
+}
+
+/// Sends bundles to Flashbots-compatible relays instead of the public mempool, so a
+/// failing trade costs nothing and can't be frontrun. Requests are signed with the auth
+/// key in `X-Flashbots-Signature`, which only builds the searcher's reputation, the
+/// bundle's transactions are signed by the wallet as usual.
+#[derive(Debug, Clone)]
+pub struct BundleSubmitter {
+    client: reqwest::Client,
+    auth: PrivateKeySigner,
+    relays: Vec<Relay>,
+    /// Blocks after the current one each bundle targets, 1 for N+1 only, 2 for N+1 and N+2
+    target_blocks: u64,
+    /// Where bundles are checked with `eth_callBundle` before they are sent, no check if None
+    pub simulation_url: Option<Url>,
+    /// Divergence from the local simulation a checked bundle may have, in basis points
+    pub tolerance_bps: u64,
+}
+
+impl BundleSubmitter {
+    pub fn new(auth: PrivateKeySigner, relays: Vec<Relay>, target_blocks: u64) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(RELAY_TIMEOUT).build()?;
+        Ok(Self {
+            client,
+            auth,
+            relays,
+            target_blocks: target_blocks.max(1),
+            simulation_url: None,
+            tolerance_bps: DEFAULT_TOLERANCE_BPS,
+        })
+    }
+
+    /// Relays from `BUNDLE_RELAYS` (`eth_sendBundle`) and `MEV_SHARE_RELAYS`
+    /// (`mev_sendBundle`), both comma separated `name=url` lists, signed with
+    /// `FLASHBOTS_AUTH_KEY`. `BUNDLE_TARGET_BLOCKS` is 1 or 2. Bundles are checked with
+    /// `eth_callBundle` at `CALL_BUNDLE_URL` when it is set. None when no relay is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let mut relays = Vec::new();
+        for (key, method) in [
+            ("BUNDLE_RELAYS", BundleMethod::EthSendBundle),
+            ("MEV_SHARE_RELAYS", BundleMethod::MevSendBundle),
+        ] {
+            let Ok(entries) = var(key) else {
+                continue;
+            };
+            for entry in entries.split(',').filter(|entry| !entry.trim().is_empty()) {
+                relays.push(Relay::parse(entry, method)?);
+            }
+        }
+        if relays.is_empty() {
+            return Ok(None);
+        }
+
+        let auth = PrivateKeySigner::from_str(&var("FLASHBOTS_AUTH_KEY")?)?;
+        let target_blocks = match var("BUNDLE_TARGET_BLOCKS") {
+            Ok(blocks) => blocks.parse()?,
+            Err(_) => 1,
+        };
+        let mut submitter = Self::new(auth, relays, target_blocks)?;
+        if let Ok(url) = var("CALL_BUNDLE_URL") {
+            submitter.simulation_url = Some(Url::from_str(&url)?);
+        }
+        if let Ok(tolerance) = var("CALL_BUNDLE_TOLERANCE_BPS") {
+            submitter.tolerance_bps = tolerance.parse()?;
+        }
+        Ok(Some(submitter))
+    }
+
+    pub fn relays(&self) -> &[Relay] {
+        &self.relays
+    }
+
+    /// Send `txs` (raw signed transactions, in order) to every relay for each target block
+    /// after `current_block`. Never fails as a whole, every relay and block gets a report.
+    pub async fn submit(&self, txs: &[Bytes], current_block: u64) -> Vec<RelayReport> {
+        let requests = self.relays.iter().flat_map(|relay| {
+            (1..=self.target_blocks).map(move |ahead| async move {
+                let block = current_block + ahead;
+                let status = match self.send(relay, txs, block).await {
+                    Ok(status) => status,
+                    Err(err) => BundleStatus::Rejected(format!("{:#}", err)),
+                };
+                RelayReport {
+                    relay: relay.name.clone(),
+                    block,
+                    status,
+                }
+            })
+        });
+        futures::future::join_all(requests).await
+    }
+
+    async fn send(&self, relay: &Relay, txs: &[Bytes], block: u64) -> Result<BundleStatus> {
+        let request = bundle_request(relay.method, txs, block);
+        match self.post(&relay.url, &request).await {
+            Ok(text) => parse_response(&text),
+            Err(err) => Ok(BundleStatus::Rejected(format!("{:#}", err))),
+        }
+    }
+
+    /// POST a signed JSON-RPC request, the body of any non-2xx answer becomes the error
+    pub(crate) async fn post(&self, url: &Url, request: &Value) -> Result<String> {
+        let body = request.to_string();
+        let response = self
+            .client
+            .post(url.clone())
+            .header("Content-Type", "application/json")
+            .header("X-Flashbots-Signature", self.signature(&body)?)
+            .body(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!("HTTP {}: {}", status, text));
+        }
+        Ok(text)
+    }
+
+    /// `address:signature` of the EIP-191 signature over the hex keccak of the body
+    fn signature(&self, body: &str) -> Result<String> {
+        let digest = format!("{:?}", keccak256(body.as_bytes()));
+        let signature = self.auth.sign_message_sync(digest.as_bytes())?;
+        Ok(format!(
+            "{:?}:0x{}",
+            self.auth.address(),
+            hex::encode(signature.as_bytes())
+        ))
+    }
+}
+
+/// JSON-RPC request for a bundle of `txs` landing in `block`
+fn bundle_request(method: BundleMethod, txs: &[Bytes], block: u64) -> Value {
+    let block = format!("0x{:x}", block);
+    let params = match method {
+        BundleMethod::EthSendBundle => json!([{
+            "txs": txs,
+            "blockNumber": block,
+        }]),
+        BundleMethod::MevSendBundle => json!([{
+            "version": "v0.1",
+            "inclusion": { "block": block, "maxBlock": block },
+            "body": txs
+                .iter()
+                .map(|tx| json!({ "tx": tx, "canRevert": false }))
+                .collect::<Vec<_>>(),
+        }]),
+    };
+    json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method.as_str(),
+        "params": params,
+    })
+}
+
+fn parse_response(text: &str) -> Result<BundleStatus> {
+    let response: Value = serde_json::from_str(text)?;
+    if let Some(error) = response.get("error") {
+        let message = error
+            .get("message")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .unwrap_or_else(|| error.to_string());
+        return Ok(BundleStatus::Rejected(message));
+    }
+    let result = response
+        .get("result")
+        .ok_or_else(|| anyhow!("Relay response has no result: {}", text))?;
+    let bundle_hash = result
+        .get("bundleHash")
+        .and_then(Value::as_str)
+        .and_then(|hash| B256::from_str(hash).ok());
+    Ok(BundleStatus::Accepted(bundle_hash))
+}
+
+/// Submit and log what every relay said
+pub async fn submit_and_report(submitter: &BundleSubmitter, txs: Vec<Bytes>, current_block: u64) {
+    for report in submitter.submit(&txs, current_block).await {
+        info!("{}", report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{Address, PrimitiveSignature};
+    use wiremock::matchers::{header_exists, method};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    async fn relay(response: Value) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header_exists("X-Flashbots-Signature"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn signer_of(request: &Request) -> Address {
+        let header = request.headers["X-Flashbots-Signature"].to_str().unwrap();
+        let (_, signature) = header.split_once(':').unwrap();
+        let signature = PrimitiveSignature::try_from(
+            &hex::decode(signature.trim_start_matches("0x")).unwrap()[..],
+        )
+        .unwrap();
+        let digest = format!("{:?}", keccak256(&request.body));
+        signature.recover_address_from_msg(digest).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reports_every_relay_and_block() {
+        let hash = B256::repeat_byte(0xbb);
+        let accepting =
+            relay(json!({"jsonrpc": "2.0", "id": 1, "result": {"bundleHash": hash}})).await;
+        let rejecting = relay(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {"code": -32000, "message": "bundle too late"}
+        }))
+        .await;
+
+        let auth = PrivateKeySigner::random();
+        let relays = vec![
+            Relay::parse(
+                &format!("good={}", accepting.uri()),
+                BundleMethod::EthSendBundle,
+            )
+            .unwrap(),
+            Relay::parse(
+                &format!("late={}", rejecting.uri()),
+                BundleMethod::EthSendBundle,
+            )
+            .unwrap(),
+        ];
+        let submitter = BundleSubmitter::new(auth.clone(), relays, 2).unwrap();
+        let tx = Bytes::from_static(&[0x02, 0xf8, 0x01]);
+        let mut reports = submitter.submit(std::slice::from_ref(&tx), 100).await;
+        reports.sort_by(|a, b| (&a.relay, a.block).cmp(&(&b.relay, b.block)));
+
+        let statuses: Vec<(&str, u64, BundleStatus)> = reports
+            .iter()
+            .map(|report| (report.relay.as_str(), report.block, report.status.clone()))
+            .collect();
+        let late = BundleStatus::Rejected("bundle too late".to_string());
+        assert_eq!(
+            statuses,
+            vec![
+                ("good", 101, BundleStatus::Accepted(Some(hash))),
+                ("good", 102, BundleStatus::Accepted(Some(hash))),
+                ("late", 101, late.clone()),
+                ("late", 102, late),
+            ]
+        );
+
+        let requests = accepting.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        let mut blocks = Vec::new();
+        for request in &requests {
+            assert_eq!(signer_of(request), auth.address());
+            let body: Value = serde_json::from_slice(&request.body).unwrap();
+            assert_eq!(body["method"], "eth_sendBundle");
+            assert_eq!(body["params"][0]["txs"], json!([tx]));
+            blocks.push(
+                body["params"][0]["blockNumber"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        blocks.sort();
+        assert_eq!(blocks, vec!["0x65", "0x66"]);
+    }
+
+    #[tokio::test]
+    async fn test_mev_share_payload_and_http_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(403).set_body_string("unknown searcher"))
+            .mount(&server)
+            .await;
+
+        let relay = Relay::parse(&server.uri(), BundleMethod::MevSendBundle).unwrap();
+        let submitter = BundleSubmitter::new(PrivateKeySigner::random(), vec![relay], 1).unwrap();
+        let tx = Bytes::from_static(&[0x02, 0xf8, 0x02]);
+        let reports = submitter.submit(std::slice::from_ref(&tx), 7).await;
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].block, 8);
+        assert!(matches!(
+            &reports[0].status,
+            BundleStatus::Rejected(reason) if reason.contains("403") && reason.contains("unknown searcher")
+        ));
+
+        let request = &server.received_requests().await.unwrap()[0];
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["method"], "mev_sendBundle");
+        assert_eq!(
+            body["params"][0]["inclusion"],
+            json!({"block": "0x8", "maxBlock": "0x8"})
+        );
+        assert_eq!(
+            body["params"][0]["body"],
+            json!([{"tx": tx, "canRevert": false}])
+        );
+    }
+}
@@ -0,0 +1,469 @@
+use super::bundle::{submit_and_report, BundleSubmitter};
+use super::outcome::SimulationOutcome;
+use super::revm::EvmSimulator;
+use alloy::primitives::{Bytes, Log, U256};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use revm::Database;
+use serde_json::{json, Value};
+use std::fmt;
+use std::str::FromStr;
+
+/// One transaction of a simulated bundle
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TxSimulation {
+    pub gas_used: u64,
+    /// Priority fees and direct transfers the block builder got
+    pub coinbase_payment: U256,
+    pub reverted: bool,
+    /// Return data, empty for a reverted transaction
+    pub output: Bytes,
+    /// None when the simulator doesn't report logs, most relays don't
+    pub logs: Option<Vec<Log>>,
+}
+
+/// What a bundle does, either in the local `EvmSimulator` or in `eth_callBundle`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BundleSimulation {
+    pub txs: Vec<TxSimulation>,
+}
+
+impl BundleSimulation {
+    pub fn gas_used(&self) -> u64 {
+        self.txs.iter().map(|tx| tx.gas_used).sum()
+    }
+
+    pub fn coinbase_payment(&self) -> U256 {
+        self.txs.iter().map(|tx| tx.coinbase_payment).sum()
+    }
+}
+
+/// Where the local and the remote simulation of a bundle disagree, one line per finding
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BundleDiff {
+    pub entries: Vec<String>,
+    /// Whether the logs of every transaction were compared. Logs are the only state
+    /// changes `eth_callBundle` reports, without them storage and balances went unchecked.
+    pub state_checked: bool,
+}
+
+impl BundleDiff {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl fmt::Display for BundleDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(f, "  {}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare gas and coinbase payments within `tolerance_bps`, revert status, return data
+/// and logs (when the remote side has them) exactly. See `BundleDiff::state_checked` for
+/// whether the logs were there.
+pub fn compare(
+    local: &BundleSimulation,
+    remote: &BundleSimulation,
+    tolerance_bps: u64,
+) -> BundleDiff {
+    let mut diff = BundleDiff::default();
+    if local.txs.len() != remote.txs.len() {
+        diff.entries.push(format!(
+            "transactions: local {} remote {}",
+            local.txs.len(),
+            remote.txs.len()
+        ));
+        return diff;
+    }
+    diff.state_checked = remote.txs.iter().all(|tx| tx.logs.is_some());
+
+    for (i, (local, remote)) in local.txs.iter().zip(&remote.txs).enumerate() {
+        if local.reverted != remote.reverted {
+            diff.entries.push(format!(
+                "tx {} reverted: local {} remote {}",
+                i, local.reverted, remote.reverted
+            ));
+            continue;
+        }
+        if !within(
+            U256::from(local.gas_used),
+            U256::from(remote.gas_used),
+            tolerance_bps,
+        ) {
+            diff.entries.push(format!(
+                "tx {} gas used: local {} remote {}",
+                i, local.gas_used, remote.gas_used
+            ));
+        }
+        if !within(
+            local.coinbase_payment,
+            remote.coinbase_payment,
+            tolerance_bps,
+        ) {
+            diff.entries.push(format!(
+                "tx {} coinbase payment: local {} remote {}",
+                i, local.coinbase_payment, remote.coinbase_payment
+            ));
+        }
+        if !local.reverted && local.output != remote.output {
+            diff.entries.push(format!(
+                "tx {} output: local {} remote {}",
+                i, local.output, remote.output
+            ));
+        }
+        if let (Some(local_logs), Some(remote_logs)) = (&local.logs, &remote.logs) {
+            if local_logs != remote_logs {
+                diff.entries.push(format!(
+                    "tx {} logs: local {} remote {}",
+                    i,
+                    local_logs.len(),
+                    remote_logs.len()
+                ));
+            }
+        }
+    }
+    diff
+}
+
+/// Whether `a` and `b` are at most `tolerance_bps` of the larger one apart
+fn within(a: U256, b: U256, tolerance_bps: u64) -> bool {
+    let larger = a.max(b);
+    a.abs_diff(b).saturating_mul(U256::from(10_000))
+        <= larger.saturating_mul(U256::from(tolerance_bps))
+}
+
+impl EvmSimulator<'_> {
+    /// Run the signed `txs` in order on a fork of the current state, at the base fee of the
+    /// block the bundle targets, exactly as `eth_callBundle` gets them. The simulator itself
+    /// is left untouched.
+    pub fn simulate_bundle(&mut self, txs: &[Bytes], base_fee: U256) -> Result<BundleSimulation> {
+        let mut fork = self.fork().simulator();
+        let coinbase = {
+            let evm = fork.evm.get_mut();
+            evm.context.evm.env.block.basefee = base_fee;
+            evm.context.evm.env.block.coinbase
+        };
+
+        let mut simulation = BundleSimulation::default();
+        for tx in txs {
+            let before = fork.coinbase_balance(coinbase)?;
+            let outcome = fork.call_raw(tx);
+            let payment = fork.coinbase_balance(coinbase)?.saturating_sub(before);
+            let tx = match outcome {
+                SimulationOutcome::Success(result) => TxSimulation {
+                    gas_used: result.gas_used,
+                    coinbase_payment: payment,
+                    reverted: false,
+                    output: result.output,
+                    logs: result.logs,
+                },
+                SimulationOutcome::Infra(err) => return Err(err),
+                outcome => TxSimulation {
+                    gas_used: outcome.gas_used().unwrap_or_default(),
+                    coinbase_payment: payment,
+                    reverted: true,
+                    output: Bytes::new(),
+                    logs: Some(Vec::new()),
+                },
+            };
+            simulation.txs.push(tx);
+        }
+        Ok(simulation)
+    }
+
+    fn coinbase_balance(&mut self, coinbase: alloy::primitives::Address) -> Result<U256> {
+        let evm = self.evm.get_mut();
+        let account = evm
+            .context
+            .evm
+            .db
+            .basic(coinbase)
+            .map_err(|err| anyhow!("Error loading coinbase: {:?}", err))?;
+        Ok(account.unwrap_or_default().balance)
+    }
+}
+
+impl BundleSubmitter {
+    /// `eth_callBundle` of `txs` for `block` on top of the state of the block before it
+    pub async fn call_bundle(&self, txs: &[Bytes], block: u64) -> Result<BundleSimulation> {
+        let url = self
+            .simulation_url
+            .as_ref()
+            .ok_or_else(|| anyhow!("No eth_callBundle url set"))?;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_callBundle",
+            "params": [{
+                "txs": txs,
+                "blockNumber": format!("0x{:x}", block),
+                "stateBlockNumber": format!("0x{:x}", block.saturating_sub(1)),
+            }],
+        });
+        parse_call_bundle(&self.post(url, &request).await?)
+    }
+}
+
+fn parse_call_bundle(text: &str) -> Result<BundleSimulation> {
+    let response: Value = serde_json::from_str(text)?;
+    if let Some(error) = response.get("error") {
+        return Err(anyhow!("eth_callBundle failed: {}", error));
+    }
+    let results = response
+        .pointer("/result/results")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("eth_callBundle response has no results: {}", text))?;
+
+    let mut simulation = BundleSimulation::default();
+    for result in results {
+        let reverted = result.get("error").is_some() || result.get("revert").is_some();
+        let output = match result.get("value").and_then(Value::as_str) {
+            Some(value) if !reverted => Bytes::from_str(value)?,
+            _ => Bytes::new(),
+        };
+        let logs = match result.get("logs") {
+            Some(logs) if !logs.is_null() => Some(serde_json::from_value(logs.clone())?),
+            _ => None,
+        };
+        simulation.txs.push(TxSimulation {
+            gas_used: quantity(result.get("gasUsed"))?.saturating_to(),
+            coinbase_payment: quantity(result.get("coinbaseDiff"))?,
+            reverted,
+            output,
+            logs,
+        });
+    }
+    Ok(simulation)
+}
+
+/// Relays send numbers as JSON numbers, decimal strings or hex strings
+fn quantity(value: Option<&Value>) -> Result<U256> {
+    match value {
+        Some(Value::Number(number)) => number
+            .as_u64()
+            .map(U256::from)
+            .ok_or_else(|| anyhow!("Bad quantity {}", number)),
+        Some(Value::String(text)) => match text.strip_prefix("0x") {
+            Some(hex) => Ok(U256::from_str_radix(hex, 16)?),
+            None => Ok(U256::from_str_radix(text, 10)?),
+        },
+        Some(other) => Err(anyhow!("Bad quantity {}", other)),
+        None => Ok(U256::ZERO),
+    }
+}
+
+/// Check the bundle with `eth_callBundle` against `local` and only submit it when the
+/// two agree. A failed check aborts too, an unchecked bundle is what the check prevents.
+pub async fn verify_and_submit(
+    submitter: &BundleSubmitter,
+    txs: Vec<Bytes>,
+    current_block: u64,
+    local: BundleSimulation,
+) {
+    if submitter.simulation_url.is_some() {
+        let remote = match submitter.call_bundle(&txs, current_block + 1).await {
+            Ok(remote) => remote,
+            Err(err) => {
+                warn!("Not sending bundle, eth_callBundle failed: {:?}", err);
+                return;
+            }
+        };
+        let diff = compare(&local, &remote, submitter.tolerance_bps);
+        if !diff.is_empty() {
+            warn!(
+                "Not sending bundle, eth_callBundle disagrees with the local simulation:\n{}",
+                diff
+            );
+            return;
+        }
+        info!(
+            "eth_callBundle agrees: {} gas, {} wei to coinbase",
+            remote.gas_used(),
+            remote.coinbase_payment()
+        );
+        if !diff.state_checked {
+            warn!(
+                "eth_callBundle returned no logs, the bundle's state changes were not checked, \
+                 only gas, coinbase payments, reverts and return data"
+            );
+        }
+    }
+    submit_and_report(submitter, txs, current_block).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::bundle::{BundleMethod, Relay};
+    use crate::common::fork::offline_simulator;
+    use alloy::eips::eip2718::Encodable2718;
+    use alloy::eips::eip2930::{AccessList, AccessListItem};
+    use alloy::network::{EthereumWallet, TransactionBuilder};
+    use alloy::primitives::{Address, LogData, B256};
+    use alloy::rpc::types::TransactionRequest;
+    use alloy::signers::local::PrivateKeySigner;
+    use wiremock::matchers::{body_partial_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn transfer_log() -> Log {
+        Log {
+            address: Address::repeat_byte(0x11),
+            data: LogData::new_unchecked(vec![B256::repeat_byte(0x22)], Bytes::from_static(&[1])),
+        }
+    }
+
+    fn local() -> BundleSimulation {
+        BundleSimulation {
+            txs: vec![TxSimulation {
+                gas_used: 150_000,
+                coinbase_payment: U256::from(300_000_000_000_000u64),
+                reverted: false,
+                output: Bytes::new(),
+                logs: Some(vec![transfer_log()]),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_parse_flashbots_response() {
+        let response = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "bundleHash": B256::repeat_byte(1),
+                "coinbaseDiff": "300000000000000",
+                "totalGasUsed": 171000,
+                "results": [
+                    {"gasUsed": 150000, "coinbaseDiff": "300000000000000", "value": "0x"},
+                    {"gasUsed": "0x5208", "coinbaseDiff": "0", "error": "execution reverted", "revert": "SPL"}
+                ]
+            }
+        });
+        let simulation = parse_call_bundle(&response.to_string()).unwrap();
+        assert_eq!(simulation.txs.len(), 2);
+        assert_eq!(simulation.gas_used(), 171_000);
+        assert_eq!(
+            simulation.coinbase_payment(),
+            U256::from(300_000_000_000_000u64)
+        );
+        assert!(!simulation.txs[0].reverted);
+        assert!(simulation.txs[1].reverted);
+        assert_eq!(simulation.txs[0].logs, None);
+    }
+
+    #[test]
+    fn test_compare_within_tolerance() {
+        let local = local();
+
+        // 0.5% more gas and no logs from the relay
+        let mut close = local.clone();
+        close.txs[0].gas_used = 150_750;
+        close.txs[0].logs = None;
+        let diff = compare(&local, &close, 100);
+        assert!(diff.is_empty());
+        assert!(!diff.state_checked);
+        assert!(!compare(&local, &close, 10).is_empty());
+        assert!(compare(&local, &local, 0).state_checked);
+
+        let mut reverted = local.clone();
+        reverted.txs[0].reverted = true;
+        assert_eq!(
+            compare(&local, &reverted, 100).entries,
+            vec!["tx 0 reverted: local false remote true"]
+        );
+
+        let mut other_logs = local.clone();
+        other_logs.txs[0].logs = Some(Vec::new());
+        other_logs.txs[0].coinbase_payment = U256::ZERO;
+        assert_eq!(compare(&local, &other_logs, 100).entries.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_call_bundle_against_mock_relay() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({
+                "method": "eth_callBundle",
+                "params": [{"blockNumber": "0x65", "stateBlockNumber": "0x64"}]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": {"results": [
+                    {"gasUsed": 151000, "coinbaseDiff": "302000000000000", "value": "0x"}
+                ]}
+            })))
+            .mount(&server)
+            .await;
+
+        let relay = Relay::parse(&server.uri(), BundleMethod::EthSendBundle).unwrap();
+        let mut submitter =
+            BundleSubmitter::new(PrivateKeySigner::random(), vec![relay], 1).unwrap();
+        submitter.simulation_url = Some(server.uri().parse().unwrap());
+
+        let remote = submitter
+            .call_bundle(&[Bytes::from_static(&[0x02])], 101)
+            .await
+            .unwrap();
+        assert_eq!(remote.gas_used(), 151_000);
+        assert!(compare(&local(), &remote, 100).is_empty());
+        assert!(!compare(&local(), &remote, 50).is_empty());
+    }
+
+    async fn signed_transfer(signer: &PrivateKeySigner, nonce: u64) -> Bytes {
+        let to = Address::repeat_byte(0x33);
+        let envelope = TransactionRequest::default()
+            .with_chain_id(1)
+            .with_to(to)
+            .with_value(U256::from(1))
+            .with_nonce(nonce)
+            .with_max_fee_per_gas(3_000_000_000)
+            .with_max_priority_fee_per_gas(1_000_000_000)
+            .with_gas_limit(50_000)
+            .with_access_list(AccessList(vec![AccessListItem {
+                address: to,
+                storage_keys: vec![B256::ZERO],
+            }]))
+            .build(&EthereumWallet::from(signer.clone()))
+            .await
+            .unwrap();
+        envelope.encoded_2718().into()
+    }
+
+    #[tokio::test]
+    async fn test_simulate_bundle_runs_signed_txs() {
+        let mut simulator = offline_simulator().await;
+        let signer = PrivateKeySigner::random();
+        let ether = U256::from(10).pow(U256::from(18));
+        simulator.set_eth_balance(signer.address(), ether).await;
+        simulator.set_nonce(signer.address(), 4).await;
+
+        let base_fee = U256::from(1_000_000_000);
+        let txs = [
+            signed_transfer(&signer, 4).await,
+            signed_transfer(&signer, 5).await,
+        ];
+        let simulation = simulator.simulate_bundle(&txs, base_fee).unwrap();
+        // the access list is paid for: one address and one slot on top of the transfer
+        assert_eq!(simulation.txs[0].gas_used, 21_000 + 2_400 + 1_900);
+        assert_eq!(simulation.txs[1].gas_used, 21_000 + 2_400 + 1_900);
+        assert_eq!(
+            simulation.coinbase_payment(),
+            U256::from(2 * 25_300 * 1_000_000_000u64)
+        );
+        assert!(simulation.txs.iter().all(|tx| !tx.reverted));
+
+        // signed for a nonce the account doesn't have
+        let stale = [signed_transfer(&signer, 3).await];
+        assert!(simulator.simulate_bundle(&stale, base_fee).is_err());
+        assert_eq!(
+            simulator.get_eth_balance(signer.address()).await,
+            ether,
+            "the bundle ran on a fork"
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use super::revm::EvmSimulator;
+use alloy::primitives::{Address, Bytes, U256};
+use alloy_sol_types::SolCall;
+use anyhow::{anyhow, Result};
+use revm::primitives::{AccountInfo, ExecutionResult, TransactTo, KECCAK_EMPTY};
+use revm::Database;
+use std::fmt;
+
+alloy::sol! {
+    function balanceOf(address account) external view returns (uint256);
+    function transfer(address to, uint256 amount) external returns (bool);
+    function paused() external view returns (bool);
+}
+
+/// Fresh accounts that receive the probe transfers, neither has ever held a token
+const PROBE_SENDER: Address = Address::repeat_byte(0xa1);
+const PROBE_RECEIVER: Address = Address::repeat_byte(0xb2);
+/// Share-based tokens lose a wei or two to rounding on every transfer
+const ROUNDING_TOLERANCE: U256 = U256::from_limbs([2, 0, 0, 0]);
+/// How far the clock is moved forward to catch balances that accrue with time
+const REBASE_PROBE_SECONDS: u64 = 86_400;
+const PROBE_GAS_LIMIT: u64 = 1_000_000;
+
+/// How a token behaves when it is moved around, decided by probe transfers in revm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenVerdict {
+    Standard,
+    /// Less arrives than was sent, the difference in basis points of the amount
+    FeeOnTransfer {
+        fee_bps: u32,
+    },
+    /// Balances move on their own, or transfers are off by share rounding
+    Rebasing,
+    /// The executor contract or the pool can't receive the token
+    Blacklisted,
+    /// Transfers revert for ordinary holders
+    TransferPaused,
+}
+
+impl TokenVerdict {
+    /// Only standard tokens survive the flash swap's buy back check
+    pub fn is_tradable(&self) -> bool {
+        matches!(self, TokenVerdict::Standard)
+    }
+}
+
+impl fmt::Display for TokenVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenVerdict::Standard => write!(f, "standard"),
+            TokenVerdict::FeeOnTransfer { fee_bps } => write!(f, "fee on transfer ({fee_bps} bps)"),
+            TokenVerdict::Rebasing => write!(f, "rebasing"),
+            TokenVerdict::Blacklisted => write!(f, "blacklisted"),
+            TokenVerdict::TransferPaused => write!(f, "transfers paused"),
+        }
+    }
+}
+
+/// Compare what arrived with what was sent, None if the transfer was exact
+pub fn transfer_verdict(sent: U256, received: U256) -> Option<TokenVerdict> {
+    if received == sent {
+        return None;
+    }
+    if received > sent {
+        return Some(TokenVerdict::Rebasing);
+    }
+    let shortfall = sent - received;
+    if shortfall <= ROUNDING_TOLERANCE {
+        return Some(TokenVerdict::Rebasing);
+    }
+    let fee_bps = (shortfall * U256::from(10_000) / sent).saturating_to::<u32>();
+    Some(TokenVerdict::FeeOnTransfer {
+        fee_bps: fee_bps.max(1),
+    })
+}
+
+impl EvmSimulator<'_> {
+    /// Classify `token` by moving part of `holder`'s balance through two fresh accounts and
+    /// back into the executor. `holder` is usually a pool of the token, which is impersonated
+    /// for the first transfer. The probe runs on a fork that is dropped afterwards, the
+    /// simulator state is left as is.
+    ///
+    /// Rebasing is caught when balances move with `block.timestamp` alone. A rebase driven by
+    /// an oracle update or a keeper call doesn't happen within the probe, so such a token
+    /// passes as `Standard`.
+    ///
+    /// Verdicts are cached per token. RPC failures are returned as errors and not cached.
+    pub fn classify_token(&mut self, token: Address, holder: Address) -> Result<TokenVerdict> {
+        if let Some(verdict) = self.token_verdicts.get(&token) {
+            return Ok(*verdict);
+        }
+
+        let verdict = self.fork().simulator().probe_token(token, holder)?;
+        self.token_verdicts.insert(token, verdict);
+        Ok(verdict)
+    }
+
+    fn probe_token(&mut self, token: Address, holder: Address) -> Result<TokenVerdict> {
+        self.impersonate(holder)?;
+        for probe in [PROBE_SENDER, PROBE_RECEIVER] {
+            self.impersonate(probe)?;
+        }
+
+        let paused = pausedCall {}.abi_encode();
+        if let Some(output) = self.probe_call(PROBE_SENDER, token, paused)? {
+            if pausedCall::abi_decode_returns(&output, false).is_ok_and(|paused| paused._0) {
+                return Ok(TokenVerdict::TransferPaused);
+            }
+        }
+
+        let holder_balance = self.token_balance(token, holder)?;
+        if holder_balance.is_zero() {
+            return Err(anyhow!("{:?} holds no {:?} to probe with", holder, token));
+        }
+        let amount = (holder_balance / U256::from(100)).max(U256::from(1));
+
+        // the holder is a pool, if it can't send the token nobody can
+        if !self.transfer(token, holder, PROBE_SENDER, amount)? {
+            return Ok(TokenVerdict::TransferPaused);
+        }
+        let received = self.token_balance(token, PROBE_SENDER)?;
+        if let Some(verdict) = transfer_verdict(amount, received) {
+            return Ok(verdict);
+        }
+
+        // plain transfer between two wallets, catches tokens gated on an allow list
+        let amount = received;
+        if !self.transfer(token, PROBE_SENDER, PROBE_RECEIVER, amount)? {
+            return Ok(TokenVerdict::TransferPaused);
+        }
+        let received = self.token_balance(token, PROBE_RECEIVER)?;
+        if let Some(verdict) = transfer_verdict(amount, received) {
+            return Ok(verdict);
+        }
+
+        {
+            let block = &mut self.evm.get_mut().context.evm.env.block;
+            block.timestamp += U256::from(REBASE_PROBE_SECONDS);
+            block.number += U256::from(REBASE_PROBE_SECONDS / 12);
+        }
+        if self.token_balance(token, PROBE_RECEIVER)? != received {
+            return Ok(TokenVerdict::Rebasing);
+        }
+
+        // both legs of the arb end with the executor or the pool receiving the token
+        let half = received / U256::from(2);
+        let contract_address = self.contract_address;
+        if !self.transfer(token, PROBE_RECEIVER, contract_address, half)?
+            || !self.transfer(token, PROBE_RECEIVER, holder, received - half)?
+        {
+            return Ok(TokenVerdict::Blacklisted);
+        }
+
+        Ok(TokenVerdict::Standard)
+    }
+
+    /// Let `account` send transactions: strip its code so the sender check passes and
+    /// give it ETH for gas
+    fn impersonate(&mut self, account: Address) -> Result<()> {
+        let db = &mut self.evm.get_mut().context.evm.db;
+        let info = db
+            .basic(account)
+            .map_err(|err| anyhow!("Error loading {:?}: {:?}", account, err))?
+            .unwrap_or_default();
+        db.insert_account_info(
+            account,
+            AccountInfo {
+                balance: U256::from(10).pow(U256::from(18)),
+                code_hash: KECCAK_EMPTY,
+                code: None,
+                ..info
+            },
+        );
+        Ok(())
+    }
+
+    fn token_balance(&mut self, token: Address, account: Address) -> Result<U256> {
+        let output = self
+            .probe_call(account, token, balanceOfCall { account }.abi_encode())?
+            .ok_or_else(|| anyhow!("balanceOf reverted on {:?}", token))?;
+        Ok(balanceOfCall::abi_decode_returns(&output, false)?._0)
+    }
+
+    /// False if the transfer reverted or returned false. Tokens that return nothing pass.
+    fn transfer(
+        &mut self,
+        token: Address,
+        from: Address,
+        to: Address,
+        amount: U256,
+    ) -> Result<bool> {
+        let data = transferCall { to, amount }.abi_encode();
+        let Some(output) = self.probe_call(from, token, data)? else {
+            return Ok(false);
+        };
+        Ok(output.is_empty()
+            || transferCall::abi_decode_returns(&output, false).is_ok_and(|ok| ok._0))
+    }
+
+    /// Commit a call, None on revert or halt and an error only when the database fails
+    fn probe_call(&mut self, caller: Address, to: Address, data: Vec<u8>) -> Result<Option<Bytes>> {
+        let evm = self.evm.get_mut();
+        let gas_price = evm.block().basefee;
+        let tx = &mut evm.context.evm.env.tx;
+        tx.caller = caller;
+        tx.transact_to = TransactTo::Call(to);
+        tx.data = data.into();
+        tx.value = U256::ZERO;
+        tx.gas_price = gas_price;
+        tx.gas_limit = PROBE_GAS_LIMIT;
+
+        match evm
+            .transact_commit()
+            .map_err(|err| anyhow!("EVM probe failed: {:?}", err))?
+        {
+            ExecutionResult::Success { output, .. } => Ok(Some(output.into_data())),
+            ExecutionResult::Revert { .. } | ExecutionResult::Halt { .. } => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_transfer_has_no_verdict() {
+        assert_eq!(transfer_verdict(U256::from(1_000), U256::from(1_000)), None);
+    }
+
+    #[test]
+    fn test_transfer_verdicts() {
+        let sent = U256::from(10).pow(U256::from(18));
+        // 5% tax
+        assert_eq!(
+            transfer_verdict(sent, sent * U256::from(95) / U256::from(100)),
+            Some(TokenVerdict::FeeOnTransfer { fee_bps: 500 })
+        );
+        // stETH style share rounding
+        assert_eq!(
+            transfer_verdict(sent, sent - U256::from(1)),
+            Some(TokenVerdict::Rebasing)
+        );
+        assert_eq!(
+            transfer_verdict(sent, sent + U256::from(1)),
+            Some(TokenVerdict::Rebasing)
+        );
+        // a tax too small to show in basis points still counts
+        assert_eq!(
+            transfer_verdict(sent, sent - U256::from(1_000)),
+            Some(TokenVerdict::FeeOnTransfer { fee_bps: 1 })
+        );
+    }
+}
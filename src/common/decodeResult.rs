@@ -1,7 +1,8 @@
+use super::error_registry::{NamedError, ERROR_REGISTRY};
 use std::fmt;
 
 /// Represents a decoded EVM revert error
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DecodedEVMRevert {
     /// Error signature (selector)
     pub selector: [u8; 4],
@@ -12,12 +13,14 @@ pub struct DecodedEVMRevert {
 }
 
 /// Types of EVM errors we can decode
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum EVMErrorType {
     /// String error (most common): Error(string)
     StringError(String),
     /// Panic error with a uint256 error code
     PanicError(u64),
+    /// Custom error found in the error registry, with decoded parameters
+    NamedError(NamedError),
     /// Custom error with raw parameters
     CustomError(Vec<u8>),
     /// Unknown or malformed error
@@ -34,6 +37,9 @@ impl fmt::Display for DecodedEVMRevert {
             EVMErrorType::PanicError(code) => {
                 write!(f, "Panic({}): {}", code, panic_code_to_message(*code))
             }
+            EVMErrorType::NamedError(error) => {
+                write!(f, "{} [0x{}]", error, hex::encode(self.selector))
+            }
             EVMErrorType::CustomError(data) => write!(
                 f,
                 "Custom Error [0x{}] with data: 0x{}",
@@ -47,6 +53,22 @@ impl fmt::Display for DecodedEVMRevert {
     }
 }
 
+impl DecodedEVMRevert {
+    /// Just the reason, e.g. `UniswapV2: INSUFFICIENT_OUTPUT_AMOUNT` for an `Error(string)`
+    pub fn reason(&self) -> String {
+        match &self.error_type {
+            EVMErrorType::StringError(msg) => msg.clone(),
+            EVMErrorType::PanicError(code) => {
+                format!("Panic({}): {}", code, panic_code_to_message(*code))
+            }
+            EVMErrorType::NamedError(error) => error.to_string(),
+            EVMErrorType::CustomError(_) => format!("0x{}", hex::encode(&self.raw_data)),
+            EVMErrorType::Unknown if self.raw_data.is_empty() => "no data".to_string(),
+            EVMErrorType::Unknown => format!("0x{}", hex::encode(&self.raw_data)),
+        }
+    }
+}
+
 /// Convert Solidity panic codes to human-readable messages
 fn panic_code_to_message(code: u64) -> &'static str {
     match code {
@@ -88,6 +110,15 @@ pub fn decode_evm_revert(data: Vec<u8>) -> DecodedEVMRevert {
         return decode_panic_error(data);
     }
 
+    // Handle custom errors we have a definition for
+    if let Some(error) = ERROR_REGISTRY.decode(&data) {
+        return DecodedEVMRevert {
+            selector,
+            error_type: EVMErrorType::NamedError(error),
+            raw_data: data,
+        };
+    }
+
     // Handle other custom errors
     DecodedEVMRevert {
         selector,
@@ -218,6 +249,26 @@ mod tests {
             _ => panic!("Expected StringError"),
         }
     }
+
+    #[test]
+    fn test_decode_registered_custom_error() {
+        use crate::arbitrage::simulation::BuyBackAmountLessThanAmountIn;
+        use alloy::primitives::U256;
+        use alloy_sol_types::SolError;
+
+        let error = BuyBackAmountLessThanAmountIn {
+            buyBackAmount: U256::from(7),
+            amountIn: U256::from(9),
+        };
+        let decoded = decode_evm_revert(error.abi_encode());
+        assert_eq!(
+            decoded.to_string(),
+            format!(
+                "EVM Revert: BuyBackAmountLessThanAmountIn(buyBackAmount: 7, amountIn: 9) [0x{}]",
+                hex::encode(BuyBackAmountLessThanAmountIn::SELECTOR)
+            )
+        );
+    }
 }
 
 /// Main function to decode a revert error from hex string
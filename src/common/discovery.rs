@@ -0,0 +1,143 @@
+use super::filter::{FilterConfig, PoolFilter};
+use super::pairs::decode_event;
+use super::pools::{
+    load_uniswap_v2_pools, load_uniswap_v3_pools, Pool, UNISWAP_V2_FACTORY, UNISWAP_V3_FACTORY,
+};
+use super::registry::PoolRegistry;
+use super::store::{PoolStatus, PoolStore, POOL_STORE_PATH};
+use crate::arbitrage::graph::TokenGraph;
+use alloy::eips::BlockNumberOrTag;
+use alloy::primitives::keccak256;
+use alloy::providers::{Provider, RootProvider};
+use alloy::pubsub::PubSubFrontend;
+use alloy::rpc::types::Filter;
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use log::info;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Follow the V2 and V3 factories and add every pool they create to the store. Pools that
+/// pass the liquidity filter are hot-inserted into the registry and the token graph.
+/// Inactive pools are checked again every `config.recheck_blocks` blocks, since liquidity
+/// can be added long after a pool was created.
+pub async fn watch_new_pools(
+    client: Arc<RootProvider<PubSubFrontend>>,
+    registry: Arc<RwLock<PoolRegistry>>,
+    graph: Arc<RwLock<TokenGraph>>,
+    config: FilterConfig,
+) -> Result<()> {
+    let mut store = PoolStore::open(Path::new(POOL_STORE_PATH))?;
+    let pool_filter = PoolFilter::new(client.clone(), config, store.load_tokens()?);
+
+    let filter = Filter::new()
+        .address(vec![UNISWAP_V2_FACTORY, UNISWAP_V3_FACTORY])
+        .event_signature(vec![
+            keccak256("PairCreated(address,address,address,uint256)"),
+            keccak256("PoolCreated(address,address,uint24,int24,address)"),
+        ])
+        .from_block(BlockNumberOrTag::Latest);
+    let sub = client.subscribe_logs(&filter).await?;
+    let mut stream = sub.into_stream();
+    info!("Watching factories for new pools");
+
+    // Pools created between the startup crawl and the subscription going live
+    let head = client.get_block_number().await?;
+    // the startup filter has just re-checked the stale pools
+    let mut last_recheck = head;
+    if let Some(synced) = store.last_synced_block()? {
+        if head > synced {
+            let (mut pools, v3_pools) = tokio::try_join!(
+                load_uniswap_v2_pools(client.clone(), synced + 1, head),
+                load_uniswap_v3_pools(client.clone(), synced + 1, head),
+            )?;
+            pools.extend(v3_pools);
+            store.insert_synced_pools(&pools, head)?;
+            add_active_pools(&mut store, &pool_filter, &registry, &graph, &pools, head).await?;
+        }
+    }
+
+    while let Some(log) = stream.next().await {
+        let Some(event) = decode_event(&log) else {
+            continue;
+        };
+        let pool = Pool::from(event);
+        // The checkpoint is left to the startup crawl, which re-reads from it and skips known pools
+        store.insert_pools(&[pool])?;
+        // Liquidity is usually added in the same transaction that creates the pool
+        let block_number = log.block_number.unwrap_or_default();
+        add_active_pools(
+            &mut store,
+            &pool_filter,
+            &registry,
+            &graph,
+            &[pool],
+            block_number,
+        )
+        .await?;
+
+        if block_number >= last_recheck + config.recheck_blocks {
+            let stale = store.load_stale_inactive_pools(block_number - config.recheck_blocks)?;
+            info!("Re-checking liquidity of {} inactive pools", stale.len());
+            add_active_pools(
+                &mut store,
+                &pool_filter,
+                &registry,
+                &graph,
+                &stale,
+                block_number,
+            )
+            .await?;
+            last_recheck = block_number;
+        }
+    }
+
+    Ok(())
+}
+
+async fn add_active_pools(
+    store: &mut PoolStore,
+    filter: &PoolFilter,
+    registry: &RwLock<PoolRegistry>,
+    graph: &RwLock<TokenGraph>,
+    pools: &[Pool],
+    block_number: u64,
+) -> Result<()> {
+    filter.clear_quotes().await;
+    let checked: Vec<(Pool, PoolStatus)> = {
+        let registry = &*registry.read().await;
+        stream::iter(pools.iter().copied())
+            .map(|pool| async move {
+                match filter.check_pool(&pool, registry, block_number).await {
+                    Ok(status) => Some((pool, status)),
+                    // left as it was, the startup filter or the next re-check retries it
+                    Err(err) => {
+                        log::debug!("Error checking pool {:?}: {:?}", pool.address, err);
+                        None
+                    }
+                }
+            })
+            .buffer_unordered(filter.config().concurrency)
+            .filter_map(|checked| async move { checked })
+            .collect()
+            .await
+    };
+    let statuses: Vec<PoolStatus> = checked.iter().map(|(_, status)| *status).collect();
+    store.set_pool_status(&statuses)?;
+    store.upsert_tokens(&filter.tokens().await)?;
+
+    let mut registry = registry.write().await;
+    let mut graph = graph.write().await;
+    for (pool, status) in checked {
+        if status.active && registry.insert(pool) {
+            graph.insert(&pool);
+            info!(
+                "New pool {} ({:.2} WETH)",
+                pool.pretty_msg(),
+                status.tvl_weth
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use crate::arbitrage::simulation::{
+    AmountLessThanZero, BuyBackAmountLessThanAmountIn, NotSender, ProfitIsZero, UnderflowError,
+};
+use alloy::dyn_abi::{DynSolValue, JsonAbiExt};
+use alloy::json_abi::{Error, JsonAbi};
+use alloy::primitives::Selector;
+use alloy_sol_types::{JsonAbiExt as SolAbiExt, SolError};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+
+lazy_static::lazy_static! {
+    /// Errors of our contracts and of the ABIs in `src/arbitrage`, used by `decode_evm_revert`
+    pub static ref ERROR_REGISTRY: ErrorRegistry = ErrorRegistry::with_known_errors();
+}
+
+/// Custom errors by selector
+#[derive(Debug, Clone, Default)]
+pub struct ErrorRegistry {
+    errors: HashMap<Selector, Error>,
+}
+
+/// A custom error with its arguments decoded against the registered definition
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedError {
+    pub name: String,
+    /// (parameter name, value), the name is empty when the definition has none
+    pub args: Vec<(String, DynSolValue)>,
+}
+
+impl ErrorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// arboo.sol errors plus every error in the JSON ABIs next to the strategy
+    pub fn with_known_errors() -> Self {
+        let mut registry = Self::new();
+        registry.register_sol::<UnderflowError>();
+        registry.register_sol::<AmountLessThanZero>();
+        registry.register_sol::<NotSender>();
+        registry.register_sol::<BuyBackAmountLessThanAmountIn>();
+        registry.register_sol::<ProfitIsZero>();
+
+        for json in [
+            include_str!("../arbitrage/v2_3000.json"),
+            include_str!("../arbitrage/v3_500.json"),
+            include_str!("../arbitrage/weth.json"),
+        ] {
+            if let Err(err) = registry.register_json(json) {
+                log::warn!("Error loading errors from an ABI: {:?}", err);
+            }
+        }
+        registry
+    }
+
+    /// A later definition with the same selector replaces the earlier one
+    pub fn register(&mut self, error: Error) {
+        self.errors.insert(error.selector(), error);
+    }
+
+    /// Register an error declared with `sol!`, it needs `#[sol(abi)]` to keep the parameter names
+    pub fn register_sol<E: SolError + SolAbiExt<Abi = Error>>(&mut self) {
+        self.register(E::abi());
+    }
+
+    pub fn register_abi(&mut self, abi: &JsonAbi) {
+        for error in abi.errors() {
+            self.register(error.clone());
+        }
+    }
+
+    /// Register the errors of a JSON ABI, e.g. a foundry artifact's `abi` field
+    pub fn register_json(&mut self, json: &str) -> Result<()> {
+        let abi: JsonAbi = serde_json::from_str(json)?;
+        self.register_abi(&abi);
+        Ok(())
+    }
+
+    pub fn get(&self, selector: Selector) -> Option<&Error> {
+        self.errors.get(&selector)
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Decode revert data (selector included), None for unknown selectors or data that
+    /// doesn't match the definition
+    pub fn decode(&self, data: &[u8]) -> Option<NamedError> {
+        let selector = Selector::try_from(data.get(..4)?).ok()?;
+        let error = self.get(selector)?;
+        let values = error.abi_decode_input(&data[4..], true).ok()?;
+        let args = error
+            .inputs
+            .iter()
+            .map(|param| param.name.clone())
+            .zip(values)
+            .collect();
+        Some(NamedError {
+            name: error.name.clone(),
+            args,
+        })
+    }
+}
+
+impl fmt::Display for NamedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        for (i, (name, value)) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            if !name.is_empty() {
+                write!(f, "{}: ", name)?;
+            }
+            write!(f, "{}", format_value(value))?;
+        }
+        write!(f, ")")
+    }
+}
+
+fn format_value(value: &DynSolValue) -> String {
+    match value {
+        DynSolValue::Bool(value) => value.to_string(),
+        DynSolValue::Int(value, _) => value.to_string(),
+        DynSolValue::Uint(value, _) => value.to_string(),
+        DynSolValue::FixedBytes(word, size) => format!("0x{}", hex::encode(&word[..*size])),
+        DynSolValue::Address(address) => address.to_string(),
+        DynSolValue::Function(function) => function.to_string(),
+        DynSolValue::Bytes(bytes) => format!("0x{}", hex::encode(bytes)),
+        DynSolValue::String(value) => format!("{:?}", value),
+        DynSolValue::Array(values) | DynSolValue::FixedArray(values) => {
+            format!("[{}]", join(values))
+        }
+        DynSolValue::Tuple(values) => format!("({})", join(values)),
+    }
+}
+
+fn join(values: &[DynSolValue]) -> String {
+    values
+        .iter()
+        .map(format_value)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{Address, U256};
+
+    #[test]
+    fn test_arboo_errors_are_decoded_with_names() {
+        let data = UnderflowError {
+            buyBackAmount: U256::from(5),
+            amountIn: U256::from(10),
+        }
+        .abi_encode();
+        let decoded = ERROR_REGISTRY.decode(&data).unwrap();
+        assert_eq!(
+            decoded.to_string(),
+            "UnderflowError(buyBackAmount: 5, amountIn: 10)"
+        );
+
+        let sender = Address::repeat_byte(0x42);
+        let decoded = ERROR_REGISTRY
+            .decode(&NotSender { sender }.abi_encode())
+            .unwrap();
+        assert_eq!(decoded.args, vec![("sender".to_string(), sender.into())]);
+        assert_eq!(
+            ERROR_REGISTRY
+                .decode(&ProfitIsZero {}.abi_encode())
+                .unwrap()
+                .to_string(),
+            "ProfitIsZero()"
+        );
+    }
+
+    #[test]
+    fn test_json_abi_errors() {
+        let mut registry = ErrorRegistry::new();
+        registry
+            .register_json(
+                r#"[{"type":"error","name":"NotProfitable","inputs":[
+                    {"name":"amountOut","type":"uint256"},{"name":"amountIn","type":"uint256"}]}]"#,
+            )
+            .unwrap();
+
+        let error =
+            Error::parse("error NotProfitable(uint256 amountOut, uint256 amountIn)").unwrap();
+        let data = error
+            .abi_encode_input(&[U256::from(1).into(), U256::from(2).into()])
+            .unwrap();
+        assert_eq!(
+            registry.decode(&data).unwrap().to_string(),
+            "NotProfitable(amountOut: 1, amountIn: 2)"
+        );
+
+        // unknown selector and truncated arguments
+        assert_eq!(registry.decode(&ProfitIsZero {}.abi_encode()), None);
+        assert_eq!(registry.decode(&data[..20]), None);
+    }
+}
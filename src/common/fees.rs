@@ -0,0 +1,236 @@
+use alloy::eips::eip1559::{calc_next_block_base_fee, BaseFeeParams};
+use alloy::eips::BlockNumberOrTag;
+use alloy::network::Ethereum;
+use alloy::providers::{Provider, RootProvider};
+use alloy::pubsub::PubSubFrontend;
+use alloy::rpc::types::Block;
+use anyhow::{anyhow, Result};
+use dotenv::var;
+use revm::primitives::U256;
+use std::str::FromStr;
+
+/// Blocks of `eth_feeHistory` the percentile policy looks at
+pub const FEE_HISTORY_BLOCKS: u64 = 10;
+/// Tip per gas when neither `PRIORITY_FEE_POLICY` nor `PRIORITY_FEE` is set
+pub const DEFAULT_PRIORITY_FEE: u128 = 2_000_000;
+
+/// Base fee of the block after a parent with `base_fee`, `gas_used` and `gas_limit`
+pub fn next_base_fee(base_fee: u64, gas_used: u64, gas_limit: u64) -> u64 {
+    let params = BaseFeeParams::ethereum();
+    // alloy divides by the gas target
+    if gas_limit < params.elasticity_multiplier as u64 {
+        return base_fee;
+    }
+    calc_next_block_base_fee(gas_used, gas_limit, base_fee, params)
+}
+
+/// Base fee of the block after `block`, the earliest one a transaction can land in
+pub fn predict_base_fee(block: &Block) -> u64 {
+    let header = &block.header;
+    match header.base_fee_per_gas {
+        Some(base_fee) => next_base_fee(base_fee, header.gas_used, header.gas_limit),
+        None => 0,
+    }
+}
+
+/// Fees of a type 2 transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxFees {
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+}
+
+impl TxFees {
+    /// The max fee covers one more full block on top of the predicted base fee, so the
+    /// transaction stays valid for the block after next. Only the actual base fee is paid.
+    pub fn new(base_fee: u64, priority_fee: u128) -> Self {
+        let worst_case = base_fee as u128
+            + (base_fee as u128).div_ceil(BaseFeeParams::ethereum().max_change_denominator);
+        Self {
+            max_fee_per_gas: worst_case + priority_fee,
+            max_priority_fee_per_gas: priority_fee,
+        }
+    }
+}
+
+/// How the tip per gas is chosen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PriorityFeePolicy {
+    /// The same tip every time, in wei
+    Fixed(u128),
+    /// Median over the last `FEE_HISTORY_BLOCKS` blocks of the given tip percentile
+    Percentile(f64),
+    /// Part of the expected profit in basis points, spread over the gas used
+    ProfitShare(u64),
+}
+
+impl Default for PriorityFeePolicy {
+    fn default() -> Self {
+        PriorityFeePolicy::Fixed(DEFAULT_PRIORITY_FEE)
+    }
+}
+
+/// `fixed:<wei>`, `percentile:<0-100>` or `profit:<basis points>`
+impl FromStr for PriorityFeePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Priority fee policy {:?} has no value", s))?;
+        match kind.trim() {
+            "fixed" => Ok(PriorityFeePolicy::Fixed(value.trim().parse()?)),
+            "percentile" => {
+                let percentile: f64 = value.trim().parse()?;
+                if !(0.0..=100.0).contains(&percentile) {
+                    return Err(anyhow!("Percentile {} is not in 0..=100", percentile));
+                }
+                Ok(PriorityFeePolicy::Percentile(percentile))
+            }
+            "profit" => {
+                let basis_points: u64 = value.trim().parse()?;
+                if basis_points > 10_000 {
+                    return Err(anyhow!("Profit share {} is over 10000 bps", basis_points));
+                }
+                Ok(PriorityFeePolicy::ProfitShare(basis_points))
+            }
+            _ => Err(anyhow!("Unknown priority fee policy {:?}", s)),
+        }
+    }
+}
+
+impl PriorityFeePolicy {
+    /// `PRIORITY_FEE_POLICY` from the environment. The older `PRIORITY_FEE=<wei>` is read
+    /// as `fixed:<wei>`. The default policy when neither is set.
+    pub fn from_env() -> Result<Self> {
+        if let Ok(policy) = var("PRIORITY_FEE_POLICY") {
+            return policy.parse();
+        }
+        match var("PRIORITY_FEE") {
+            Ok(fee) => Ok(PriorityFeePolicy::Fixed(fee.trim().parse()?)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Tip per gas for a trade expected to make `profit` wei with `gas_used` gas
+    pub async fn priority_fee(
+        &self,
+        provider: &RootProvider<PubSubFrontend, Ethereum>,
+        profit: U256,
+        gas_used: u64,
+    ) -> Result<u128> {
+        match *self {
+            PriorityFeePolicy::Fixed(fee) => Ok(fee),
+            PriorityFeePolicy::Percentile(percentile) => {
+                let history = provider
+                    .get_fee_history(FEE_HISTORY_BLOCKS, BlockNumberOrTag::Latest, &[percentile])
+                    .await?;
+                median_tip(&history.reward.unwrap_or_default())
+                    .ok_or_else(|| anyhow!("Fee history has no rewards"))
+            }
+            PriorityFeePolicy::ProfitShare(basis_points) => {
+                Ok(profit_share(profit, gas_used, basis_points))
+            }
+        }
+    }
+}
+
+/// Median of the first percentile of every block in an `eth_feeHistory` reward list
+fn median_tip(rewards: &[Vec<u128>]) -> Option<u128> {
+    let mut tips: Vec<u128> = rewards
+        .iter()
+        .filter_map(|block| block.first().copied())
+        .collect();
+    if tips.is_empty() {
+        return None;
+    }
+    tips.sort_unstable();
+    Some(tips[tips.len() / 2])
+}
+
+fn profit_share(profit: U256, gas_used: u64, basis_points: u64) -> u128 {
+    if gas_used == 0 {
+        return 0;
+    }
+    let share = profit.saturating_mul(U256::from(basis_points)) / U256::from(10_000);
+    (share / U256::from(gas_used)).saturating_to()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::providers::{ProviderBuilder, WsConnect};
+
+    #[test]
+    fn test_next_base_fee() {
+        let gas_limit = 30_000_000;
+        // target, full and empty blocks
+        assert_eq!(
+            next_base_fee(10_000_000_000, 15_000_000, gas_limit),
+            10_000_000_000
+        );
+        assert_eq!(
+            next_base_fee(10_000_000_000, 30_000_000, gas_limit),
+            11_250_000_000
+        );
+        assert_eq!(next_base_fee(10_000_000_000, 0, gas_limit), 8_750_000_000);
+        // too small to move by 1/8, still goes up by a wei
+        assert_eq!(next_base_fee(7, 15_000_001, gas_limit), 8);
+
+        // no gas target, nothing to compare against
+        assert_eq!(next_base_fee(7, 0, 0), 7);
+    }
+
+    #[test]
+    fn test_max_fee_survives_another_full_block() {
+        let fees = TxFees::new(8_000_000_001, 2_000_000);
+        assert_eq!(fees.max_priority_fee_per_gas, 2_000_000);
+        assert!(
+            fees.max_fee_per_gas
+                >= next_base_fee(8_000_000_001, 30_000_000, 30_000_000) as u128 + 2_000_000
+        );
+    }
+
+    #[test]
+    fn test_policies() {
+        assert_eq!(
+            "fixed:3000000".parse::<PriorityFeePolicy>().unwrap(),
+            PriorityFeePolicy::Fixed(3_000_000)
+        );
+        assert_eq!(
+            "percentile: 50".parse::<PriorityFeePolicy>().unwrap(),
+            PriorityFeePolicy::Percentile(50.0)
+        );
+        assert_eq!(
+            "profit:2500".parse::<PriorityFeePolicy>().unwrap(),
+            PriorityFeePolicy::ProfitShare(2_500)
+        );
+        assert!("percentile:150".parse::<PriorityFeePolicy>().is_err());
+        assert!("fixed".parse::<PriorityFeePolicy>().is_err());
+
+        assert_eq!(median_tip(&[vec![5], vec![1], vec![], vec![100]]), Some(5));
+        assert_eq!(median_tip(&[]), None);
+
+        // a quarter of 1e15 wei over 125k gas
+        let profit = U256::from(1_000_000_000_000_000u128);
+        assert_eq!(profit_share(profit, 125_000, 2_500), 2_000_000_000);
+        assert_eq!(profit_share(profit, 0, 2_500), 0);
+    }
+
+    /// Needs a node: `WS_URL=... cargo test -- --ignored`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_percentile_from_fee_history() {
+        dotenv::dotenv().ok();
+        let ws_url = std::env::var("WS_URL").expect("no ws url");
+        let provider = ProviderBuilder::new()
+            .on_ws(WsConnect::new(ws_url))
+            .await
+            .unwrap();
+        let tip = PriorityFeePolicy::Percentile(50.0)
+            .priority_fee(&provider, U256::ZERO, 0)
+            .await
+            .unwrap();
+        assert!(tip > 0);
+    }
+}
@@ -0,0 +1,470 @@
+use super::pools::{DexVariant, Pool, PoolLiquidity};
+use super::registry::PoolRegistry;
+use super::store::{PoolStatus, PoolStore, TokenInfo};
+use crate::arbitrage::simulation::{get_address, AddressType};
+use crate::arbitrage::uniswap_v2::IV2Pair;
+use crate::arbitrage::v3_math::mul_div;
+use alloy::eips::BlockId;
+use alloy::primitives::{Address, Bytes, U256};
+use alloy::providers::{Provider, RootProvider};
+use alloy::pubsub::PubSubFrontend;
+use alloy::rpc::types::TransactionRequest;
+use alloy::transports::RpcError;
+use alloy_sol_types::{SolCall, SolValue};
+use anyhow::Result;
+use dotenv::var;
+use futures::{stream, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// The old `liquidity_test` pass wanted at least 5 ETH in a pool
+pub const DEFAULT_MIN_TVL_WETH: f64 = 5.0;
+pub const DEFAULT_MAX_TOKEN_DECIMALS: u8 = 24;
+pub const DEFAULT_FILTER_CONCURRENCY: usize = 16;
+/// About a week of blocks
+pub const DEFAULT_RECHECK_BLOCKS: u64 = 50_400;
+
+alloy::sol! {
+    interface IERC20Metadata {
+        function decimals() external view returns (uint8);
+        function symbol() external view returns (string);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterConfig {
+    /// Pools below this WETH-denominated TVL (in ether) are marked inactive
+    pub min_tvl_weth: f64,
+    /// Tokens reporting more decimals than this are treated as broken
+    pub max_token_decimals: u8,
+    /// Pools checked in parallel
+    pub concurrency: usize,
+    /// Inactive pools are checked again once their verdict is this many blocks old
+    pub recheck_blocks: u64,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            min_tvl_weth: DEFAULT_MIN_TVL_WETH,
+            max_token_decimals: DEFAULT_MAX_TOKEN_DECIMALS,
+            concurrency: DEFAULT_FILTER_CONCURRENCY,
+            recheck_blocks: DEFAULT_RECHECK_BLOCKS,
+        }
+    }
+}
+
+impl FilterConfig {
+    /// Thresholds from `MIN_POOL_TVL_WETH`, `MAX_TOKEN_DECIMALS`, `FILTER_CONCURRENCY` and
+    /// `POOL_RECHECK_BLOCKS`, falling back to the defaults for anything unset or unparsable
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            min_tvl_weth: env_or("MIN_POOL_TVL_WETH", default.min_tvl_weth),
+            max_token_decimals: env_or("MAX_TOKEN_DECIMALS", default.max_token_decimals),
+            concurrency: env_or("FILTER_CONCURRENCY", default.concurrency).max(1),
+            recheck_blocks: env_or("POOL_RECHECK_BLOCKS", default.recheck_blocks).max(1),
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Amounts of WETH and `token` in the deepest pool pricing one against the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WethQuote {
+    pub weth: U256,
+    pub token: U256,
+}
+
+/// Virtual reserves of a V3 pool at its current price, `x = L / sqrt(P)` and `y = L * sqrt(P)`.
+/// This is the in-range liquidity only, positions outside the current tick don't count.
+pub fn v3_virtual_reserves(liquidity: U256, sqrt_price_x96: U256) -> Result<(U256, U256)> {
+    let q96 = U256::from(1) << 96usize;
+    Ok((
+        mul_div(liquidity, q96, sqrt_price_x96)?,
+        mul_div(liquidity, sqrt_price_x96, q96)?,
+    ))
+}
+
+/// TVL of a pool in WETH. Both sides of a pool are worth the same at its own price, so this is
+/// twice the WETH side, or twice one side converted through that token's WETH quote.
+pub fn tvl_in_weth(
+    pool: &Pool,
+    reserves: (U256, U256),
+    weth: Address,
+    quote0: Option<WethQuote>,
+    quote1: Option<WethQuote>,
+) -> U256 {
+    let (reserve0, reserve1) = reserves;
+    let one_side = if pool.token0 == weth {
+        Some(reserve0)
+    } else if pool.token1 == weth {
+        Some(reserve1)
+    } else {
+        let convert = |amount: U256, quote: Option<WethQuote>| {
+            quote.and_then(|quote| mul_div(amount, quote.weth, quote.token).ok())
+        };
+        convert(reserve0, quote0).or_else(|| convert(reserve1, quote1))
+    };
+    one_side.unwrap_or_default().saturating_mul(U256::from(2))
+}
+
+/// ERC20 `symbol()` is `string` for most tokens and `bytes32` for a few old ones (MKR, SAI)
+pub fn decode_symbol(output: &[u8]) -> Option<String> {
+    let symbol = match String::abi_decode(output, true) {
+        Ok(symbol) => symbol,
+        Err(_) if output.len() == 32 => String::from_utf8(output.to_vec())
+            .ok()?
+            .trim_end_matches('\0')
+            .to_string(),
+        Err(_) => return None,
+    };
+    let printable = !symbol.is_empty() && symbol.chars().all(|c| c.is_ascii_graphic());
+    printable.then_some(symbol)
+}
+
+pub fn wei_to_ether(amount: U256) -> f64 {
+    let micro: u128 = (amount / U256::from(10u64.pow(12)))
+        .try_into()
+        .unwrap_or(u128::MAX);
+    micro as f64 / 1e6
+}
+
+/// Checks pools against the liquidity and token thresholds, caching token metadata and
+/// WETH quotes so a token shared by many pools is only looked up once
+pub struct PoolFilter {
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    config: FilterConfig,
+    weth: Address,
+    tokens: Mutex<HashMap<Address, TokenInfo>>,
+    quotes: Mutex<HashMap<Address, Option<WethQuote>>>,
+}
+
+impl PoolFilter {
+    pub fn new(
+        provider: Arc<RootProvider<PubSubFrontend>>,
+        config: FilterConfig,
+        known_tokens: Vec<TokenInfo>,
+    ) -> Self {
+        Self {
+            provider,
+            config,
+            weth: get_address(AddressType::Weth),
+            tokens: Mutex::new(
+                known_tokens
+                    .into_iter()
+                    .map(|token| (token.address, token))
+                    .collect(),
+            ),
+            quotes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> FilterConfig {
+        self.config
+    }
+
+    /// Token metadata looked up so far, to be persisted
+    pub async fn tokens(&self) -> Vec<TokenInfo> {
+        self.tokens.lock().await.values().cloned().collect()
+    }
+
+    /// WETH quotes are only valid for the block they were read at
+    pub async fn clear_quotes(&self) {
+        self.quotes.lock().await.clear();
+    }
+
+    /// `registry` is used to find the WETH pools that price tokens not paired with WETH
+    pub async fn check_pool(
+        &self,
+        pool: &Pool,
+        registry: &PoolRegistry,
+        block_number: u64,
+    ) -> Result<PoolStatus> {
+        let block = BlockId::from(block_number);
+        let mut status = PoolStatus {
+            address: pool.address,
+            active: false,
+            tvl_weth: 0.0,
+            checked_block: block_number,
+        };
+
+        for token in [pool.token0, pool.token1] {
+            if !self.token_info(token, block_number).await?.valid {
+                return Ok(status);
+            }
+        }
+
+        let reserves = self.reserves(pool, block).await?;
+        let quote = |token: Address| async move {
+            if pool.token0 == self.weth || pool.token1 == self.weth {
+                return Ok(None);
+            }
+            self.weth_quote(token, registry, block).await
+        };
+        let quote0 = quote(pool.token0).await?;
+        let quote1 = if quote0.is_none() {
+            quote(pool.token1).await?
+        } else {
+            None
+        };
+
+        let tvl = tvl_in_weth(pool, reserves, self.weth, quote0, quote1);
+        status.tvl_weth = wei_to_ether(tvl);
+        status.active = status.tvl_weth >= self.config.min_tvl_weth;
+        Ok(status)
+    }
+
+    /// Metadata of `token`, only cached once both calls were answered
+    async fn token_info(&self, token: Address, block_number: u64) -> Result<TokenInfo> {
+        if let Some(info) = self.tokens.lock().await.get(&token) {
+            return Ok(info.clone());
+        }
+
+        let block = BlockId::from(block_number);
+        let decimals = self
+            .raw_call(token, IERC20Metadata::decimalsCall {}.abi_encode(), block)
+            .await?
+            .and_then(|output| U256::abi_decode(&output, true).ok())
+            .and_then(|decimals| u8::try_from(decimals).ok());
+        let symbol = self
+            .raw_call(token, IERC20Metadata::symbolCall {}.abi_encode(), block)
+            .await?
+            .and_then(|output| decode_symbol(&output));
+
+        let info = TokenInfo {
+            address: token,
+            valid: symbol.is_some()
+                && decimals.is_some_and(|decimals| decimals <= self.config.max_token_decimals),
+            decimals,
+            symbol,
+            checked_block: block_number,
+        };
+        self.tokens.lock().await.insert(token, info.clone());
+        Ok(info)
+    }
+
+    /// Output of an `eth_call`, None if it reverted. Any other error is returned, so a
+    /// node that failed to answer isn't mistaken for a token without the function.
+    async fn raw_call(&self, to: Address, input: Vec<u8>, block: BlockId) -> Result<Option<Bytes>> {
+        let tx = TransactionRequest::default()
+            .to(to)
+            .input(Bytes::from(input).into());
+        match self.provider.call(&tx).block(block).await {
+            Ok(output) => Ok(Some(output)),
+            // geth answers reverts with code 3, other clients only say so in the message
+            Err(RpcError::ErrorResp(payload))
+                if payload.code == 3 || payload.message.to_lowercase().contains("revert") =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn reserves(&self, pool: &Pool, block: BlockId) -> Result<(U256, U256)> {
+        match pool.version {
+            DexVariant::UniswapV2 => {
+                let reserves = IV2Pair::new(pool.address, self.provider.clone())
+                    .getReserves()
+                    .block(block)
+                    .call()
+                    .await?;
+                Ok((U256::from(reserves.reserve0), U256::from(reserves.reserve1)))
+            }
+            DexVariant::UniswapV3 => {
+                let slot0 = PoolLiquidity::load(self.provider.clone(), pool.address, block).await?;
+                if slot0.sqrt_price_x96.is_zero() {
+                    // created but never initialized
+                    return Ok((U256::ZERO, U256::ZERO));
+                }
+                v3_virtual_reserves(slot0.liquidity, slot0.sqrt_price_x96)
+            }
+        }
+    }
+
+    async fn weth_quote(
+        &self,
+        token: Address,
+        registry: &PoolRegistry,
+        block: BlockId,
+    ) -> Result<Option<WethQuote>> {
+        if let Some(quote) = self.quotes.lock().await.get(&token) {
+            return Ok(*quote);
+        }
+
+        let mut best: Option<WethQuote> = None;
+        for pool in registry.pools_for_pair(token, self.weth) {
+            let (reserve0, reserve1) = self.reserves(pool, block).await?;
+            let quote = if pool.token0 == self.weth {
+                WethQuote {
+                    weth: reserve0,
+                    token: reserve1,
+                }
+            } else {
+                WethQuote {
+                    weth: reserve1,
+                    token: reserve0,
+                }
+            };
+            if quote.token.is_zero() {
+                continue;
+            }
+            if best.is_none_or(|best| quote.weth > best.weth) {
+                best = Some(quote);
+            }
+        }
+
+        self.quotes.lock().await.insert(token, best);
+        Ok(best)
+    }
+}
+
+/// Run the filter over `pools` and record the verdicts in the store. Pools that fail to load
+/// (RPC errors) keep their previous verdict, or none, so the next run retries them. Token
+/// metadata is only cached and saved once the token answered.
+pub async fn filter_pools(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    store: &mut PoolStore,
+    registry: &PoolRegistry,
+    pools: Vec<Pool>,
+    config: FilterConfig,
+) -> Result<Vec<PoolStatus>> {
+    let block_number = provider.get_block_number().await?;
+    let filter = PoolFilter::new(provider, config, store.load_tokens()?);
+
+    let pb = ProgressBar::new(pools.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+
+    let statuses: Vec<PoolStatus> = stream::iter(pools.iter())
+        .map(|pool| {
+            let (filter, pb) = (&filter, &pb);
+            async move {
+                let status = filter.check_pool(pool, registry, block_number).await;
+                pb.inc(1);
+                status
+                    .inspect_err(|err| {
+                        log::debug!("Error checking pool {:?}: {:?}", pool.address, err)
+                    })
+                    .ok()
+            }
+        })
+        .buffer_unordered(config.concurrency)
+        .filter_map(|status| async move { status })
+        .collect()
+        .await;
+
+    store.set_pool_status(&statuses)?;
+    store.upsert_tokens(&filter.tokens().await)?;
+
+    let active = statuses.iter().filter(|status| status.active).count();
+    info!(
+        "Liquidity filter: {} of {} checked pools active",
+        active,
+        statuses.len()
+    );
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::pools::test_pool;
+
+    fn ether(amount: u64) -> U256 {
+        U256::from(amount) * U256::from(10).pow(U256::from(18))
+    }
+
+    #[test]
+    fn test_tvl_of_weth_pair() {
+        let weth = Address::repeat_byte(1);
+        let tvl = tvl_in_weth(
+            &test_pool(0x10, DexVariant::UniswapV2, 1, 2, 300),
+            (ether(10), ether(20_000)),
+            weth,
+            None,
+            None,
+        );
+        assert_eq!(tvl, ether(20));
+        let tvl = tvl_in_weth(
+            &test_pool(0x10, DexVariant::UniswapV2, 2, 1, 300),
+            (ether(20_000), ether(10)),
+            weth,
+            None,
+            None,
+        );
+        assert_eq!(tvl, ether(20));
+    }
+
+    #[test]
+    fn test_tvl_through_weth_quote() {
+        let weth = Address::repeat_byte(1);
+        // token 2 trades at 1 WETH = 2000 token
+        let quote = WethQuote {
+            weth: ether(100),
+            token: ether(200_000),
+        };
+        let tvl = tvl_in_weth(
+            &test_pool(0x10, DexVariant::UniswapV2, 2, 3, 300),
+            (ether(4_000), ether(1)),
+            weth,
+            Some(quote),
+            None,
+        );
+        assert_eq!(tvl, ether(4));
+        // unpriceable pools have no TVL
+        assert_eq!(
+            tvl_in_weth(
+                &test_pool(0x10, DexVariant::UniswapV2, 2, 3, 300),
+                (ether(4_000), ether(1)),
+                weth,
+                None,
+                None
+            ),
+            U256::ZERO
+        );
+    }
+
+    #[test]
+    fn test_v3_virtual_reserves_at_price_one() {
+        // sqrt(1) * 2^96, both virtual reserves equal the liquidity
+        let sqrt_price = U256::from(1) << 96usize;
+        let (reserve0, reserve1) = v3_virtual_reserves(ether(5), sqrt_price).unwrap();
+        assert_eq!(reserve0, ether(5));
+        assert_eq!(reserve1, ether(5));
+    }
+
+    #[test]
+    fn test_decode_symbol() {
+        assert_eq!(
+            decode_symbol(&"WETH".to_string().abi_encode()),
+            Some("WETH".to_string())
+        );
+        let mut bytes32 = [0u8; 32];
+        bytes32[..3].copy_from_slice(b"MKR");
+        assert_eq!(decode_symbol(&bytes32), Some("MKR".to_string()));
+        assert_eq!(decode_symbol(&[0u8; 32]), None);
+        assert_eq!(decode_symbol(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn test_wei_to_ether() {
+        assert_eq!(wei_to_ether(ether(5)), 5.0);
+        assert_eq!(wei_to_ether(ether(1) / U256::from(4)), 0.25);
+    }
+}
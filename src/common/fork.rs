@@ -0,0 +1,682 @@
+use super::balance_slot::BalanceSlot;
+use super::classifier::TokenVerdict;
+use super::persistent_db::{AccountSource, PersistentDB, EVM_CACHE_DIR};
+use super::revm::EvmSimulator;
+use super::state_diff::apply_state_diff;
+use alloy::eips::{BlockId, BlockNumberOrTag};
+use alloy::network::Ethereum;
+use alloy::primitives::{Address, U64};
+use alloy::providers::{Provider, RootProvider};
+use alloy::pubsub::PubSubFrontend;
+use alloy::rpc::types::trace::geth::DiffMode;
+use alloy::rpc::types::BlockTransactionsKind;
+use alloy::transports::TransportError;
+use anyhow::{anyhow, Result};
+use revm::db::{AccountState, AlloyDB, CacheDB, DbAccount, EmptyDB};
+use revm::primitives::{Account, AccountInfo, Bytecode, Env, B256, KECCAK_EMPTY, U256};
+use revm::{Database, DatabaseCommit, DatabaseRef};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::IntoFuture;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// Chain state at a pinned block, fetched over RPC. Accounts are looked up with
+/// `eth_getProof`, which carries the code hash, so code already on disk isn't downloaded.
+#[derive(Debug)]
+pub struct RpcDB {
+    inner: AlloyDB<PubSubFrontend, Ethereum, Arc<RootProvider<PubSubFrontend, Ethereum>>>,
+    provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+    block: BlockId,
+    handle: Handle,
+}
+
+impl RpcDB {
+    /// None outside a multi-threaded tokio runtime
+    pub fn new(
+        provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+        block: BlockId,
+    ) -> Option<Self> {
+        let handle = multi_thread_handle()?;
+        Some(Self {
+            inner: AlloyDB::new(provider.clone(), block)?,
+            provider,
+            block,
+            handle,
+        })
+    }
+}
+
+impl DatabaseRef for RpcDB {
+    type Error = TransportError;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        self.inner.basic_ref(address)
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.inner.code_by_hash_ref(code_hash)
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        self.inner.storage_ref(address, index)
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        self.inner.block_hash_ref(number)
+    }
+}
+
+impl AccountSource for RpcDB {
+    fn account_without_code(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        let request = self
+            .provider
+            .get_proof(address, Vec::new())
+            .block_id(self.block);
+        let proof = tokio::task::block_in_place(|| self.handle.block_on(request.into_future()))?;
+        // accounts that were never touched come back with a zero code hash
+        let code_hash = match proof.code_hash {
+            B256::ZERO => KECCAK_EMPTY,
+            code_hash => code_hash,
+        };
+        Ok(Some(AccountInfo {
+            balance: proof.balance,
+            nonce: proof.nonce,
+            code_hash,
+            code: None,
+        }))
+    }
+
+    fn code_at(&self, address: Address) -> Result<Bytecode, Self::Error> {
+        let request = self.provider.get_code_at(address).block_id(self.block);
+        let code = tokio::task::block_in_place(|| self.handle.block_on(request.into_future()))?;
+        Ok(Bytecode::new_raw(code))
+    }
+}
+
+fn multi_thread_handle() -> Option<Handle> {
+    Handle::try_current()
+        .ok()
+        .filter(|handle| handle.runtime_flavor() != RuntimeFlavor::CurrentThread)
+}
+
+/// Whether `block_number` is at or below the node's finalized block, so its state can't be
+/// reorged any more. False when the node can't be asked.
+pub fn is_finalized(provider: &RootProvider<PubSubFrontend, Ethereum>, block_number: u64) -> bool {
+    let Some(handle) = multi_thread_handle() else {
+        return false;
+    };
+    let request =
+        provider.get_block_by_number(BlockNumberOrTag::Finalized, BlockTransactionsKind::Hashes);
+    match tokio::task::block_in_place(|| handle.block_on(request)) {
+        Ok(Some(block)) => block_number <= block.header.number,
+        Ok(None) => false,
+        Err(err) => {
+            log::debug!("Error fetching the finalized block: {:?}", err);
+            false
+        }
+    }
+}
+
+/// Where the shared base reads what it hasn't cached yet
+#[derive(Debug)]
+pub enum BaseDB {
+    /// The node, through the on-disk cache
+    Rpc(Box<PersistentDB<RpcDB>>),
+    /// Nothing, every account starts empty. For state that is built up locally.
+    Empty(EmptyDB),
+}
+
+/// State of `block_number`, read from the evm cache before asking the node. Only code is
+/// cached on disk unless the block is `finalized`.
+pub fn base_db(
+    provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+    block_number: u64,
+    finalized: bool,
+) -> Result<BaseDB> {
+    let rpc = RpcDB::new(provider, BlockId::from(block_number))
+        .ok_or_else(|| anyhow!("AlloyDB needs a multi-threaded tokio runtime"))?;
+    let db = match finalized {
+        true => PersistentDB::finalized(rpc, block_number, EVM_CACHE_DIR),
+        false => PersistentDB::code_only(rpc, EVM_CACHE_DIR),
+    };
+    Ok(BaseDB::Rpc(Box::new(db)))
+}
+
+impl DatabaseRef for BaseDB {
+    type Error = <PersistentDB<RpcDB> as DatabaseRef>::Error;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        match self {
+            BaseDB::Rpc(db) => db.basic_ref(address),
+            BaseDB::Empty(db) => db.basic_ref(address).map_err(|never| match never {}),
+        }
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        match self {
+            BaseDB::Rpc(db) => db.code_by_hash_ref(code_hash),
+            BaseDB::Empty(db) => db
+                .code_by_hash_ref(code_hash)
+                .map_err(|never| match never {}),
+        }
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        match self {
+            BaseDB::Rpc(db) => db.storage_ref(address, index),
+            BaseDB::Empty(db) => db
+                .storage_ref(address, index)
+                .map_err(|never| match never {}),
+        }
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        match self {
+            BaseDB::Rpc(db) => db.block_hash_ref(number),
+            BaseDB::Empty(db) => db.block_hash_ref(number).map_err(|never| match never {}),
+        }
+    }
+}
+
+/// Per-simulator layer on the shared base. Only what the simulator writes lives here,
+/// reads go to the base and stay cached there for every fork of the block, so copying a
+/// fork copies its writes and nothing else.
+#[derive(Debug, Clone)]
+pub struct ForkDB {
+    pub accounts: HashMap<Address, DbAccount>,
+    pub contracts: HashMap<B256, Bytecode>,
+    pub db: SharedBase,
+}
+
+impl ForkDB {
+    pub fn new(db: SharedBase) -> Self {
+        Self {
+            accounts: HashMap::new(),
+            contracts: HashMap::new(),
+            db,
+        }
+    }
+
+    /// Keep the code of `info` by hash, filling in the hash if it is missing
+    pub fn insert_contract(&mut self, info: &mut AccountInfo) {
+        if let Some(code) = info.code.as_ref().filter(|code| !code.is_empty()) {
+            if info.code_hash == KECCAK_EMPTY {
+                info.code_hash = code.hash_slow();
+            }
+            self.contracts
+                .entry(info.code_hash)
+                .or_insert_with(|| code.clone());
+        }
+        if info.code_hash.is_zero() {
+            info.code_hash = KECCAK_EMPTY;
+        }
+    }
+
+    pub fn insert_account_info(&mut self, address: Address, mut info: AccountInfo) {
+        self.insert_contract(&mut info);
+        self.accounts.entry(address).or_default().info = info;
+    }
+
+    /// The account in this layer, copied up from the base first so it can be written
+    pub fn load_account(&mut self, address: Address) -> Result<&mut DbAccount, BaseError> {
+        match self.accounts.entry(address) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(self.db.basic_ref(address)?.into())),
+        }
+    }
+
+    /// Write one slot, the account info stays as it is
+    pub fn insert_account_storage(
+        &mut self,
+        address: Address,
+        slot: U256,
+        value: U256,
+    ) -> Result<(), BaseError> {
+        self.load_account(address)?.storage.insert(slot, value);
+        Ok(())
+    }
+}
+
+impl DatabaseRef for ForkDB {
+    type Error = BaseError;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        match self.accounts.get(&address) {
+            Some(account) => Ok(account.info()),
+            None => self.db.basic_ref(address),
+        }
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        match self.contracts.get(&code_hash) {
+            Some(code) => Ok(code.clone()),
+            None => self.db.code_by_hash_ref(code_hash),
+        }
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        let Some(account) = self.accounts.get(&address) else {
+            return self.db.storage_ref(address, index);
+        };
+        match account.storage.get(&index) {
+            Some(value) => Ok(*value),
+            // created or destroyed here, nothing below applies to it any more
+            None if matches!(
+                account.account_state,
+                AccountState::StorageCleared | AccountState::NotExisting
+            ) =>
+            {
+                Ok(U256::ZERO)
+            }
+            None => self.db.storage_ref(address, index),
+        }
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        self.db.block_hash_ref(number)
+    }
+}
+
+impl Database for ForkDB {
+    type Error = BaseError;
+
+    fn basic(&mut self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        self.basic_ref(address)
+    }
+
+    fn code_by_hash(&mut self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.code_by_hash_ref(code_hash)
+    }
+
+    fn storage(&mut self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        self.storage_ref(address, index)
+    }
+
+    fn block_hash(&mut self, number: u64) -> Result<B256, Self::Error> {
+        self.block_hash_ref(number)
+    }
+}
+
+/// Same as `CacheDB`, except that slots a transaction only read stay in the base
+impl DatabaseCommit for ForkDB {
+    fn commit(&mut self, changes: revm::primitives::HashMap<Address, Account>) {
+        for (address, mut account) in changes {
+            if !account.is_touched() {
+                continue;
+            }
+            if account.is_selfdestructed() {
+                let db_account = self.accounts.entry(address).or_default();
+                db_account.storage.clear();
+                db_account.account_state = AccountState::NotExisting;
+                db_account.info = AccountInfo::default();
+                continue;
+            }
+            let is_newly_created = account.is_created();
+            self.insert_contract(&mut account.info);
+
+            let db_account = self.accounts.entry(address).or_default();
+            db_account.info = account.info;
+            db_account.account_state = if is_newly_created {
+                db_account.storage.clear();
+                AccountState::StorageCleared
+            } else if db_account.account_state.is_storage_cleared() {
+                AccountState::StorageCleared
+            } else {
+                AccountState::Touched
+            };
+            db_account.storage.extend(
+                account
+                    .storage
+                    .into_iter()
+                    .filter(|(_, slot)| slot.is_changed())
+                    .map(|(index, slot)| (index, slot.present_value())),
+            );
+        }
+    }
+}
+
+/// Error of a read that reached the node
+pub type BaseError = <BaseDB as DatabaseRef>::Error;
+
+/// Read-through cache of one block's chain state, shared by every fork of that block.
+/// Nothing a simulation does is written here, only what was fetched from the node, so
+/// each account and slot is fetched once per block no matter how many forks read it.
+#[derive(Debug, Clone)]
+pub struct SharedBase {
+    /// Everything fetched so far, misses aren't looked up in it
+    cache: Arc<RwLock<CacheDB<EmptyDB>>>,
+    db: Arc<BaseDB>,
+}
+
+impl SharedBase {
+    pub fn new(db: BaseDB) -> Self {
+        Self {
+            cache: Arc::new(RwLock::new(CacheDB::new(EmptyDB::default()))),
+            db: Arc::new(db),
+        }
+    }
+
+    /// Base with nothing behind it, every account not inserted is empty
+    pub fn empty() -> Self {
+        Self::new(BaseDB::Empty(EmptyDB::default()))
+    }
+
+    /// Base for the next block: everything fetched so far, patched with the block's state
+    /// diffs, with misses going to `db`. The old base is left as is for forks still using it.
+    pub fn patched(&self, db: BaseDB, diffs: &[DiffMode]) -> Self {
+        let mut cache = CacheDB::new(EmptyDB::default());
+        {
+            let old = self.read();
+            cache.accounts = old.accounts.clone();
+            cache.contracts = old.contracts.clone();
+            cache.block_hashes = old.block_hashes.clone();
+        }
+        let patched: usize = diffs
+            .iter()
+            .map(|diff| apply_state_diff(&mut cache, diff))
+            .sum();
+        log::debug!(
+            "Patched {} cached accounts from {} transactions",
+            patched,
+            diffs.len()
+        );
+        Self {
+            cache: Arc::new(RwLock::new(cache)),
+            db: Arc::new(db),
+        }
+    }
+
+    /// Number of accounts fetched so far
+    pub fn account_count(&self) -> usize {
+        self.read().accounts.len()
+    }
+
+    pub fn has_account(&self, address: Address) -> bool {
+        self.read().accounts.contains_key(&address)
+    }
+
+    /// Value of a slot fetched so far, None if it would have to be fetched
+    pub fn cached_storage(&self, address: Address, index: U256) -> Option<U256> {
+        self.read()
+            .accounts
+            .get(&address)
+            .and_then(|account| account.storage.get(&index).copied())
+    }
+
+    /// Add an account fetched outside the base, e.g. by a batched prefetch
+    pub fn insert_account(&self, address: Address, info: AccountInfo) {
+        self.write().insert_account_info(address, info);
+    }
+
+    /// Add a slot fetched outside the base. The account is fetched first if it isn't cached.
+    pub fn insert_storage(&self, address: Address, index: U256, value: U256) -> Result<()> {
+        self.basic_ref(address)
+            .map_err(|err| anyhow!("Error caching slot {} of {:?}: {:?}", index, address, err))?;
+        if let Some(account) = self.write().accounts.get_mut(&address) {
+            account.storage.insert(index, value);
+        }
+        Ok(())
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, CacheDB<EmptyDB>> {
+        // the cache only ever holds fetched state, a panic mid-insert leaves nothing half written
+        self.cache.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, CacheDB<EmptyDB>> {
+        self.cache.write().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Misses are fetched without holding the lock, so forks missing different state don't
+/// wait on each other. Two forks missing the same entry both fetch it, the first one to
+/// finish is kept.
+impl DatabaseRef for SharedBase {
+    type Error = BaseError;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        if let Some(account) = self.read().accounts.get(&address) {
+            return Ok(account.info());
+        }
+        let mut info = self.db.basic_ref(address)?;
+        let mut cache = self.write();
+        if let Some(info) = info.as_mut() {
+            cache.insert_contract(info);
+        }
+        Ok(cache.accounts.entry(address).or_insert(info.into()).info())
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        if let Some(code) = self.read().contracts.get(&code_hash) {
+            return Ok(code.clone());
+        }
+        let code = self.db.code_by_hash_ref(code_hash)?;
+        Ok(self
+            .write()
+            .contracts
+            .entry(code_hash)
+            .or_insert(code)
+            .clone())
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        if let Some(value) = self.cached_storage(address, index) {
+            return Ok(value);
+        }
+        // the account decides whether there is anything to fetch
+        if self.basic_ref(address)?.is_none() {
+            return Ok(U256::ZERO);
+        }
+        let cleared =
+            self.read().accounts.get(&address).is_some_and(|account| {
+                matches!(account.account_state, AccountState::StorageCleared)
+            });
+        if cleared {
+            return Ok(U256::ZERO);
+        }
+        let value = self.db.storage_ref(address, index)?;
+        let mut cache = self.write();
+        let Some(account) = cache.accounts.get_mut(&address) else {
+            return Ok(value);
+        };
+        Ok(*account.storage.entry(index).or_insert(value))
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        if let Some(hash) = self.read().block_hashes.get(&U256::from(number)) {
+            return Ok(*hash);
+        }
+        let hash = self.db.block_hash_ref(number)?;
+        Ok(*self
+            .write()
+            .block_hashes
+            .entry(U256::from(number))
+            .or_insert(hash))
+    }
+}
+
+/// Copy everything the simulator wrote into `old`, e.g. the deployed executor and its
+/// balances, onto `new`. A transaction also commits the accounts it only touched, so an
+/// account is copied only where it differs from what the base fetched for it. Returns the
+/// number of accounts copied.
+pub(crate) fn carry_written_state(old: &ForkDB, new: &mut ForkDB) -> Result<usize> {
+    let base = old.db.read();
+    let mut carried = 0;
+    for (address, account) in &old.accounts {
+        let cached = base.accounts.get(address);
+        let info = account.info();
+        let info_written = match (cached.and_then(|cached| cached.info()), &info) {
+            (Some(before), Some(after)) => {
+                (before.balance, before.nonce, before.code_hash)
+                    != (after.balance, after.nonce, after.code_hash)
+            }
+            (None, None) => false,
+            _ => true,
+        };
+        let storage: Vec<(U256, U256)> = account
+            .storage
+            .iter()
+            .filter(|(slot, value)| {
+                cached.and_then(|cached| cached.storage.get(*slot)) != Some(*value)
+            })
+            .map(|(slot, value)| (*slot, *value))
+            .collect();
+        if !info_written && storage.is_empty() {
+            continue;
+        }
+
+        if let Some(mut info) = info.filter(|_| info_written) {
+            info.code = old.contracts.get(&info.code_hash).cloned().or(info.code);
+            new.insert_account_info(*address, info);
+        }
+        for (slot, value) in storage {
+            new.insert_account_storage(*address, slot, value)
+                .map_err(|err| {
+                    anyhow!("Error copying slot {} of {:?}: {:?}", slot, address, err)
+                })?;
+        }
+        carried += 1;
+    }
+    Ok(carried)
+}
+
+/// Pre-state of one opportunity, taken from a simulator with `EvmSimulator::fork`.
+/// Every simulator built from it starts from the same state and never sees what the
+/// others do, and since it is `Send` each one can run on its own thread. Each one gets
+/// its own copy of the written state, the base behind it is shared.
+#[derive(Debug, Clone)]
+pub struct EvmFork {
+    pub(crate) db: ForkDB,
+    pub(crate) env: Box<Env>,
+    pub owner: Address,
+    pub contract_address: Address,
+    pub block_number: U64,
+    pub(crate) provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+    pub(crate) token_verdicts: HashMap<Address, TokenVerdict>,
+    pub(crate) balance_slots: HashMap<Address, BalanceSlot>,
+    pub(crate) balance_slot_misses: HashMap<Address, U64>,
+}
+
+impl EvmFork {
+    /// A fresh simulator over a private copy of the forked state
+    pub fn simulator(&self) -> EvmSimulator<'static> {
+        EvmSimulator::from_fork(self.clone())
+    }
+}
+
+/// Transport that accepts requests and never answers them
+#[cfg(test)]
+struct Offline;
+
+#[cfg(test)]
+impl alloy::pubsub::PubSubConnect for Offline {
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    async fn connect(&self) -> alloy::transports::TransportResult<alloy::pubsub::ConnectionHandle> {
+        let (handle, mut interface) = alloy::pubsub::ConnectionHandle::new();
+        tokio::spawn(async move { while interface.recv_from_frontend().await.is_some() {} });
+        Ok(handle)
+    }
+}
+
+/// Simulator over `SharedBase::empty()` with a provider that is never answered, for tests
+/// that build all the state they need locally
+#[cfg(test)]
+pub(crate) async fn offline_simulator() -> EvmSimulator<'static> {
+    use alloy::pubsub::PubSubConnect;
+    use alloy::rpc::client::RpcClient;
+
+    let frontend = Offline.into_service().await.unwrap();
+    let provider = Arc::new(RootProvider::new(RpcClient::new(frontend, true)));
+    EvmSimulator::with_base(None, U64::ZERO, provider, SharedBase::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_forks_do_not_see_each_other() {
+        let mut simulator = offline_simulator().await;
+
+        let wallet = Address::repeat_byte(0x42);
+        simulator.set_eth_balance(wallet, U256::from(1)).await;
+        let fork = simulator.fork();
+
+        let mut first = fork.simulator();
+        let mut second = fork.simulator();
+        first.set_eth_balance(wallet, U256::from(2)).await;
+
+        assert_eq!(first.get_eth_balance(wallet).await, U256::from(2));
+        assert_eq!(second.get_eth_balance(wallet).await, U256::from(1));
+        assert_eq!(simulator.get_eth_balance(wallet).await, U256::from(1));
+    }
+
+    #[tokio::test]
+    async fn test_reads_stay_in_the_base() {
+        let mut simulator = offline_simulator().await;
+        let (owner, reader) = (simulator.owner, Address::repeat_byte(0x33));
+        // on chain already: SLOAD(0) POP STOP
+        let code = Bytecode::new_raw(vec![0x60, 0x00, 0x54, 0x50, 0x00].into());
+        let info = AccountInfo::new(U256::ZERO, 1, code.hash_slow(), code);
+        simulator
+            .evm
+            .get_mut()
+            .context
+            .evm
+            .db
+            .db
+            .insert_account(reader, info);
+        simulator.set_eth_balance(owner, U256::from(1)).await;
+        simulator.get_eth_balance(Address::repeat_byte(0x44)).await;
+        simulator
+            .call(crate::common::revm::Tx {
+                caller: owner,
+                transact_to: reader,
+                data: Default::default(),
+                value: U256::ZERO,
+                gas_price: U256::ZERO,
+                gas_limit: 100_000,
+            })
+            .into_result()
+            .unwrap();
+
+        let fork = simulator.fork();
+        assert!(!fork.db.accounts.contains_key(&Address::repeat_byte(0x44)));
+        assert!(fork.db.accounts[&reader].storage.is_empty());
+        assert!(fork.db.db.has_account(Address::repeat_byte(0x44)));
+        assert_eq!(
+            fork.db.db.cached_storage(reader, U256::ZERO),
+            Some(U256::ZERO)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_only_written_state_is_carried() {
+        let mut simulator = offline_simulator().await;
+        let (read, written) = (Address::repeat_byte(0x11), Address::repeat_byte(0x22));
+        let executor = simulator.contract_address;
+        simulator
+            .deploy_code_at(executor, Bytecode::new_raw(vec![0x60, 0x00].into()))
+            .await;
+        simulator.get_eth_balance(read).await;
+        let db = &mut simulator.evm.get_mut().context.evm.db;
+        db.storage(read, U256::from(1)).unwrap();
+        db.insert_account_storage(written, U256::from(2), U256::from(7))
+            .unwrap();
+
+        let mut next = ForkDB::new(SharedBase::empty());
+        assert_eq!(carry_written_state(db, &mut next).unwrap(), 2);
+        assert!(!next.accounts.contains_key(&read));
+        assert_eq!(next.storage(written, U256::from(2)).unwrap(), U256::from(7));
+        let info = next.basic(executor).unwrap().unwrap();
+        assert_eq!(info.balance, U256::MAX);
+        assert_eq!(
+            next.code_by_hash(info.code_hash).unwrap(),
+            Bytecode::new_raw(vec![0x60, 0x00].into())
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use super::pairs::Event;
+use super::pools::{DexVariant, Pool};
+use super::registry::PoolRegistry;
+use crate::arbitrage::graph::{RouteCandidate, TokenGraph, MAX_HOPS};
+use crate::arbitrage::simulation::{get_address, AddressType};
 use alloy::eips::BlockNumberOrTag;
 use alloy::primitives::Address;
 use alloy::providers::{Provider, RootProvider};
@@ -8,13 +11,16 @@ use alloy_primitives::aliases::U24;
 use futures::StreamExt;
 use log::info;
 use revm::primitives::keccak256;
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 use tokio::sync::broadcast::Sender;
+use tokio::sync::RwLock;
 
 pub async fn get_logs(
     client: Arc<RootProvider<PubSubFrontend>>,
-    pairs: HashMap<Address, Event>,
+    registry: Arc<RwLock<PoolRegistry>>,
     event_sender: Sender<LogEvent>,
+    graph: Arc<RwLock<TokenGraph>>,
+    route_sender: Sender<RouteCandidate>,
 ) {
     // before we do this we will need a bunch of addresses to filter on.
     // One way of doing this maybe is just having a bunch of filters? not sure
@@ -31,52 +37,63 @@ pub async fn get_logs(
         .from_block(BlockNumberOrTag::Latest);
     let sub = client.subscribe_logs(&filter).await.unwrap();
     let mut stream = sub.into_stream();
+    let weth = get_address(AddressType::Weth);
 
     while let Some(res) = stream.next().await {
         let key = res.address();
-        //info!("Log Pool Address: {:?}", key);   
-        // The strategy needs both the log pool address and the corresponding other v pool address, they are in hashmap
-        if let Some(event) = pairs.get(&key) {
 
-            match event {
-            Event::PairCreated(pair) => {
-                if let Some(Event::PoolCreated(v3_pair)) = pairs.values().find(|value| {
-                matches!(value, Event::PoolCreated(v3_pair) if (v3_pair.token0 == pair.token0 && v3_pair.token1 == pair.token1) || (v3_pair.token0 == pair.token1 && v3_pair.token1 == pair.token0))
-                }) {
+        let routes = graph.read().await.cycles_through(weth, key, MAX_HOPS);
+        if !routes.is_empty() {
+            let _ = route_sender.send(RouteCandidate {
+                touched_pool: key,
+                block_number: res.block_number,
+                routes,
+            });
+        }
 
-                    //info!("Log Block Number: {:?}", res.block_number);
-                    if v3_pair.token0 == v3_pair.token1 {continue}
-                    
-                    let _ = event_sender.send(LogEvent {
-                    pool_variant: 2,
-                    corresponding_pool_address: v3_pair.pair_address,
-                    log_pool_address: key,
-                    token0:pair.token0,
-                    token1: pair.token1,
-                    fee: U24::from(pair.fee),
-                    });
-                }
+        // The strategy needs both the log pool address and the corresponding other v pool address
+        let pools = registry.read().await;
+        let Some(pool) = pools.get(&key) else {
+            continue;
+        };
+        if pool.token0 == pool.token1 {
+            continue;
+        }
+        // same lock order as discovery, registry before graph
+        {
+            let graph = graph.read().await;
+            if graph.is_excluded(&pool.token0) || graph.is_excluded(&pool.token1) {
+                continue;
             }
-            Event::PoolCreated(pair) => {
-                if let Some(Event::PairCreated(v2_pair)) = pairs.values().find(|value| {
-                matches!(value, Event::PairCreated(v2_pair) if (v2_pair.token0 == pair.token0 && v2_pair.token1 == pair.token1) || (v2_pair.token0 == pair.token1 && v2_pair.token1 == pair.token0))
-                }) {
+        }
 
-                let _ =event_sender.send(LogEvent {
-                    pool_variant: 3,
-                    corresponding_pool_address: v2_pair.pair_address,
-                    log_pool_address: key,
-                    token0: pair.token0,
-                    token1: pair.token1,
-                    fee: U24::from(pair.fee),
-                });
-                }
-            }
-            }
+        for event in counterpart_events(&pools, pool) {
+            let _ = event_sender.send(event);
         }
     }
 }
 
+/// One event per counterpart of `pool`, a V2 pair can have a V3 pool in every fee tier
+pub fn counterpart_events(registry: &PoolRegistry, pool: &Pool) -> Vec<LogEvent> {
+    registry
+        .counterparts(&pool.address)
+        .map(|counterpart| {
+            let v3_fee = match pool.version {
+                DexVariant::UniswapV2 => counterpart.fee,
+                DexVariant::UniswapV3 => pool.fee,
+            };
+            LogEvent {
+                pool_variant: pool.version.num() as usize,
+                corresponding_pool_address: counterpart.address,
+                log_pool_address: pool.address,
+                token0: pool.token0,
+                token1: pool.token1,
+                fee: U24::from(v3_fee),
+            }
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct LogEvent {
     pub pool_variant: usize,
@@ -0,0 +1,344 @@
+use super::logs::{counterpart_events, LogEvent};
+use super::pools::{DexVariant, Pool};
+use super::registry::PoolRegistry;
+use super::revm::VictimTx;
+use crate::arbitrage::graph::TokenGraph;
+use crate::arbitrage::simulation::{get_address, AddressType};
+use alloy::primitives::{Address, Bytes};
+use alloy::providers::{Provider, RootProvider};
+use alloy::pubsub::PubSubFrontend;
+use alloy_primitives::aliases::U24;
+use alloy_sol_types::SolInterface;
+use anyhow::Result;
+use futures::StreamExt;
+use log::info;
+use std::sync::Arc;
+use tokio::sync::broadcast::Sender;
+use tokio::sync::RwLock;
+
+alloy::sol! {
+    interface IV2Router {
+        function swapExactTokensForTokens(uint256 amountIn, uint256 amountOutMin, address[] path, address to, uint256 deadline) external returns (uint256[]);
+        function swapTokensForExactTokens(uint256 amountOut, uint256 amountInMax, address[] path, address to, uint256 deadline) external returns (uint256[]);
+        function swapExactETHForTokens(uint256 amountOutMin, address[] path, address to, uint256 deadline) external payable returns (uint256[]);
+        function swapTokensForExactETH(uint256 amountOut, uint256 amountInMax, address[] path, address to, uint256 deadline) external returns (uint256[]);
+        function swapExactTokensForETH(uint256 amountIn, uint256 amountOutMin, address[] path, address to, uint256 deadline) external returns (uint256[]);
+        function swapETHForExactTokens(uint256 amountOut, address[] path, address to, uint256 deadline) external payable returns (uint256[]);
+        function swapExactTokensForTokensSupportingFeeOnTransferTokens(uint256 amountIn, uint256 amountOutMin, address[] path, address to, uint256 deadline) external;
+        function swapExactETHForTokensSupportingFeeOnTransferTokens(uint256 amountOutMin, address[] path, address to, uint256 deadline) external payable;
+        function swapExactTokensForETHSupportingFeeOnTransferTokens(uint256 amountIn, uint256 amountOutMin, address[] path, address to, uint256 deadline) external;
+    }
+
+    interface ISwapRouter02 {
+        struct ExactInputSingleParams {
+            address tokenIn;
+            address tokenOut;
+            uint24 fee;
+            address recipient;
+            uint256 amountIn;
+            uint256 amountOutMinimum;
+            uint160 sqrtPriceLimitX96;
+        }
+        struct ExactOutputSingleParams {
+            address tokenIn;
+            address tokenOut;
+            uint24 fee;
+            address recipient;
+            uint256 amountOut;
+            uint256 amountInMaximum;
+            uint160 sqrtPriceLimitX96;
+        }
+        struct ExactInputParams {
+            bytes path;
+            address recipient;
+            uint256 amountIn;
+            uint256 amountOutMinimum;
+        }
+        struct ExactOutputParams {
+            bytes path;
+            address recipient;
+            uint256 amountOut;
+            uint256 amountInMaximum;
+        }
+
+        function exactInputSingle(ExactInputSingleParams params) external payable returns (uint256);
+        function exactOutputSingle(ExactOutputSingleParams params) external payable returns (uint256);
+        function exactInput(ExactInputParams params) external payable returns (uint256);
+        function exactOutput(ExactOutputParams params) external payable returns (uint256);
+        function swapExactTokensForTokens(uint256 amountIn, uint256 amountOutMin, address[] path, address to) external payable returns (uint256);
+        function swapTokensForExactTokens(uint256 amountOut, uint256 amountInMax, address[] path, address to) external payable returns (uint256);
+        function multicall(uint256 deadline, bytes[] data) external payable returns (bytes[]);
+        function multicall(bytes[] data) external payable returns (bytes[]);
+    }
+}
+
+/// One pool a router swap goes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapHop {
+    pub token_in: Address,
+    pub token_out: Address,
+    /// Fee tier of a V3 hop, None for a V2 hop
+    pub fee: Option<u32>,
+}
+
+/// A pending swap worth backrunning: `event` is the arbitrage the victim's pool can open up
+#[derive(Debug, Clone)]
+pub struct Backrun {
+    pub victim: VictimTx,
+    pub event: LogEvent,
+}
+
+/// Hops of a call to the Uniswap V2 router or SwapRouter02, empty for anything else
+pub fn decode_swap(router: Address, input: &[u8]) -> Vec<SwapHop> {
+    if router == get_address(AddressType::V2Router) {
+        decode_v2_router(input)
+    } else if router == get_address(AddressType::V3Router) {
+        decode_swap_router02(input)
+    } else {
+        Vec::new()
+    }
+}
+
+fn decode_v2_router(input: &[u8]) -> Vec<SwapHop> {
+    use IV2Router::IV2RouterCalls as Call;
+    let path = match Call::abi_decode(input, false) {
+        Ok(Call::swapExactTokensForTokens(call)) => call.path,
+        Ok(Call::swapTokensForExactTokens(call)) => call.path,
+        Ok(Call::swapExactETHForTokens(call)) => call.path,
+        Ok(Call::swapTokensForExactETH(call)) => call.path,
+        Ok(Call::swapExactTokensForETH(call)) => call.path,
+        Ok(Call::swapETHForExactTokens(call)) => call.path,
+        Ok(Call::swapExactTokensForTokensSupportingFeeOnTransferTokens(call)) => call.path,
+        Ok(Call::swapExactETHForTokensSupportingFeeOnTransferTokens(call)) => call.path,
+        Ok(Call::swapExactTokensForETHSupportingFeeOnTransferTokens(call)) => call.path,
+        Err(_) => return Vec::new(),
+    };
+    v2_hops(&path)
+}
+
+fn decode_swap_router02(input: &[u8]) -> Vec<SwapHop> {
+    use ISwapRouter02::ISwapRouter02Calls as Call;
+    match Call::abi_decode(input, false) {
+        Ok(Call::exactInputSingle(call)) => vec![SwapHop {
+            token_in: call.params.tokenIn,
+            token_out: call.params.tokenOut,
+            fee: Some(call.params.fee.to::<u32>()),
+        }],
+        Ok(Call::exactOutputSingle(call)) => vec![SwapHop {
+            token_in: call.params.tokenIn,
+            token_out: call.params.tokenOut,
+            fee: Some(call.params.fee.to::<u32>()),
+        }],
+        Ok(Call::exactInput(call)) => v3_hops(&call.params.path),
+        // the path of an exact output swap runs from the output token back to the input
+        Ok(Call::exactOutput(call)) => v3_hops(&call.params.path)
+            .into_iter()
+            .rev()
+            .map(|hop| SwapHop {
+                token_in: hop.token_out,
+                token_out: hop.token_in,
+                fee: hop.fee,
+            })
+            .collect(),
+        Ok(Call::swapExactTokensForTokens(call)) => v2_hops(&call.path),
+        Ok(Call::swapTokensForExactTokens(call)) => v2_hops(&call.path),
+        Ok(Call::multicall_0(call)) => multicall_hops(&call.data),
+        Ok(Call::multicall_1(call)) => multicall_hops(&call.data),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn multicall_hops(calls: &[Bytes]) -> Vec<SwapHop> {
+    calls
+        .iter()
+        .flat_map(|call| decode_swap_router02(call))
+        .collect()
+}
+
+fn v2_hops(path: &[Address]) -> Vec<SwapHop> {
+    path.windows(2)
+        .map(|pair| SwapHop {
+            token_in: pair[0],
+            token_out: pair[1],
+            fee: None,
+        })
+        .collect()
+}
+
+/// `token (20 bytes) | fee (3 bytes) | token | ...`, empty if the path is malformed
+fn v3_hops(path: &[u8]) -> Vec<SwapHop> {
+    const HOP: usize = 23;
+    if path.len() < HOP + 20 || !(path.len() - 20).is_multiple_of(HOP) {
+        return Vec::new();
+    }
+    (0..(path.len() - 20) / HOP)
+        .map(|i| {
+            let hop = &path[i * HOP..];
+            SwapHop {
+                token_in: Address::from_slice(&hop[..20]),
+                token_out: Address::from_slice(&hop[HOP..HOP + 20]),
+                fee: Some(U24::from_be_slice(&hop[20..HOP]).to::<u32>()),
+            }
+        })
+        .collect()
+}
+
+/// Pools in the registry the hops trade on
+pub fn touched_pools(registry: &PoolRegistry, hops: &[SwapHop]) -> Vec<Pool> {
+    hops.iter()
+        .flat_map(|hop| {
+            registry
+                .pools_for_pair(hop.token_in, hop.token_out)
+                .filter(move |pool| match hop.fee {
+                    Some(fee) => pool.version == DexVariant::UniswapV3 && pool.fee == fee,
+                    None => pool.version == DexVariant::UniswapV2,
+                })
+                .copied()
+        })
+        .collect()
+}
+
+/// Decode router swaps from the pending transactions and send a `Backrun` for every
+/// counterpart of every pool they touch
+pub async fn watch_pending_swaps(
+    client: Arc<RootProvider<PubSubFrontend>>,
+    registry: Arc<RwLock<PoolRegistry>>,
+    graph: Arc<RwLock<TokenGraph>>,
+    backrun_sender: Sender<Backrun>,
+) -> Result<()> {
+    info!("Subscribing to pending transactions");
+    let sub = client.subscribe_full_pending_transactions().await?;
+    let mut stream = sub.into_stream();
+
+    while let Some(tx) = stream.next().await {
+        let Some(victim) = VictimTx::from_transaction(&tx) else {
+            continue;
+        };
+        let hops = decode_swap(victim.to, &victim.data);
+        if hops.is_empty() {
+            continue;
+        }
+
+        // same lock order as discovery, registry before graph
+        let registry = registry.read().await;
+        let graph = graph.read().await;
+        for pool in touched_pools(&registry, &hops) {
+            if graph.is_excluded(&pool.token0) || graph.is_excluded(&pool.token1) {
+                continue;
+            }
+            for event in counterpart_events(&registry, &pool) {
+                let _ = backrun_sender.send(Backrun {
+                    victim: victim.clone(),
+                    event,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{address, U256};
+    use alloy_primitives::U160;
+    use alloy_sol_types::SolCall;
+
+    const USDC: Address = address!("A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48");
+    const DAI: Address = address!("6B175474E89094C44Da98b954EedeAC495271d0F");
+
+    fn weth() -> Address {
+        get_address(AddressType::Weth)
+    }
+
+    fn v3_path(tokens: &[Address], fees: &[u32]) -> Bytes {
+        let mut path = tokens[0].to_vec();
+        for (token, fee) in tokens[1..].iter().zip(fees) {
+            path.extend_from_slice(&U24::from(*fee).to_be_bytes::<3>());
+            path.extend_from_slice(token.as_slice());
+        }
+        path.into()
+    }
+
+    #[test]
+    fn test_decode_v2_router_swaps() {
+        let input = IV2Router::swapExactETHForTokensCall {
+            amountOutMin: U256::ZERO,
+            path: vec![weth(), USDC, DAI],
+            to: Address::ZERO,
+            deadline: U256::MAX,
+        }
+        .abi_encode();
+        assert_eq!(
+            decode_swap(get_address(AddressType::V2Router), &input),
+            vec![
+                SwapHop {
+                    token_in: weth(),
+                    token_out: USDC,
+                    fee: None
+                },
+                SwapHop {
+                    token_in: USDC,
+                    token_out: DAI,
+                    fee: None
+                },
+            ]
+        );
+        // the same call to any other contract isn't ours to decode
+        assert!(decode_swap(Address::repeat_byte(1), &input).is_empty());
+    }
+
+    #[test]
+    fn test_decode_swap_router02_multicall() {
+        let single = ISwapRouter02::exactInputSingleCall {
+            params: ISwapRouter02::ExactInputSingleParams {
+                tokenIn: weth(),
+                tokenOut: USDC,
+                fee: U24::from(500),
+                recipient: Address::ZERO,
+                amountIn: U256::from(1),
+                amountOutMinimum: U256::ZERO,
+                sqrtPriceLimitX96: U160::ZERO,
+            },
+        }
+        .abi_encode();
+        // DAI <- USDC <- WETH, paid in WETH
+        let exact_output = ISwapRouter02::exactOutputCall {
+            params: ISwapRouter02::ExactOutputParams {
+                path: v3_path(&[DAI, USDC, weth()], &[100, 3000]),
+                recipient: Address::ZERO,
+                amountOut: U256::from(1),
+                amountInMaximum: U256::MAX,
+            },
+        }
+        .abi_encode();
+        let input = ISwapRouter02::multicall_0Call {
+            deadline: U256::MAX,
+            data: vec![single.into(), exact_output.into()],
+        }
+        .abi_encode();
+
+        let hops = decode_swap(get_address(AddressType::V3Router), &input);
+        assert_eq!(
+            hops,
+            vec![
+                SwapHop {
+                    token_in: weth(),
+                    token_out: USDC,
+                    fee: Some(500)
+                },
+                SwapHop {
+                    token_in: weth(),
+                    token_out: USDC,
+                    fee: Some(3000)
+                },
+                SwapHop {
+                    token_in: USDC,
+                    token_out: DAI,
+                    fee: Some(100)
+                },
+            ]
+        );
+        assert!(v3_hops(&[0u8; 30]).is_empty());
+    }
+}
@@ -1,9 +1,26 @@
+pub mod access_list;
+pub mod balance_slot;
+pub mod bundle;
+pub mod call_bundle;
+pub mod classifier;
 pub mod decodeResult;
+pub mod discovery;
+pub mod error_registry;
+pub mod fees;
+pub mod filter;
+pub mod fork;
 pub mod logger;
 pub mod logs;
+pub mod mempool;
+pub mod outcome;
 pub mod pairs;
+pub mod persistent_db;
 pub mod pools;
+pub mod prefetch;
+pub mod registry;
 pub mod revm;
 pub mod revmInspector;
+pub mod state_diff;
+pub mod store;
 pub mod transaction;
 pub mod utils;
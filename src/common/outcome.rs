@@ -0,0 +1,180 @@
+use super::decodeResult::{decode_evm_revert, DecodedEVMRevert};
+use super::revm::TxResult;
+use super::revmInspector::{deepest_revert, CallInfo, RevertFrame};
+use alloy_sol_types::SolError;
+use anyhow::{anyhow, Result};
+use revm::primitives::{ExecutionResult, HaltReason, Output};
+use std::fmt;
+
+/// What happened to a simulated transaction. Reverts and halts are results of the
+/// transaction itself, `Infra` means it never ran, e.g. the database failed or the
+/// transaction was invalid for the block.
+#[derive(Debug)]
+pub enum SimulationOutcome {
+    Success(TxResult),
+    Revert {
+        gas_used: u64,
+        revert: DecodedEVMRevert,
+        trace: Vec<CallInfo>,
+    },
+    Halt {
+        gas_used: u64,
+        reason: HaltReason,
+        trace: Vec<CallInfo>,
+    },
+    Infra(anyhow::Error),
+}
+
+impl SimulationOutcome {
+    pub fn from_execution(result: ExecutionResult, trace: Vec<CallInfo>) -> Self {
+        match result {
+            ExecutionResult::Success {
+                gas_used,
+                gas_refunded,
+                output,
+                logs,
+                ..
+            } => {
+                let output = match output {
+                    Output::Call(output) => output,
+                    Output::Create(output, _) => output,
+                };
+                SimulationOutcome::Success(TxResult {
+                    output,
+                    logs: Some(logs),
+                    gas_used,
+                    gas_refunded,
+                    trace,
+                })
+            }
+            ExecutionResult::Revert { gas_used, output } => SimulationOutcome::Revert {
+                gas_used,
+                revert: decode_evm_revert(output.to_vec()),
+                trace,
+            },
+            ExecutionResult::Halt { reason, gas_used } => SimulationOutcome::Halt {
+                gas_used,
+                reason,
+                trace,
+            },
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self, SimulationOutcome::Success(_))
+    }
+
+    /// Gas the transaction burned, None if it never ran
+    pub fn gas_used(&self) -> Option<u64> {
+        match self {
+            SimulationOutcome::Success(result) => Some(result.gas_used),
+            SimulationOutcome::Revert { gas_used, .. }
+            | SimulationOutcome::Halt { gas_used, .. } => Some(*gas_used),
+            SimulationOutcome::Infra(_) => None,
+        }
+    }
+
+    /// Call frames of the transaction, empty if it never ran
+    pub fn trace(&self) -> &[CallInfo] {
+        match self {
+            SimulationOutcome::Success(result) => &result.trace,
+            SimulationOutcome::Revert { trace, .. } | SimulationOutcome::Halt { trace, .. } => {
+                trace
+            }
+            SimulationOutcome::Infra(_) => &[],
+        }
+    }
+
+    /// The revert, if the transaction reverted
+    pub fn revert(&self) -> Option<&DecodedEVMRevert> {
+        match self {
+            SimulationOutcome::Revert { revert, .. } => Some(revert),
+            _ => None,
+        }
+    }
+
+    /// The inner call the revert or halt started in, None for a success
+    pub fn deepest_revert(&self) -> Option<RevertFrame> {
+        deepest_revert(self.trace())
+    }
+
+    /// Whether the transaction reverted with the custom error `E`, e.g. `ProfitIsZero`
+    pub fn reverted_with<E: SolError>(&self) -> bool {
+        self.revert()
+            .is_some_and(|revert| revert.selector == E::SELECTOR)
+    }
+
+    /// Decode the arguments of the custom error `E`, None for any other outcome
+    pub fn revert_as<E: SolError>(&self) -> Option<E> {
+        let revert = self.revert()?;
+        E::abi_decode(&revert.raw_data, true).ok()
+    }
+
+    /// For callers that only care about success, everything else becomes an error
+    pub fn into_result(self) -> Result<TxResult> {
+        match self {
+            SimulationOutcome::Success(result) => Ok(result),
+            SimulationOutcome::Infra(err) => Err(err),
+            other => Err(anyhow!("{}", other)),
+        }
+    }
+}
+
+impl fmt::Display for SimulationOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimulationOutcome::Success(result) => {
+                return write!(f, "Success, gas used: {}", result.gas_used)
+            }
+            SimulationOutcome::Revert {
+                gas_used, revert, ..
+            } => write!(f, "{} / Gas used: {}", revert, gas_used)?,
+            SimulationOutcome::Halt {
+                gas_used, reason, ..
+            } => write!(f, "EVM Halt: {:?} / Gas used: {}", reason, gas_used)?,
+            SimulationOutcome::Infra(err) => return write!(f, "Simulation failed: {:?}", err),
+        }
+        // the transaction's own frame is already described above
+        match self.deepest_revert() {
+            Some(frame) if frame.depth > 0 => write!(f, " / {}", frame),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrage::simulation::{NotSender, ProfitIsZero};
+    use alloy::primitives::{Address, Bytes};
+
+    fn reverted(output: Vec<u8>) -> SimulationOutcome {
+        SimulationOutcome::from_execution(
+            ExecutionResult::Revert {
+                gas_used: 21_000,
+                output: Bytes::from(output),
+            },
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn test_custom_errors_are_matched_by_selector() {
+        let sender = Address::repeat_byte(0x42);
+        let outcome = reverted(NotSender { sender }.abi_encode());
+
+        assert!(outcome.reverted_with::<NotSender>());
+        assert!(!outcome.reverted_with::<ProfitIsZero>());
+        assert_eq!(outcome.revert_as::<NotSender>(), Some(NotSender { sender }));
+        assert_eq!(outcome.gas_used(), Some(21_000));
+        assert!(outcome.into_result().is_err());
+    }
+
+    #[test]
+    fn test_infra_errors_have_no_gas() {
+        let outcome = SimulationOutcome::Infra(anyhow!("EVM lock failed"));
+        assert_eq!(outcome.gas_used(), None);
+        assert!(!outcome.reverted_with::<ProfitIsZero>());
+        assert!(outcome.trace().is_empty());
+    }
+}
@@ -1,10 +1,11 @@
+use super::pools::{DexVariant, Pool};
 use alloy::primitives::{keccak256, Address};
 use alloy::providers::{Provider, RootProvider};
 use alloy::pubsub::PubSubFrontend;
 use alloy::rpc::types::{Filter, Log};
 use anyhow::Result;
 use log::info;
-use revm::primitives::{B256, U256};
+use revm::primitives::{I256, U256};
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -33,7 +34,7 @@ pub async fn get_pairs(
     // Process the logs into a hashmap
     let mut pools = vec![];
     for log in logs {
-        if let Some(event) = decode_event(&log).await {
+        if let Some(event) = decode_event(&log) {
             pools.push(event);
         }
     }
@@ -60,40 +61,46 @@ pub async fn get_pairs(
     Ok(result)
 }
 
-async fn decode_event(log: &Log) -> Option<Event> {
+/// Decode a `PairCreated` (V2 factory) or `PoolCreated` (V3 factory) log
+pub fn decode_event(log: &Log) -> Option<Event> {
     let uniswap_v2_pool_create_sig =
-        keccak256("PairCreated(address,address,address,uint256)".as_bytes()).to_vec();
+        keccak256("PairCreated(address,address,address,uint256)".as_bytes());
     let uniswap_v3_pool_create_sig =
-        keccak256("PoolCreated(address,address,uint24,int24,address)".as_bytes()).to_vec();
-
-    if log.address().len() > 0 {
-        if log.topics()[0] == B256::from_slice(&uniswap_v2_pool_create_sig) {
-            let address = Address::from_slice(&log.data().data.0[12..32]);
-            let topic_1_address_slice = &log.topics()[1].0.as_slice()[12..32];
-            let topic_2_address_slice = &log.topics()[2].0.as_slice()[12..32];
-            let token0 = Address::from_slice(topic_1_address_slice);
-            let token1 = Address::from_slice(topic_2_address_slice);
-
-            return Some(Event::PairCreated(V2PoolCreated {
-                pair_address: address,
-                token0,
-                token1,
-                block_number: log.block_number.unwrap(),
-                fee: 500,
-            }));
-        } else if log.topics()[0] == B256::from_slice(&uniswap_v3_pool_create_sig) {
-            let address = Address::from_slice(&log.data().data.0[44..64]);
-            let token0 = Address::from_slice(&log.topics()[1].0.as_slice()[12..32]);
-            let token1 = Address::from_slice(&log.topics()[1].0.as_slice()[12..32]);
-
-            return Some(Event::PoolCreated(V3PoolCreated {
-                pair_address: address,
-                token0,
-                token1,
-                fee: 500,
-                tick_spacing: 100,
-            }));
-        }
+        keccak256("PoolCreated(address,address,uint24,int24,address)".as_bytes());
+
+    let topics = log.topics();
+    let data = &log.data().data;
+    let block_number = log.block_number.unwrap_or_default();
+
+    if topics.len() == 3 && topics[0] == uniswap_v2_pool_create_sig && data.len() >= 64 {
+        // data is (address pair, uint256 allPairsLength)
+        let address = Address::from_slice(&data[12..32]);
+        let token0 = Address::from_slice(&topics[1][12..32]);
+        let token1 = Address::from_slice(&topics[2][12..32]);
+
+        return Some(Event::PairCreated(V2PoolCreated {
+            pair_address: address,
+            token0,
+            token1,
+            block_number,
+            fee: 300,
+        }));
+    } else if topics.len() == 4 && topics[0] == uniswap_v3_pool_create_sig && data.len() >= 64 {
+        // fee is indexed, data is (int24 tickSpacing, address pool)
+        let address = Address::from_slice(&data[44..64]);
+        let token0 = Address::from_slice(&topics[1][12..32]);
+        let token1 = Address::from_slice(&topics[2][12..32]);
+        let fee = U256::from_be_bytes(topics[3].0).to::<u32>();
+        let tick_spacing = I256::from_raw(U256::from_be_slice(&data[0..32])).as_i32();
+
+        return Some(Event::PoolCreated(V3PoolCreated {
+            pair_address: address,
+            token0,
+            token1,
+            fee,
+            tick_spacing,
+            block_number,
+        }));
     }
     None
 }
@@ -148,6 +155,7 @@ pub struct V3PoolCreated {
     pub fee: u32,
     pub tick_spacing: i32,
     pub pair_address: Address,
+    pub block_number: u64,
 }
 
 trait CommonFields {
@@ -208,3 +216,103 @@ impl GetAddress for Event {
         }
     }
 }
+
+impl From<Event> for Pool {
+    fn from(event: Event) -> Self {
+        match event {
+            Event::PairCreated(pair) => Pool {
+                id: -1,
+                address: pair.pair_address,
+                version: DexVariant::UniswapV2,
+                token0: pair.token0,
+                token1: pair.token1,
+                fee: pair.fee,
+                tick_spacing: 0,
+                block_number: pair.block_number,
+            },
+            Event::PoolCreated(pool) => Pool {
+                id: -1,
+                address: pool.pair_address,
+                version: DexVariant::UniswapV3,
+                token0: pool.token0,
+                token1: pool.token1,
+                fee: pool.fee,
+                tick_spacing: pool.tick_spacing,
+                block_number: pool.block_number,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{Bytes, LogData, B256};
+
+    fn log(topics: Vec<B256>, data: Vec<u8>) -> Log {
+        Log {
+            inner: alloy::primitives::Log {
+                address: Address::ZERO,
+                data: LogData::new_unchecked(topics, Bytes::from(data)),
+            },
+            block_number: Some(12_345),
+            ..Default::default()
+        }
+    }
+
+    fn word(address: Address) -> B256 {
+        address.into_word()
+    }
+
+    #[test]
+    fn test_decode_pair_created() {
+        let token0 = Address::repeat_byte(1);
+        let token1 = Address::repeat_byte(2);
+        let pair = Address::repeat_byte(3);
+        let mut data = word(pair).to_vec();
+        data.extend_from_slice(&U256::from(7).to_be_bytes::<32>());
+
+        let topics = vec![
+            keccak256("PairCreated(address,address,address,uint256)"),
+            word(token0),
+            word(token1),
+        ];
+        let Some(Event::PairCreated(event)) = decode_event(&log(topics, data)) else {
+            panic!("expected PairCreated");
+        };
+        assert_eq!(event.pair_address, pair);
+        assert_eq!(event.token0, token0);
+        assert_eq!(event.token1, token1);
+        assert_eq!(event.block_number, 12_345);
+    }
+
+    #[test]
+    fn test_decode_pool_created() {
+        let token0 = Address::repeat_byte(1);
+        let token1 = Address::repeat_byte(2);
+        let pool = Address::repeat_byte(3);
+        let mut data = I256::try_from(60).unwrap().to_be_bytes::<32>().to_vec();
+        data.extend_from_slice(word(pool).as_slice());
+
+        let topics = vec![
+            keccak256("PoolCreated(address,address,uint24,int24,address)"),
+            word(token0),
+            word(token1),
+            B256::from(U256::from(3000)),
+        ];
+        let event = decode_event(&log(topics, data)).expect("expected PoolCreated");
+        let pool_record = Pool::from(event);
+        assert_eq!(pool_record.address, pool);
+        assert_eq!(pool_record.version, DexVariant::UniswapV3);
+        assert_eq!(pool_record.token0, token0);
+        assert_eq!(pool_record.token1, token1);
+        assert_eq!(pool_record.fee, 3000);
+        assert_eq!(pool_record.tick_spacing, 60);
+    }
+
+    #[test]
+    fn test_decode_ignores_other_logs() {
+        let topics = vec![keccak256("Transfer(address,address,uint256)")];
+        assert!(decode_event(&log(topics, vec![0; 64])).is_none());
+    }
+}
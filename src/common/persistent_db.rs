@@ -0,0 +1,326 @@
+use alloy::primitives::{Address, B256, U256};
+use revm::primitives::{AccountInfo, Bytecode};
+use revm::DatabaseRef;
+use std::path::PathBuf;
+
+/// cacache directory, local to each checkout
+pub const EVM_CACHE_DIR: &str = ".evm_cache";
+
+/// Node access that lets the disk cache skip downloading code it already has
+pub trait AccountSource: DatabaseRef {
+    /// Balance, nonce and code hash of `address`, the code is left out
+    fn account_without_code(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error>;
+
+    /// Code deployed at `address`
+    fn code_at(&self, address: Address) -> Result<Bytecode, Self::Error>;
+}
+
+/// Disk cache between the in-memory `CacheDB` and the RPC. Code is stored by hash and
+/// kept forever, an account whose code is on disk costs one lookup instead of downloading
+/// the code again. Accounts, slots and block hashes are only stored for a block that can't
+/// be reorged any more, keyed by its number, so a restart or a second backtest of the
+/// same block reads them from disk instead of the node. The state of a live block is
+/// never read again once the next block arrives, so it isn't written at all.
+#[derive(Debug)]
+pub struct PersistentDB<ExtDB> {
+    inner: ExtDB,
+    /// Finalized block whose state is stored, None to store code only
+    state_block: Option<u64>,
+    dir: PathBuf,
+}
+
+impl<ExtDB: DatabaseRef> PersistentDB<ExtDB> {
+    /// Cache of a finalized `block_number`, state included
+    pub fn finalized(inner: ExtDB, block_number: u64, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            state_block: Some(block_number),
+            dir: dir.into(),
+        }
+    }
+
+    /// Cache of a block that may still be reorged, only code is stored
+    pub fn code_only(inner: ExtDB, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            state_block: None,
+            dir: dir.into(),
+        }
+    }
+
+    fn read(&self, key: &str) -> Option<Vec<u8>> {
+        match cacache::read_sync(&self.dir, key) {
+            Ok(data) => Some(data),
+            Err(cacache::Error::EntryNotFound(..)) => None,
+            Err(err) => {
+                log::debug!("Error reading {} from the evm cache: {:?}", key, err);
+                None
+            }
+        }
+    }
+
+    fn write(&self, key: &str, data: &[u8]) {
+        if let Err(err) = cacache::write_sync(&self.dir, key, data) {
+            log::debug!("Error writing {} to the evm cache: {:?}", key, err);
+        }
+    }
+
+    fn cached_code(&self, code_hash: B256) -> Option<Bytecode> {
+        self.read(&code_key(code_hash))
+            .map(|code| Bytecode::new_raw(code.into()))
+    }
+}
+
+impl<ExtDB: AccountSource> PersistentDB<ExtDB> {
+    /// Account from the node, its code from disk unless it was never seen before
+    fn fetch_account(&self, address: Address) -> Result<Option<AccountInfo>, ExtDB::Error> {
+        let Some(mut info) = self.inner.account_without_code(address)? else {
+            return Ok(None);
+        };
+        if info.is_empty_code_hash() {
+            info.code = Some(Bytecode::default());
+            return Ok(Some(info));
+        }
+        let code = match self.cached_code(info.code_hash) {
+            Some(code) => code,
+            None => {
+                let code = self.inner.code_at(address)?;
+                self.write(&code_key(info.code_hash), &code.original_bytes());
+                code
+            }
+        };
+        info.code = Some(code);
+        Ok(Some(info))
+    }
+}
+
+impl<ExtDB: AccountSource> DatabaseRef for PersistentDB<ExtDB> {
+    type Error = ExtDB::Error;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        let Some(block_number) = self.state_block else {
+            return self.fetch_account(address);
+        };
+        let key = format!("account-{}-{:?}", block_number, address);
+        if let Some(account) = self.read(&key).and_then(|data| decode_account(&data)) {
+            let Some(mut info) = account else {
+                return Ok(None);
+            };
+            if info.is_empty_code_hash() {
+                return Ok(Some(info));
+            }
+            // only trust the entry when its code is there too, the RPC db can't look up code
+            if let Some(code) = self.cached_code(info.code_hash) {
+                info.code = Some(code);
+                return Ok(Some(info));
+            }
+        }
+
+        let account = self.fetch_account(address)?;
+        self.write(&key, &encode_account(account.as_ref()));
+        Ok(account)
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        if let Some(code) = self.cached_code(code_hash) {
+            return Ok(code);
+        }
+        let code = self.inner.code_by_hash_ref(code_hash)?;
+        self.write(&code_key(code_hash), &code.original_bytes());
+        Ok(code)
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        let Some(block_number) = self.state_block else {
+            return self.inner.storage_ref(address, index);
+        };
+        let key = format!("storage-{}-{:?}-{:x}", block_number, address, index);
+        if let Some(value) = self.read(&key).filter(|data| data.len() == 32) {
+            return Ok(U256::from_be_slice(&value));
+        }
+        let value = self.inner.storage_ref(address, index)?;
+        self.write(&key, &value.to_be_bytes::<32>());
+        Ok(value)
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        if self.state_block.is_none() {
+            return self.inner.block_hash_ref(number);
+        }
+        let key = format!("block_hash-{}", number);
+        if let Some(hash) = self.read(&key).filter(|data| data.len() == 32) {
+            return Ok(B256::from_slice(&hash));
+        }
+        let hash = self.inner.block_hash_ref(number)?;
+        self.write(&key, hash.as_slice());
+        Ok(hash)
+    }
+}
+
+fn code_key(code_hash: B256) -> String {
+    format!("code-{:?}", code_hash)
+}
+
+/// balance, nonce and code hash, or nothing for an account that doesn't exist
+fn encode_account(account: Option<&AccountInfo>) -> Vec<u8> {
+    let Some(info) = account else {
+        return Vec::new();
+    };
+    let mut data = Vec::with_capacity(72);
+    data.extend_from_slice(&info.balance.to_be_bytes::<32>());
+    data.extend_from_slice(&info.nonce.to_be_bytes());
+    data.extend_from_slice(info.code_hash.as_slice());
+    data
+}
+
+/// None when the entry is corrupt, Some(None) for an account that doesn't exist
+fn decode_account(data: &[u8]) -> Option<Option<AccountInfo>> {
+    match data.len() {
+        0 => Some(None),
+        72 => Some(Some(AccountInfo {
+            balance: U256::from_be_slice(&data[..32]),
+            nonce: u64::from_be_bytes(data[32..40].try_into().ok()?),
+            code_hash: B256::from_slice(&data[40..]),
+            code: None,
+        })),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::convert::Infallible;
+
+    /// Answers every lookup with fixed values and counts how often it was asked
+    #[derive(Default)]
+    struct CountingDB {
+        lookups: Cell<usize>,
+    }
+
+    impl DatabaseRef for CountingDB {
+        type Error = Infallible;
+
+        fn basic_ref(&self, _address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+            unreachable!("accounts are looked up without their code")
+        }
+
+        fn code_by_hash_ref(&self, _code_hash: B256) -> Result<Bytecode, Self::Error> {
+            unreachable!("code is looked up by address")
+        }
+
+        fn storage_ref(&self, _address: Address, index: U256) -> Result<U256, Self::Error> {
+            self.lookups.set(self.lookups.get() + 1);
+            Ok(index + U256::from(1))
+        }
+
+        fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+            self.lookups.set(self.lookups.get() + 1);
+            Ok(B256::with_last_byte(number as u8))
+        }
+    }
+
+    impl AccountSource for CountingDB {
+        fn account_without_code(
+            &self,
+            _address: Address,
+        ) -> Result<Option<AccountInfo>, Self::Error> {
+            self.lookups.set(self.lookups.get() + 1);
+            Ok(Some(AccountInfo {
+                balance: U256::from(42),
+                nonce: 7,
+                code_hash: test_code().hash_slow(),
+                code: None,
+            }))
+        }
+
+        fn code_at(&self, _address: Address) -> Result<Bytecode, Self::Error> {
+            self.lookups.set(self.lookups.get() + 1);
+            Ok(test_code())
+        }
+    }
+
+    fn test_code() -> Bytecode {
+        Bytecode::new_raw(vec![0x60, 0x00, 0xf3].into())
+    }
+
+    fn temp_cache(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("arbooo-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_account_encoding_round_trip() {
+        let info = AccountInfo {
+            balance: U256::MAX,
+            nonce: 3,
+            code_hash: B256::repeat_byte(0xab),
+            code: None,
+        };
+        assert_eq!(
+            decode_account(&encode_account(Some(&info))),
+            Some(Some(info))
+        );
+        assert_eq!(decode_account(&encode_account(None)), Some(None));
+        assert_eq!(decode_account(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn test_second_lookup_is_served_from_disk() {
+        let dir = temp_cache("persistent-db");
+        let address = Address::repeat_byte(0x11);
+
+        let first = PersistentDB::finalized(CountingDB::default(), 100, &dir);
+        let info = first.basic_ref(address).unwrap().unwrap();
+        let value = first.storage_ref(address, U256::from(5)).unwrap();
+        let hash = first.block_hash_ref(99).unwrap();
+        assert_eq!(first.inner.lookups.get(), 4);
+
+        // a restart at the same block only touches the disk
+        let second = PersistentDB::finalized(CountingDB::default(), 100, &dir);
+        assert_eq!(second.basic_ref(address).unwrap().unwrap(), info);
+        assert_eq!(second.storage_ref(address, U256::from(5)).unwrap(), value);
+        assert_eq!(second.block_hash_ref(99).unwrap(), hash);
+        assert_eq!(
+            second.code_by_hash_ref(info.code_hash).unwrap(),
+            info.code.clone().unwrap()
+        );
+        assert_eq!(second.inner.lookups.get(), 0);
+
+        // slots and accounts are per block, code isn't
+        let next_block = PersistentDB::finalized(CountingDB::default(), 101, &dir);
+        next_block.storage_ref(address, U256::from(5)).unwrap();
+        assert_eq!(next_block.basic_ref(address).unwrap().unwrap(), info);
+        assert_eq!(next_block.inner.lookups.get(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_live_blocks_only_store_code() {
+        let dir = temp_cache("persistent-db-live");
+        let address = Address::repeat_byte(0x11);
+
+        let first = PersistentDB::code_only(CountingDB::default(), &dir);
+        let info = first.basic_ref(address).unwrap().unwrap();
+        first.storage_ref(address, U256::from(5)).unwrap();
+        assert_eq!(first.inner.lookups.get(), 3);
+
+        // state comes from the node every time, the code from disk
+        let second = PersistentDB::code_only(CountingDB::default(), &dir);
+        assert_eq!(second.basic_ref(address).unwrap().unwrap(), info);
+        second.storage_ref(address, U256::from(5)).unwrap();
+        assert_eq!(second.inner.lookups.get(), 2);
+        assert_eq!(
+            second.code_by_hash_ref(info.code_hash).unwrap(),
+            info.code.unwrap()
+        );
+        assert!(cacache::index::ls(&dir)
+            .filter_map(|entry| entry.ok())
+            .all(|entry| entry.key.starts_with("code-")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
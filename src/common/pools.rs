@@ -1,18 +1,16 @@
-use std::{
-    fs::{create_dir_all, OpenOptions},
-    str::FromStr,
-    sync::Arc,
-};
+use std::{str::FromStr, sync::Arc};
 use {
+    super::store::{PoolStore, LEGACY_CSV_PATHS, POOL_STORE_PATH},
     ::log::info,
     alloy::{
-        primitives::{Address, FixedBytes, B256, U256},
+        eips::BlockId,
+        primitives::{Address, FixedBytes, B256, I256, U256},
         providers::{Provider, ProviderBuilder, RootProvider},
         pubsub::PubSubFrontend,
         rpc::{client::WsConnect, types::eth::Filter},
     },
     alloy_sol_types::SolValue,
-    anyhow::Result,
+    anyhow::{anyhow, Result},
     csv::StringRecord,
     indicatif::{ProgressBar, ProgressStyle},
     serde::{Deserialize, Serialize},
@@ -50,40 +48,67 @@ pub struct Pool {
     pub token0: Address,
     pub token1: Address,
     pub fee: u32, // uniswap v3 specific
+    pub tick_spacing: i32,
     pub block_number: u64,
 }
 
-impl From<StringRecord> for Pool {
-    fn from(record: StringRecord) -> Self {
-        let version = match record.get(2).unwrap().parse().unwrap() {
-            2 => DexVariant::UniswapV2,
+/// Pool at `repeat_byte(address)` between `repeat_byte(token0)` and `repeat_byte(token1)`.
+/// A V3 pool gets the tick spacing of its fee tier.
+#[cfg(test)]
+pub(crate) fn test_pool(
+    address: u8,
+    version: DexVariant,
+    token0: u8,
+    token1: u8,
+    fee: u32,
+) -> Pool {
+    let tick_spacing = match (version, fee) {
+        (DexVariant::UniswapV3, 100) => 1,
+        (DexVariant::UniswapV3, 500) => 10,
+        (DexVariant::UniswapV3, 3000) => 60,
+        (DexVariant::UniswapV3, 10000) => 200,
+        _ => 0,
+    };
+    Pool {
+        id: address as i64,
+        address: Address::repeat_byte(address),
+        version,
+        token0: Address::repeat_byte(token0),
+        token1: Address::repeat_byte(token1),
+        fee,
+        tick_spacing,
+        block_number: 0,
+    }
+}
+
+/// A row of the legacy CSV cache, every field checked
+impl TryFrom<&StringRecord> for Pool {
+    type Error = anyhow::Error;
+
+    fn try_from(record: &StringRecord) -> Result<Self> {
+        let field = |index: usize| {
+            record
+                .get(index)
+                .ok_or_else(|| anyhow!("Missing column {}", index))
+        };
+        let version = match field(2)?.parse()? {
+            3 => DexVariant::UniswapV3,
             _ => DexVariant::UniswapV2,
         };
-        Self {
-            id: record.get(0).unwrap().parse().unwrap(),
-            address: Address::from_str(record.get(1).unwrap()).unwrap(),
+        Ok(Self {
+            id: field(0)?.parse()?,
+            address: Address::from_str(field(1)?)?,
             version,
-            token0: Address::from_str(record.get(3).unwrap()).unwrap(),
-            token1: Address::from_str(record.get(4).unwrap()).unwrap(),
-            fee: record.get(5).unwrap().parse().unwrap(),
-            block_number: record.get(6).unwrap().parse().unwrap(),
-        }
+            token0: Address::from_str(field(3)?)?,
+            token1: Address::from_str(field(4)?)?,
+            fee: field(5)?.parse()?,
+            tick_spacing: 0,
+            block_number: field(6)?.parse()?,
+        })
     }
 }
 
 impl Pool {
-    pub fn cache_row(&self) -> (i64, String, i32, String, String, u32, u64) {
-        (
-            self.id,
-            format!("{:?}", self.address),
-            self.version.num() as i32,
-            format!("{:?}", self.token0),
-            format!("{:?}", self.token1),
-            self.fee,
-            self.block_number,
-        )
-    }
-
     pub fn trades(&self, token_a: Address, token_b: Address) -> bool {
         let is_zero_for_one = self.token0 == token_a && self.token1 == token_b;
         let is_one_for_zero = self.token1 == token_a && self.token0 == token_b;
@@ -116,86 +141,34 @@ pub async fn get_touched_pools(
     Ok(touched_pools)
 }
 
-pub async fn load_all_pools(
-    wss_url: String,
-    from_block: u64,
-    chunk: u64,
-) -> Result<(Vec<Pool>, i64)> {
-    create_dir_all("cache").expect("Error creating directory");
-    info!("Creating cache file");
-    let cache_file = "~/cache/.cached-pools.csv";
-    let file_path = Path::new(cache_file);
-    let file_exists = file_path.exists();
-    let file = OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(cache_file)
-        .unwrap();
-    let mut writer = csv::Writer::from_writer(file);
-
-    let mut pools = Vec::new();
-
-    let mut v2_pool_cnt = 0;
-
-    if file_exists {
-        let mut reader = csv::Reader::from_path(cache_file)?;
-
-        for row in reader.records() {
-            let row = row.unwrap();
-            let pool = Pool::from(row);
-            if let DexVariant::UniswapV2 = pool.version {
-                v2_pool_cnt += 1
+/// Crawl factory events into the pool store, resuming from its last synced block,
+/// and return every known pool
+pub async fn load_all_pools(wss_url: String, from_block: u64, chunk: u64) -> Result<Vec<Pool>> {
+    let mut store = PoolStore::open(Path::new(POOL_STORE_PATH))?;
+
+    if store.pool_count()? == 0 {
+        for legacy in LEGACY_CSV_PATHS.iter().map(Path::new) {
+            if legacy.exists() {
+                let imported = store.import_csv(legacy)?;
+                info!("Imported {} pools from {:?}", imported, legacy);
+                break;
             }
-            pools.push(pool);
         }
-    } else {
-        info!("Writing");
-        writer.write_record([
-            "id",
-            "address",
-            "version",
-            "token0",
-            "token1",
-            "fee",
-            "block_number",
-        ])?;
     }
-    info!("Pools loaded: {:?}", pools.len());
-    info!("V2 pools: {:?}", v2_pool_cnt);
+    info!("Pools in store: {:?}", store.pool_count()?);
+
     let ws_client = WsConnect::new(wss_url);
     let ws = ProviderBuilder::new().on_ws(ws_client).await?;
     let provider = Arc::new(ws);
 
-    let mut id = if !pools.is_empty() {
-        pools.last().as_ref().unwrap().id
-    } else {
-        -1
-    };
-    let last_id = id;
-
-    let from_block = if id != -1 {
-        pools.last().as_ref().unwrap().block_number + 1
-    } else {
-        from_block
+    let from_block = match store.last_synced_block()? {
+        Some(block) => block + 1,
+        None => from_block,
     };
 
-    let to_block = provider.get_block_number().await.unwrap();
+    let to_block = provider.get_block_number().await?;
 
-    let mut blocks_processed = 0;
-
-    let mut block_range = Vec::new();
-
-    loop {
-        let start_idx = from_block + blocks_processed;
-        let mut end_idx = start_idx + chunk - 1;
-        if end_idx > to_block {
-            end_idx = to_block;
-            block_range.push((start_idx, end_idx));
-            break;
-        }
-        block_range.push((start_idx, end_idx));
-        blocks_processed += chunk;
-    }
+    let block_range = block_ranges(from_block, to_block, chunk);
     info!("Block range: {:?}", block_range);
 
     let pb = ProgressBar::new(block_range.len() as u64);
@@ -207,80 +180,32 @@ pub async fn load_all_pools(
         .progress_chars("##-"),
     );
 
+    let mut added = 0;
     for range in block_range {
-        let requests = vec![
-            tokio::task::spawn(load_uniswap_v2_pools(provider.clone(), range.0, range.1)),
-            tokio::task::spawn(load_uniswap_v3_pools(provider.clone(), range.0, range.1)),
-        ];
-
-        let results = futures::future::join_all(requests).await;
-        results.into_iter().for_each(|result| {
-            if let Ok(response) = result {
-                if let Ok(pools_response) = response {
-                    pools.extend(pools_response);
-                }
-            }
-        });
-        // now that we have all the pools, what we need to do is make sure they have atleast 5 eth of liquidity
-        // to do this we need to setup an evm, get the storage for the contract,
-        // Then query the balance of the contract for the token0 and token1
-        // if either of them are less than 5 eth, we will skip the pool
-        // if they are more than 5 eth, we will add the pool to the list
+        let (v2_pools, v3_pools) = tokio::try_join!(
+            load_uniswap_v2_pools(provider.clone(), range.0, range.1),
+            load_uniswap_v3_pools(provider.clone(), range.0, range.1),
+        )?;
 
-        pb.inc(1);
-    }
-
-    info!("amount of pools before liquidity test: {:?}", pools.len());
-
-    //    let (evm, caller_address) = create_evm(provider.clone()).await;
-    //let evm = Arc::new(tokio::sync::Mutex::new(evm));
-    //let required_liquidity = BigInt::from_signed_bytes_be(&one_ether().to_be_bytes_vec());
+        let mut pools = v2_pools;
+        pools.extend(v3_pools);
+        pools.sort_by_key(|p| p.block_number);
+        // the checkpoint only moves once the whole chunk is stored
+        added += store.insert_synced_pools(&pools, range.1)?;
 
-    //#let mut filtered_pools: Vec<Pool> = vec![];
-    let pb = ProgressBar::new(pools.len() as u64);
-
-    pb.set_style(
-        ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-        )
-        .unwrap()
-        .progress_chars("##-"),
-    );
-    //    for (_, pool) in pools.clone().iter_mut().enumerate() {
-    //        let has_liquidity = liquidity_test(
-    //            evm.clone(),
-    //            &pool.address,
-    //            required_liquidity.clone(),
-    //            caller_address,
-    //        )
-    //        .await
-    //        .unwrap_or_else(|e| false);
-    //        if !has_liquidity {
-    //            pb.inc(1);
-    //            continue;
-    //        }
-    //        filtered_pools.push(pool.clone());
-    //        pb.inc(1);
-    //    }
-    //
-    //let mut pools = filtered_pools;
-    //info!("amount of pools after liquidity test: {:?}", pools.len());
-    let mut added = 0;
-    pools.sort_by_key(|p| p.block_number);
-    for pool in pools.iter_mut() {
-        if pool.id == -1 {
-            id += 1;
-            pool.id = id;
-        }
-        if pool.id > last_id {
-            writer.serialize(pool.cache_row())?;
-            added += 1;
-        }
+        pb.inc(1);
     }
-    writer.flush()?;
     info!("Added {:?} new pools", added);
 
-    Ok((pools, last_id))
+    store.load_pools()
+}
+
+/// Inclusive `(from, to)` ranges of at most `chunk` blocks covering `from_block..=to_block`
+fn block_ranges(from_block: u64, to_block: u64, chunk: u64) -> Vec<(u64, u64)> {
+    (from_block..=to_block)
+        .step_by(chunk.max(1) as usize)
+        .map(|start| (start, (start + chunk.max(1) - 1).min(to_block)))
+        .collect()
 }
 
 pub async fn load_uniswap_v2_pools(
@@ -293,7 +218,7 @@ pub async fn load_uniswap_v2_pools(
     let event_filter = Filter::new()
         .from_block(from_block)
         .to_block(to_block)
-        //        .address(UNISWAP_V2_FACTORY)
+        .address(UNISWAP_V2_FACTORY)
         .event("PairCreated(address,address,address,uint256)");
 
     let logs = provider.get_logs(&event_filter).await?;
@@ -317,6 +242,7 @@ pub async fn load_uniswap_v2_pools(
             token0,
             token1,
             fee: 300,
+            tick_spacing: 0,
             block_number,
         };
         pools.push(pool_data);
@@ -335,12 +261,12 @@ pub async fn load_uniswap_v3_pools(
     let event_filter = Filter::new()
         .from_block(from_block)
         .to_block(to_block)
-        //        .address(UNISWAP_V3_FACTORY)
+        .address(UNISWAP_V3_FACTORY)
         .event("PoolCreated(address,address,uint24,int24,address)");
 
     let logs = provider.get_logs(&event_filter).await?;
     for log in logs {
-        if log.topics()[1].is_zero() {
+        if log.topics().len() < 4 || log.topics()[1].is_zero() {
             info!("V3 log 1 empty");
             continue;
         }
@@ -354,14 +280,14 @@ pub async fn load_uniswap_v3_pools(
         let topic1 = FixedBytes::<20>::try_from(&topic1[12..32]).unwrap();
         let token1 = Address::from(topic1);
 
-        // Decode the log data
+        // fee is the third indexed topic, the data is (int24 tickSpacing, address pool)
+        let fee = U256::from_be_bytes(log.topics()[3].0).to::<u32>();
         let log_data = &log.inner.data.data;
         let decoded: (B256, B256) = SolValue::abi_decode(log_data, false).unwrap();
+        let tick_spacing = I256::from_be_bytes(decoded.0 .0).as_i32();
         let pool_address = decoded.1;
         let pool_address = FixedBytes::<20>::try_from(&pool_address[12..32]).unwrap();
         let pool_address = Address::from(pool_address);
-        let fee = u32::from_str_radix(decoded.0.to_string().as_str().trim_start_matches("0x"), 16)
-            .unwrap();
 
         // info!("is v3: {:?}", is_v3);
         let pool_data = Pool {
@@ -371,6 +297,7 @@ pub async fn load_uniswap_v3_pools(
             token0,
             token1,
             fee,
+            tick_spacing,
             block_number,
         };
         pools.push(pool_data);
@@ -380,6 +307,7 @@ pub async fn load_uniswap_v3_pools(
 }
 
 alloy::sol! {
+    #[sol(rpc)]
     interface IV3Pool {
         function liquidity() external view returns (uint128);
         function slot0() external view returns (
@@ -391,11 +319,58 @@ alloy::sol! {
             uint8 feeProtocol,
             bool unlocked
         );
+        function fee() external view returns (uint24);
+        function tickSpacing() external view returns (int24);
+        function tickBitmap(int16 wordPosition) external view returns (uint256);
+        function ticks(int24 tick) external view returns (
+            uint128 liquidityGross,
+            int128 liquidityNet,
+            uint256 feeGrowthOutside0X128,
+            uint256 feeGrowthOutside1X128,
+            int56 tickCumulativeOutside,
+            uint160 secondsPerLiquidityOutsideX128,
+            uint32 secondsOutside,
+            bool initialized
+        );
     }
 }
 
+#[derive(Debug, Clone, Copy, Default)]
 pub struct PoolLiquidity {
     pub liquidity: U256,
     pub sqrt_price_x96: U256,
     pub tick: i32,
 }
+
+impl PoolLiquidity {
+    /// Read `slot0()` and `liquidity()` of a V3 pool at the given block
+    pub async fn load(
+        provider: Arc<RootProvider<PubSubFrontend>>,
+        pool: Address,
+        block: BlockId,
+    ) -> Result<Self> {
+        let contract = IV3Pool::new(pool, provider);
+        let slot0 = contract.slot0().block(block).call().await?;
+        let liquidity = contract.liquidity().block(block).call().await?._0;
+
+        Ok(Self {
+            liquidity: U256::from(liquidity),
+            sqrt_price_x96: U256::from(slot0.sqrtPriceX96),
+            tick: slot0.tick.as_i32(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_ranges() {
+        assert_eq!(block_ranges(10, 29, 10), vec![(10, 19), (20, 29)]);
+        assert_eq!(block_ranges(10, 25, 10), vec![(10, 19), (20, 25)]);
+        assert_eq!(block_ranges(10, 10, 10), vec![(10, 10)]);
+        // already synced past the head
+        assert!(block_ranges(11, 10, 10).is_empty());
+    }
+}
@@ -0,0 +1,366 @@
+use super::fork::SharedBase;
+use super::pools::{DexVariant, Pool};
+use super::revm::EvmSimulator;
+use crate::arbitrage::uniswap_v2::RESERVES_SLOT;
+use crate::arbitrage::uniswap_v3::DEFAULT_WORD_RANGE;
+use crate::arbitrage::v3_math::{tick_position, MAX_TICK, MIN_TICK};
+use alloy::eips::BlockId;
+use alloy::network::Ethereum;
+use alloy::primitives::{keccak256, Address, Bytes, I256, U256, U64};
+use alloy::providers::{Provider, RootProvider};
+use alloy::pubsub::PubSubFrontend;
+use alloy::rpc::client::BatchRequest;
+use anyhow::Result;
+use futures::future::try_join_all;
+use revm::primitives::{AccountInfo, Bytecode};
+use std::collections::HashSet;
+
+/// UniswapV2Pair slots a swap touches: token0, token1, the packed reserves, both
+/// cumulative prices and the reentrancy lock. Slots 0-5 are the LP token and factory.
+const V2_SWAP_SLOTS: [U256; 6] = [
+    U256::from_limbs([6, 0, 0, 0]),
+    U256::from_limbs([7, 0, 0, 0]),
+    RESERVES_SLOT,
+    U256::from_limbs([9, 0, 0, 0]),
+    U256::from_limbs([10, 0, 0, 0]),
+    U256::from_limbs([12, 0, 0, 0]),
+];
+
+/// UniswapV3Pool layout. token0, token1, fee and tick spacing are immutables in the code.
+pub(crate) const V3_SLOT0: U256 = U256::ZERO;
+pub(crate) const V3_LIQUIDITY_SLOT: U256 = U256::from_limbs([4, 0, 0, 0]);
+/// feeGrowthGlobal0X128, feeGrowthGlobal1X128, protocolFees and liquidity
+const V3_GLOBAL_SLOTS: [U256; 4] = [
+    U256::from_limbs([1, 0, 0, 0]),
+    U256::from_limbs([2, 0, 0, 0]),
+    U256::from_limbs([3, 0, 0, 0]),
+    V3_LIQUIDITY_SLOT,
+];
+const V3_TICKS_SLOT: U256 = U256::from_limbs([5, 0, 0, 0]);
+const V3_TICK_BITMAP_SLOT: U256 = U256::from_limbs([6, 0, 0, 0]);
+/// `observations` is a fixed array of one slot observations starting here
+const V3_OBSERVATIONS_SLOT: u64 = 8;
+/// Slots of one `Tick.Info`, all of them are written when a swap crosses the tick
+const TICK_INFO_SLOTS: u64 = 4;
+
+/// Fields of a V3 pool's packed `slot0` word needed to find the rest of its state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot0 {
+    pub sqrt_price_x96: U256,
+    pub tick: i32,
+    pub observation_index: u16,
+    pub observation_cardinality: u16,
+}
+
+impl Slot0 {
+    /// Unpack `sqrtPriceX96 | tick | observationIndex | observationCardinality | ...`
+    pub fn from_slot(value: U256) -> Self {
+        let bits = |shift: usize, width: usize| {
+            ((value >> shift) & ((U256::from(1) << width) - U256::from(1))).to::<u64>()
+        };
+        let tick = bits(160, 24) as u32;
+        Self {
+            sqrt_price_x96: value & ((U256::from(1) << 160) - U256::from(1)),
+            // sign extend the int24
+            tick: ((tick << 8) as i32) >> 8,
+            observation_index: bits(184, 16) as u16,
+            observation_cardinality: bits(200, 16) as u16,
+        }
+    }
+}
+
+/// Storage key of `mapping(intN => ...)` at `slot`, the key is sign extended to a word
+pub fn signed_mapping_key(key: i32, slot: U256) -> U256 {
+    let mut preimage = [0u8; 64];
+    let key = I256::try_from(key).expect("i32 fits in I256").into_raw();
+    preimage[..32].copy_from_slice(&key.to_be_bytes::<32>());
+    preimage[32..].copy_from_slice(&slot.to_be_bytes::<32>());
+    keccak256(preimage).into()
+}
+
+/// Bitmap words within `word_range` of the word holding `tick`, clamped to the valid ticks
+pub fn bitmap_words(tick: i32, tick_spacing: i32, word_range: i16) -> Vec<i16> {
+    let mut compressed = tick / tick_spacing;
+    if tick < 0 && tick % tick_spacing != 0 {
+        compressed -= 1;
+    }
+    let (current_word, _) = tick_position(compressed);
+    let min_word = (MIN_TICK / tick_spacing) >> 8;
+    let max_word = (MAX_TICK / tick_spacing) >> 8;
+    (current_word as i32 - word_range as i32..=current_word as i32 + word_range as i32)
+        .filter(|word| (min_word..=max_word).contains(word))
+        .map(|word| word as i16)
+        .collect()
+}
+
+/// Ticks flagged as initialized in one bitmap word
+pub fn initialized_ticks(word: i16, bits: U256, tick_spacing: i32) -> Vec<i32> {
+    (0..256usize)
+        .filter(|bit| bits.bit(*bit))
+        .map(|bit| ((word as i32) * 256 + bit as i32) * tick_spacing)
+        .collect()
+}
+
+impl EvmSimulator<'_> {
+    /// Fetch the state `pools` read during a swap into the shared base, so simulations
+    /// start without a single blocking RPC round trip. Every step is one batched request
+    /// over all pools: accounts and fixed slots, then the V3 bitmap words around the
+    /// current tick and the oracle observations, then the initialized ticks in those words.
+    /// The steps can't be merged since each one needs the values of the previous one.
+    ///
+    /// Pool balances are fetched for tokens whose balance slot is already known.
+    /// Anything already cached is skipped. Returns the number of slots fetched.
+    pub async fn prefetch_pools(&mut self, pools: &[Pool]) -> Result<usize> {
+        let block = BlockId::from(self.pinned_block().to::<u64>());
+        let base = self.evm.get_mut().context.evm.db.db.clone();
+
+        let mut accounts: HashSet<Address> = HashSet::new();
+        let mut keys = Vec::new();
+        for pool in pools {
+            accounts.insert(pool.address);
+            match pool.version {
+                DexVariant::UniswapV2 => {
+                    keys.extend(V2_SWAP_SLOTS.map(|slot| (pool.address, slot)));
+                }
+                DexVariant::UniswapV3 => {
+                    keys.push((pool.address, V3_SLOT0));
+                    keys.extend(V3_GLOBAL_SLOTS.map(|slot| (pool.address, slot)));
+                }
+            }
+            for token in [pool.token0, pool.token1] {
+                if let Some(slot) = self.balance_slots.get(&token) {
+                    accounts.insert(token);
+                    keys.push((token, slot.storage_key(pool.address)));
+                }
+            }
+        }
+        let mut fetched = prefetch_into(&self.provider, &base, block, accounts, keys).await?;
+
+        let mut keys = Vec::new();
+        let mut words = Vec::new();
+        for pool in pools {
+            if pool.version != DexVariant::UniswapV3 || pool.tick_spacing <= 0 {
+                continue;
+            }
+            let Some(slot0) = base.cached_storage(pool.address, V3_SLOT0) else {
+                continue;
+            };
+            let slot0 = Slot0::from_slot(slot0);
+            let cardinality = slot0.observation_cardinality.max(1) as u64;
+            for index in [
+                slot0.observation_index as u64,
+                (slot0.observation_index as u64 + 1) % cardinality,
+            ] {
+                let slot = U256::from(V3_OBSERVATIONS_SLOT + index);
+                keys.push((pool.address, slot));
+            }
+            for word in bitmap_words(slot0.tick, pool.tick_spacing, DEFAULT_WORD_RANGE) {
+                let key = signed_mapping_key(word as i32, V3_TICK_BITMAP_SLOT);
+                keys.push((pool.address, key));
+                words.push((*pool, word, key));
+            }
+        }
+        fetched += fetch_into(&self.provider, &base, block, keys).await?;
+
+        let mut keys = Vec::new();
+        for (pool, word, key) in words {
+            let bits = base.cached_storage(pool.address, key).unwrap_or_default();
+            for tick in initialized_ticks(word, bits, pool.tick_spacing) {
+                let info = signed_mapping_key(tick, V3_TICKS_SLOT);
+                keys.extend(
+                    (0..TICK_INFO_SLOTS).map(|offset| (pool.address, info + U256::from(offset))),
+                );
+            }
+        }
+        fetched += fetch_into(&self.provider, &base, block, keys).await?;
+
+        Ok(fetched)
+    }
+}
+
+/// Fetch the accounts and slots `base` doesn't hold yet and cache them, one batch for the
+/// accounts and one for the slots. Returns the number of slots fetched.
+pub(crate) async fn prefetch_into(
+    provider: &RootProvider<PubSubFrontend, Ethereum>,
+    base: &SharedBase,
+    block: BlockId,
+    accounts: HashSet<Address>,
+    keys: Vec<(Address, U256)>,
+) -> Result<usize> {
+    let accounts: Vec<Address> = accounts
+        .into_iter()
+        .filter(|address| !base.has_account(*address))
+        .collect();
+    for (address, info) in fetch_accounts(provider, block, &accounts).await? {
+        base.insert_account(address, info);
+    }
+    fetch_into(provider, base, block, keys).await
+}
+
+/// Fetch the slots of `keys` that `base` doesn't hold yet in one batch and cache them
+async fn fetch_into(
+    provider: &RootProvider<PubSubFrontend, Ethereum>,
+    base: &SharedBase,
+    block: BlockId,
+    keys: Vec<(Address, U256)>,
+) -> Result<usize> {
+    let mut keys: Vec<(Address, U256)> = keys
+        .into_iter()
+        .filter(|(address, slot)| base.cached_storage(*address, *slot).is_none())
+        .collect();
+    keys.sort();
+    keys.dedup();
+    if keys.is_empty() {
+        return Ok(0);
+    }
+
+    let mut batch = BatchRequest::new(provider.client());
+    let waiters = keys
+        .iter()
+        .map(|(address, slot)| {
+            batch.add_call::<_, U256>("eth_getStorageAt", &(*address, *slot, block))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    batch.send().await?;
+    let values = try_join_all(waiters).await?;
+
+    for ((address, slot), value) in keys.iter().zip(values) {
+        base.insert_storage(*address, *slot, value)?;
+    }
+    Ok(keys.len())
+}
+
+/// Balance, nonce and code of every account in one batch, the way `AlloyDB` builds them
+async fn fetch_accounts(
+    provider: &RootProvider<PubSubFrontend, Ethereum>,
+    block: BlockId,
+    accounts: &[Address],
+) -> Result<Vec<(Address, AccountInfo)>> {
+    if accounts.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut batch = BatchRequest::new(provider.client());
+    let mut waiters = Vec::with_capacity(accounts.len());
+    for address in accounts {
+        let balance = batch.add_call::<_, U256>("eth_getBalance", &(*address, block))?;
+        let nonce = batch.add_call::<_, U64>("eth_getTransactionCount", &(*address, block))?;
+        let code = batch.add_call::<_, Bytes>("eth_getCode", &(*address, block))?;
+        waiters.push((*address, balance, nonce, code));
+    }
+    batch.send().await?;
+
+    let mut infos = Vec::with_capacity(waiters.len());
+    for (address, balance, nonce, code) in waiters {
+        let code = Bytecode::new_raw(code.await?);
+        infos.push((
+            address,
+            AccountInfo::new(
+                balance.await?,
+                nonce.await?.to::<u64>(),
+                code.hash_slow(),
+                code,
+            ),
+        ));
+    }
+    Ok(infos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_sol_types::SolValue;
+
+    #[test]
+    fn test_slot0_unpacking() {
+        // tick -201_000, observation 7 of 100, sqrtPriceX96 just below 2^96
+        let sqrt_price = (U256::from(1) << 96) - U256::from(1);
+        let tick = U256::from((-201_000i32 as u32) & 0xff_ffff);
+        let packed = sqrt_price
+            | (tick << 160)
+            | (U256::from(7) << 184)
+            | (U256::from(100) << 200)
+            | (U256::from(100) << 216)
+            | (U256::from(1) << 240);
+        assert_eq!(
+            Slot0::from_slot(packed),
+            Slot0 {
+                sqrt_price_x96: sqrt_price,
+                tick: -201_000,
+                observation_index: 7,
+                observation_cardinality: 100,
+            }
+        );
+    }
+
+    #[test]
+    fn test_signed_mapping_key_matches_abi_encoding() {
+        for key in [-887_272, -1, 0, 42] {
+            let expected: U256 =
+                keccak256((I256::try_from(key).unwrap(), V3_TICKS_SLOT).abi_encode()).into();
+            assert_eq!(signed_mapping_key(key, V3_TICKS_SLOT), expected);
+        }
+    }
+
+    #[test]
+    fn test_bitmap_words_and_ticks() {
+        // tick -1 in a 60 spacing pool compresses to -1, the last bit of word -1
+        assert_eq!(bitmap_words(-1, 60, 1), vec![-2, -1, 0]);
+        // the edge of the tick range has no word beyond it
+        assert_eq!(bitmap_words(MAX_TICK - 1, 60, 1), vec![56, 57]);
+
+        let bits = (U256::from(1) << 255) | U256::from(1);
+        assert_eq!(initialized_ticks(-1, bits, 60), vec![-256 * 60, -60]);
+    }
+
+    /// Needs a node: `WS_URL=... cargo test -- --ignored`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_prefetch_is_idempotent() {
+        use alloy::primitives::address;
+        use alloy::providers::{ProviderBuilder, WsConnect};
+        use std::sync::Arc;
+
+        dotenv::dotenv().ok();
+        let ws_url = std::env::var("WS_URL").expect("no ws url");
+        let provider = ProviderBuilder::new()
+            .on_ws(WsConnect::new(ws_url))
+            .await
+            .unwrap();
+        let provider = Arc::new(provider);
+        let block_number = provider.get_block_number().await.unwrap();
+        let mut simulator = EvmSimulator::new(provider, None, U64::from(block_number));
+
+        let usdc = address!("A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48");
+        let weth = address!("C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2");
+        let pool = |address, version, fee, tick_spacing| Pool {
+            id: 0,
+            address,
+            version,
+            token0: usdc,
+            token1: weth,
+            fee,
+            tick_spacing,
+            block_number,
+        };
+        let pools = [
+            pool(
+                address!("B4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc"),
+                DexVariant::UniswapV2,
+                3000,
+                0,
+            ),
+            pool(
+                address!("88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640"),
+                DexVariant::UniswapV3,
+                500,
+                10,
+            ),
+        ];
+
+        assert!(simulator.prefetch_pools(&pools).await.unwrap() > V2_SWAP_SLOTS.len());
+        assert_eq!(simulator.prefetch_pools(&pools).await.unwrap(), 0);
+        let reserves = simulator.get_v2_reserves(pools[0].address).await.unwrap();
+        assert!(reserves.reserve0 > 0);
+    }
+}
@@ -0,0 +1,180 @@
+use super::pools::{DexVariant, Pool};
+use super::store::PoolStore;
+use anyhow::Result;
+use revm::primitives::Address;
+use std::collections::HashMap;
+
+/// Every known pool, indexed by address, by unordered token pair and by token.
+/// A pair can have several pools: one V2 pair plus a V3 pool per fee tier.
+#[derive(Debug, Default)]
+pub struct PoolRegistry {
+    pools: HashMap<Address, Pool>,
+    by_pair: HashMap<(Address, Address), Vec<Address>>,
+    by_token: HashMap<Address, Vec<Address>>,
+}
+
+/// Order-independent key for a token pair
+pub fn pair_key(token_a: Address, token_b: Address) -> (Address, Address) {
+    if token_a < token_b {
+        (token_a, token_b)
+    } else {
+        (token_b, token_a)
+    }
+}
+
+impl PoolRegistry {
+    pub fn from_pools(pools: impl IntoIterator<Item = Pool>) -> Self {
+        let mut registry = Self::default();
+        for pool in pools {
+            registry.insert(pool);
+        }
+        registry
+    }
+
+    /// Build the registry from everything in the pool store
+    pub fn load(store: &PoolStore) -> Result<Self> {
+        Ok(Self::from_pools(store.load_pools()?))
+    }
+
+    /// Add a pool, returns false if it was already known
+    pub fn insert(&mut self, pool: Pool) -> bool {
+        if self.pools.contains_key(&pool.address) {
+            return false;
+        }
+        self.by_pair
+            .entry(pair_key(pool.token0, pool.token1))
+            .or_default()
+            .push(pool.address);
+        self.by_token
+            .entry(pool.token0)
+            .or_default()
+            .push(pool.address);
+        if pool.token1 != pool.token0 {
+            self.by_token
+                .entry(pool.token1)
+                .or_default()
+                .push(pool.address);
+        }
+        self.pools.insert(pool.address, pool);
+        true
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&Pool> {
+        self.pools.get(address)
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.pools.contains_key(address)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pools.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pools.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Pool> {
+        self.pools.values()
+    }
+
+    /// All pools trading `token_a` against `token_b`, in either order
+    pub fn pools_for_pair(
+        &self,
+        token_a: Address,
+        token_b: Address,
+    ) -> impl Iterator<Item = &Pool> + '_ {
+        self.lookup(self.by_pair.get(&pair_key(token_a, token_b)))
+    }
+
+    /// All pools with `token` on either side
+    pub fn pools_for_token(&self, token: Address) -> impl Iterator<Item = &Pool> + '_ {
+        self.lookup(self.by_token.get(&token))
+    }
+
+    /// Pools of the other variant on the same pair as `address`, the second leg of a two-pool arb
+    pub fn counterparts(&self, address: &Address) -> impl Iterator<Item = &Pool> + '_ {
+        let pool = self.pools.get(address);
+        let key = pool.map(|pool| pair_key(pool.token0, pool.token1));
+        let variant = pool.map(|pool| pool.version);
+        self.lookup(key.and_then(|key| self.by_pair.get(&key)))
+            .filter(move |other| Some(other.version) != variant)
+    }
+
+    /// Counts of V2 and V3 pools
+    pub fn variant_counts(&self) -> (usize, usize) {
+        self.pools
+            .values()
+            .fold((0, 0), |(v2, v3), pool| match pool.version {
+                DexVariant::UniswapV2 => (v2 + 1, v3),
+                DexVariant::UniswapV3 => (v2, v3 + 1),
+            })
+    }
+
+    fn lookup<'a>(
+        &'a self,
+        addresses: Option<&'a Vec<Address>>,
+    ) -> impl Iterator<Item = &'a Pool> + 'a {
+        addresses
+            .into_iter()
+            .flatten()
+            .filter_map(|address| self.pools.get(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::pools::test_pool;
+
+    fn registry() -> PoolRegistry {
+        PoolRegistry::from_pools([
+            test_pool(0x10, DexVariant::UniswapV2, 1, 2, 300),
+            test_pool(0x11, DexVariant::UniswapV3, 1, 2, 500),
+            test_pool(0x12, DexVariant::UniswapV3, 1, 2, 3000),
+            test_pool(0x13, DexVariant::UniswapV3, 1, 2, 10000),
+            test_pool(0x14, DexVariant::UniswapV2, 2, 3, 300),
+        ])
+    }
+
+    #[test]
+    fn test_pair_lookup_is_unordered() {
+        let registry = registry();
+        let a = Address::repeat_byte(1);
+        let b = Address::repeat_byte(2);
+        assert_eq!(registry.pools_for_pair(a, b).count(), 4);
+        assert_eq!(registry.pools_for_pair(b, a).count(), 4);
+        assert_eq!(registry.pools_for_token(b).count(), 5);
+    }
+
+    #[test]
+    fn test_counterparts_cover_every_fee_tier() {
+        let registry = registry();
+        let mut fees: Vec<u32> = registry
+            .counterparts(&Address::repeat_byte(0x10))
+            .map(|pool| pool.fee)
+            .collect();
+        fees.sort();
+        assert_eq!(fees, vec![500, 3000, 10000]);
+
+        let v2: Vec<_> = registry
+            .counterparts(&Address::repeat_byte(0x12))
+            .map(|pool| pool.address)
+            .collect();
+        assert_eq!(v2, vec![Address::repeat_byte(0x10)]);
+
+        assert_eq!(
+            registry.counterparts(&Address::repeat_byte(0x14)).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_insert_is_idempotent() {
+        let mut registry = registry();
+        assert!(!registry.insert(test_pool(0x10, DexVariant::UniswapV2, 1, 2, 300)));
+        assert_eq!(registry.len(), 5);
+        assert_eq!(registry.variant_counts(), (2, 3));
+    }
+}
@@ -1,27 +1,59 @@
 use crate::arbitrage::simulation::{arboo_bytecode, get_address, AddressType};
-
-use super::revmInspector::{self, RevmInspector};
+use crate::arbitrage::uniswap_v2::{V2Reserves, RESERVES_SLOT};
+
+use super::access_list::{AccessSet, RouteKey};
+use super::balance_slot::BalanceSlot;
+use super::classifier::TokenVerdict;
+use super::fork::{base_db, carry_written_state, is_finalized, EvmFork, ForkDB, SharedBase};
+use super::outcome::SimulationOutcome;
+use super::pools::PoolLiquidity;
+use super::prefetch::{Slot0, V3_LIQUIDITY_SLOT, V3_SLOT0};
+use super::revmInspector::{CallInfo, RevmInspector};
+use super::state_diff::try_fetch_block_diffs;
+use alloy::consensus::{Transaction as _, TxEnvelope};
 use alloy::contract::{ContractInstance, Interface};
+use alloy::eips::eip2718::{Decodable2718, Encodable2718};
 use alloy::eips::BlockId;
 use alloy::network::{AnyNetwork, Ethereum};
 use alloy::primitives::{Address, U64};
-use alloy::providers::RootProvider;
+use alloy::providers::{Provider, RootProvider};
 use alloy::pubsub::PubSubFrontend;
+use alloy::rpc::types::trace::geth::DiffMode;
+use alloy::rpc::types::{BlockTransactionsKind, Header, Transaction};
 use alloy::signers::local::PrivateKeySigner;
 use alloy_sol_types::SolCall;
 use anyhow::{anyhow, Error, Result};
+use futures::StreamExt;
 use log::info;
-use revm::db::{AlloyDB, CacheDB};
+use revm::db::AccountState;
 use revm::inspector_handle_register;
-use revm::primitives::{Bytes, Log};
+use revm::primitives::{Bytes, Env, ExecutionResult, Log};
 use revm::{
-    primitives::{AccountInfo, Bytecode, ExecutionResult, Output, TransactTo, B256, U256},
+    primitives::{AccountInfo, Bytecode, TransactTo, B256, U256},
     Database, Evm,
 };
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex as TokioMutex;
 
+/// The trace of `calls` is kept for failures, and for every call when `trace_calls` is set
+fn outcome<E: std::fmt::Debug>(
+    result: Result<ExecutionResult, E>,
+    trace_calls: bool,
+    calls: &[CallInfo],
+) -> SimulationOutcome {
+    match result {
+        Ok(result) => {
+            let trace = match result.is_success() && !trace_calls {
+                true => Vec::new(),
+                false => calls.to_vec(),
+            };
+            SimulationOutcome::from_execution(result, trace)
+        }
+        Err(e) => SimulationOutcome::Infra(anyhow!("EVM call failed: {:?}", e)),
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct VictimTx {
     pub tx_hash: B256,
@@ -29,8 +61,28 @@ pub struct VictimTx {
     pub to: Address,
     pub data: Bytes,
     pub value: U256,
+    /// Max fee per gas, the gas price of a legacy transaction
     pub gas_price: U256,
     pub gas_limit: Option<u64>,
+    /// The signed transaction as it was broadcast, what goes into a backrun bundle
+    pub raw: Bytes,
+}
+
+impl VictimTx {
+    /// A pending transaction from `eth_subscribe`, None for contract creations
+    pub fn from_transaction(tx: &Transaction) -> Option<Self> {
+        let envelope = &tx.inner;
+        Some(Self {
+            tx_hash: *envelope.tx_hash(),
+            from: tx.from,
+            to: envelope.to()?,
+            data: envelope.input().clone(),
+            value: envelope.value(),
+            gas_price: U256::from(envelope.max_fee_per_gas()),
+            gas_limit: Some(envelope.gas_limit()),
+            raw: envelope.encoded_2718().into(),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -63,6 +115,8 @@ pub struct TxResult {
     pub logs: Option<Vec<Log>>,
     pub gas_used: u64,
     pub gas_refunded: u64,
+    /// Every call frame of the transaction
+    pub trace: Vec<CallInfo>,
 }
 
 // type My_Evm_Context = EvmContext<CacheDB<AlloyDB<Client, AnyNetwork, RootProvider<PubSubFrontend>>>>;
@@ -71,14 +125,19 @@ pub struct TxResult {
 pub struct EvmSimulator<'a> {
     pub owner: Address,
     pub contract_address: Address,
-    pub evm: TokioMutex<
-        Evm<
-            'a,
-            RevmInspector,
-            CacheDB<AlloyDB<PubSubFrontend, Ethereum, Arc<RootProvider<PubSubFrontend, Ethereum>>>>,
-        >,
-    >,
+    pub evm: TokioMutex<Evm<'a, RevmInspector, ForkDB>>,
     pub block_number: U64,
+    /// Hash of the pinned block, unknown until the first header arrives
+    pub block_hash: Option<B256>,
+    pub provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+    pub token_verdicts: std::collections::HashMap<Address, TokenVerdict>,
+    pub balance_slots: std::collections::HashMap<Address, BalanceSlot>,
+    /// Tokens without a balance mapping, with the block they were traced at
+    pub balance_slot_misses: std::collections::HashMap<Address, U64>,
+    /// Slots the last simulation of each route touched
+    pub route_accesses: std::collections::HashMap<RouteKey, AccessSet>,
+    /// Attach the call frames to successful outcomes too, failed ones always have them
+    pub trace_calls: bool,
 }
 impl<'a> EvmSimulator<'a> {
     pub fn new(
@@ -119,41 +178,85 @@ impl<'a> EvmSimulator<'a> {
             gas_price: U256::from(10000000000u128),
         };
 
-        self.call(new_tx).unwrap();
+        self.call(new_tx).into_result().unwrap();
     }
 
+    /// A block the node reports as finalized, e.g. one being backtested, has its state
+    /// stored in the disk cache. Otherwise it may still be reorged and only code is stored.
     pub fn new_with_db(
         owner: Option<Address>,
         block_number: U64,
         provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+    ) -> Self {
+        let number = block_number.to::<u64>();
+        let finalized = is_finalized(&provider, number);
+        let base = SharedBase::new(base_db(provider.clone(), number, finalized).unwrap());
+        EvmSimulator::with_base(owner, block_number, provider, base)
+    }
+
+    /// Simulator on top of `base`, e.g. `SharedBase::empty()` for state built up locally
+    pub fn with_base(
+        owner: Option<Address>,
+        block_number: U64,
+        provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+        base: SharedBase,
     ) -> Self {
         let owner = match owner {
             Some(owner) => owner,
             None => PrivateKeySigner::random().address(),
         };
         let contract_wallet = PrivateKeySigner::random();
-        let inspector = revmInspector::RevmInspector::new();
-
-        let alloy_db = CacheDB::new(AlloyDB::new(provider, BlockId::from(block_number)).unwrap());
 
-        let evm = Evm::builder()
-            .with_db(alloy_db)
-            .with_external_context(inspector)
-            .append_handler_register(inspector_handle_register)
-            .modify_env(|env| {
-                env.block.number = U256::from(block_number);
-                env.block.coinbase =
-                    Address::from_str("0xDAFEA492D9c6733ae3d56b7Ed1ADB60692c98Bc5").unwrap();
-            })
-            .build();
-
-        let evm = TokioMutex::new(evm);
+        let mut env = Env::default();
+        env.block.number = U256::from(block_number);
+        env.block.coinbase =
+            Address::from_str("0xDAFEA492D9c6733ae3d56b7Ed1ADB60692c98Bc5").unwrap();
 
         Self {
             owner,
-            evm,
+            evm: TokioMutex::new(build_evm(ForkDB::new(base), Box::new(env))),
             block_number,
+            block_hash: None,
+            provider,
             contract_address: contract_wallet.address(),
+            token_verdicts: Default::default(),
+            balance_slots: Default::default(),
+            balance_slot_misses: Default::default(),
+            route_accesses: Default::default(),
+            trace_calls: false,
+        }
+    }
+
+    /// Freeze the current state as the starting point for independent simulations.
+    /// Only what this simulator wrote is copied, everything it read is in the shared base.
+    pub fn fork(&mut self) -> EvmFork {
+        let evm = self.evm.get_mut();
+        EvmFork {
+            db: evm.context.evm.db.clone(),
+            env: evm.context.evm.env.clone(),
+            owner: self.owner,
+            contract_address: self.contract_address,
+            block_number: self.block_number,
+            provider: self.provider.clone(),
+            token_verdicts: self.token_verdicts.clone(),
+            balance_slots: self.balance_slots.clone(),
+            balance_slot_misses: self.balance_slot_misses.clone(),
+        }
+    }
+
+    pub(crate) fn from_fork(fork: EvmFork) -> Self {
+        Self {
+            owner: fork.owner,
+            evm: TokioMutex::new(build_evm(fork.db, fork.env)),
+            block_number: fork.block_number,
+            block_hash: None,
+            provider: fork.provider,
+            contract_address: fork.contract_address,
+            token_verdicts: fork.token_verdicts,
+            balance_slots: fork.balance_slots,
+            balance_slot_misses: fork.balance_slot_misses,
+            route_accesses: Default::default(),
+            trace_calls: false,
         }
     }
 
@@ -161,6 +264,55 @@ impl<'a> EvmSimulator<'a> {
         Arc::new(TokioMutex::new(self))
     }
 
+    /// Block whose post-state every simulation runs on
+    pub fn pinned_block(&self) -> U64 {
+        self.block_number
+    }
+
+    /// Re-pin to `header` with `env.block` taken from the header. When `diffs` holds the
+    /// block's state diffs and the header extends the pinned block, the cached state is
+    /// patched and stays warm, otherwise the base starts empty at the new block.
+    ///
+    /// Everything written into the simulator, the deployed executor and its funding, is
+    /// copied onto the new state, so a search that started before the new block still finds
+    /// them. Headers older than the pinned block are ignored, a header at the same height is
+    /// a reorg and replaces the state.
+    pub fn advance_to(&mut self, header: &Header, diffs: Option<&[DiffMode]>) -> Result<()> {
+        if header.number < self.block_number.to::<u64>() {
+            return Ok(());
+        }
+        // a new head is never finalized yet
+        let db = base_db(self.provider.clone(), header.number, false)?;
+
+        let extends_pinned = self.block_hash == Some(header.parent_hash);
+        let evm = self.evm.get_mut();
+        let base = match diffs {
+            Some(diffs) if extends_pinned => evm.context.evm.db.db.patched(db, diffs),
+            _ => SharedBase::new(db),
+        };
+        let mut db = ForkDB::new(base);
+        let carried = carry_written_state(&evm.context.evm.db, &mut db)?;
+        evm.context.evm.db = db;
+        let block = &mut evm.context.evm.env.block;
+        block.number = U256::from(header.number);
+        block.timestamp = U256::from(header.timestamp);
+        block.basefee = U256::from(header.base_fee_per_gas.unwrap_or_default());
+        block.coinbase = header.beneficiary;
+        block.prevrandao = Some(header.mix_hash);
+        block.difficulty = header.difficulty;
+        block.gas_limit = U256::from(header.gas_limit);
+
+        self.block_number = U64::from(header.number);
+        self.block_hash = Some(header.hash);
+        log::debug!(
+            "Simulator pinned to block {} ({:?}), {} written accounts kept",
+            header.number,
+            header.hash,
+            carried
+        );
+        Ok(())
+    }
+
     pub async fn get_block_number(&mut self) -> U256 {
         let evm = self.evm.lock().await;
         evm.block().number
@@ -181,73 +333,73 @@ impl<'a> EvmSimulator<'a> {
         evm.context.evm.env.block.basefee = base_fee;
     }
 
-    pub fn staticcall(&mut self, tx: Tx) -> Result<TxResult> {
+    /// Run `tx` without keeping its state changes
+    pub fn staticcall(&mut self, tx: Tx) -> SimulationOutcome {
         self._call(tx, false)
     }
 
-    pub fn call(&mut self, tx: Tx) -> Result<TxResult> {
+    /// Run `tx` and commit its state changes
+    pub fn call(&mut self, tx: Tx) -> SimulationOutcome {
         self._call(tx, true)
     }
 
-    pub fn _call(&mut self, tx: Tx, commit: bool) -> Result<TxResult> {
-        if let Ok(mut evm) = self.evm.try_lock() {
-            evm.context.evm.env.tx.caller = tx.caller;
-            evm.context.evm.env.tx.transact_to = TransactTo::Call(tx.transact_to);
-            evm.context.evm.env.tx.data = tx.data.clone();
-            evm.context.evm.env.tx.value = tx.value;
-            evm.context.evm.env.tx.gas_price = tx.gas_price;
-            evm.context.evm.env.tx.gas_limit = tx.gas_limit;
-
-            let result = match commit {
-                true => match evm.transact_commit() {
-                    Ok(result) => result,
-                    Err(e) => return Err(anyhow!("EVM call failed: {:?}", e)),
-                },
-                false => {
-                    let ref_tx = evm
-                        .transact()
-                        .map_err(|e| anyhow!("EVM staticcall failed: {:?}", e))?;
-                    ref_tx.result
-                }
-            };
-            //info!("Result: {:?}", result);
-            let output = match result {
-                ExecutionResult::Success {
-                    gas_used,
-                    gas_refunded,
-                    output,
-                    logs,
-                    ..
-                } => match output {
-                    Output::Call(o) => TxResult {
-                        output: o,
-                        logs: Some(logs),
-                        gas_used,
-                        gas_refunded,
-                    },
-                    Output::Create(o, _) => TxResult {
-                        output: o,
-                        logs: Some(logs),
-                        gas_used,
-                        gas_refunded,
-                    },
-                },
-                ExecutionResult::Revert { gas_used, output } => {
-                    return Err(anyhow!(
-                        "EVM REVERT: {:?} / Gas used: {:?}",
-                        output,
-                        gas_used
-                    ))
-                }
-                ExecutionResult::Halt { reason, .. } => {
-                    return Err(anyhow!("EVM HALT: {:?}", reason))
-                }
-            };
+    pub fn _call(&mut self, tx: Tx, commit: bool) -> SimulationOutcome {
+        let Ok(mut evm) = self.evm.try_lock() else {
+            return SimulationOutcome::Infra(anyhow!("EVM lock failed"));
+        };
+        // a transaction that failed mid-call can leave frames behind
+        evm.context.external.reset();
+        evm.context.evm.env.tx.caller = tx.caller;
+        evm.context.evm.env.tx.transact_to = TransactTo::Call(tx.transact_to);
+        evm.context.evm.env.tx.data = tx.data;
+        evm.context.evm.env.tx.value = tx.value;
+        evm.context.evm.env.tx.gas_price = tx.gas_price;
+        evm.context.evm.env.tx.gas_limit = tx.gas_limit;
+
+        let result = match commit {
+            true => evm.transact_commit(),
+            false => evm.transact().map(|result| result.result),
+        };
+        outcome(result, self.trace_calls, &evm.context.external.calls)
+    }
 
-            Ok(output)
-        } else {
-            Err(anyhow!("EVM lock failed"))
-        }
+    /// Run and commit the signed transaction `raw` the way the chain would: as its signer,
+    /// with its nonce, fees and access list
+    pub fn call_raw(&mut self, raw: &[u8]) -> SimulationOutcome {
+        let envelope = match TxEnvelope::decode_2718(&mut &raw[..]) {
+            Ok(envelope) => envelope,
+            Err(err) => return SimulationOutcome::Infra(anyhow!("Error decoding tx: {:?}", err)),
+        };
+        let caller = match envelope.recover_signer() {
+            Ok(caller) => caller,
+            Err(err) => {
+                return SimulationOutcome::Infra(anyhow!("Error recovering signer: {:?}", err))
+            }
+        };
+        let Ok(mut evm) = self.evm.try_lock() else {
+            return SimulationOutcome::Infra(anyhow!("EVM lock failed"));
+        };
+        // `_call` only sets some of the fields, the rest must not outlive this transaction
+        let previous = evm.context.evm.env.tx.clone();
+        let tx = &mut evm.context.evm.env.tx;
+        tx.caller = caller;
+        tx.transact_to = envelope.kind();
+        tx.data = envelope.input().clone();
+        tx.value = envelope.value();
+        tx.gas_limit = envelope.gas_limit();
+        tx.gas_price = U256::from(envelope.max_fee_per_gas());
+        tx.gas_priority_fee = envelope.max_priority_fee_per_gas().map(U256::from);
+        tx.nonce = Some(envelope.nonce());
+        tx.chain_id = envelope.chain_id();
+        tx.access_list = envelope
+            .access_list()
+            .map(|list| list.0.clone())
+            .unwrap_or_default();
+
+        evm.context.external.reset();
+        let result = evm.transact_commit();
+        evm.context.evm.env.tx = previous;
+        outcome(result, self.trace_calls, &evm.context.external.calls)
     }
 
     pub async fn insert_account_info(&mut self, target: Address, account_info: AccountInfo) {
@@ -274,6 +426,15 @@ impl<'a> EvmSimulator<'a> {
         let contract_info = AccountInfo::new(U256::MAX, 0, code_hash, bytecode.clone());
         self.insert_account_info(target, contract_info).await;
     }
+    /// Whether `address` holds code, on chain or deployed into the simulator
+    pub fn has_code(&mut self, address: Address) -> Result<bool> {
+        let db = &mut self.evm.get_mut().context.evm.db;
+        let info = db
+            .basic(address)
+            .map_err(|err| anyhow!("Error loading {:?}: {:?}", address, err))?;
+        Ok(info.is_some_and(|info| !info.is_empty_code_hash()))
+    }
+
     pub async fn get_account(&mut self, address: Address) -> Result<AccountInfo, Error> {
         let mut evm = self.evm.lock().await;
         let account = evm.context.evm.db.basic(address).unwrap().unwrap();
@@ -289,26 +450,42 @@ impl<'a> EvmSimulator<'a> {
         Ok(())
     }
 
+    /// Overwrite the balance of `target`, its nonce and code stay
     pub async fn set_eth_balance(&mut self, target: Address, amount: U256) {
-        let user_balance = amount;
-        let user_info = AccountInfo::new(user_balance, 0, B256::ZERO, Bytecode::default());
-        self.insert_account_info(target, user_info).await;
+        self.edit_account(target, |info| info.balance = amount)
+            .await;
+    }
+
+    /// Overwrite the nonce of `target`, so transactions signed against the chain go through
+    pub async fn set_nonce(&mut self, target: Address, nonce: u64) {
+        self.edit_account(target, |info| info.nonce = nonce).await;
+    }
+
+    async fn edit_account(&mut self, target: Address, edit: impl FnOnce(&mut AccountInfo)) {
+        let mut evm = self.evm.lock().await;
+        let account = match evm.context.evm.db.load_account(target) {
+            Ok(account) => account,
+            Err(err) => {
+                log::error!("Error loading {:?}: {:?}", target, err);
+                return;
+            }
+        };
+        // an account the chain doesn't know reads as missing until it is touched
+        if account.account_state == AccountState::NotExisting {
+            account.account_state = AccountState::Touched;
+        }
+        edit(&mut account.info);
     }
 
     pub async fn get_eth_balance(&mut self, address: Address) -> U256 {
         let mut evm = self.evm.lock().await;
-        evm.context
-            .evm
-            .db
-            .load_account(address)
-            .unwrap()
-            .info
-            .balance
+        let info = evm.context.evm.db.basic(address).unwrap();
+        info.unwrap_or_default().balance
     }
 
     pub async fn load_account(&mut self, address: Address) -> () {
         let mut evm = self.evm.lock().await;
-        evm.context.evm.db.load_account(address).unwrap();
+        evm.context.evm.db.basic(address).unwrap();
     }
 
     pub async fn get_code_at(&mut self, address: Address) -> Result<AccountInfo, Error> {
@@ -338,10 +515,9 @@ impl<'a> EvmSimulator<'a> {
         evm.context
             .evm
             .db
-            .load_account(address)
+            .basic(address)
             .unwrap()
-            .info
-            .clone()
+            .unwrap_or_default()
     }
 
     pub async fn insert_account_storage(&mut self, target: Address, index: U256, value: U256) {
@@ -387,7 +563,7 @@ impl<'a> EvmSimulator<'a> {
             gas_limit: *latest_gas_limit,
         };
 
-        let result = self.call(tx).unwrap();
+        let result = self.call(tx).into_result().unwrap();
 
         print!("result from balance of call: {:?}", result);
 
@@ -414,199 +590,63 @@ impl<'a> EvmSimulator<'a> {
         info!("Logs: {:?}", db);
     }
 
-    pub async fn load_pool_state(&self, pool_address: Address) -> Result<(), Error> {
+    /// Read the packed reserves of a V2 pair straight from storage slot 8
+    pub async fn get_v2_reserves(&self, pair: Address) -> Result<V2Reserves, Error> {
         let mut evm = self.evm.lock().await;
-
-        // Get all storage slots from the provider
-        // You might want to batch this or load specific slots based on the pool type (V2 or V3)
-        let storage_slots = vec![
-            U256::from(0), // reserves for V2
-            U256::from(1), // fees
-            U256::from(2), // token balances
-                           // Add more slots based on the pool type
-        ];
-
-        for slot in storage_slots {
-            let value = evm.context.evm.db.storage(pool_address, slot)?;
-            evm.context
-                .evm
-                .db
-                .insert_account_storage(pool_address, slot, value)?;
-        }
-
-        Ok(())
+        let value = evm.context.evm.db.storage(pair, RESERVES_SLOT)?;
+        Ok(V2Reserves::from_slot(value))
     }
 
-    // Helper method to load V2 pool specific storage
-    pub async fn load_v2_pool_state(&self, pool_address: Address) -> Result<(), Error> {
+    /// Read the price, tick and liquidity of a V3 pool straight from storage
+    pub async fn get_v3_liquidity(&self, pool: Address) -> Result<PoolLiquidity, Error> {
         let mut evm = self.evm.lock().await;
-
-        // V2 pools store reserves in slot 0
-        let reserves_slot = U256::from(0);
-        let reserves = evm.context.evm.db.storage(pool_address, reserves_slot)?;
-        evm.context
-            .evm
-            .db
-            .insert_account_storage(pool_address, reserves_slot, reserves)?;
-
-        // Load other V2-specific storage slots
-        // token0 balance
-        let token0_balance_slot = U256::from(1);
-        let token0_balance = evm
-            .context
-            .evm
-            .db
-            .storage(pool_address, token0_balance_slot)?;
-        evm.context.evm.db.insert_account_storage(
-            pool_address,
-            token0_balance_slot,
-            token0_balance,
-        )?;
-
-        // token1 balance
-        let token1_balance_slot = U256::from(2);
-        let token1_balance = evm
-            .context
-            .evm
-            .db
-            .storage(pool_address, token1_balance_slot)?;
-        evm.context.evm.db.insert_account_storage(
-            pool_address,
-            token1_balance_slot,
-            token1_balance,
-        )?;
-
-        Ok(())
+        let slot0 = Slot0::from_slot(evm.context.evm.db.storage(pool, V3_SLOT0)?);
+        let liquidity = evm.context.evm.db.storage(pool, V3_LIQUIDITY_SLOT)?;
+        Ok(PoolLiquidity {
+            // uint128, alone in its slot
+            liquidity: liquidity & U256::from(u128::MAX),
+            sqrt_price_x96: slot0.sqrt_price_x96,
+            tick: slot0.tick,
+        })
     }
+}
 
-    // Helper method to load V3 pool specific storage
-    pub async fn load_v3_pool_state(&self, pool_address: Address) -> Result<(), Error> {
-        let mut evm = self.evm.lock().await;
-
-        // Basic pool state
-        let liquidity_slot = U256::from(0);
-        let liquidity = evm.context.evm.db.storage(pool_address, liquidity_slot)?;
-
-        // info!("liquidity {:?}", liquidity);
-
-        evm.context
-            .evm
-            .db
-            .insert_account_storage(pool_address, liquidity_slot, liquidity)?;
-
-        let sqrt_price_slot = U256::from(1);
-        let sqrt_price = evm.context.evm.db.storage(pool_address, sqrt_price_slot)?;
-        evm.context
-            .evm
-            .db
-            .insert_account_storage(pool_address, sqrt_price_slot, sqrt_price)?;
-
-        let tick_slot = U256::from(2);
-        let tick = evm.context.evm.db.storage(pool_address, tick_slot)?;
-        evm.context
-            .evm
-            .db
-            .insert_account_storage(pool_address, tick_slot, tick)?;
-
-        // Fee and protocol fee settings
-        let fee_slot = U256::from(3);
-        let fee = evm.context.evm.db.storage(pool_address, fee_slot)?;
-
-        evm.context
-            .evm
-            .db
-            .insert_account_storage(pool_address, fee_slot, fee)?;
-
-        let token0_slot = U256::from(4);
-        let token0 = evm.context.evm.db.storage(pool_address, token0_slot)?;
-        evm.context
-            .evm
-            .db
-            .insert_account_storage(pool_address, token0_slot, token0)?;
-
-        let token1_slot = U256::from(5);
-        let token1 = evm.context.evm.db.storage(pool_address, token1_slot)?;
-        evm.context
-            .evm
-            .db
-            .insert_account_storage(pool_address, token1_slot, token1)?;
-
-        // Fee growth trackers
-        //        let fee_growth_global0_slot = U256::from(6);
-        //        let fee_growth_global0 = evm
-        //            .context
-        //            .evm
-        //            .db
-        //            .storage(pool_address, fee_growth_global0_slot)?;
-        //        evm.context.evm.db.insert_account_storage(
-        //            pool_address,
-        //            fee_growth_global0_slot,
-        //            fee_growth_global0,
-        //        )?;
-        //
-        //        let fee_growth_global1_slot = U256::from(7);
-        //        let fee_growth_global1 = evm
-        //            .context
-        //            .evm
-        //            .db
-        //            .storage(pool_address, fee_growth_global1_slot)?;
-        //        evm.context.evm.db.insert_account_storage(
-        //            pool_address,
-        //            fee_growth_global1_slot,
-        //            fee_growth_global1,
-        //        )?;
-
-        // Protocol fees
-        let protocol_fees0_slot = U256::from(8);
-        let protocol_fees0 = evm
-            .context
-            .evm
-            .db
-            .storage(pool_address, protocol_fees0_slot)?;
-        evm.context.evm.db.insert_account_storage(
-            pool_address,
-            protocol_fees0_slot,
-            protocol_fees0,
-        )?;
-
-        let protocol_fees1_slot = U256::from(9);
-        let protocol_fees1 = evm
-            .context
-            .evm
-            .db
-            .storage(pool_address, protocol_fees1_slot)?;
-        evm.context.evm.db.insert_account_storage(
-            pool_address,
-            protocol_fees1_slot,
-            protocol_fees1,
-        )?;
-
-        // Token balances (tracked in ERC20 contracts)
-        let token0_addr = Address::from_slice(&token0.to_be_bytes::<32>()[12..]);
-        let balance0_slot = get_balance_slot(pool_address);
-
-        let balance0 = evm.context.evm.db.storage(token0_addr, balance0_slot)?;
-        evm.context
-            .evm
-            .db
-            .insert_account_storage(token0_addr, balance0_slot, balance0)?;
-
-        let token1_addr = Address::from_slice(&token1.to_be_bytes::<32>()[12..]);
-        let balance1_slot = get_balance_slot(pool_address);
-
-        let balance1 = evm.context.evm.db.storage(token1_addr, balance1_slot)?;
-        evm.context
-            .evm
-            .db
-            .insert_account_storage(token1_addr, balance1_slot, balance1)?;
+/// Keep the simulator on the chain head, re-pinning it to every new block
+pub async fn sync_blocks(
+    simulator: Arc<TokioMutex<EvmSimulator<'_>>>,
+    provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+) -> Result<()> {
+    let sub = provider.subscribe_blocks().await?;
+    let mut stream = sub.into_stream();
+
+    // the simulator was built from a block number only, take the rest of the env from the head
+    if let Some(block) = provider
+        .get_block(BlockId::latest(), BlockTransactionsKind::Hashes)
+        .await?
+    {
+        if let Err(err) = simulator.lock().await.advance_to(&block.header, None) {
+            log::error!("Error pinning block {}: {:?}", block.header.number, err);
+        }
+    }
 
-        Ok(())
+    while let Some(header) = stream.next().await {
+        // fetched before locking, the strategy keeps simulating on the old block meanwhile
+        let diffs = try_fetch_block_diffs(provider.clone(), header.number).await;
+        let advanced = simulator.lock().await.advance_to(&header, diffs.as_deref());
+        // a failed advance leaves the pinned hash as it was, so the next header doesn't
+        // extend it and gets a fresh base instead of a patched one
+        if let Err(err) = advanced {
+            log::error!("Error pinning block {}: {:?}", header.number, err);
+        }
     }
+    Err(anyhow!("Block subscription closed"))
 }
-// Helper function to calculate balance slot for an address
-fn get_balance_slot(address: Address) -> U256 {
-    // This is a simplified version - you might need to adjust based on actual storage layout
-    let mut bytes = [0u8; 32];
-    bytes[12..32].copy_from_slice(address.as_slice());
-    U256::from_be_bytes(bytes)
+
+fn build_evm<'a>(db: ForkDB, env: Box<Env>) -> Evm<'a, RevmInspector, ForkDB> {
+    Evm::builder()
+        .with_db(db)
+        .with_env(env)
+        .with_external_context(RevmInspector::new())
+        .append_handler_register(inspector_handle_register)
+        .build()
 }
@@ -1,46 +1,178 @@
-use alloy_primitives::Address;
-use log::info;
+use super::decodeResult::{decode_evm_revert, DecodedEVMRevert};
+use super::mempool::ISwapRouter02;
+use crate::arbitrage::simulation::{get_address, AddressType};
+use alloy_primitives::{Address, I256};
+use alloy_sol_types::{SolCall, SolEvent};
 use revm::interpreter::{
-    opcode, CallInputs, CallOutcome, CreateInputs, CreateOutcome, EOFCreateInputs,
+    opcode, CallInputs, CallOutcome, CallScheme, CreateInputs, CreateOutcome, EOFCreateInputs,
     InstructionResult, Interpreter,
 };
-use revm::primitives::{Bytes, Log, B256, U256};
+use revm::primitives::{Bytes, CreateScheme, Log, B256, U256};
 use revm::Database;
 use revm::EvmContext;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write};
 
-/// A comprehensive REVM Inspector that tracks:
+alloy::sol! {
+    event Transfer(address indexed from, address indexed to, uint256 value);
+
+    interface IERC20 {
+        function transfer(address to, uint256 amount) external returns (bool);
+        function transferFrom(address from, address to, uint256 amount) external returns (bool);
+        function approve(address spender, uint256 amount) external returns (bool);
+        function balanceOf(address account) external view returns (uint256);
+    }
+
+    interface IWETH {
+        function deposit() external payable;
+        function withdraw(uint256 amount) external;
+    }
+
+    interface IUniswapV2Pair {
+        function getReserves() external view returns (uint112, uint112, uint32);
+        function swap(uint256 amount0Out, uint256 amount1Out, address to, bytes data) external;
+    }
+
+    interface IUniswapV3Pool {
+        function slot0() external view returns (uint160, int24, uint16, uint16, uint16, uint8, bool);
+        function liquidity() external view returns (uint128);
+        function swap(address recipient, bool zeroForOne, int256 amountSpecified, uint160 sqrtPriceLimitX96, bytes data) external returns (int256, int256);
+        function flash(address recipient, uint256 amount0, uint256 amount1, bytes data) external;
+    }
+
+    interface ICallbacks {
+        function uniswapV2Call(address sender, uint256 amount0, uint256 amount1, bytes data) external;
+        function uniswapV3SwapCallback(int256 amount0Delta, int256 amount1Delta, bytes data) external;
+        function uniswapV3FlashCallback(uint256 fee0, uint256 fee1, bytes data) external;
+    }
+
+    interface IUniswapV2Router {
+        function swapExactTokensForTokens(uint256 amountIn, uint256 amountOutMin, address[] path, address to, uint256 deadline) external returns (uint256[]);
+    }
+
+    interface IArboo {
+        function flashSwap_V3_to_V2(address pool0, uint24 fee1, address tokenIn, address tokenOut, uint256 amountIn) external;
+    }
+}
+
+/// Selectors of the calls an arbitrage makes, shown by name in the trace
+const KNOWN_FUNCTIONS: &[([u8; 4], &str)] = &[
+    (
+        IERC20::transferCall::SELECTOR,
+        IERC20::transferCall::SIGNATURE,
+    ),
+    (
+        IERC20::transferFromCall::SELECTOR,
+        IERC20::transferFromCall::SIGNATURE,
+    ),
+    (
+        IERC20::approveCall::SELECTOR,
+        IERC20::approveCall::SIGNATURE,
+    ),
+    (
+        IERC20::balanceOfCall::SELECTOR,
+        IERC20::balanceOfCall::SIGNATURE,
+    ),
+    (IWETH::depositCall::SELECTOR, IWETH::depositCall::SIGNATURE),
+    (
+        IWETH::withdrawCall::SELECTOR,
+        IWETH::withdrawCall::SIGNATURE,
+    ),
+    (
+        IUniswapV2Pair::getReservesCall::SELECTOR,
+        IUniswapV2Pair::getReservesCall::SIGNATURE,
+    ),
+    (
+        IUniswapV2Pair::swapCall::SELECTOR,
+        IUniswapV2Pair::swapCall::SIGNATURE,
+    ),
+    (
+        IUniswapV3Pool::slot0Call::SELECTOR,
+        IUniswapV3Pool::slot0Call::SIGNATURE,
+    ),
+    (
+        IUniswapV3Pool::liquidityCall::SELECTOR,
+        IUniswapV3Pool::liquidityCall::SIGNATURE,
+    ),
+    (
+        IUniswapV3Pool::swapCall::SELECTOR,
+        IUniswapV3Pool::swapCall::SIGNATURE,
+    ),
+    (
+        IUniswapV3Pool::flashCall::SELECTOR,
+        IUniswapV3Pool::flashCall::SIGNATURE,
+    ),
+    (
+        ICallbacks::uniswapV2CallCall::SELECTOR,
+        ICallbacks::uniswapV2CallCall::SIGNATURE,
+    ),
+    (
+        ICallbacks::uniswapV3SwapCallbackCall::SELECTOR,
+        ICallbacks::uniswapV3SwapCallbackCall::SIGNATURE,
+    ),
+    (
+        ICallbacks::uniswapV3FlashCallbackCall::SELECTOR,
+        ICallbacks::uniswapV3FlashCallbackCall::SIGNATURE,
+    ),
+    (
+        IUniswapV2Router::swapExactTokensForTokensCall::SELECTOR,
+        IUniswapV2Router::swapExactTokensForTokensCall::SIGNATURE,
+    ),
+    (
+        ISwapRouter02::exactInputSingleCall::SELECTOR,
+        ISwapRouter02::exactInputSingleCall::SIGNATURE,
+    ),
+    (
+        IArboo::flashSwap_V3_to_V2Call::SELECTOR,
+        IArboo::flashSwap_V3_to_V2Call::SIGNATURE,
+    ),
+];
+
+/// Call tree tracer. Records every call frame with its depth and kind, the logs each frame
+/// emitted and, when `track_storage` is set, the storage slots it touched. Everything is
+/// cleared when the next transaction starts.
 #[derive(Debug, Default)]
 pub struct RevmInspector {
-    /// Track the call stack
+    /// Every frame in the order it was entered, the first one is the transaction itself
     pub calls: Vec<CallInfo>,
     /// Track all storage slot accesses
     pub storage_accesses: HashMap<B256, Vec<StorageAccess>>,
-    /// Track gas usage by opcode
-    pub gas_by_opcode: HashMap<u8, u64>,
-    /// Track emitted logs
+    /// Every log emitted, including those of frames that reverted later
     pub logs: Vec<LogInfo>,
-    /// Track any errors that occurred
+    /// Revert reasons and halts, in the order they happened
     pub errors: Vec<ErrorInfo>,
-    /// Track balance changes
-    pub balance_changes: HashMap<B256, i128>,
+    /// Record SLOADs and SSTOREs into `storage_accesses`, off by default since it runs on
+    /// every opcode
+    pub track_storage: bool,
+    /// SLOAD seen in `step`, its value is on the stack in `step_end`
+    pending_sload: Option<StorageAccess>,
+    /// Frames entered but not returned from yet, innermost last
+    call_stack: Vec<usize>,
 }
 
 #[derive(Debug, Clone)]
 pub struct CallInfo {
-    //pub depth: usize,
+    pub depth: usize,
+    /// Index of the calling frame in `calls`, None for the transaction itself
+    pub parent: Option<usize>,
+    pub kind: CallKind,
     pub caller: Address,
+    /// Whose storage and balance the frame runs on, None until a create returns
     pub address: Option<Address>,
-    //pub kind: CallKind,
+    /// Where the code came from when it isn't `address`, e.g. the implementation under a
+    /// delegatecall
+    pub code_address: Option<Address>,
     pub value: U256,
     pub input: Option<Bytes>,
     pub gas_limit: u64,
     pub gas_used: Option<u64>,
     pub output: Option<Bytes>,
     pub error: Option<String>,
+    /// Sub calls and logs in the order they happened
+    pub entries: Vec<TraceEntry>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CallKind {
     Call,
     StaticCall,
@@ -50,6 +182,13 @@ pub enum CallKind {
     Create2,
 }
 
+/// Something that happened inside a frame, by index into `calls` or `logs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEntry {
+    Call(usize),
+    Log(usize),
+}
+
 #[derive(Debug, Clone)]
 pub struct StorageAccess {
     pub address: B256,
@@ -61,8 +200,27 @@ pub struct StorageAccess {
 #[derive(Debug, Clone)]
 pub struct LogInfo {
     pub address: Address,
-    //pub topics: Vec<B256>,
+    pub topics: Vec<B256>,
     pub data: Bytes,
+    /// Frame that emitted it
+    pub call: Option<usize>,
+}
+
+/// The frame a revert started in, before the callers bubbled it up
+#[derive(Debug, Clone)]
+pub struct RevertFrame {
+    /// Index in `calls`
+    pub index: usize,
+    pub depth: usize,
+    pub address: Option<Address>,
+    /// Signature of the function that failed, see `CallInfo::function_name`
+    pub function: String,
+    /// "Revert", or the halt reason, e.g. "OutOfGas"
+    pub error: String,
+    /// Decoded revert data, None for a halt
+    pub revert: Option<DecodedEVMRevert>,
+    pub gas_used: u64,
+    pub gas_limit: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -71,114 +229,457 @@ pub struct ErrorInfo {
     pub message: String,
 }
 
+impl From<CallScheme> for CallKind {
+    fn from(scheme: CallScheme) -> Self {
+        match scheme {
+            CallScheme::Call | CallScheme::ExtCall => CallKind::Call,
+            CallScheme::StaticCall | CallScheme::ExtStaticCall => CallKind::StaticCall,
+            CallScheme::CallCode => CallKind::CallCode,
+            CallScheme::DelegateCall | CallScheme::ExtDelegateCall => CallKind::DelegateCall,
+        }
+    }
+}
+
+impl fmt::Display for CallKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallKind::Call => write!(f, "call"),
+            CallKind::StaticCall => write!(f, "staticcall"),
+            CallKind::CallCode => write!(f, "callcode"),
+            CallKind::DelegateCall => write!(f, "delegatecall"),
+            CallKind::Create => write!(f, "create"),
+            CallKind::Create2 => write!(f, "create2"),
+        }
+    }
+}
+
+impl CallInfo {
+    /// Signature of a known selector, the raw selector otherwise
+    pub fn function_name(&self) -> String {
+        let input = self
+            .input
+            .as_ref()
+            .map(|input| &input[..])
+            .unwrap_or_default();
+        if matches!(self.kind, CallKind::Create | CallKind::Create2) {
+            return "new".to_string();
+        }
+        if input.len() < 4 {
+            return "fallback()".to_string();
+        }
+        KNOWN_FUNCTIONS
+            .iter()
+            .find(|(selector, _)| selector[..] == input[..4])
+            .map(|(_, signature)| signature.to_string())
+            .unwrap_or_else(|| format!("0x{}", hex::encode(&input[..4])))
+    }
+}
+
+impl fmt::Display for CallInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] ", self.gas_used.unwrap_or_default())?;
+        match self.address {
+            Some(address) => write!(f, "{}", address)?,
+            None => write!(f, "<create failed>")?,
+        }
+        write!(f, "::{}", self.function_name())?;
+        if !self.value.is_zero() {
+            write!(f, " {{value: {}}}", self.value)?;
+        }
+        if self.kind != CallKind::Call {
+            write!(f, " [{}]", self.kind)?;
+        }
+        if let Some(code_address) = self.code_address {
+            write!(f, " (code at {})", code_address)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RevertFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let contract = self
+            .address
+            .map(contract_name)
+            .unwrap_or_else(|| "<create>".to_string());
+        let function = self.function.split('(').next().unwrap_or_default();
+        match &self.revert {
+            Some(revert) => write!(f, "{}.{} reverted: {}", contract, function, revert.reason())?,
+            None => write!(f, "{}.{} failed: {}", contract, function, self.error)?,
+        }
+        write!(
+            f,
+            " at depth {} (gas {}/{})",
+            self.depth, self.gas_used, self.gas_limit
+        )
+    }
+}
+
+/// Find the frame a failed transaction actually failed in. From the transaction, follow
+/// the last call of every failed frame as long as that call failed too: a caller that
+/// caught the failure and carried on would have made another call or succeeded.
+pub fn deepest_revert(calls: &[CallInfo]) -> Option<RevertFrame> {
+    calls.first()?.error.as_ref()?;
+    let mut index = 0;
+    while let Some(child) = last_call(&calls[index]) {
+        if calls[child].error.is_none() {
+            break;
+        }
+        index = child;
+    }
+
+    let call = &calls[index];
+    let error = call.error.clone().unwrap_or_default();
+    let revert = (error == "Revert")
+        .then(|| decode_evm_revert(call.output.clone().unwrap_or_default().to_vec()));
+    Some(RevertFrame {
+        index,
+        depth: call.depth,
+        address: call.address,
+        function: call.function_name(),
+        error,
+        revert,
+        gas_used: call.gas_used.unwrap_or_default(),
+        gas_limit: call.gas_limit,
+    })
+}
+
+fn last_call(call: &CallInfo) -> Option<usize> {
+    call.entries.iter().rev().find_map(|entry| match entry {
+        TraceEntry::Call(child) => Some(*child),
+        TraceEntry::Log(_) => None,
+    })
+}
+
+/// Name of the contracts the strategy talks to, the address for everything else
+fn contract_name(address: Address) -> String {
+    [
+        (get_address(AddressType::Weth), "WETH"),
+        (get_address(AddressType::V2Router), "UniswapV2Router"),
+        (get_address(AddressType::V3Router), "SwapRouter02"),
+        (get_address(AddressType::V2Factory), "UniswapV2Factory"),
+        (get_address(AddressType::V3Factory), "UniswapV3Factory"),
+        (get_address(AddressType::V2Quoter), "QuoterV2"),
+    ]
+    .into_iter()
+    .find(|(known, _)| *known == address)
+    .map(|(_, name)| name.to_string())
+    .unwrap_or_else(|| address.to_string())
+}
+
+impl LogInfo {
+    /// `(from, to, value)` of an ERC20 `Transfer`, ERC721 transfers have a fourth topic
+    pub fn erc20_transfer(&self) -> Option<(Address, Address, U256)> {
+        if self.topics.len() != 3 || self.topics[0] != Transfer::SIGNATURE_HASH {
+            return None;
+        }
+        if self.data.len() != 32 {
+            return None;
+        }
+        Some((
+            Address::from_word(self.topics[1]),
+            Address::from_word(self.topics[2]),
+            U256::from_be_slice(&self.data),
+        ))
+    }
+}
+
+impl fmt::Display for LogInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((from, to, value)) = self.erc20_transfer() {
+            return write!(
+                f,
+                "emit Transfer(from: {}, to: {}, value: {})",
+                from, to, value
+            );
+        }
+        write!(f, "emit {}", self.address)?;
+        for (i, topic) in self.topics.iter().enumerate() {
+            write!(f, " topic{}: {}", i, topic)?;
+        }
+        write!(f, " data: 0x{}", hex::encode(&self.data))
+    }
+}
+
 impl RevmInspector {
     pub fn new() -> Self {
-        Self {
-            calls: Vec::new(),
-            storage_accesses: HashMap::new(),
-            gas_by_opcode: HashMap::new(),
-            logs: Vec::new(),
-            errors: Vec::new(),
-            balance_changes: HashMap::new(),
+        Self::default()
+    }
+
+    /// Drop everything recorded for the previous transaction
+    pub fn reset(&mut self) {
+        self.calls.clear();
+        self.storage_accesses.clear();
+        self.logs.clear();
+        self.errors.clear();
+        self.pending_sload = None;
+        self.call_stack.clear();
+    }
+
+    /// Slots read from `address` since the accesses were last cleared, in order
+    pub fn loaded_slots(&self, address: Address) -> Vec<U256> {
+        self.storage_accesses
+            .get(&address.into_word())
+            .into_iter()
+            .flatten()
+            .filter(|access| !access.is_write)
+            .map(|access| access.slot.into())
+            .collect()
+    }
+
+    /// Whether the frame and every frame around it returned, i.e. its effects were kept
+    pub fn survived(&self, index: usize) -> bool {
+        let mut current = Some(index);
+        while let Some(call) = current.and_then(|index| self.calls.get(index)) {
+            if call.error.is_some() {
+                return false;
+            }
+            current = call.parent;
         }
+        true
     }
 
-    /// Generate a human-readable report of what was captured
-    pub fn generate_report(&self) -> String {
-        let mut report = String::new();
-
-        // Summarize calls
-        report.push_str("\n=== Calls Summary ===\n");
-        for (i, call) in self.calls.iter().enumerate() {
-            report.push_str(&format!(
-                "Call #{}: {} -> {} (), value: {}, gas: {}/{}, success: {}\n",
-                i,
-                hex::encode(call.caller.to_string()),
-                hex::encode(call.address.unwrap()),
-                //format!("{:?}", call.kind),
-                call.value,
-                call.gas_used.unwrap_or(0),
-                call.gas_limit,
-                call.error.is_none()
-            ));
-        }
-
-        // Summarize storage accesses
-        report.push_str("\n=== Storage Accesses ===\n");
-        for (address, accesses) in &self.storage_accesses {
-            report.push_str(&format!(
-                "Contract: 0x{}...\n",
-                hex::encode(address.to_string())
-            ));
-            for access in accesses {
-                report.push_str(&format!(
-                    "  {} slot: 0x{}... value: 0x{}...\n",
-                    if access.is_write { "WRITE" } else { "READ " },
-                    hex::encode(access.slot),
-                    hex::encode(access.value)
-                ));
+    /// The frame the last transaction failed in, None if it succeeded
+    pub fn deepest_revert(&self) -> Option<RevertFrame> {
+        deepest_revert(&self.calls)
+    }
+
+    /// Net wei moved by calls and creates that were kept, per address. Gas is not included.
+    pub fn eth_deltas(&self) -> BTreeMap<Address, I256> {
+        let mut deltas = BTreeMap::new();
+        for (index, call) in self.calls.iter().enumerate() {
+            // delegatecalls only show the value of their caller
+            if call.value.is_zero() || call.kind == CallKind::DelegateCall || !self.survived(index)
+            {
+                continue;
             }
+            let Some(to) = call.address else {
+                continue;
+            };
+            add_delta(&mut deltas, call.caller, to, call.value);
         }
+        deltas.retain(|_, delta| !delta.is_zero());
+        deltas
+    }
 
-        // Summarize gas usage
-        report.push_str("\n=== Gas Usage By Opcode ===\n");
-        let mut opcodes: Vec<(u8, u64)> =
-            self.gas_by_opcode.iter().map(|(&k, &v)| (k, v)).collect();
-        opcodes.sort_by(|a, b| b.1.cmp(&a.1)); // Sort by gas used, descending
+    /// Net ERC20 `Transfer`s of frames that were kept, per token and holder
+    pub fn token_deltas(&self) -> BTreeMap<Address, BTreeMap<Address, I256>> {
+        let mut deltas: BTreeMap<Address, BTreeMap<Address, I256>> = BTreeMap::new();
+        for log in &self.logs {
+            if !log.call.is_some_and(|call| self.survived(call)) {
+                continue;
+            }
+            if let Some((from, to, value)) = log.erc20_transfer() {
+                add_delta(deltas.entry(log.address).or_default(), from, to, value);
+            }
+        }
+        for holders in deltas.values_mut() {
+            holders.retain(|_, delta| !delta.is_zero());
+        }
+        deltas.retain(|_, holders| !holders.is_empty());
+        deltas
+    }
 
-        for (opcode, gas) in opcodes.iter().take(10) {
-            // Show top 10
-            report.push_str(&format!("0x{:02x}: {} gas\n", opcode, gas));
+    /// Render the last transaction like `cast run`: the call tree with logs and return
+    /// values, then the balance changes and errors
+    pub fn generate_report(&self) -> String {
+        let mut report = String::from("Traces:\n");
+        for (index, _) in self
+            .calls
+            .iter()
+            .enumerate()
+            .filter(|(_, call)| call.parent.is_none())
+        {
+            self.render_call(&mut report, index, "  ", "  ");
         }
 
-        // Summarize logs
-        report.push_str("\n=== Logs ===\n");
-        for (i, log) in self.logs.iter().enumerate() {
-            report.push_str(&format!(
-                "Log #{}: Contract: 0x{}..., , Data size: {} bytes\n",
-                i,
-                hex::encode(log.address),
-                //log.topics.len(),
-                log.data.len()
-            ));
+        let eth_deltas = self.eth_deltas();
+        if !eth_deltas.is_empty() {
+            report.push_str("\nETH changes:\n");
+            for (address, delta) in eth_deltas {
+                let _ = writeln!(report, "  {}: {}", address, signed(delta));
+            }
+        }
+
+        let token_deltas = self.token_deltas();
+        if !token_deltas.is_empty() {
+            report.push_str("\nToken changes:\n");
+            for (token, holders) in token_deltas {
+                let _ = writeln!(report, "  {}:", token);
+                for (holder, delta) in holders {
+                    let _ = writeln!(report, "    {}: {}", holder, signed(delta));
+                }
+            }
         }
 
-        // Summarize errors
         if !self.errors.is_empty() {
-            report.push_str("\n=== Errors ===\n");
+            report.push_str("\nErrors:\n");
             for error in &self.errors {
-                report.push_str(&format!("[{}] {}\n", error.phase, error.message));
+                let _ = writeln!(report, "  [{}] {}", error.phase, error.message);
             }
         }
-
-        // Summarize balance changes
-        report.push_str("\n=== Balance Changes ===\n");
-        for (address, change) in &self.balance_changes {
-            let change_str = if *change >= 0 {
-                format!("+{}", change)
-            } else {
-                format!("{}", change)
-            };
-            report.push_str(&format!(
-                "0x{}...: {} wei\n",
-                hex::encode(address),
-                change_str
-            ));
+        if let Some(frame) = self.deepest_revert() {
+            let _ = writeln!(report, "\nFailed in: {}", frame);
         }
-        info!("REPORT: {:?}", report);
         report
     }
+
+    fn render_call(&self, out: &mut String, index: usize, prefix: &str, indent: &str) {
+        let call = &self.calls[index];
+        let _ = writeln!(out, "{}{}", prefix, call);
+        for entry in &call.entries {
+            match entry {
+                TraceEntry::Call(child) => self.render_call(
+                    out,
+                    *child,
+                    &format!("{}├─ ", indent),
+                    &format!("{}│  ", indent),
+                ),
+                TraceEntry::Log(log) => {
+                    let _ = writeln!(out, "{}├─ {}", indent, self.logs[*log]);
+                }
+            }
+        }
+        let output = call.output.as_ref().map(hex::encode).unwrap_or_default();
+        let _ = match &call.error {
+            None => writeln!(out, "{}└─ ← [Return] 0x{}", indent, output),
+            Some(error) => writeln!(out, "{}└─ ← [{}] 0x{}", indent, error, output),
+        };
+    }
+
+    /// Open a frame, a frame with nothing open above it is a new transaction
+    fn enter(&mut self, mut call: CallInfo) {
+        if self.call_stack.is_empty() {
+            self.reset();
+        }
+        let index = self.calls.len();
+        call.depth = self.call_stack.len();
+        call.parent = self.call_stack.last().copied();
+        if let Some(parent) = call.parent {
+            self.calls[parent].entries.push(TraceEntry::Call(index));
+        }
+        self.calls.push(call);
+        self.call_stack.push(index);
+    }
+
+    /// Close the innermost frame with its result
+    fn exit(&mut self, phase: &str, result: InstructionResult, gas_used: u64, output: &Bytes) {
+        let Some(index) = self.call_stack.pop() else {
+            return;
+        };
+        let call = &mut self.calls[index];
+        call.gas_used = Some(gas_used);
+        call.output = Some(output.clone());
+        if result.is_ok() {
+            return;
+        }
+
+        if result.is_revert() {
+            call.error = Some("Revert".to_string());
+            if let Some(reason) = revert_reason(output) {
+                self.errors.push(ErrorInfo {
+                    phase: phase.to_string(),
+                    message: reason,
+                });
+            }
+        } else {
+            call.error = Some(format!("{:?}", result));
+            self.errors.push(ErrorInfo {
+                phase: phase.to_string(),
+                message: format!("EVM error: {:?}", result),
+            });
+        }
+    }
+}
+
+/// Move `value` from `from` to `to`
+fn add_delta(deltas: &mut BTreeMap<Address, I256>, from: Address, to: Address, value: U256) {
+    let value = I256::try_from(value).unwrap_or(I256::MAX);
+    let from = deltas.entry(from).or_default();
+    *from = from.saturating_sub(value);
+    let to = deltas.entry(to).or_default();
+    *to = to.saturating_add(value);
+}
+
+fn signed(delta: I256) -> String {
+    if delta.is_negative() {
+        delta.to_string()
+    } else {
+        format!("+{}", delta)
+    }
+}
+
+/// Message of a Solidity `Error(string)` revert
+fn revert_reason(output: &Bytes) -> Option<String> {
+    if output.len() < 4 + 32 + 32 || output[0..4] != [0x08, 0xc3, 0x79, 0xa0] {
+        return None;
+    }
+    let str_len = u32::from_be_bytes([output[64], output[65], output[66], output[67]]) as usize;
+    let message = output.get(4 + 32 + 32..4 + 32 + 32 + str_len)?;
+    Some(String::from_utf8_lossy(message).to_string())
 }
 
 impl<DB: Database> revm::Inspector<DB> for RevmInspector {
-    //
+    #[inline]
+    fn step(&mut self, interp: &mut Interpreter, _context: &mut EvmContext<DB>) {
+        if !self.track_storage {
+            return;
+        }
+        // storage belongs to the target, which is the proxy under a delegatecall
+        let address = interp.contract.target_address.into_word();
+        match interp.current_opcode() {
+            opcode::SLOAD => {
+                if let Ok(slot) = interp.stack().peek(0) {
+                    self.pending_sload = Some(StorageAccess {
+                        address,
+                        slot: slot.into(),
+                        value: B256::ZERO,
+                        is_write: false,
+                    });
+                }
+            }
+            opcode::SSTORE => {
+                if let (Ok(slot), Ok(value)) = (interp.stack().peek(0), interp.stack().peek(1)) {
+                    self.storage_accesses
+                        .entry(address)
+                        .or_default()
+                        .push(StorageAccess {
+                            address,
+                            slot: slot.into(),
+                            value: value.into(),
+                            is_write: true,
+                        });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[inline]
+    fn step_end(&mut self, interp: &mut Interpreter, _context: &mut EvmContext<DB>) {
+        let Some(mut access) = self.pending_sload.take() else {
+            return;
+        };
+        if let Ok(value) = interp.stack().peek(0) {
+            access.value = value.into();
+        }
+        self.storage_accesses
+            .entry(access.address)
+            .or_default()
+            .push(access);
+    }
 
     fn log(&mut self, _interp: &mut Interpreter, _context: &mut EvmContext<DB>, log: &Log) {
-        // Capture the log
+        let index = self.logs.len();
+        let call = self.call_stack.last().copied();
+        if let Some(call) = call {
+            self.calls[call].entries.push(TraceEntry::Log(index));
+        }
         self.logs.push(LogInfo {
             address: log.address,
-            //topics: log.topics.clone(),
-            data: Bytes::copy_from_slice(&log.data.data),
+            topics: log.topics().to_vec(),
+            data: log.data.data.clone(),
+            call,
         });
     }
 
@@ -187,22 +688,24 @@ impl<DB: Database> revm::Inspector<DB> for RevmInspector {
         _context: &mut EvmContext<DB>,
         inputs: &mut CallInputs,
     ) -> Option<CallOutcome> {
-        //log::debug!("call : {:?}", inputs);
-        // Record the call
-        let call_info = CallInfo {
-            address: Some(inputs.target_address),
+        let code_address =
+            (inputs.bytecode_address != inputs.target_address).then_some(inputs.bytecode_address);
+        self.enter(CallInfo {
+            depth: 0,
+            parent: None,
+            kind: inputs.scheme.into(),
             caller: inputs.caller,
+            address: Some(inputs.target_address),
+            code_address,
             value: inputs.value.get(),
             input: Some(inputs.input.clone()),
             gas_limit: inputs.gas_limit,
-            gas_used: None, // Will be populated in call_end
-            output: None,   // Will be populated in call_end
-            error: None,    // Will be populated in call_end
-        };
-
-        self.calls.push(call_info.clone());
-
-        None // Allow call to proceed normally
+            gas_used: None,
+            output: None,
+            error: None,
+            entries: Vec::new(),
+        });
+        None
     }
 
     fn call_end(
@@ -211,51 +714,12 @@ impl<DB: Database> revm::Inspector<DB> for RevmInspector {
         _inputs: &CallInputs,
         outcome: CallOutcome,
     ) -> CallOutcome {
-        //log::debug!("call outcome: {:?}", outcome);
-        if let Some(last_call) = self.calls.last_mut() {
-            // Extract information from the outcome
-            last_call.gas_used = Some(outcome.gas().spent());
-            last_call.output = Some(outcome.output().clone());
-
-            // Set error information based on the instruction result
-            match outcome.instruction_result() {
-                InstructionResult::Return => {
-                    // Success case, no error
-                }
-                InstructionResult::Revert => {
-                    last_call.error = Some("Reverted".to_string());
-                    // Try to decode the revert reason
-                    let output = outcome.output();
-                    if output.len() >= 4 + 32 + 32 {
-                        // Solidity revert with string error message format (keccak256("Error(string)"))
-                        if output[0..4] == [0x08, 0xc3, 0x79, 0xa0] {
-                            // Skip the function selector and offset
-                            let str_len = u32::from_be_bytes([
-                                output[36], output[37], output[38], output[39],
-                            ]) as usize;
-                            if output.len() >= 4 + 32 + 32 + str_len {
-                                let error_msg = String::from_utf8_lossy(
-                                    &output[4 + 32 + 32..4 + 32 + 32 + str_len],
-                                );
-                                self.errors.push(ErrorInfo {
-                                    phase: "call".to_string(),
-                                    message: error_msg.to_string(),
-                                });
-                            }
-                        }
-                    }
-                }
-                error => {
-                    last_call.error = Some(format!("Error: {:?}", error));
-                    // Add to errors collection
-                    self.errors.push(ErrorInfo {
-                        phase: "call".to_string(),
-                        message: format!("EVM error: {:?}", error),
-                    });
-                }
-            }
-        }
-
+        self.exit(
+            "call",
+            *outcome.instruction_result(),
+            outcome.gas().spent(),
+            outcome.output(),
+        );
         outcome
     }
 
@@ -264,71 +728,43 @@ impl<DB: Database> revm::Inspector<DB> for RevmInspector {
         _context: &mut EvmContext<DB>,
         inputs: &mut CreateInputs,
     ) -> Option<CreateOutcome> {
-        // Record the create
-        let call_info = CallInfo {
-            address: None,
+        let kind = match inputs.scheme {
+            CreateScheme::Create => CallKind::Create,
+            CreateScheme::Create2 { .. } => CallKind::Create2,
+        };
+        self.enter(CallInfo {
+            depth: 0,
+            parent: None,
+            kind,
             caller: inputs.caller,
+            address: None,
+            code_address: None,
             value: inputs.value,
-            input: None,
+            input: Some(inputs.init_code.clone()),
             gas_limit: inputs.gas_limit,
-            gas_used: None, // Will be populated in call_end
-            output: None,   // Will be populated in call_end
-            error: None,    // Will be populated in call_end
-        };
-
-        self.calls.push(call_info);
-
-        None // Allow create to proceed normally
+            gas_used: None,
+            output: None,
+            error: None,
+            entries: Vec::new(),
+        });
+        None
     }
+
     fn create_end(
         &mut self,
         _context: &mut EvmContext<DB>,
         _inputs: &CreateInputs,
         outcome: CreateOutcome,
     ) -> CreateOutcome {
-        // For CreateOutcome, we need to handle it similarly to CallOutcome
-        // Based on the InterpreterResult inside CreateOutcome
-        if let Some(last_call) = self.calls.last_mut() {
-            last_call.gas_used = Some(outcome.result.gas.spent());
-
-            // Determine the outcome type
-            match &outcome.result.result {
-                InstructionResult::Return => {
-                    last_call.output = Some(outcome.result.output.clone());
-                }
-                InstructionResult::Revert => {
-                    last_call.output = Some(outcome.result.output.clone());
-                    last_call.error = Some("Reverted".to_string());
-
-                    // Try to decode the revert reason
-                    let output = &outcome.result.output;
-                    if output.len() >= 4 + 32 + 32 && output[0..4] == [0x08, 0xc3, 0x79, 0xa0] {
-                        let str_len =
-                            u32::from_be_bytes([output[36], output[37], output[38], output[39]])
-                                as usize;
-                        if output.len() >= 4 + 32 + 32 + str_len {
-                            let error_msg = String::from_utf8_lossy(
-                                &output[4 + 32 + 32..4 + 32 + 32 + str_len],
-                            );
-                            self.errors.push(ErrorInfo {
-                                phase: "create".to_string(),
-                                message: error_msg.to_string(),
-                            });
-                        }
-                    }
-                }
-                error => {
-                    last_call.error = Some(format!("Error: {:?}", error));
-
-                    self.errors.push(ErrorInfo {
-                        phase: "create".to_string(),
-                        message: format!("EVM error: {:?}", error),
-                    });
-                }
-            }
+        if let Some(index) = self.call_stack.last() {
+            self.calls[*index].address = outcome.address;
         }
-
-        //log::debug!("outcome of end: {:?}", outcome);
+        self.exit(
+            "create",
+            outcome.result.result,
+            outcome.result.gas.spent(),
+            &outcome.result.output,
+        );
         outcome
     }
 
@@ -337,20 +773,21 @@ impl<DB: Database> revm::Inspector<DB> for RevmInspector {
         _context: &mut EvmContext<DB>,
         inputs: &mut EOFCreateInputs,
     ) -> Option<CreateOutcome> {
-        // Record the EOF create (similar to create)
-        let call_info = CallInfo {
+        self.enter(CallInfo {
+            depth: 0,
+            parent: None,
+            kind: CallKind::Create,
             caller: inputs.caller,
             address: None,
+            code_address: None,
             value: inputs.value,
             input: None,
             gas_limit: inputs.gas_limit,
             gas_used: None,
             output: None,
             error: None,
-        };
-
-        self.calls.push(call_info);
-
+            entries: Vec::new(),
+        });
         None
     }
 
@@ -360,62 +797,248 @@ impl<DB: Database> revm::Inspector<DB> for RevmInspector {
         _inputs: &EOFCreateInputs,
         outcome: CreateOutcome,
     ) -> CreateOutcome {
-        // Handle EOF create outcome (same structure as create_end)
-        if let Some(last_call) = self.calls.last_mut() {
-            last_call.gas_used = Some(outcome.result.gas.spent());
+        if let Some(index) = self.call_stack.last() {
+            self.calls[*index].address = outcome.address;
+        }
+        self.exit(
+            "eofcreate",
+            outcome.result.result,
+            outcome.result.gas.spent(),
+            &outcome.result.output,
+        );
+        outcome
+    }
+}
 
-            match &outcome.result.result {
-                InstructionResult::Return => {
-                    last_call.output = Some(outcome.result.output.clone());
-                }
-                InstructionResult::Revert => {
-                    last_call.output = Some(outcome.result.output.clone());
-                    last_call.error = Some("Reverted".to_string());
-
-                    // Try to decode the revert reason
-                    let output = &outcome.result.output;
-                    if output.len() >= 4 + 32 + 32 && output[0..4] == [0x08, 0xc3, 0x79, 0xa0] {
-                        let str_len =
-                            u32::from_be_bytes([output[36], output[37], output[38], output[39]])
-                                as usize;
-                        if output.len() >= 4 + 32 + 32 + str_len {
-                            let error_msg = String::from_utf8_lossy(
-                                &output[4 + 32 + 32..4 + 32 + 32 + str_len],
-                            );
-                            self.errors.push(ErrorInfo {
-                                phase: "eofcreate".to_string(),
-                                message: error_msg.to_string(),
-                            });
-                        }
-                    }
-                }
-                error => {
-                    last_call.error = Some(format!("Error: {:?}", error));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_sol_types::{Revert, SolError};
+    use revm::db::{CacheDB, EmptyDB};
+    use revm::primitives::{AccountInfo, Bytecode, TransactTo};
+    use revm::{inspector_handle_register, Evm};
 
-                    self.errors.push(ErrorInfo {
-                        phase: "eofcreate".to_string(),
-                        message: format!("EVM error: {:?}", error),
-                    });
-                }
-            }
+    const CALLER: Address = Address::repeat_byte(0xca);
+    const ENTRY: Address = Address::repeat_byte(0xaa);
+    const TOKEN: Address = Address::repeat_byte(0xbb);
+    const REVERTER: Address = Address::repeat_byte(0xcc);
+    const HOLDER: Address = Address::repeat_byte(0x11);
+
+    fn push_address(code: &mut Vec<u8>, address: Address) {
+        code.push(opcode::PUSH20);
+        code.extend_from_slice(address.as_slice());
+    }
+
+    /// `emit Transfer(ENTRY, HOLDER, 100)` from the executing contract
+    fn emit_transfer(code: &mut Vec<u8>) {
+        code.extend_from_slice(&[opcode::PUSH1, 100, opcode::PUSH1, 0, opcode::MSTORE]);
+        push_address(code, HOLDER);
+        push_address(code, ENTRY);
+        code.push(opcode::PUSH32);
+        code.extend_from_slice(Transfer::SIGNATURE_HASH.as_slice());
+        code.extend_from_slice(&[opcode::PUSH1, 32, opcode::PUSH1, 0, opcode::LOG3]);
+    }
+
+    /// `target.call{value}("")`, the result is dropped
+    fn call(code: &mut Vec<u8>, target: Address, value: u8) {
+        code.extend_from_slice(&[opcode::PUSH1, 0, opcode::PUSH1, 0, opcode::PUSH1, 0]);
+        code.extend_from_slice(&[opcode::PUSH1, 0, opcode::PUSH1, value]);
+        push_address(code, target);
+        code.extend_from_slice(&[opcode::GAS, opcode::CALL, opcode::POP]);
+    }
+
+    fn deploy(db: &mut CacheDB<EmptyDB>, address: Address, code: Vec<u8>, balance: u64) {
+        let code = Bytecode::new_raw(code.into());
+        db.insert_account_info(
+            address,
+            AccountInfo::new(U256::from(balance), 1, code.hash_slow(), code),
+        );
+    }
+
+    /// `revert(data)`, data is written to memory a word at a time
+    fn revert_with(code: &mut Vec<u8>, data: &[u8]) {
+        for (i, chunk) in data.chunks(32).enumerate() {
+            let mut word = [0u8; 32];
+            word[..chunk.len()].copy_from_slice(chunk);
+            code.push(opcode::PUSH32);
+            code.extend_from_slice(&word);
+            code.extend_from_slice(&[opcode::PUSH1, (i * 32) as u8, opcode::MSTORE]);
         }
+        code.extend_from_slice(&[opcode::PUSH1, data.len() as u8, opcode::PUSH1, 0]);
+        code.push(opcode::REVERT);
+    }
 
-        outcome
+    /// Revert with what the last call returned, like solidity does for a failed call
+    fn bubble(code: &mut Vec<u8>) {
+        code.extend_from_slice(&[opcode::RETURNDATASIZE, opcode::PUSH1, 0, opcode::PUSH1, 0]);
+        code.extend_from_slice(&[opcode::RETURNDATACOPY, opcode::RETURNDATASIZE]);
+        code.extend_from_slice(&[opcode::PUSH1, 0, opcode::REVERT]);
+    }
+
+    /// ENTRY sends 5 wei to TOKEN, which emits a transfer, then calls REVERTER, whose
+    /// transfer is rolled back
+    fn evm() -> Evm<'static, RevmInspector, CacheDB<EmptyDB>> {
+        let mut db = CacheDB::new(EmptyDB::default());
+
+        let mut entry = Vec::new();
+        call(&mut entry, TOKEN, 5);
+        call(&mut entry, REVERTER, 0);
+        entry.push(opcode::STOP);
+        deploy(&mut db, ENTRY, entry, 1_000);
+
+        let mut token = Vec::new();
+        emit_transfer(&mut token);
+        token.push(opcode::STOP);
+        deploy(&mut db, TOKEN, token, 0);
+
+        let mut reverter = Vec::new();
+        emit_transfer(&mut reverter);
+        reverter.extend_from_slice(&[opcode::PUSH1, 0, opcode::PUSH1, 0, opcode::REVERT]);
+        deploy(&mut db, REVERTER, reverter, 0);
+        build(db)
+    }
+
+    fn build(db: CacheDB<EmptyDB>) -> Evm<'static, RevmInspector, CacheDB<EmptyDB>> {
+        Evm::builder()
+            .with_db(db)
+            .with_external_context(RevmInspector::new())
+            .append_handler_register(inspector_handle_register)
+            .modify_tx_env(|tx| {
+                tx.caller = CALLER;
+                tx.transact_to = TransactTo::Call(ENTRY);
+                tx.gas_limit = 1_000_000;
+            })
+            .build()
+    }
+
+    #[test]
+    fn test_call_tree_and_deltas() {
+        let mut evm = evm();
+        evm.transact().unwrap();
+        let inspector = &evm.context.external;
+
+        let calls: Vec<(usize, Option<usize>, Option<Address>, bool)> = inspector
+            .calls
+            .iter()
+            .map(|call| (call.depth, call.parent, call.address, call.error.is_some()))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                (0, None, Some(ENTRY), false),
+                (1, Some(0), Some(TOKEN), false),
+                (1, Some(0), Some(REVERTER), true),
+            ]
+        );
+        assert_eq!(
+            inspector.calls[0].entries,
+            vec![TraceEntry::Call(1), TraceEntry::Call(2)]
+        );
+        assert_eq!(inspector.calls[1].entries, vec![TraceEntry::Log(0)]);
+
+        assert_eq!(
+            inspector.eth_deltas(),
+            BTreeMap::from([
+                (ENTRY, I256::try_from(-5).unwrap()),
+                (TOKEN, I256::try_from(5).unwrap())
+            ])
+        );
+        // the reverted frame's transfer doesn't count
+        assert_eq!(
+            inspector.token_deltas(),
+            BTreeMap::from([(
+                TOKEN,
+                BTreeMap::from([
+                    (ENTRY, I256::try_from(-100).unwrap()),
+                    (HOLDER, I256::try_from(100).unwrap())
+                ])
+            )])
+        );
+
+        let report = inspector.generate_report();
+        assert!(report.contains("├─ emit Transfer("));
+        assert!(report.contains("└─ ← [Revert]"));
+    }
+
+    #[test]
+    fn test_resets_between_transactions() {
+        let mut evm = evm();
+        evm.transact().unwrap();
+        evm.transact().unwrap();
+        let inspector = &evm.context.external;
+        assert_eq!(inspector.calls.len(), 3);
+        assert_eq!(inspector.logs.len(), 2);
+    }
+
+    #[test]
+    fn test_deepest_revert_skips_the_bubbling_frames() {
+        let router = get_address(AddressType::V2Router);
+        let mut db = CacheDB::new(EmptyDB::default());
+
+        // ENTRY -> TOKEN -> router, the router reverts and both callers bubble it up
+        let mut entry = Vec::new();
+        call(&mut entry, TOKEN, 0);
+        bubble(&mut entry);
+        deploy(&mut db, ENTRY, entry, 0);
+
+        let mut token = Vec::new();
+        call(&mut token, router, 0);
+        bubble(&mut token);
+        deploy(&mut db, TOKEN, token, 0);
+
+        let mut reverter = Vec::new();
+        let reason = Revert::from("INSUFFICIENT_OUTPUT_AMOUNT");
+        revert_with(&mut reverter, &reason.abi_encode());
+        deploy(&mut db, router, reverter, 0);
+
+        let mut evm = build(db);
+        evm.transact().unwrap();
+        let frame = evm.context.external.deepest_revert().unwrap();
+        assert_eq!(frame.index, 2);
+        assert_eq!(frame.depth, 2);
+        assert_eq!(frame.address, Some(router));
+        assert!(frame.gas_used > 0);
+        assert!(frame.to_string().starts_with(
+            "UniswapV2Router.fallback reverted: INSUFFICIENT_OUTPUT_AMOUNT at depth 2"
+        ));
+    }
+
+    #[test]
+    fn test_caught_revert_is_not_blamed() {
+        // REVERTER fails but ENTRY ignores it and returns
+        let mut evm = evm();
+        evm.transact().unwrap();
+        assert!(evm.context.external.calls[2].error.is_some());
+        assert!(evm.context.external.deepest_revert().is_none());
+    }
+
+    #[test]
+    fn test_known_selectors_are_named() {
+        let call = CallInfo {
+            depth: 0,
+            parent: None,
+            kind: CallKind::Call,
+            caller: CALLER,
+            address: Some(TOKEN),
+            code_address: None,
+            value: U256::ZERO,
+            input: Some(
+                IERC20::balanceOfCall { account: HOLDER }
+                    .abi_encode()
+                    .into(),
+            ),
+            gas_limit: 0,
+            gas_used: None,
+            output: None,
+            error: None,
+            entries: Vec::new(),
+        };
+        assert_eq!(call.function_name(), "balanceOf(address)");
+
+        let unknown = CallInfo {
+            input: Some(Bytes::from_static(&[0xde, 0xad, 0xbe, 0xef])),
+            ..call
+        };
+        assert_eq!(unknown.function_name(), "0xdeadbeef");
     }
-    //    #[inline]
-    //    fn step(&mut self, interp: &mut Interpreter, context: &mut EvmContext<DB>) {
-    //        info!("step Interpreter: {:?}", interp);
-    //    }
-    //
-    //    #[inline]
-    //    fn step_end(&mut self, interp: &mut Interpreter, context: &mut EvmContext<DB>) {
-    //        info!("step end interp: {:?}", interp);
-    //    }
-    //fn selfdestruct(&mut self, contract: Address, target: Address, value: U256) {
-    //    self.selfdestructs.push(SelfDestructInfo {
-    //        contract,
-    //        target,
-    //        value,
-    //    });
-    //}
 }
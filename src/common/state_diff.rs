@@ -0,0 +1,213 @@
+use alloy::eips::BlockNumberOrTag;
+use alloy::network::Ethereum;
+use alloy::providers::{Provider, RootProvider};
+use alloy::pubsub::PubSubFrontend;
+use alloy::rpc::types::trace::geth::{DiffMode, GethDebugTracingOptions, PreStateConfig};
+use anyhow::Result;
+use revm::db::{AccountState, CacheDB};
+use revm::primitives::{AccountInfo, Bytecode, U256};
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+struct TxStateDiff {
+    result: DiffMode,
+}
+
+/// Per transaction state diffs of a block, in execution order, from the prestate tracer
+pub async fn fetch_block_diffs(
+    provider: &RootProvider<PubSubFrontend, Ethereum>,
+    block_number: u64,
+) -> Result<Vec<DiffMode>> {
+    let options = GethDebugTracingOptions::prestate_tracer(PreStateConfig {
+        diff_mode: Some(true),
+        ..Default::default()
+    });
+    let traces: Vec<TxStateDiff> = provider
+        .raw_request(
+            "debug_traceBlockByNumber".into(),
+            (BlockNumberOrTag::Number(block_number), options),
+        )
+        .await?;
+    Ok(traces.into_iter().map(|trace| trace.result).collect())
+}
+
+/// Apply one transaction's diff to the accounts already in `cache`. Accounts the cache has
+/// never loaded are skipped, they are fetched at the new block when first read.
+///
+/// In diff mode an account only in `pre` was destroyed, and a slot only in `pre` was
+/// cleared to zero. Returns the number of accounts patched.
+pub fn apply_state_diff<ExtDB>(cache: &mut CacheDB<ExtDB>, diff: &DiffMode) -> usize {
+    let mut patched = 0;
+
+    for (address, pre) in &diff.pre {
+        let Some(post) = diff.post.get(address) else {
+            if let Some(account) = cache.accounts.get_mut(address) {
+                account.info = AccountInfo::default();
+                account.storage.clear();
+                account.account_state = AccountState::NotExisting;
+                patched += 1;
+            }
+            continue;
+        };
+        if let Some(account) = cache.accounts.get_mut(address) {
+            for slot in pre.storage.keys() {
+                if !post.storage.contains_key(slot) {
+                    account.storage.insert((*slot).into(), U256::ZERO);
+                }
+            }
+        }
+    }
+
+    for (address, post) in &diff.post {
+        if !cache.accounts.contains_key(address) {
+            continue;
+        }
+        let code = post.code.as_ref().map(|code| {
+            let bytecode = Bytecode::new_raw(code.clone());
+            let hash = bytecode.hash_slow();
+            cache.contracts.insert(hash, bytecode.clone());
+            (hash, bytecode)
+        });
+        let Some(account) = cache.accounts.get_mut(address) else {
+            continue;
+        };
+
+        if matches!(account.account_state, AccountState::NotExisting) {
+            // created in this block, every slot it didn't write is zero
+            account.account_state = AccountState::StorageCleared;
+        }
+        if let Some(balance) = post.balance {
+            account.info.balance = balance;
+        }
+        if let Some(nonce) = post.nonce {
+            account.info.nonce = nonce;
+        }
+        if let Some((hash, bytecode)) = code {
+            account.info.code_hash = hash;
+            account.info.code = Some(bytecode);
+        }
+        for (slot, value) in &post.storage {
+            account.storage.insert((*slot).into(), (*value).into());
+        }
+        patched += 1;
+    }
+
+    patched
+}
+
+/// Fetch the diffs for `block_number`, logging instead of failing so the caller can fall
+/// back to a full refetch on nodes without the debug namespace
+pub async fn try_fetch_block_diffs(
+    provider: Arc<RootProvider<PubSubFrontend, Ethereum>>,
+    block_number: u64,
+) -> Option<Vec<DiffMode>> {
+    fetch_block_diffs(&provider, block_number)
+        .await
+        .inspect_err(|err| log::debug!("No state diff for block {}: {:?}", block_number, err))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{Address, Bytes, B256};
+    use alloy::rpc::types::trace::geth::AccountState as TracedAccount;
+    use revm::db::EmptyDB;
+    use revm::primitives::KECCAK_EMPTY;
+    use std::collections::BTreeMap;
+
+    fn slot(n: u64) -> B256 {
+        U256::from(n).into()
+    }
+
+    fn traced(balance: Option<u64>, storage: &[(u64, u64)]) -> TracedAccount {
+        TracedAccount {
+            balance: balance.map(U256::from),
+            code: None,
+            nonce: None,
+            storage: storage
+                .iter()
+                .map(|(key, value)| (slot(*key), slot(*value)))
+                .collect(),
+        }
+    }
+
+    fn cache_with(address: Address) -> CacheDB<EmptyDB> {
+        let mut cache = CacheDB::new(EmptyDB::default());
+        cache.insert_account_info(
+            address,
+            AccountInfo::new(U256::from(10), 1, KECCAK_EMPTY, Bytecode::default()),
+        );
+        for (key, value) in [(1, 100), (2, 200), (3, 300)] {
+            cache
+                .insert_account_storage(address, U256::from(key), U256::from(value))
+                .unwrap();
+        }
+        cache
+    }
+
+    #[test]
+    fn test_patches_cached_accounts() {
+        let pool = Address::repeat_byte(0x10);
+        let unknown = Address::repeat_byte(0x20);
+        let mut cache = cache_with(pool);
+
+        let diff = DiffMode {
+            pre: BTreeMap::from([
+                (pool, traced(Some(10), &[(1, 100), (2, 200)])),
+                (unknown, traced(Some(1), &[])),
+            ]),
+            post: BTreeMap::from([
+                (pool, traced(Some(7), &[(1, 150), (4, 400)])),
+                (unknown, traced(Some(2), &[])),
+            ]),
+        };
+        assert_eq!(apply_state_diff(&mut cache, &diff), 1);
+
+        let account = &cache.accounts[&pool];
+        assert_eq!(account.info.balance, U256::from(7));
+        assert_eq!(account.info.nonce, 1);
+        assert_eq!(account.storage[&U256::from(1)], U256::from(150));
+        // only in pre, so it was zeroed
+        assert_eq!(account.storage[&U256::from(2)], U256::ZERO);
+        assert_eq!(account.storage[&U256::from(3)], U256::from(300));
+        assert_eq!(account.storage[&U256::from(4)], U256::from(400));
+        assert!(!cache.accounts.contains_key(&unknown));
+    }
+
+    #[test]
+    fn test_destroyed_and_created_accounts() {
+        let destroyed = Address::repeat_byte(0x10);
+        let created = Address::repeat_byte(0x11);
+        let mut cache = cache_with(destroyed);
+        cache
+            .accounts
+            .insert(created, revm::db::DbAccount::new_not_existing());
+
+        let code = Bytes::from_static(&[0x60, 0x00, 0x60, 0x00, 0xf3]);
+        let diff = DiffMode {
+            pre: BTreeMap::from([(destroyed, traced(Some(10), &[(1, 100)]))]),
+            post: BTreeMap::from([(
+                created,
+                TracedAccount {
+                    code: Some(code.clone()),
+                    ..traced(Some(5), &[(9, 9)])
+                },
+            )]),
+        };
+        assert_eq!(apply_state_diff(&mut cache, &diff), 2);
+
+        assert!(cache.accounts[&destroyed].info().is_none());
+        assert!(cache.accounts[&destroyed].storage.is_empty());
+
+        let account = &cache.accounts[&created];
+        assert!(account.account_state.is_storage_cleared());
+        assert_eq!(account.info.balance, U256::from(5));
+        assert_eq!(account.storage[&U256::from(9)], U256::from(9));
+        assert_eq!(
+            cache.contracts[&account.info.code_hash].original_bytes(),
+            code
+        );
+    }
+}
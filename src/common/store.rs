@@ -0,0 +1,560 @@
+use super::access_list::{AccessSet, RouteKey};
+use super::pools::{DexVariant, Pool};
+use anyhow::{anyhow, Result};
+use revm::primitives::{Address, U256};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::path::Path;
+
+/// Default location of the pool store, relative to the working directory
+pub const POOL_STORE_PATH: &str = "cache/pools.db";
+
+/// CSV caches written by older versions. `load_all_pools` used to write to a literal
+/// `~/cache` directory, which never expanded and ended up relative to the working directory.
+pub const LEGACY_CSV_PATHS: [&str; 2] = ["cache/.cached-pools.csv", "~/cache/.cached-pools.csv"];
+
+const LAST_SYNCED_BLOCK: &str = "last_synced_block";
+
+/// Schema migrations, `MIGRATIONS[n]` takes the store from `user_version` n to n + 1
+const MIGRATIONS: &[&str] = &[
+    // 1: pools with typed columns and the crawler checkpoint
+    "CREATE TABLE pools (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        address BLOB NOT NULL UNIQUE,
+        variant INTEGER NOT NULL CHECK (variant IN (2, 3)),
+        token0 BLOB NOT NULL,
+        token1 BLOB NOT NULL,
+        fee INTEGER NOT NULL,
+        tick_spacing INTEGER NOT NULL,
+        block_number INTEGER NOT NULL
+    );
+    CREATE INDEX pools_token0 ON pools (token0);
+    CREATE INDEX pools_token1 ON pools (token1);
+    CREATE TABLE sync_state (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );",
+    // 2: liquidity and token-quality filter results, `active` is NULL until a pool is checked
+    "ALTER TABLE pools ADD COLUMN active INTEGER;
+    ALTER TABLE pools ADD COLUMN tvl_weth REAL;
+    ALTER TABLE pools ADD COLUMN checked_block INTEGER;
+    CREATE INDEX pools_active ON pools (active);
+    CREATE TABLE tokens (
+        address BLOB PRIMARY KEY,
+        decimals INTEGER,
+        symbol TEXT,
+        valid INTEGER NOT NULL,
+        checked_block INTEGER NOT NULL
+    );",
+    // 3: slots the last simulation of each flash swap route touched, for its access list
+    "CREATE TABLE route_accesses (
+        pool BLOB NOT NULL,
+        token_in BLOB NOT NULL,
+        token_out BLOB NOT NULL,
+        address BLOB NOT NULL,
+        slot BLOB NOT NULL,
+        PRIMARY KEY (pool, token_in, token_out, address, slot)
+    );",
+];
+
+const POOL_COLUMNS: &str = "id, address, variant, token0, token1, fee, tick_spacing, block_number";
+
+/// Result of the liquidity filter for one pool
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolStatus {
+    pub address: Address,
+    pub active: bool,
+    /// WETH-denominated TVL in ether, for the V3 pools only the in-range liquidity
+    pub tvl_weth: f64,
+    pub checked_block: u64,
+}
+
+/// ERC20 metadata, `None` when the call reverted or returned garbage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub address: Address,
+    pub decimals: Option<u8>,
+    pub symbol: Option<String>,
+    pub valid: bool,
+    pub checked_block: u64,
+}
+
+/// SQLite backed pool store. Pools and the last crawled block are written in the same
+/// transaction, so a crawl that dies halfway resumes from the last complete chunk.
+pub struct PoolStore {
+    conn: Connection,
+}
+
+impl PoolStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        Self::migrate(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::migrate(Connection::open_in_memory()?)
+    }
+
+    fn migrate(mut conn: Connection) -> Result<Self> {
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(anyhow!(
+                "pool store schema version {} is newer than this binary ({})",
+                version,
+                MIGRATIONS.len()
+            ));
+        }
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+        }
+        Ok(Self { conn })
+    }
+
+    pub fn schema_version(&self) -> Result<usize> {
+        Ok(self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))?)
+    }
+
+    pub fn pool_count(&self) -> Result<usize> {
+        Ok(self
+            .conn
+            .query_row("SELECT COUNT(*) FROM pools", [], |row| row.get(0))?)
+    }
+
+    /// All pools in creation order, with `id` set to their row id
+    pub fn load_pools(&self) -> Result<Vec<Pool>> {
+        self.query_pools("TRUE")
+    }
+
+    /// Pools that passed the liquidity filter
+    pub fn load_active_pools(&self) -> Result<Vec<Pool>> {
+        self.query_pools("active = 1")
+    }
+
+    /// Pools the liquidity filter hasn't looked at yet
+    pub fn load_unchecked_pools(&self) -> Result<Vec<Pool>> {
+        self.query_pools("active IS NULL")
+    }
+
+    /// Pools the liquidity filter turned down at a block before `checked_before`
+    pub fn load_stale_inactive_pools(&self, checked_before: u64) -> Result<Vec<Pool>> {
+        self.query_pools(&format!(
+            "active = 0 AND checked_block < {}",
+            checked_before
+        ))
+    }
+
+    fn query_pools(&self, condition: &str) -> Result<Vec<Pool>> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT {POOL_COLUMNS} FROM pools WHERE {condition} ORDER BY block_number, id"
+        ))?;
+        let pools = statement
+            .query_map([], pool_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(pools)
+    }
+
+    pub fn set_pool_status(&mut self, statuses: &[PoolStatus]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut statement = tx.prepare_cached(
+                "UPDATE pools SET active = ?2, tvl_weth = ?3, checked_block = ?4 WHERE address = ?1",
+            )?;
+            for status in statuses {
+                statement.execute(params![
+                    status.address.as_slice(),
+                    status.active,
+                    status.tvl_weth,
+                    status.checked_block,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn load_tokens(&self) -> Result<Vec<TokenInfo>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT address, decimals, symbol, valid, checked_block FROM tokens")?;
+        let tokens = statement
+            .query_map([], |row| {
+                Ok(TokenInfo {
+                    address: address_column(row, 0)?,
+                    decimals: row.get(1)?,
+                    symbol: row.get(2)?,
+                    valid: row.get(3)?,
+                    checked_block: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(tokens)
+    }
+
+    pub fn upsert_tokens(&mut self, tokens: &[TokenInfo]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut statement = tx.prepare_cached(
+                "INSERT OR REPLACE INTO tokens (address, decimals, symbol, valid, checked_block)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for token in tokens {
+                statement.execute(params![
+                    token.address.as_slice(),
+                    token.decimals,
+                    token.symbol,
+                    token.valid,
+                    token.checked_block,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Replace the slots recorded for `route`, the last simulation wins
+    pub fn save_route_accesses(&mut self, route: &RouteKey, accesses: &AccessSet) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        let key = params![
+            route.pool.as_slice(),
+            route.token_in.as_slice(),
+            route.token_out.as_slice()
+        ];
+        tx.execute(
+            "DELETE FROM route_accesses WHERE pool = ?1 AND token_in = ?2 AND token_out = ?3",
+            key,
+        )?;
+        {
+            let mut statement = tx.prepare_cached(
+                "INSERT INTO route_accesses (pool, token_in, token_out, address, slot)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (address, slot) in accesses.slots() {
+                statement.execute(params![
+                    route.pool.as_slice(),
+                    route.token_in.as_slice(),
+                    route.token_out.as_slice(),
+                    address.as_slice(),
+                    slot.to_be_bytes::<32>().as_slice(),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn load_route_accesses(&self) -> Result<HashMap<RouteKey, AccessSet>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT pool, token_in, token_out, address, slot FROM route_accesses")?;
+        let rows = statement.query_map([], |row| {
+            let route = RouteKey {
+                pool: address_column(row, 0)?,
+                token_in: address_column(row, 1)?,
+                token_out: address_column(row, 2)?,
+            };
+            let slot: Vec<u8> = row.get(4)?;
+            Ok((route, address_column(row, 3)?, U256::from_be_slice(&slot)))
+        })?;
+        let mut routes: HashMap<RouteKey, AccessSet> = HashMap::new();
+        for row in rows {
+            let (route, address, slot) = row?;
+            routes.entry(route).or_default().insert(address, slot);
+        }
+        Ok(routes)
+    }
+
+    pub fn last_synced_block(&self) -> Result<Option<u64>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT value FROM sync_state WHERE key = ?1",
+                [LAST_SYNCED_BLOCK],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Insert pools that aren't known yet, returns how many were added
+    pub fn insert_pools(&mut self, pools: &[Pool]) -> Result<usize> {
+        let tx = self.conn.transaction()?;
+        let added = insert_pools(&tx, pools)?;
+        tx.commit()?;
+        Ok(added)
+    }
+
+    /// Insert the pools found in a crawled block range and move the checkpoint to its end
+    pub fn insert_synced_pools(&mut self, pools: &[Pool], synced_to: u64) -> Result<usize> {
+        let tx = self.conn.transaction()?;
+        let added = insert_pools(&tx, pools)?;
+        tx.execute(
+            "INSERT INTO sync_state (key, value) VALUES (?1, ?2)
+             ON CONFLICT (key) DO UPDATE SET value = MAX(value, excluded.value)",
+            params![LAST_SYNCED_BLOCK, synced_to],
+        )?;
+        tx.commit()?;
+        Ok(added)
+    }
+
+    /// One-off import of a legacy CSV cache. The checkpoint is set to the newest pool in it,
+    /// which is the block the old crawler would have resumed from.
+    pub fn import_csv(&mut self, path: &Path) -> Result<usize> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let mut pools = Vec::new();
+        for (line, row) in reader.records().enumerate() {
+            // a damaged row of an old cache only loses that pool, the crawler finds it again
+            match row
+                .map_err(anyhow::Error::from)
+                .and_then(|row| Pool::try_from(&row))
+            {
+                Ok(pool) => pools.push(repair_legacy_fee(pool)),
+                Err(err) => log::warn!("Skipping row {} of {:?}: {}", line + 1, path, err),
+            }
+        }
+        let synced_to = pools.iter().map(|pool| pool.block_number).max();
+        match synced_to {
+            Some(block) => self.insert_synced_pools(&pools, block),
+            None => Ok(0),
+        }
+    }
+}
+
+fn insert_pools(conn: &Connection, pools: &[Pool]) -> Result<usize> {
+    let mut statement = conn.prepare_cached(
+        "INSERT OR IGNORE INTO pools (address, variant, token0, token1, fee, tick_spacing, block_number)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    let mut added = 0;
+    for pool in pools {
+        added += statement.execute(params![
+            pool.address.as_slice(),
+            pool.version.num(),
+            pool.token0.as_slice(),
+            pool.token1.as_slice(),
+            pool.fee,
+            pool.tick_spacing,
+            pool.block_number,
+        ])?;
+    }
+    Ok(added)
+}
+
+fn address_column(row: &Row<'_>, index: usize) -> rusqlite::Result<Address> {
+    let bytes: Vec<u8> = row.get(index)?;
+    Address::try_from(bytes.as_slice()).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Blob, err.into())
+    })
+}
+
+fn pool_from_row(row: &Row<'_>) -> rusqlite::Result<Pool> {
+    let address = |index: usize| address_column(row, index);
+    let version = match row.get::<_, u8>(2)? {
+        3 => DexVariant::UniswapV3,
+        _ => DexVariant::UniswapV2,
+    };
+    Ok(Pool {
+        id: row.get(0)?,
+        address: address(1)?,
+        version,
+        token0: address(3)?,
+        token1: address(4)?,
+        fee: row.get(5)?,
+        tick_spacing: row.get(6)?,
+        block_number: row.get(7)?,
+    })
+}
+
+/// The old crawler decoded the first word of `PoolCreated` data as the fee, but the fee is
+/// indexed and that word is the tick spacing. Every fee tier has its own spacing, so map it back.
+fn repair_legacy_fee(mut pool: Pool) -> Pool {
+    if pool.version != DexVariant::UniswapV3 {
+        return pool;
+    }
+    let fee = match pool.fee {
+        1 => 100,
+        10 => 500,
+        60 => 3000,
+        200 => 10000,
+        _ => return pool,
+    };
+    pool.tick_spacing = pool.fee as i32;
+    pool.fee = fee;
+    pool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::pools::test_pool;
+    use std::io::Write;
+
+    #[test]
+    fn test_migrates_to_latest_version() {
+        let store = PoolStore::open_in_memory().unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        assert_eq!(store.pool_count().unwrap(), 0);
+        assert_eq!(store.last_synced_block().unwrap(), None);
+    }
+
+    #[test]
+    fn test_pools_round_trip_with_checkpoint() {
+        let mut store = PoolStore::open_in_memory().unwrap();
+        let pools = [
+            Pool {
+                block_number: 10,
+                ..test_pool(0x10, DexVariant::UniswapV2, 1, 2, 300)
+            },
+            Pool {
+                block_number: 12,
+                ..test_pool(0x11, DexVariant::UniswapV3, 1, 2, 3000)
+            },
+        ];
+        assert_eq!(store.insert_synced_pools(&pools, 20).unwrap(), 2);
+        // same pools again from an overlapping range
+        assert_eq!(store.insert_synced_pools(&pools, 15).unwrap(), 0);
+
+        let loaded = store.load_pools().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].address, pools[1].address);
+        assert_eq!(loaded[1].version, DexVariant::UniswapV3);
+        assert_eq!(loaded[1].fee, 3000);
+        assert_eq!(loaded[1].tick_spacing, 60);
+        // the checkpoint never moves backwards
+        assert_eq!(store.last_synced_block().unwrap(), Some(20));
+    }
+
+    #[test]
+    fn test_import_legacy_csv() {
+        let path = std::env::temp_dir().join(format!("pools-{}.csv", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "id,address,version,token0,token1,fee,block_number").unwrap();
+        writeln!(
+            file,
+            "0,{:?},2,{:?},{:?},300,100",
+            Address::repeat_byte(0x10),
+            Address::repeat_byte(1),
+            Address::repeat_byte(2)
+        )
+        .unwrap();
+        writeln!(
+            file,
+            "1,{:?},3,{:?},{:?},10,150",
+            Address::repeat_byte(0x11),
+            Address::repeat_byte(1),
+            Address::repeat_byte(2)
+        )
+        .unwrap();
+        writeln!(file, "2,not-an-address,2,,,300,200").unwrap();
+        drop(file);
+
+        let mut store = PoolStore::open_in_memory().unwrap();
+        let imported = store.import_csv(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(imported.unwrap(), 2);
+
+        let loaded = store.load_pools().unwrap();
+        assert_eq!(loaded[1].version, DexVariant::UniswapV3);
+        assert_eq!(loaded[1].fee, 500);
+        assert_eq!(loaded[1].tick_spacing, 10);
+        assert_eq!(store.last_synced_block().unwrap(), Some(150));
+    }
+
+    #[test]
+    fn test_pool_status_splits_active_and_unchecked() {
+        let mut store = PoolStore::open_in_memory().unwrap();
+        let pools = [
+            test_pool(0x10, DexVariant::UniswapV2, 1, 2, 300),
+            test_pool(0x11, DexVariant::UniswapV3, 1, 2, 3000),
+            test_pool(0x12, DexVariant::UniswapV3, 1, 2, 500),
+        ];
+        store.insert_pools(&pools).unwrap();
+        assert_eq!(store.load_unchecked_pools().unwrap().len(), 3);
+
+        store
+            .set_pool_status(&[
+                PoolStatus {
+                    address: pools[0].address,
+                    active: true,
+                    tvl_weth: 12.5,
+                    checked_block: 20,
+                },
+                PoolStatus {
+                    address: pools[1].address,
+                    active: false,
+                    tvl_weth: 0.01,
+                    checked_block: 20,
+                },
+            ])
+            .unwrap();
+
+        let active = store.load_active_pools().unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].address, pools[0].address);
+        let unchecked = store.load_unchecked_pools().unwrap();
+        assert_eq!(unchecked.len(), 1);
+        assert_eq!(unchecked[0].address, pools[2].address);
+
+        // only the inactive pool comes up for a re-check, once its verdict is old enough
+        assert!(store.load_stale_inactive_pools(20).unwrap().is_empty());
+        let stale = store.load_stale_inactive_pools(21).unwrap();
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].address, pools[1].address);
+    }
+
+    #[test]
+    fn test_route_accesses_round_trip() {
+        let mut store = PoolStore::open_in_memory().unwrap();
+        let route = RouteKey {
+            pool: Address::repeat_byte(0x10),
+            token_in: Address::repeat_byte(1),
+            token_out: Address::repeat_byte(2),
+        };
+        let mut accesses = AccessSet::default();
+        accesses.insert(route.pool, U256::from(8));
+        accesses.insert(route.token_in, U256::MAX);
+        store.save_route_accesses(&route, &accesses).unwrap();
+        assert_eq!(store.load_route_accesses().unwrap()[&route], accesses);
+
+        // a newer simulation replaces the old slots
+        let mut newer = AccessSet::default();
+        newer.insert(route.pool, U256::from(12));
+        store.save_route_accesses(&route, &newer).unwrap();
+        let loaded = store.load_route_accesses().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[&route], newer);
+    }
+
+    #[test]
+    fn test_tokens_round_trip() {
+        let mut store = PoolStore::open_in_memory().unwrap();
+        let token = TokenInfo {
+            address: Address::repeat_byte(1),
+            decimals: Some(18),
+            symbol: Some("WETH".to_string()),
+            valid: true,
+            checked_block: 20,
+        };
+        let broken = TokenInfo {
+            address: Address::repeat_byte(2),
+            decimals: None,
+            symbol: None,
+            valid: false,
+            checked_block: 20,
+        };
+        store
+            .upsert_tokens(&[token.clone(), broken.clone()])
+            .unwrap();
+        store.upsert_tokens(std::slice::from_ref(&token)).unwrap();
+
+        let mut tokens = store.load_tokens().unwrap();
+        tokens.sort_by_key(|token| token.address);
+        assert_eq!(tokens, vec![token, broken]);
+    }
+}
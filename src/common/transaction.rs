@@ -1,4 +1,7 @@
+use super::fees::TxFees;
 use alloy::{
+    consensus::TxEnvelope,
+    eips::eip2930::AccessList,
     network::{EthereumWallet, TransactionBuilder},
     primitives::{Address, U256},
     providers::{Provider, ProviderBuilder},
@@ -13,70 +16,70 @@ use log::info;
 use reqwest::Url;
 use std::str::FromStr;
 
-pub async fn send_transaction(
+/// Account that signs and pays for the arbitrage transactions, the one behind `PRIVATE_KEY`
+pub const SENDER: Address = address!("5f1F5565561aC146d24B102D9CDC288992Ab2938");
+
+/// The deployed executor contract, `CONTRACT_ADDRESS` from the environment
+pub fn executor_from_env() -> Result<Option<Address>> {
+    match var("CONTRACT_ADDRESS") {
+        Ok(address) => Ok(Some(Address::from_str(&address)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Sign the arbitrage transaction with `PRIVATE_KEY`
+pub async fn sign_transaction(
     contract_address: Address,
-    gas_price: Option<u128>,
-    gas_limit: Option<u64>,
-    base_fee: Option<u128>,
-    bribe: Option<u128>,
+    fees: TxFees,
+    gas_limit: u64,
     input: Vec<u8>,
     nonce: u64,
-) -> Result<()> {
-    let http_url = var::<&str>("HTTP_URL").unwrap();
-    let http_url = http_url.as_str();
-
-    let private_key = var("PRIVATE_KEY").unwrap();
-    let signer = PrivateKeySigner::from_str(&private_key).unwrap();
-    let wallet = EthereumWallet::from(signer.clone());
-
-    let http_url = Url::from_str(http_url).unwrap();
-    let provider = ProviderBuilder::new()
-        .with_recommended_fillers()
-        .wallet(wallet.clone())
-        .on_http(http_url);
+    access_list: Option<AccessList>,
+) -> Result<TxEnvelope> {
+    let private_key = var("PRIVATE_KEY")?;
+    let signer = PrivateKeySigner::from_str(&private_key)?;
+    let wallet = EthereumWallet::from(signer);
 
     let input_as_bytes = revm::primitives::Bytes::from(input);
 
     info!(
-        "Sending transaction with parameters:\n\
+        "Signing transaction with parameters:\n\
         contract_address: {}\n\
-        gas_price: {:?}\n\
-        gas_limit: {:?}\n\
-        base_fee: {:?}\n\
-        bribe: {:?}\n\
+        max_fee_per_gas: {}\n\
+        max_priority_fee_per_gas: {}\n\
+        gas_limit: {}\n\
         nonce: {}",
-        contract_address,
-        gas_price,
-        gas_limit,
-        base_fee,
-        bribe.unwrap(),
-        nonce
+        contract_address, fees.max_fee_per_gas, fees.max_priority_fee_per_gas, gas_limit, nonce
     );
-    //NOTE:  gas limit should be the amount of gas that was simulated for hte transaction to have taken up
 
     let tx = TransactionRequest::default()
-        .with_from(address!("5f1F5565561aC146d24B102D9CDC288992Ab2938"))
+        .with_from(SENDER)
         .with_chain_id(1)
         .with_value(U256::ZERO)
         .with_input(input_as_bytes)
         .with_to(contract_address)
         .with_nonce(nonce)
-        // NOTE: this should be gas price?
-        .with_max_fee_per_gas(base_fee.unwrap())
-        // NOTE: This too
-        .with_max_priority_fee_per_gas(bribe.unwrap())
-        .with_gas_limit(gas_limit.unwrap());
+        .with_max_fee_per_gas(fees.max_fee_per_gas)
+        .with_max_priority_fee_per_gas(fees.max_priority_fee_per_gas)
+        .with_gas_limit(gas_limit)
+        // slots learned from the simulation are paid for warm
+        .with_access_list(access_list.unwrap_or_default());
 
     info!("TX: {:?}", tx);
 
     let envelope = tx.build(&wallet).await?;
+    info!("Signed TX Hash: {:?}", envelope.tx_hash());
+    Ok(envelope)
+}
 
-    info!("Pending TX Hash: {:?}", envelope.tx_hash());
+/// Send a signed transaction through the public mempool, then wait for it to be mined
+pub async fn send_transaction(envelope: TxEnvelope) -> Result<()> {
+    let http_url = Url::from_str(&var("HTTP_URL")?)?;
+    let provider = ProviderBuilder::new().on_http(http_url);
 
     let pending = provider
         .send_tx_envelope(envelope)
-        .await
-        .unwrap()
+        .await?
         .with_timeout(Some(std::time::Duration::from_secs_f32(20_f32)));
 
     let res = pending.watch().await?;
@@ -15,7 +15,8 @@ use crate::arbitrage::simulation::{
     arboo_bytecode, check_weth_balance, five_hundred_thousand_eth, get_address, one_hundred_ether,
     one_thousand_eth, simulation, AddressType,
 };
-use crate::arbitrage::strategy::find_optimal_amount_v3_to_v2;
+use crate::arbitrage::strategy::{find_optimal_amount_v3_to_v2, FlashSwapParams};
+use crate::common::fees::predict_base_fee;
 use crate::common::revm::Tx;
 use alloy_primitives::aliases::U24;
 use alloy_primitives::{address, U160, U256, U64};
@@ -83,7 +84,7 @@ pub async fn sim_test(
         gas_price: latest_gas_price,
     };
 
-    simulator.lock().await.call(new_tx)?;
+    simulator.lock().await.call(new_tx).into_result()?;
 
     alloy::sol! {
         function approve(address spender, uint256 amount) external returns (bool);
@@ -103,7 +104,7 @@ pub async fn sim_test(
         gas_price: latest_gas_price,
     };
 
-    simulator.lock().await.call(approve_tx)?;
+    simulator.lock().await.call(approve_tx).into_result()?;
 
     alloy::sol! {
         interface ISwapRouter {
@@ -148,23 +149,30 @@ pub async fn sim_test(
         .lock()
         .await
         .call(big_swap_tx)
+        .into_result()
         .inspect_err(|e| info!("Error running Link Swap: {:?}", e))?;
 
     let instant = std::time::Instant::now();
 
-    find_optimal_amount_v3_to_v2(
-        address!("514910771AF9Ca656af840dff83E8264EcF986CA"),
-        address!("c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
-        simulator.clone(),
-        U256::MAX / U256::from(2),
-        U24::from(3000),
-        latest_block,
-        address!("a6Cc3C2531FdaA6Ae1A3CA84c2855806728693e8"),
-        provider.clone(),
-    )
-    .await?;
+    let gas_price = U256::from(predict_base_fee(&latest_block));
+    let params = FlashSwapParams {
+        token_in: address!("514910771AF9Ca656af840dff83E8264EcF986CA"),
+        token_out: address!("c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+        fee: U24::from(3000),
+        v3_pool: address!("a6Cc3C2531FdaA6Ae1A3CA84c2855806728693e8"),
+        v2_pool: address!("a2107FA5B38d9bbd2C461D6EDf11B11A50F6b974"),
+        gas_price,
+    };
+    let result = find_optimal_amount_v3_to_v2(params, simulator.clone(), provider.clone()).await?;
 
-    info!("Time taken to run sim: {:?}", instant.elapsed());
+    info!(
+        "Time taken to run sim: {:?}, optimal amount {} for profit {} (net {}) after {} iterations",
+        instant.elapsed(),
+        result.optimal_amount,
+        result.possible_profit,
+        result.net_profit,
+        result.iterations
+    );
 
     Ok(())
 }
@@ -1,30 +1,26 @@
 use alloy::providers::{Provider, ProviderBuilder, RootProvider};
-use alloy::{
-    network::Ethereum, primitives::U64, pubsub::PubSubFrontend, rpc::client::WsConnect,
-    signers::local::PrivateKeySigner,
-};
+use alloy::{network::Ethereum, primitives::U64, pubsub::PubSubFrontend, rpc::client::WsConnect};
 use anyhow::Result;
-use arbooo::arbitrage::strategy::strategy;
+use arbooo::arbitrage::graph::{RouteCandidate, TokenGraph};
+use arbooo::arbitrage::strategy::{backrun_strategy, route_strategy, strategy};
+use arbooo::common::bundle::BundleSubmitter;
+use arbooo::common::discovery;
+use arbooo::common::filter::{self, FilterConfig};
 use arbooo::common::logger;
 use arbooo::common::logs;
+use arbooo::common::mempool::{self, Backrun};
 use arbooo::common::pools;
-use arbooo::common::{
-    logs::LogEvent,
-    pairs::{Event, V2PoolCreated, V3PoolCreated},
-    revm::EvmSimulator,
-};
+use arbooo::common::revm::{self, EvmSimulator};
+use arbooo::common::store::{PoolStore, POOL_STORE_PATH};
+use arbooo::common::transaction::{executor_from_env, SENDER};
+use arbooo::common::{logs::LogEvent, registry::PoolRegistry};
 use dotenv::dotenv;
 use dotenv::var;
 use log::info;
-use revm::primitives::Address;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, BufRead};
 use std::path::Path;
-use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::broadcast::{self, Sender};
-use tokio::sync::Mutex as TokioMutex;
+use tokio::sync::{Mutex as TokioMutex, RwLock};
 use tokio::task::JoinSet;
 
 #[tokio::main]
@@ -38,63 +34,81 @@ async fn main() -> Result<()> {
     let provider = ProviderBuilder::new().on_ws(ws_client).await.unwrap();
     let provider = Arc::new(provider);
 
-    if !Path::new("cache/.cached-pools.csv").try_exists()? {
-        info!("Cache doesn't exist, crawling blocks for pools");
-        pools::load_all_pools(ws_url, 100_000, 50_000)
-            .await
-            .unwrap();
+    // 1. Get all pools, crawling whatever was created since the last run
+    let all_pools = pools::load_all_pools(ws_url, 100_000, 50_000).await?;
+
+    // 2. Drop dust pools and broken tokens, only pools that pass are traded
+    let mut store = PoolStore::open(Path::new(POOL_STORE_PATH))?;
+    // pools turned down a while ago are checked again, liquidity may have been added since
+    let config = FilterConfig::from_env();
+    let head = provider.get_block_number().await?;
+    let mut to_check = store.load_unchecked_pools()?;
+    let checked_before = head.saturating_sub(config.recheck_blocks);
+    to_check.extend(store.load_stale_inactive_pools(checked_before)?);
+    if !to_check.is_empty() {
+        info!("Checking liquidity of {} pools", to_check.len());
+        filter::filter_pools(
+            provider.clone(),
+            &mut store,
+            &PoolRegistry::from_pools(all_pools),
+            to_check,
+            config,
+        )
+        .await?;
     }
+    let registry = PoolRegistry::from_pools(store.load_active_pools()?);
+    let (v2_pools, v3_pools) = registry.variant_counts();
+    info!("Pools loaded: {} V2, {} V3", v2_pools, v3_pools);
+    let registry = Arc::new(RwLock::new(registry));
 
     let mut set = JoinSet::new();
 
     let (sender, _): (Sender<LogEvent>, _) = broadcast::channel(512);
-
-    // 1. Get all pools
-
-    let mut pools_map: HashMap<Address, Event> = HashMap::new();
-    let path = Path::new("~/cache/.cached-pools.csv");
-    let file = File::open(path)?;
-    let reader = io::BufReader::new(file);
-    // id,address,version,token0,oken1,fee,block_number,timestamp
-    for line in reader.lines().skip(1) {
-        // Skip the header line
-        let line = line?;
-        let fields: Vec<&str> = line.split(',').collect();
-
-        match fields[2] {
-            "2" => {
-                let pair_address = Address::from_str(fields[1]).unwrap();
-                pools_map.insert(
-                    pair_address,
-                    Event::PairCreated(V2PoolCreated {
-                        pair_address: Address::from_str(fields[1]).unwrap(),
-                        token0: Address::from_str(fields[3]).unwrap(),
-                        token1: Address::from_str(fields[4]).unwrap(),
-                        fee: fields[5].parse::<u32>().unwrap(),
-                        block_number: fields[6].parse::<u64>().unwrap(),
-                    }),
-                );
+    let (route_sender, _): (Sender<RouteCandidate>, _) = broadcast::channel(512);
+    let (backrun_sender, _): (Sender<Backrun>, _) = broadcast::channel(512);
+
+    let graph = TokenGraph::from_pools(registry.read().await.iter());
+    info!(
+        "Token graph: {} tokens, {} pools",
+        graph.token_count(),
+        graph.pool_count()
+    );
+    let graph = Arc::new(RwLock::new(graph));
+
+    set.spawn({
+        let (provider, registry, graph) = (provider.clone(), registry.clone(), graph.clone());
+        async move {
+            let config = FilterConfig::from_env();
+            if let Err(err) = discovery::watch_new_pools(provider, registry, graph, config).await {
+                info!("Pool discovery stopped: {:?}", err);
             }
-            "3" => {
-                let pair_address = Address::from_str(fields[1]).unwrap();
-                pools_map.insert(
-                    pair_address,
-                    Event::PoolCreated(V3PoolCreated {
-                        pair_address: Address::from_str(fields[1]).unwrap(),
-                        token0: Address::from_str(fields[3]).unwrap(),
-                        token1: Address::from_str(fields[4]).unwrap(),
-                        fee: fields[5].parse::<u32>().unwrap(),
-                        tick_spacing: 0i32,
-                    }),
-                );
+        }
+    });
+
+    // 3. Listen for logs on pools
+    set.spawn(logs::get_logs(
+        provider.clone(),
+        registry.clone(),
+        sender.clone(),
+        graph.clone(),
+        route_sender.clone(),
+    ));
+
+    // backruns only go out as bundles, the pending swaps aren't needed without relays
+    if BundleSubmitter::from_env()?.is_some() {
+        set.spawn({
+            let (provider, registry, graph) = (provider.clone(), registry.clone(), graph.clone());
+            let backrun_sender = backrun_sender.clone();
+            async move {
+                if let Err(err) =
+                    mempool::watch_pending_swaps(provider, registry, graph, backrun_sender).await
+                {
+                    info!("Pending swap watcher stopped: {:?}", err);
+                }
             }
-            &_ => continue,
-        };
+        });
     }
 
-    // 2. Listen for logs on pools
-    set.spawn(logs::get_logs(provider.clone(), pools_map, sender.clone()));
-
     let ws_client = WsConnect::new(std::env::var("WS_URL").expect("no ws url"));
 
     let provider: RootProvider<PubSubFrontend, Ethereum> = ProviderBuilder::new()
@@ -110,22 +124,53 @@ async fn main() -> Result<()> {
         .await
         .expect("Error getting block number");
 
-    let contract_wallet = PrivateKeySigner::random();
-    let contract_wallet_address = contract_wallet.address();
-
-    let simulator = EvmSimulator::new(
+    // simulate as the real sender and executor, so the slots recorded for the access lists
+    // are the ones the sent transactions touch
+    let mut simulator = EvmSimulator::new(
         provider.clone(),
-        Some(contract_wallet_address),
+        Some(SENDER),
         U64::from(latest_block_number),
     );
+    if let Some(executor) = executor_from_env()? {
+        simulator.contract_address = executor;
+    }
 
     let simulator: Arc<TokioMutex<EvmSimulator<'_>>> = Arc::new(TokioMutex::new(simulator));
 
+    let route_provider = provider.clone();
+    set.spawn(async move {
+        if let Err(err) = route_strategy(route_sender, route_provider).await {
+            info!("Route strategy stopped: {:?}", err);
+        }
+    });
+
     info!("Spawning evm");
 
-    strategy(sender, simulator.clone(), provider.clone())
-        .await
-        .unwrap();
+    // the simulator isn't Send, so the block sync runs on this task next to the strategy
+    let (strategy_result, backrun_result, sync_result) = tokio::join!(
+        strategy(
+            sender,
+            simulator.clone(),
+            provider.clone(),
+            registry.clone(),
+            graph.clone()
+        ),
+        backrun_strategy(
+            backrun_sender,
+            simulator.clone(),
+            provider.clone(),
+            registry,
+            graph
+        ),
+        revm::sync_blocks(simulator.clone(), provider.clone()),
+    );
+    if let Err(err) = backrun_result {
+        info!("Backrun strategy stopped: {:?}", err);
+    }
+    if let Err(err) = sync_result {
+        info!("Block sync stopped: {:?}", err);
+    }
+    strategy_result.unwrap();
 
     while let Some(res) = set.join_next().await {
         info!("{:?}", res);